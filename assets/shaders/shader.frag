@@ -1,10 +1,77 @@
 #version 450
+#ifdef BINDLESS_TEXTURES
+#extension GL_EXT_nonuniform_qualifier : require
+#endif
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    ivec4 origin;
+    vec4 sun;
+} ubo;
+
+#ifdef BINDLESS_TEXTURES
+// one texture per tile, the size of the array is set when the descriptor sets are allocated
+layout(binding = 3) uniform sampler2D textures[];
+#else
+layout(binding = 1) uniform sampler2D atlas;
+#endif
 
 layout(location = 0) in vec3 fragColor;
+layout(location = 1) flat in uint fragFace;
+layout(location = 2) in vec2 fragUv;
+layout(location = 3) flat in uint fragTile;
 
 layout(location = 0) out vec4 outColor;
 
+// face index of the vertices to their normal
+const vec3 NORMALS[6] = vec3[](
+    vec3(1.0, 0.0, 0.0),
+    vec3(0.0, 1.0, 0.0),
+    vec3(0.0, 0.0, 1.0),
+    vec3(-1.0, 0.0, 0.0),
+    vec3(0.0, -1.0, 0.0),
+    vec3(0.0, 0.0, -1.0)
+);
+const uint UNLIT = 6;
+// must match ATLAS_TILES_PER_ROW in config.rs
+const uint ATLAS_TILES_PER_ROW = 4;
+
+#ifdef BINDLESS_TEXTURES
+// the sampler repeats the tile over the merged faces, there are no neighbor tiles to bleed in
+vec4 sampleTile() {
+    // the tile can change within a draw and so within a subgroup
+    return texture(textures[nonuniformEXT(fragTile)], fragUv);
+}
+#else
+// the uv repeat the tile of the block over the merged faces
+vec4 sampleTile() {
+    vec2 tile = vec2(fragTile % ATLAS_TILES_PER_ROW, fragTile / ATLAS_TILES_PER_ROW);
+    // kept half a texel inside the tile so the neighbor tiles don't bleed in, the texels
+    // of the next mip level are the largest the linear filtering between levels reads
+    float level = ceil(textureQueryLod(atlas, fragUv / float(ATLAS_TILES_PER_ROW)).x);
+    float halfTexel = min(
+        0.5 * exp2(level) * float(ATLAS_TILES_PER_ROW) / float(textureSize(atlas, 0).x),
+        0.5
+    );
+    vec2 uv = clamp(fract(fragUv), halfTexel, 1.0 - halfTexel);
+    // the gradients of the continuous uv, fract would make them jump at the block edges
+    return textureGrad(
+        atlas,
+        (tile + uv) / float(ATLAS_TILES_PER_ROW),
+        dFdx(fragUv) / float(ATLAS_TILES_PER_ROW),
+        dFdy(fragUv) / float(ATLAS_TILES_PER_ROW)
+    );
+}
+#endif
+
 void main() {
-    outColor = vec4(fragColor, 1.0);
+    vec4 color = vec4(fragColor, 1.0);
+    if (fragFace != UNLIT) {
+        float lambert = max(dot(NORMALS[fragFace], ubo.sun.xyz), 0.0);
+        color *= sampleTile();
+        color.rgb *= ubo.sun.w + (1.0 - ubo.sun.w) * lambert;
+    }
+    // only used by the blending of the transparent blocks
+    outColor = color;
 }
-  
\ No newline at end of file
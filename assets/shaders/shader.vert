@@ -3,15 +3,41 @@
 layout(binding = 0) uniform UniformBufferObject {
     mat4 view;
     mat4 proj;
+    ivec4 origin;
+    vec4 sun;
 } ubo;
 
-layout(location = 0) in ivec3 inPosition;
+// origin of the chunk being drawn, the vertices are relative to it
+layout(push_constant) uniform DrawConstants {
+    ivec4 origin;
+} draw;
+
+// x | y << 10 | z << 20 | ao << 30
+layout(location = 0) in uint inPositionAo;
 layout(location = 1) in lowp uvec3 inColor;
-layout(location = 2) in lowp uint lightModifier;
+layout(location = 2) in lowp uint inFace;
+// u | v << 10 | tile << 20
+layout(location = 3) in uint inUvTile;
 
 layout(location = 0) out vec3 fragColor;
+layout(location = 1) flat out uint fragFace;
+layout(location = 2) out vec2 fragUv;
+layout(location = 3) flat out uint fragTile;
 
 void main() {
-    gl_Position = ubo.proj * ubo.view * vec4(inPosition, 1.0);
-    fragColor = (vec3(inColor) / 256.0) * (lightModifier / 10.0);
+    ivec3 position = ivec3(
+        inPositionAo & 0x3FFu,
+        (inPositionAo >> 10) & 0x3FFu,
+        (inPositionAo >> 20) & 0x3FFu
+    );
+    uint ao = inPositionAo >> 30;
+    // relative to the origin in integers so the floats stay small
+    gl_Position = ubo.proj * ubo.view * vec4(position + (draw.origin.xyz - ubo.origin.xyz), 1.0);
+    // the sun light is applied per face in the fragment shader
+    fragColor = (vec3(inColor) / 256.0) * (0.4 + 0.2 * ao);
+    fragFace = inFace;
+    fragUv = vec2(inUvTile & 0x3FFu, (inUvTile >> 10) & 0x3FFu);
+    fragTile = inUvTile >> 20;
+    // only used by the points pipeline
+    gl_PointSize = 2.0;
 }
@@ -1,38 +1,40 @@
 use std::{
     alloc::{dealloc, Layout},
+    collections::VecDeque,
     sync::{Arc, Mutex},
 };
 
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use criterion::{criterion_group, BatchSize, BenchmarkId, Criterion, Throughput};
 use pretty_env_logger::env_logger::Target;
 use vulkan_voxels::{
+    config::{ALLOC_CHUNK_SIZE, ALLOC_DEDICATED_THRESHOLD},
     render::{
-        memory::{AllocRequirements, AllocUsage, Allocator, Block},
+        buffer::BufferPool,
+        memory::{AllocRequirements, AllocStrategy, AllocUsage, Allocator, Block},
         vertex::Vertex,
         Renderer,
     },
-    world::{Chunk, ChunkPos},
+    threads::MeshingThreadPool,
+    world::{
+        generation::{DemoGenerator, TerrainGenerator},
+        Chunk, ChunkPos, ChunkState,
+    },
 };
 
 extern crate alloc;
 use alloc::alloc::alloc;
 
 use lazy_static::lazy_static;
+use rand::Rng;
 use vulkanalia::{
     loader::{LibloadingLoader, LIBRARY},
     vk::{self, DeviceV1_0, HasBuilder},
     Entry,
 };
-use winit::{event_loop::EventLoop, window::WindowBuilder};
-
-unsafe fn create_renderer() -> Arc<Mutex<Renderer>> {
-    let event_loop = EventLoop::new();
-    let window = WindowBuilder::new()
-        .with_visible(false)
-        .build(&event_loop)
-        .unwrap();
 
-    Arc::new(Mutex::new(Renderer::new(&window, &ENTRY)))
+// headless so the benches run without a display server
+unsafe fn create_renderer() -> Mutex<Option<Renderer>> {
+    Mutex::new(Some(Renderer::new_headless(&ENTRY).unwrap()))
 }
 
 lazy_static! {
@@ -40,7 +42,8 @@ lazy_static! {
         let loader = LibloadingLoader::new(LIBRARY).unwrap();
         Entry::new(loader).unwrap()
     };
-    static ref RENDERER: Arc<Mutex<Renderer>> = {
+    // taken in main after the benches so the renderer is dropped before exiting
+    static ref RENDERER: Mutex<Option<Renderer>> = {
         pretty_env_logger::formatted_builder()
             .target(Target::Stdout)
             .init();
@@ -49,9 +52,17 @@ lazy_static! {
     };
 }
 
+// the same blocks in every chunk whatever the noise
+fn demo_chunk(pos: ChunkPos) -> Chunk {
+    let mut chunk = Chunk::new(pos);
+    DemoGenerator.generate(pos, &mut chunk);
+    chunk.state = ChunkState::Generated;
+    chunk
+}
+
 fn chunk_bench(c: &mut Criterion) {
     c.bench_function("Mesh chunk", |b| unsafe {
-        let mut chunk = Chunk::new(ChunkPos { x: 0, y: 0, z: 0 }).unwrap();
+        let mut chunk = demo_chunk(ChunkPos { x: 0, y: 0, z: 0 });
         let layout = Layout::new::<[Vertex; 22000]>();
         let buff1 = alloc(layout);
         let buff2 = alloc(layout);
@@ -60,6 +71,7 @@ fn chunk_bench(c: &mut Criterion) {
                 .mesh(
                     std::slice::from_raw_parts_mut(buff1.cast(), 22000),
                     std::slice::from_raw_parts_mut(buff2.cast(), 22000),
+                    0,
                 )
                 .unwrap();
         });
@@ -68,9 +80,48 @@ fn chunk_bench(c: &mut Criterion) {
     });
 }
 
+fn remesh_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Remesh after edit");
+    group.bench_function("Full chunk", |b| unsafe {
+        let mut chunk = demo_chunk(ChunkPos { x: 0, y: 0, z: 0 });
+        let layout = Layout::new::<[Vertex; 22000]>();
+        let buff1 = alloc(layout);
+        let buff2 = alloc(layout);
+        b.iter(|| {
+            chunk.mark_dirty(5);
+            chunk
+                .mesh_full(
+                    std::slice::from_raw_parts_mut(buff1.cast(), 22000),
+                    std::slice::from_raw_parts_mut(buff2.cast(), 22000),
+                )
+                .unwrap();
+        });
+        dealloc(buff1, layout);
+        dealloc(buff2, layout);
+    });
+    group.bench_function("Section", |b| unsafe {
+        let mut chunk = demo_chunk(ChunkPos { x: 0, y: 0, z: 0 });
+        let layout = Layout::new::<[Vertex; 22000]>();
+        let buff1 = alloc(layout);
+        let buff2 = alloc(layout);
+        b.iter(|| {
+            chunk.mark_dirty(5);
+            chunk
+                .mesh_sections(
+                    std::slice::from_raw_parts_mut(buff1.cast(), 22000),
+                    std::slice::from_raw_parts_mut(buff2.cast(), 22000),
+                )
+                .unwrap();
+        });
+        dealloc(buff1, layout);
+        dealloc(buff2, layout);
+    });
+    group.finish();
+}
+
 fn alloc_bench(c: &mut Criterion) {
-    RENDERER.as_ref();
     let renderer = RENDERER.lock().unwrap();
+    let renderer = renderer.as_ref().unwrap();
     let data = renderer.data.read().unwrap();
 
     static KB: usize = 1024;
@@ -80,8 +131,16 @@ fn alloc_bench(c: &mut Criterion) {
     for size in [16 * KB, 128 * KB, KB * KB, 16 * KB * KB].iter() {
         group.throughput(Throughput::Bytes(*size as u64));
         group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| unsafe {
-            let mut allocator =
-                Allocator::new(&data.device, &data.instance, data.physical_device.device);
+            let mut allocator = Allocator::new(
+                &data.device,
+                &data.instance,
+                data.physical_device.device,
+                data.api_version,
+                AllocStrategy::default(),
+                ALLOC_CHUNK_SIZE,
+                ALLOC_DEDICATED_THRESHOLD,
+            )
+            .unwrap();
             let info = vk::BufferCreateInfo::builder()
                 .size(size as u64)
                 .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
@@ -109,5 +168,130 @@ fn alloc_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, chunk_bench, alloc_bench);
-criterion_main!(benches);
+// chunks loading and unloading while flying: each iteration frees the oldest buffer and gets a new one
+fn buffer_pool_bench(c: &mut Criterion) {
+    let renderer = RENDERER.lock().unwrap();
+    let data = renderer.as_ref().unwrap().data.read().unwrap();
+
+    const LIVE_BUFFERS: usize = 256;
+    let usage = vk::BufferUsageFlags::VERTEX_BUFFER
+        | vk::BufferUsageFlags::INDEX_BUFFER
+        | vk::BufferUsageFlags::TRANSFER_DST;
+
+    let mut group = c.benchmark_group("Chunk buffers streaming");
+    for max_per_bucket in [0, 32] {
+        let name = if max_per_bucket == 0 {
+            "Allocator"
+        } else {
+            "Pool"
+        };
+        group.bench_function(name, |b| unsafe {
+            let mut rng = rand::thread_rng();
+            let mut pool = BufferPool::new(max_per_bucket);
+            let mut live = VecDeque::with_capacity(LIVE_BUFFERS);
+            b.iter(|| {
+                if live.len() == LIVE_BUFFERS {
+                    pool.give_back(live.pop_front().unwrap());
+                }
+                let size = rng.gen_range(16 * 1024..256 * 1024);
+                live.push_back(
+                    pool.get(&data, size, usage, AllocUsage::DeviceLocal)
+                        .unwrap(),
+                );
+            });
+            live.clear();
+            pool.clear();
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    chunk_bench,
+    remesh_bench,
+    alloc_bench,
+    buffer_pool_bench,
+    load_bench
+);
+// generation, meshing and upload of chunks through the meshing threads, like when streaming
+fn load_bench(c: &mut Criterion) {
+    let renderer = RENDERER.lock().unwrap();
+    let renderer = renderer.as_ref().unwrap();
+    let mut pool = MeshingThreadPool::new();
+    unsafe { pool.start_threads(renderer.data.clone()) };
+
+    fn positions(count: usize) -> impl Iterator<Item = ChunkPos> {
+        (0..count).map(|i| ChunkPos {
+            x: (i % 16) as i32,
+            y: 0,
+            z: (i / 16) as i32,
+        })
+    }
+
+    fn generate(count: usize) -> Vec<Arc<Mutex<Chunk>>> {
+        positions(count)
+            .map(|pos| {
+                let mut chunk = demo_chunk(pos);
+                chunk.state = ChunkState::Meshing;
+                Arc::new(Mutex::new(chunk))
+            })
+            .collect()
+    }
+
+    fn mesh_and_upload(pool: &MeshingThreadPool, chunks: &[Arc<Mutex<Chunk>>]) {
+        for chunk in chunks {
+            let pos = chunk.lock().unwrap().pos;
+            pool.mesh_thread(Arc::downgrade(chunk), pos);
+        }
+        let mut done = 0;
+        while done < chunks.len() {
+            done += pool.try_iter().count();
+            std::thread::yield_now();
+        }
+    }
+
+    let mut group = c.benchmark_group("Load pipeline");
+    for count in [16, 64, 256] {
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(
+            BenchmarkId::new("Generation", count),
+            &count,
+            |b, &count| {
+                b.iter(|| generate(count));
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("Mesh and upload", count),
+            &count,
+            |b, &count| {
+                b.iter_batched(
+                    || generate(count),
+                    |chunks| {
+                        mesh_and_upload(&pool, &chunks);
+                        chunks
+                    },
+                    BatchSize::PerIteration,
+                );
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("Full", count), &count, |b, &count| {
+            b.iter_with_large_drop(|| {
+                let chunks = generate(count);
+                mesh_and_upload(&pool, &chunks);
+                chunks
+            });
+        });
+    }
+    group.finish();
+
+    pool.exit_all();
+    pool.free_buffers();
+}
+
+fn main() {
+    benches();
+    // drop the renderer and its vulkan objects instead of leaking them
+    RENDERER.lock().unwrap().take();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -1,21 +1,57 @@
-use std::process::Command;
+use std::{env, fs, io::ErrorKind, path::Path, process::Command};
+
+// sources, the names of their SPIR-V in OUT_DIR and the glslc defines
+const SHADERS: &[(&str, &str, &[&str])] = &[
+    ("assets/shaders/shader.vert", "vert.spv", &[]),
+    ("assets/shaders/shader.frag", "frag.spv", &[]),
+];
+// the fragment shader indexing the texture array, only with the bindless-textures feature
+const BINDLESS_SHADER: (&str, &str, &[&str]) = (
+    "assets/shaders/shader.frag",
+    "frag_bindless.spv",
+    &["-DBINDLESS_TEXTURES"],
+);
+// the shaders-from-disk feature loads them from here at runtime
+const DISK_SHADERS_DIR: &str = "assets/shaders";
 
 fn main() {
     println!("cargo:rerun-if-changed=assets/shaders/");
-    Command::new("glslc")
-        .args([
-            "-o",
-            "assets/shaders/vert.spv",
-            "assets/shaders/shader.vert",
-        ])
-        .status()
-        .unwrap();
-    Command::new("glslc")
-        .args([
-            "-o",
-            "assets/shaders/frag.spv",
-            "assets/shaders/shader.frag",
-        ])
-        .status()
-        .unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let from_disk = env::var_os("CARGO_FEATURE_SHADERS_FROM_DISK").is_some();
+    let bindless = env::var_os("CARGO_FEATURE_BINDLESS_TEXTURES").is_some();
+    let shaders = SHADERS.iter().chain(bindless.then_some(&BINDLESS_SHADER));
+    for (source, name, defines) in shaders {
+        let output = Path::new(&out_dir).join(name);
+        compile(source, defines, &output);
+        if from_disk {
+            fs::copy(&output, Path::new(DISK_SHADERS_DIR).join(name)).unwrap();
+        }
+    }
+}
+
+fn compile(source: &str, defines: &[&str], output: &Path) {
+    match Command::new("glslc")
+        .args(defines)
+        .arg("-o")
+        .arg(output)
+        .arg(source)
+        .output()
+    {
+        Ok(result) if result.status.success() => {}
+        // the diagnostics give the line and column of each error
+        Ok(result) => {
+            let stderr = String::from_utf8_lossy(&result.stderr);
+            for line in stderr.lines() {
+                println!("cargo:warning={line}");
+            }
+            panic!(
+                "Failed to compile {source} ({}), see the glslc diagnostics above",
+                result.status
+            );
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            panic!("glslc not found to compile {source}: install the Vulkan SDK or add glslc to the PATH")
+        }
+        Err(e) => panic!("Failed to run glslc on {source}: {e}"),
+    }
 }
@@ -1,5 +1,13 @@
-use crate::{inputs::Inputs, render::Renderer, threads::MeshingThreadPool, world::World};
+use crate::{
+    config::MAX_DT,
+    inputs::Inputs,
+    render::{memory::format_size, Renderer},
+    threads::{is_device_lost, GenerationThreadPool, MeshingThreadPool},
+    world::{ChunkPos, LoadMode, World},
+};
 use anyhow::Result;
+use log::{error, info};
+use std::path::Path;
 use vulkanalia::{vk::DeviceV1_0, Entry};
 use winit::window::Window;
 
@@ -9,39 +17,138 @@ pub struct App {
     pub inputs: Inputs,
 
     pub meshing_threads: MeshingThreadPool,
+    pub generation_threads: GenerationThreadPool,
+
+    // the world and the camera are stopped but the frames are still rendered
+    paused: bool,
+    // the next update is the first one since the pause
+    resumed: bool,
 }
 
 impl App {
-    pub fn create(window: &Window, entry: &Entry) -> Result<Self> {
-        let renderer = unsafe { Renderer::new(window, entry) };
-        let world = unsafe { World::new()? };
+    pub fn create(
+        window: &Window,
+        entry: &Entry,
+        preferred_device_name: Option<String>,
+        save_dir: Option<&Path>,
+        load_mode: LoadMode,
+    ) -> Result<Self> {
+        let renderer = unsafe { Renderer::new(window, entry, preferred_device_name)? };
+        let world = World::new(save_dir, load_mode)?;
         let mut thread_pool = MeshingThreadPool::new();
         unsafe { thread_pool.start_threads(renderer.data.clone()) };
+        let mut generation_threads = GenerationThreadPool::new();
+        generation_threads.start_threads();
         Ok(Self {
             renderer,
             world,
             inputs: Inputs::new(),
             meshing_threads: thread_pool,
+            generation_threads,
+            paused: false,
+            resumed: false,
         })
     }
 
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        info!("{}", if paused { "Paused" } else { "Resumed" });
+        self.paused = paused;
+        self.resumed = !paused;
+        self.inputs.release_all();
+    }
+
+    #[inline]
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn tick(&mut self) -> Result<()> {
-        self.world.tick(
-            &self.renderer.data.read().unwrap(),
-            &self.meshing_threads,
-            self.renderer.camera.borrow().pos,
-        )?;
+        if self.paused {
+            // the uploads already submitted are still drawn
+            self.world
+                .tick_paused(&self.meshing_threads, self.renderer.frame_count());
+        } else {
+            self.world.tick(
+                &self.meshing_threads,
+                &self.generation_threads,
+                self.renderer.camera.borrow().pos,
+                self.renderer.frame_count(),
+            )?;
+        }
+        for e in self.meshing_threads.errors() {
+            if is_device_lost(&e) {
+                return Err(e);
+            }
+            error!("{e:?}");
+        }
+        Ok(())
+    }
+
+    pub fn regenerate_camera_chunk(&mut self, keep_edits: bool) -> Result<()> {
+        let pos = self.renderer.camera.borrow().pos.map(|c| c.floor() as i32);
+        if let Some((pos, _)) = ChunkPos::from_block_pos(pos) {
+            self.world
+                .regenerate(pos, keep_edits, &self.meshing_threads)?;
+        }
+        Ok(())
+    }
+
+    // stops the world until the memory is compacted
+    pub fn defragment_memory(&mut self) -> Result<()> {
+        let data = self.renderer.data.read().unwrap();
+        let before = data.allocator.stats();
+        let moved = {
+            // the meshing threads can't use the chunks buffers while they are locked
+            let mut chunks: Vec<_> = self
+                .world
+                .chunks
+                .values()
+                .map(|chunk| chunk.lock().unwrap())
+                .collect();
+            unsafe {
+                data.device.device_wait_idle()?;
+                self.meshing_threads.defragment(&data, &mut chunks)?
+            }
+        };
+        let after = data.allocator.stats();
+        info!(
+            "Defragmentation moved {} buffers, GPU memory: {} used of {} -> {} used of {}",
+            moved,
+            format_size(before.used()),
+            format_size(before.size()),
+            format_size(after.used()),
+            format_size(after.size())
+        );
         Ok(())
     }
 
     pub fn update(&mut self, dt: f32) -> Result<()> {
-        unsafe { self.renderer.update(&self.inputs, dt) }
+        if self.paused {
+            return Ok(());
+        }
+        // the frame before may be long ago, the camera doesn't jump
+        let dt = if std::mem::take(&mut self.resumed) {
+            0.0
+        } else {
+            dt.min(MAX_DT)
+        };
+        self.renderer.update(&self.inputs, dt)
     }
 
     pub fn render(&mut self, window: &Window, dt: f32) -> Result<()> {
+        // the sum changes whenever one of them does, they only increase
+        let draw_list_version =
+            self.world.render_list_version + self.meshing_threads.mesh_version();
         unsafe {
-            self.renderer
-                .render(window, &mut self.world.chunks_to_render, dt)?;
+            self.renderer.render(
+                window,
+                &mut self.world.chunks_to_render,
+                draw_list_version,
+                dt,
+            )?;
         }
         Ok(())
     }
@@ -49,7 +156,9 @@ impl App {
 
 impl Drop for App {
     fn drop(&mut self) {
+        self.generation_threads.exit_all();
         self.meshing_threads.exit_all();
+        self.world.save();
         unsafe {
             self.renderer
                 .data
@@ -59,5 +168,7 @@ impl Drop for App {
                 .device_wait_idle()
                 .unwrap();
         }
+        // the pool is dropped after the renderer
+        self.meshing_threads.free_buffers();
     }
 }
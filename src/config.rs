@@ -1,12 +1,196 @@
-use vulkanalia::vk;
+use vulkanalia::{vk, Version};
 
+use crate::{inputs::KeyboardLayout, render::memory::AllocStrategy};
+
+// vulkan version requested, lowered to what the loader and the device support
+pub const API_VERSION: Version = Version::V1_2_0;
+
+// default, overridden at runtime by VALIDATION_ENV_VAR
 pub const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
+// environment variable enabling (1, true, on) or disabling (0, false, off) the validation
+pub const VALIDATION_ENV_VAR: &str = "VOXELS_VALIDATION";
+// message ids of the validation and driver messages not logged, for the known noise
+pub const MUTED_DEBUG_MESSAGES: &[i32] = &[];
+// environment variable with more ids to mute, comma separated, decimal or hex (0x...)
+pub const MUTED_DEBUG_MESSAGES_ENV_VAR: &str = "VOXELS_MUTED_MESSAGES";
 pub const VALIDATION_LAYER: vk::ExtensionName =
     vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
 
 pub const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
+// enabled when supported
+pub const OPTIONAL_DEVICE_EXTENSIONS: &[vk::ExtensionName] =
+    &[vk::EXT_MEMORY_BUDGET_EXTENSION.name];
+
+// width of the lines drawn by the debug pipelines, needs the `wide_lines` feature if != 1
+pub const DEBUG_LINE_WIDTH: f32 = 2.0;
+
+// Frames recorded ahead of the gpu, each one can add a frame of input latency. More than
+// the swapchain images don't help: a frame waits for the last one drawn to its image.
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+// the sync objects and the resources retired after the frames in flight need one at least,
+// and the swapchains rarely have more than 3 images to use more
+const _: () = assert!(
+    MAX_FRAMES_IN_FLIGHT >= 1 && MAX_FRAMES_IN_FLIGHT <= 3,
+    "MAX_FRAMES_IN_FLIGHT should be between 1 and 3"
+);
+
+// frames the fps and frame times are computed over
+pub const FRAME_STATS_WINDOW: usize = 240;
+// wait for the vertical blank to present, F12 toggles it
+pub const VSYNC: bool = false;
+
+// Reverse-Z: the depth goes from 1 at the near plane to 0 at the far plane. With the
+// float depth format the precision is spread evenly over the distance instead of being
+// packed near the camera, the far terrain doesn't z-fight anymore.
+pub const REVERSE_Z: bool = true;
+
+// samples per pixel to smooth the edges, 1 disables the multisampling.
+// Lowered to the highest count the device supports
+pub const MSAA_SAMPLES: u32 = 4;
 
-pub const MAX_FRAMES_IN_FLIGHT: usize = 3;
+// BestFit fragments the chunk buffers memory less but makes each allocation slower
+pub const ALLOC_STRATEGY: AllocStrategy = AllocStrategy::FirstFit;
+// initial size of the GPU memory chunks, smaller saves memory on small heaps
+pub const ALLOC_CHUNK_SIZE: u64 = 1024 * 1024 * 16;
+// allocations larger than this get their own memory
+pub const ALLOC_DEDICATED_THRESHOLD: u64 = ALLOC_CHUNK_SIZE / 2;
 
-pub const CHUNK_SIZE: usize = 16;
+// Chunks waiting to be meshed per meshing thread before the world stops adding new
+// chunks for the tick. The remeshes of loaded chunks are always queued.
+pub const MESH_QUEUE_CHUNKS_PER_THREAD: usize = 64;
+
+// per chunk data in the dynamic uniform buffer, enough for all the loaded chunks
+pub const CHUNK_UNIFORM_SLOTS: usize = 1 << 14;
+
+// freed chunk buffers kept for reuse per power of two size, 0 to always allocate exact sizes
+pub const CHUNK_BUFFER_POOL_SIZE: usize = 32;
+
+// chunk dimensions in blocks, they don't need to be the same
+pub const CHUNK_SIZE_X: usize = 16;
+pub const CHUNK_SIZE_Y: usize = 16;
+pub const CHUNK_SIZE_Z: usize = 16;
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z;
+// in chunks, around the player chunk, + and - change it between 1 and MAX_RENDER_DISTANCE
 pub const RENDER_DISTANCE: usize = 16;
+pub const MAX_RENDER_DISTANCE: usize = 32;
+// Chunks farther than LOD_DISTANCES[i] chunks from the player chunk are meshed with cubes
+// of 2^(i + 1) blocks merged in a single voxel. Empty to mesh every block of all the chunks.
+pub const LOD_DISTANCES: &[usize] = &[8, 16];
+// in chunks, above and below the player chunk
+pub const VERTICAL_RENDER_DISTANCE: usize = 8;
+// chunk heights of the world (inclusive), nothing is loaded outside of them
+pub const WORLD_MIN_CHUNK_Y: u32 = 0;
+pub const WORLD_MAX_CHUNK_Y: u32 = 10;
+// longest frame time the camera moves for, in seconds, so it doesn't jump after a hitch
+pub const MAX_DT: f32 = 1.0 / 15.0;
+// time the world can spend loading chunks each frame, in milliseconds
+pub const WORLD_TICK_BUDGET_MS: f32 = 4.0;
+// debug cap on the number of chunks drawn, None to draw all the loaded chunks
+pub const MAX_DRAWN_CHUNKS: Option<usize> = None;
+// Skip the chunks hidden behind the terrain in the last frames, toggled with O.
+// The chunks past MAX_OCCLUSION_QUERIES in a frame are always drawn.
+pub const OCCLUSION_CULLING: bool = true;
+pub const MAX_OCCLUSION_QUERIES: usize = 1 << 14;
+
+// None to use a different seed each session
+pub const WORLD_SEED: Option<u64> = None;
+// terrain of the noise generator, heights in blocks
+pub const TERRAIN_BASE_HEIGHT: i32 = 48;
+pub const TERRAIN_AMPLITUDE: f32 = 24.0;
+// size of the biggest hills in blocks, each octave halves it
+pub const TERRAIN_SCALE: f32 = 96.0;
+pub const TERRAIN_OCTAVES: u32 = 4;
+// the camera starts above the highest terrain
+pub const SPAWN_HEIGHT: f32 = 80.0;
+
+// directory where the edited chunks and the seed are saved, None to not save the world.
+// Overridden with --save-dir
+pub const SAVE_DIR: Option<&str> = Some("world");
+
+// Remesh only the modified horizontal sections of a chunk.
+// This keeps a cpu copy of the mesh of each chunk.
+pub const SECTION_MESHING: bool = false;
+pub const CHUNK_SECTION_HEIGHT: usize = 4;
+
+// file written when dumping the render list with F8
+pub const CHUNK_DUMP_FILE: &str = "chunks.json";
+
+// directory where P saves the screenshots
+pub const SCREENSHOT_DIR: &str = "screenshots";
+// shift + P draws the frame this many times larger and downsamples it to the window size,
+// rounded down to a power of two
+pub const SCREENSHOT_SCALE: u32 = 4;
+
+// environment variable naming the gpu to use instead of the best scoring one
+pub const GPU_ENV_VAR: &str = "VOXELS_GPU";
+
+// Direction towards the sun, the faces are lit by how much they face it.
+// The faces turned away from the sun only get the ambient light.
+pub const SUN_DIRECTION: [f32; 3] = [0.3, 1.0, 0.5];
+pub const AMBIENT_LIGHT: f32 = 0.4;
+
+// Tiles per row of the square texture atlas, must match the fragment shader.
+// Anisotropic filtering of the textures, clamped to what the gpu supports.
+pub const ATLAS_TILES_PER_ROW: u32 = 4;
+pub const MAX_ANISOTROPY: f32 = 16.0;
+// upper bound of the texture array with the bindless-textures feature, lowered to the limits
+pub const MAX_BINDLESS_TEXTURES: u32 = 1024;
+
+// opacity of the blocks in the cave view (F2)
+pub const CAVE_VIEW_OPACITY: f32 = 0.2;
+
+// chunks drawn around the camera's chunk in each direction by the chunk grid overlay
+pub const CHUNK_GRID_RADIUS: usize = 2;
+
+// Offset the view while moving horizontally, disabled for accessibility.
+// The amplitude is in blocks and the frequency in steps per block moved.
+pub const VIEW_BOBBING: bool = false;
+pub const VIEW_BOBBING_AMPLITUDE: f32 = 0.05;
+pub const VIEW_BOBBING_FREQUENCY: f32 = 0.6;
+
+// Also write the logs to this file, the previous files are kept with a number suffix.
+// Only the targets starting with one of LOG_FILE_TARGETS are written, all of them if empty.
+pub const LOG_FILE: Option<&str> = None;
+pub const LOG_FILE_LEVEL: log::LevelFilter = log::LevelFilter::Trace;
+pub const LOG_FILE_TARGETS: &[&str] = &[
+    "vulkan_voxels::world",
+    "vulkan_voxels::threads",
+    "vulkan_voxels::render",
+];
+pub const LOG_FILES_KEPT: usize = 3;
+
+// default movement keys, Z/Q/S/D with Azerty and W/A/S/D with Qwerty
+pub const KEYBOARD_LAYOUT: KeyboardLayout = KeyboardLayout::Azerty;
+
+// camera speed in blocks per second, multiplied by SPRINT_MULTIPLIER while left control is held
+pub const MOVE_SPEED: f32 = 400.0;
+pub const SPRINT_MULTIPLIER: f32 = 2.0;
+pub const MOUSE_SENSITIVITY: f32 = 5.0;
+
+// Scale the camera speed with the fov so a wide view moves faster.
+// The scaled speed is capped, in blocks per second.
+pub const FOV_SPEED_SCALING: bool = false;
+pub const MAX_SCALED_SPEED: f32 = 1000.0;
+
+// field of view range in degrees, the fov is widened by SPRINT_FOV_INCREASE while sprinting
+pub const MIN_FOV: f32 = 30.0;
+pub const MAX_FOV: f32 = 110.0;
+pub const SPRINT_FOV_INCREASE: f32 = 15.0;
+// fov change per line scrolled, scrolling up zooms in
+pub const FOV_SCROLL_STEP: f32 = 5.0;
+
+// Latency probe: F10 draws a white square in the top left corner for a few frames.
+// To measure the input latency, film the keyboard and the screen with a high speed
+// camera and count the camera frames between the key press and the square appearing.
+// The log gives the first frame with the square and the time from the key press to its
+// presentation being queued, the display adds its own latency after that.
+pub const LATENCY_PROBE_SIZE: u32 = 128; // in pixels
+pub const LATENCY_PROBE_FRAMES: u64 = 10;
+
+// The rendering is done relative to an origin that jumps to the camera's chunk when the camera
+// is further than this from it on any axis, in blocks. The vertices are relative to their chunk,
+// whose origin is pushed with each draw (DrawConstants), and the shader subtracts the rebase
+// origin of the uniform buffer from it in integers, so only the view matrix and the uniform
+// buffer change on a rebase, the recorded draws stay valid.
+// Culling done on the cpu uses world coordinates and is not affected.
+pub const ORIGIN_REBASE_DISTANCE: f32 = 1024.0;
@@ -0,0 +1,78 @@
+use log::{info, warn};
+use winit::{dpi::PhysicalPosition, window::Window};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorGrab {
+    Grabbed,    // grabbed by the platform
+    CenterWarp, // moved back to the center of the window each frame
+    Free,       // nothing worked, the cursor can leave the window
+}
+
+pub struct Cursor {
+    pub mode: CursorGrab,
+    // false when released by the user or when the window lost the focus
+    pub captured: bool,
+}
+
+impl Cursor {
+    pub fn new() -> Self {
+        Self {
+            mode: CursorGrab::Free,
+            captured: false,
+        }
+    }
+
+    // Try the platform grab, then warping the cursor, never fail.
+    // The camera uses the raw mouse motion so the warps don't move it.
+    pub fn capture(&mut self, window: &Window) {
+        let mode = if let Err(e) = window.set_cursor_grab(true) {
+            warn!("Failed to grab the cursor: {e}");
+            match Self::warp_to_center(window) {
+                Ok(()) => CursorGrab::CenterWarp,
+                Err(e) => {
+                    warn!("Failed to move the cursor: {e}");
+                    CursorGrab::Free
+                }
+            }
+        } else {
+            CursorGrab::Grabbed
+        };
+        if mode != self.mode || !self.captured {
+            info!("Cursor grab mode: {:?}", mode);
+        }
+        self.mode = mode;
+        self.captured = true;
+        window.set_cursor_visible(false);
+    }
+
+    pub fn release(&mut self, window: &Window) {
+        if self.mode == CursorGrab::Grabbed {
+            window
+                .set_cursor_grab(false)
+                .unwrap_or_else(|e| warn!("Failed to release the cursor: {e}"));
+        }
+        self.captured = false;
+        window.set_cursor_visible(true);
+    }
+
+    // should be called each frame
+    pub fn update(&mut self, window: &Window) {
+        if self.captured && self.mode == CursorGrab::CenterWarp {
+            if let Err(e) = Self::warp_to_center(window) {
+                warn!("Failed to move the cursor: {e}");
+                self.mode = CursorGrab::Free;
+            }
+        }
+    }
+
+    fn warp_to_center(window: &Window) -> Result<(), winit::error::ExternalError> {
+        let size = window.inner_size();
+        window.set_cursor_position(PhysicalPosition::new(size.width / 2, size.height / 2))
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
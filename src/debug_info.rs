@@ -0,0 +1,107 @@
+use std::fmt::Write;
+
+use crate::{app::App, render::memory::format_size, world::ChunkPos};
+
+// the title is not updated every frame, some window managers are slow to redraw it
+const REFRESH_INTERVAL: f32 = 0.25;
+
+// F3 readout of the camera and world state, the frame times come from the renderer FrameStats
+pub struct DebugInfo {
+    pub enabled: bool,
+    elapsed: f32,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            elapsed: 0.0,
+        }
+    }
+
+    // returns true when the title should be refreshed
+    pub fn update(&mut self, dt: f32) -> bool {
+        self.elapsed += dt;
+        if self.elapsed < REFRESH_INTERVAL {
+            return false;
+        }
+        self.elapsed = 0.0;
+        true
+    }
+
+    // only reads values already computed by the last frame, and the allocator blocks
+    pub fn lines(&self, app: &App) -> Vec<String> {
+        let camera = app.renderer.camera.borrow();
+        let pos = camera.pos;
+        let front = camera.front();
+
+        let mut lines = Vec::new();
+        lines.push(format!("XYZ: {:.3} / {:.3} / {:.3}", pos.x, pos.y, pos.z));
+
+        let mut chunk = String::from("Chunk: ");
+        match ChunkPos::from_block_pos(pos.map(|c| c.floor() as i32)) {
+            Some((chunk_pos, (x, y, z))) => write!(
+                chunk,
+                "{} {} {} (block {x} {y} {z})",
+                chunk_pos.x, chunk_pos.y, chunk_pos.z
+            )
+            .unwrap(),
+            None => chunk.push_str("below the world"),
+        }
+        lines.push(chunk);
+
+        let facing = if front.x.abs() > front.z.abs() {
+            if front.x > 0.0 {
+                "+X"
+            } else {
+                "-X"
+            }
+        } else if front.z > 0.0 {
+            "+Z"
+        } else {
+            "-Z"
+        };
+        lines.push(format!(
+            "Facing: {facing} (yaw {:.1}, pitch {:.1})",
+            camera.yaw().rem_euclid(360.0),
+            camera.pitch()
+        ));
+
+        lines.push(format!(
+            "Chunks: {} loaded, {} drawn, {} culled, {} occluded, {} empty, render distance {}",
+            app.world.chunks.len(),
+            app.renderer.drawn_chunks.get(),
+            app.renderer.culled_chunks.get(),
+            app.renderer.occluded_chunks.get(),
+            app.renderer.skipped_empty_chunks.get(),
+            app.world.render_distance()
+        ));
+        let memory = app.renderer.data.read().unwrap().allocator.stats();
+        lines.push(format!(
+            "GPU memory: {} used of {}",
+            format_size(memory.used()),
+            format_size(memory.size())
+        ));
+        let stats = &app.renderer.frame_stats;
+        let mut frame = format!(
+            "Frame {:.2} ms (p99 {:.2} ms)",
+            stats.avg_frame_time() * 1000.0,
+            stats.p99_frame_time() * 1000.0,
+        );
+        if let Some(gpu) = stats.avg_gpu_frame_time() {
+            frame += &format!(", GPU {:.2} ms", gpu * 1000.0);
+        }
+        lines.push(format!(
+            "{frame}, world tick {:.2} ms",
+            app.world.last_tick_time.as_secs_f32() * 1000.0
+        ));
+        lines.push(format!("Present mode: {:?}", app.renderer.present_mode()));
+        lines
+    }
+}
+
+impl Default for DebugInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,28 +1,100 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use winit::event::VirtualKeyCode;
+use winit::event::{MouseButton, VirtualKeyCode};
+
+use crate::config::KEYBOARD_LAYOUT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    Sprint,
+}
+
+// default bindings profiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    Qwerty,
+    Azerty,
+}
+
+#[derive(Debug, Clone)]
+pub struct InputMap {
+    bindings: HashMap<Action, VirtualKeyCode>,
+}
+
+impl InputMap {
+    pub fn new(layout: KeyboardLayout) -> Self {
+        let (forward, left) = match layout {
+            KeyboardLayout::Qwerty => (VirtualKeyCode::W, VirtualKeyCode::A),
+            KeyboardLayout::Azerty => (VirtualKeyCode::Z, VirtualKeyCode::Q),
+        };
+        let bindings = HashMap::from([
+            (Action::MoveForward, forward),
+            (Action::MoveBackward, VirtualKeyCode::S),
+            (Action::MoveLeft, left),
+            (Action::MoveRight, VirtualKeyCode::D),
+            (Action::MoveUp, VirtualKeyCode::Space),
+            (Action::MoveDown, VirtualKeyCode::LShift),
+            (Action::Sprint, VirtualKeyCode::LControl),
+        ]);
+        Self { bindings }
+    }
+
+    // replaces the previous key of the action
+    #[inline]
+    pub fn bind(&mut self, action: Action, key: VirtualKeyCode) {
+        self.bindings.insert(action, key);
+    }
+
+    #[inline]
+    pub fn key(&self, action: Action) -> Option<VirtualKeyCode> {
+        self.bindings.get(&action).copied()
+    }
+}
 
 pub struct Inputs {
     keys: HashSet<VirtualKeyCode>,
+    // pressed since the last reset, without the key repeats
+    just_pressed: HashSet<VirtualKeyCode>,
+    // released since the last reset
+    just_released: HashSet<VirtualKeyCode>,
     pub mouse_delta: (f64, f64),
+    mouse_buttons: HashSet<MouseButton>,
+    // in lines, positive away from the user
+    pub scroll_delta: f32,
+    pub map: InputMap,
 }
 
 impl Inputs {
     pub fn new() -> Self {
         Self {
             keys: HashSet::new(),
+            just_pressed: HashSet::new(),
+            just_released: HashSet::new(),
             mouse_delta: (0.0, 0.0),
+            mouse_buttons: HashSet::new(),
+            scroll_delta: 0.0,
+            map: InputMap::new(KEYBOARD_LAYOUT),
         }
     }
 
     #[inline]
     pub fn key_pressed(&mut self, key: VirtualKeyCode) {
-        self.keys.insert(key);
+        if self.keys.insert(key) {
+            self.just_pressed.insert(key);
+        }
     }
 
     #[inline]
     pub fn key_released(&mut self, key: VirtualKeyCode) {
-        self.keys.remove(&key);
+        if self.keys.remove(&key) {
+            self.just_released.insert(key);
+        }
     }
 
     #[inline]
@@ -31,13 +103,100 @@ impl Inputs {
         self.mouse_delta.1 += delta.1;
     }
 
+    #[inline]
+    pub fn mouse_pressed(&mut self, button: MouseButton) {
+        self.mouse_buttons.insert(button);
+    }
+
+    #[inline]
+    pub fn mouse_released(&mut self, button: MouseButton) {
+        self.mouse_buttons.remove(&button);
+    }
+
+    #[inline]
+    pub fn scrolled(&mut self, delta: f32) {
+        self.scroll_delta += delta;
+    }
+
+    // forget the held keys and buttons, their release may be missed without the focus
+    pub fn release_all(&mut self) {
+        self.keys.clear();
+        self.mouse_buttons.clear();
+    }
+
     // this should called after rendering
     pub fn reset(&mut self) {
         self.mouse_delta = (0.0, 0.0);
+        self.scroll_delta = 0.0;
+        self.just_pressed.clear();
+        self.just_released.clear();
     }
 
     #[inline]
     pub fn is_key_pressed(&self, key: VirtualKeyCode) -> bool {
         self.keys.contains(&key)
     }
+
+    #[inline]
+    pub fn action_pressed(&self, action: Action) -> bool {
+        self.map
+            .key(action)
+            .is_some_and(|key| self.is_key_pressed(key))
+    }
+
+    #[inline]
+    pub fn is_mouse_pressed(&self, button: MouseButton) -> bool {
+        self.mouse_buttons.contains(&button)
+    }
+
+    #[inline]
+    pub fn is_key_just_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.just_pressed.contains(&key)
+    }
+
+    #[inline]
+    pub fn is_key_just_released(&self, key: VirtualKeyCode) -> bool {
+        self.just_released.contains(&key)
+    }
+}
+
+impl Default for Inputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn press_hold_release() {
+        let key = VirtualKeyCode::A;
+        let mut inputs = Inputs::new();
+        inputs.key_pressed(key);
+        assert!(inputs.is_key_just_pressed(key) && inputs.is_key_pressed(key));
+        assert!(!inputs.is_key_just_released(key));
+
+        // held, the key repeats aren't new presses
+        inputs.reset();
+        inputs.key_pressed(key);
+        assert!(!inputs.is_key_just_pressed(key) && inputs.is_key_pressed(key));
+        inputs.reset();
+        assert!(!inputs.is_key_just_pressed(key) && inputs.is_key_pressed(key));
+
+        inputs.key_released(key);
+        assert!(inputs.is_key_just_released(key) && !inputs.is_key_pressed(key));
+        inputs.reset();
+        assert!(!inputs.is_key_just_released(key));
+        // a release without a press, e.g. pressed before the window got the focus
+        inputs.key_released(key);
+        assert!(!inputs.is_key_just_released(key));
+
+        // pressed and released between two resets
+        inputs.key_pressed(key);
+        inputs.key_released(key);
+        assert!(inputs.is_key_just_pressed(key) && inputs.is_key_just_released(key));
+        assert!(!inputs.is_key_pressed(key));
+    }
 }
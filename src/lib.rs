@@ -2,7 +2,10 @@
 
 pub mod app;
 pub mod config;
+pub mod cursor;
+pub mod debug_info;
 pub mod inputs;
+pub mod logger;
 pub mod render;
 pub mod threads;
-pub mod world;
\ No newline at end of file
+pub mod world;
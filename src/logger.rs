@@ -0,0 +1,64 @@
+use std::{fs, path::Path, time::Instant};
+
+use anyhow::Result;
+
+use crate::config::{LOG_FILE, LOG_FILES_KEPT, LOG_FILE_LEVEL, LOG_FILE_TARGETS};
+
+// log.txt -> log.txt.1 -> log.txt.2 ...
+fn rotate(path: &str) {
+    for i in (1..LOG_FILES_KEPT).rev() {
+        let from = if i == 1 {
+            path.to_string()
+        } else {
+            format!("{path}.{}", i - 1)
+        };
+        if Path::new(&from).exists() {
+            let _ = fs::rename(&from, format!("{path}.{i}"));
+        }
+    }
+}
+
+// Stdout logger configured with RUST_LOG like pretty_env_logger::init, and the optional log file.
+pub fn init() -> Result<()> {
+    let mut stdout = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        stdout.parse_filters(&filters);
+    }
+    let stdout = stdout.build();
+    let mut max_level = stdout.filter();
+    let mut dispatch = fern::Dispatch::new().chain(
+        fern::Dispatch::new()
+            .level(stdout.filter())
+            .chain(Box::new(stdout) as Box<dyn log::Log>),
+    );
+
+    if let Some(path) = LOG_FILE {
+        rotate(path);
+        let start = Instant::now();
+        max_level = max_level.max(LOG_FILE_LEVEL);
+        dispatch = dispatch.chain(
+            fern::Dispatch::new()
+                .level(LOG_FILE_LEVEL)
+                .filter(|metadata| {
+                    LOG_FILE_TARGETS.is_empty()
+                        || LOG_FILE_TARGETS
+                            .iter()
+                            .any(|target| metadata.target().starts_with(target))
+                })
+                .format(move |out, message, record| {
+                    out.finish(format_args!(
+                        "[{:>10.3} {:<5} {} {}] {}",
+                        start.elapsed().as_secs_f32(),
+                        record.level(),
+                        std::thread::current().name().unwrap_or("?"),
+                        record.target(),
+                        message
+                    ))
+                })
+                .chain(fern::log_file(path)?),
+        );
+    }
+
+    dispatch.level(max_level).apply()?;
+    Ok(())
+}
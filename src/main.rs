@@ -1,14 +1,17 @@
-mod app;
-mod config;
-mod inputs;
-mod render;
-mod threads;
-mod world;
-
-use std::time::Instant;
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
 
-use app::App;
-use log::warn;
+use log::{error, info, warn};
+use vulkan_voxels::{
+    app::App,
+    config::{CHUNK_DUMP_FILE, GPU_ENV_VAR, SAVE_DIR, SCREENSHOT_DIR, SCREENSHOT_SCALE},
+    cursor::Cursor,
+    debug_info::DebugInfo,
+    world::{LoadMode, Storage},
+};
 use vulkanalia::{
     loader::{LibloadingLoader, LIBRARY},
     Entry,
@@ -16,16 +19,202 @@ use vulkanalia::{
 use winit::{
     dpi::LogicalSize,
     event::Event,
-    event::{DeviceEvent, VirtualKeyCode, WindowEvent},
+    event::{DeviceEvent, ElementState, MouseScrollDelta, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::{Fullscreen, WindowBuilder},
+    window::{Fullscreen, Window, WindowBuilder},
 };
 
 #[cfg(feature = "profile-with-tracy")]
 use profiling::tracy_client;
 
+const TITLE: &str = "Vulkan Voxels";
+
+fn update_title(window: &Window, app: &App, debug_info: &DebugInfo) {
+    let mut title = format!("{TITLE} - {:.0} FPS", app.renderer.frame_stats.fps());
+    if let Some(max) = app.renderer.max_drawn_chunks {
+        title += &format!(" - drawing the {max} nearest chunks");
+    }
+    if app.paused() {
+        title += " - paused";
+    }
+    // the title is the only text we can show for now
+    if debug_info.enabled {
+        for line in debug_info.lines(app) {
+            title += " | ";
+            title += &line;
+        }
+    }
+    window.set_title(&title);
+}
+
+// named after the time so they don't overwrite each other
+// scaled draws the frame SCREENSHOT_SCALE times larger and downsamples it
+fn save_screenshot(app: &App, scaled: bool) {
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let path = Path::new(SCREENSHOT_DIR).join(format!("screenshot-{millis}.png"));
+    let saved = if scaled {
+        unsafe {
+            app.renderer
+                .capture_screenshot_scaled(&path, SCREENSHOT_SCALE)
+        }
+    } else {
+        unsafe { app.renderer.capture_frame() }.and_then(|s| s.save_png(&path))
+    };
+    match saved {
+        Ok(()) => info!("Screenshot saved to {}", path.display()),
+        Err(e) => warn!("Failed to save the screenshot: {e}"),
+    }
+}
+
+// The one-shot actions, checked once per frame after the events so the key repeats and the
+// presses held over several frames trigger them once.
+fn handle_key_presses(window: &Window, app: &mut App, cursor: &mut Cursor, debug_info: &DebugInfo) {
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F11) {
+        if window.fullscreen().is_some() {
+            window.set_fullscreen(None);
+        } else {
+            window.set_fullscreen(Some(Fullscreen::Borderless(None)));
+        }
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::Escape) {
+        cursor.release(window);
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::O) {
+        app.renderer.occlusion_culling = !app.renderer.occlusion_culling;
+        info!("Occlusion culling: {}", app.renderer.occlusion_culling);
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F8) {
+        let camera_pos = app.renderer.camera.borrow().pos;
+        app.world
+            .dump_render_list(CHUNK_DUMP_FILE, camera_pos)
+            .unwrap_or_else(|e| warn!("Failed to dump the chunks: {e}"));
+    }
+    // shift for a supersampled one
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::P) {
+        let scaled = app.inputs.is_key_pressed(VirtualKeyCode::LShift);
+        save_screenshot(app, scaled);
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F10) {
+        app.renderer.probe_latency();
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F12) {
+        let vsync = !app.renderer.vsync();
+        unsafe { app.renderer.set_vsync(window, vsync) }
+            .unwrap_or_else(|e| warn!("Failed to toggle the vsync: {e}"));
+        update_title(window, app, debug_info);
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::R) {
+        match unsafe { app.renderer.reload_shaders() } {
+            Ok(()) => info!("Shaders reloaded"),
+            Err(e) => error!("Failed to reload the shaders: {e}"),
+        }
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F1) {
+        app.defragment_memory()
+            .unwrap_or_else(|e| warn!("Failed to defragment the memory: {e}"));
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F2) {
+        app.renderer.cave_view = !app.renderer.cave_view;
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F4) {
+        app.renderer.show_chunk_grid = !app.renderer.show_chunk_grid;
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F5) {
+        app.renderer.draw_points = !app.renderer.draw_points;
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F6) {
+        let loaded = app.world.chunks_to_render.len();
+        app.renderer.max_drawn_chunks = Some(
+            app.renderer
+                .max_drawn_chunks
+                .unwrap_or(loaded)
+                .div_ceil(2)
+                .max(1),
+        );
+        update_title(window, app, debug_info);
+    }
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F7) {
+        let loaded = app.world.chunks_to_render.len();
+        app.renderer.max_drawn_chunks = match app.renderer.max_drawn_chunks {
+            Some(max) if max * 2 < loaded => Some(max * 2),
+            _ => None,
+        };
+        update_title(window, app, debug_info);
+    }
+    // shift to discard the edits
+    if app.inputs.is_key_just_pressed(VirtualKeyCode::F9) {
+        let keep_edits = !app.inputs.is_key_pressed(VirtualKeyCode::LShift);
+        app.regenerate_camera_chunk(keep_edits)
+            .unwrap_or_else(|e| warn!("Failed to regenerate the chunk: {e}"));
+    }
+}
+
+// Command line options:
+// --save-dir <dir>  save the world in dir instead of SAVE_DIR
+// --fresh           start a new world from the saved seed, the saved chunks are overwritten
+fn parse_args() -> (Option<PathBuf>, LoadMode) {
+    let mut save_dir = SAVE_DIR.map(PathBuf::from);
+    let mut load_mode = LoadMode::Continue;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fresh" => load_mode = LoadMode::Fresh,
+            "--save-dir" => match args.next() {
+                Some(dir) => save_dir = Some(PathBuf::from(dir)),
+                None => warn!("--save-dir needs a directory"),
+            },
+            _ => warn!("Unknown argument {arg}"),
+        }
+    }
+    if let (Some(dir), LoadMode::Fresh) = (&save_dir, load_mode) {
+        if !confirm_fresh(dir) {
+            info!("Continuing the saved world");
+            load_mode = LoadMode::Continue;
+        }
+    }
+    (save_dir, load_mode)
+}
+
+// ask before a fresh world overwrites the edited chunks, no answer keeps them
+fn confirm_fresh(dir: &Path) -> bool {
+    match Storage::has_saved_chunks(dir) {
+        Ok(false) => return true,
+        Ok(true) => {}
+        // there may be chunks we can't see, ask anyway
+        Err(e) => warn!("Failed to look for a saved world in {}: {e}", dir.display()),
+    }
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        warn!(
+            "--fresh ignored: the edited chunks saved in {} can't be confirmed without a terminal",
+            dir.display()
+        );
+        return false;
+    }
+    print!(
+        "The edited chunks saved in {} will be deleted when the new world is saved. Start a fresh world? [y/N] ",
+        dir.display()
+    );
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    match stdin.lock().read_line(&mut answer) {
+        Ok(0) => {
+            warn!("--fresh ignored: no answer");
+            false
+        }
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(e) => {
+            warn!("--fresh ignored: failed to read the answer: {e}");
+            false
+        }
+    }
+}
+
 fn main() {
-    pretty_env_logger::init();
+    vulkan_voxels::logger::init().unwrap();
+    let (save_dir, load_mode) = parse_args();
 
     #[cfg(feature = "profile-with-tracy")]
     let _ = tracy_client::Client::start();
@@ -34,7 +223,7 @@ fn main() {
 
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
-        .with_title("Vulkan Voxels")
+        .with_title(TITLE)
         .with_inner_size(LogicalSize::new(1080, 720))
         .build(&event_loop)
         .unwrap();
@@ -42,12 +231,20 @@ fn main() {
     let loader = unsafe { LibloadingLoader::new(LIBRARY) }.unwrap();
     let entry = unsafe { Entry::new(loader) }.unwrap();
 
-    let mut app = App::create(&window, &entry).unwrap();
+    let preferred_device_name = std::env::var(GPU_ENV_VAR).ok();
+    let mut app = App::create(
+        &window,
+        &entry,
+        preferred_device_name,
+        save_dir.as_deref(),
+        load_mode,
+    )
+    .unwrap();
+    let mut debug_info = DebugInfo::new();
+    update_title(&window, &app, &debug_info);
 
-    window
-        .set_cursor_grab(true)
-        .unwrap_or_else(|_| warn!("Failed to grab cursor"));
-    window.set_cursor_visible(false);
+    let mut cursor = Cursor::new();
+    cursor.capture(&window);
     let mut last_frame_time = Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
@@ -59,18 +256,21 @@ fn main() {
                 ..
             } => {
                 if let Some(key) = input.virtual_keycode {
-                    if key == VirtualKeyCode::F11
+                    // the key repeats change it step by step
+                    if matches!(
+                        key,
+                        VirtualKeyCode::Equals | VirtualKeyCode::Plus | VirtualKeyCode::NumpadAdd
+                    ) && input.state == winit::event::ElementState::Pressed
+                    {
+                        let distance = app.world.render_distance() + 1;
+                        app.world.set_render_distance(distance);
+                    }
+                    if matches!(key, VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract)
                         && input.state == winit::event::ElementState::Pressed
                     {
-                        if window.fullscreen().is_some() {
-                            window.set_fullscreen(None);
-                        } else {
-                            window.set_fullscreen(Some(Fullscreen::Borderless(None)));
-                        }
+                        let distance = app.world.render_distance().saturating_sub(1);
+                        app.world.set_render_distance(distance);
                     }
-                    // if key == VirtualKeyCode::F1 && input.state == winit::event::ElementState::Pressed {
-                    //     app.renderer.data.read().unwrap().allocator.snapchot();
-                    // }
                     if input.state == winit::event::ElementState::Pressed {
                         app.inputs.key_pressed(key);
                     } else {
@@ -83,32 +283,71 @@ fn main() {
             Event::DeviceEvent {
                 event: DeviceEvent::MouseMotion { delta },
                 ..
-            } => {
+            } if cursor.captured => {
                 app.inputs.mouse_moved(delta);
             }
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } => {
+                if state == ElementState::Released {
+                    app.inputs.mouse_released(button);
+                } else if !cursor.captured {
+                    // the click capturing the cursor isn't an input
+                    cursor.capture(&window);
+                } else {
+                    app.inputs.mouse_pressed(button);
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseWheel { delta, .. },
+                ..
+            } if cursor.captured => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    // about the height of a line
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+                };
+                app.inputs.scrolled(lines);
+            }
             Event::WindowEvent {
                 event: WindowEvent::Focused(focused),
                 ..
             } => {
                 if focused {
-                    window
-                        .set_cursor_grab(true)
-                        .unwrap_or_else(|_| warn!("Failed to grab cursor"));
-                    window.set_cursor_visible(false);
+                    cursor.capture(&window);
                 } else {
-                    window
-                        .set_cursor_grab(false)
-                        .unwrap_or_else(|_| warn!("Failed to release cursor"));
-                    window.set_cursor_visible(true);
+                    cursor.release(&window);
                 }
+                app.set_paused(!focused);
+                update_title(&window, &app, &debug_info);
             }
             Event::MainEventsCleared => {
                 let dt = last_frame_time.elapsed().as_secs_f32();
                 last_frame_time = Instant::now();
 
-                app.tick().unwrap();
+                handle_key_presses(&window, &mut app, &mut cursor, &debug_info);
+                cursor.update(&window);
+                // the app is dropped with the event loop, stopping the threads and saving the world
+                if let Err(e) = app.tick() {
+                    error!("Stopping: {e:?}");
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
                 app.update(dt).unwrap();
                 app.render(&window, dt).unwrap();
+                // parked until an event restores the window, each event polls again
+                if app.renderer.minimized() {
+                    *control_flow = ControlFlow::Wait;
+                }
+
+                let toggled = app.inputs.is_key_just_pressed(VirtualKeyCode::F3);
+                if toggled {
+                    debug_info.enabled = !debug_info.enabled;
+                }
+                if debug_info.update(dt) || toggled {
+                    update_title(&window, &app, &debug_info);
+                }
                 app.inputs.reset();
             }
             Event::WindowEvent {
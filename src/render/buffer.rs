@@ -1,15 +1,18 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use vulkanalia::{
     vk::{self, DeviceV1_0, HasBuilder},
     Device,
 };
 
 use super::{
-    memory::{AllocRequirements, AllocUsage, Allocator, Block},
+    memory::{AllocRequirements, AllocUsage, Allocator, Block, Relocation},
     renderer::RendererData,
 };
 
-use std::sync::{self, Arc};
+use std::{
+    collections::HashMap,
+    sync::{self, Arc},
+};
 
 #[derive(Debug)]
 pub struct Buffer {
@@ -18,17 +21,48 @@ pub struct Buffer {
     pub buffer: vk::Buffer,
     pub alloc: Block,
     pub ptr: *mut u8, // null if not staging buffer
+    pub size: usize,
+    usage: vk::BufferUsageFlags,
+    memory_usage: AllocUsage,
 }
 
 unsafe impl Send for Buffer {}
 unsafe impl Sync for Buffer {}
 
 impl Buffer {
+    /// # Safety
+    ///
+    /// The device and allocator of data must outlive the buffer, it is freed on drop.
     pub unsafe fn create(
         data: &RendererData,
         size: usize,
         buffer_usage: vk::BufferUsageFlags,
         memory_usage: AllocUsage,
+    ) -> Result<Self> {
+        Self::create_inner(data, size, buffer_usage, memory_usage, false)
+    }
+
+    /// the owner must call relocate for its relocations after Allocator::defragment
+    ///
+    /// # Safety
+    ///
+    /// The renderer data must hold a live device and allocator. The owner must keep the buffer away
+    /// from the gpu between Allocator::defragment and its relocate call.
+    pub unsafe fn create_movable(
+        data: &RendererData,
+        size: usize,
+        buffer_usage: vk::BufferUsageFlags,
+        memory_usage: AllocUsage,
+    ) -> Result<Self> {
+        Self::create_inner(data, size, buffer_usage, memory_usage, true)
+    }
+
+    unsafe fn create_inner(
+        data: &RendererData,
+        size: usize,
+        buffer_usage: vk::BufferUsageFlags,
+        memory_usage: AllocUsage,
+        movable: bool,
     ) -> Result<Self> {
         let info = vk::BufferCreateInfo::builder()
             .size(size as u64)
@@ -38,12 +72,24 @@ impl Buffer {
 
         let memory_requirements = data.device.get_buffer_memory_requirements(buffer);
 
-        let (alloc, ptr) = data
-            .allocator
-            .alloc(AllocRequirements::new(memory_requirements, memory_usage))?;
+        let mut requirements = AllocRequirements::new(memory_requirements, memory_usage);
+        requirements.movable = movable;
+        let (alloc, ptr) = match data.allocator.alloc(requirements) {
+            Ok(alloc) => alloc,
+            Err(e) => {
+                data.device.destroy_buffer(buffer, None);
+                return Err(e);
+            }
+        };
 
-        data.device
-            .bind_buffer_memory(buffer, alloc.memory, alloc.offset)?;
+        if let Err(e) = data
+            .device
+            .bind_buffer_memory(buffer, alloc.memory, alloc.offset)
+        {
+            data.device.destroy_buffer(buffer, None);
+            data.allocator.free(alloc);
+            return Err(e.into());
+        }
 
         Ok(Self {
             device: Arc::downgrade(&data.device),
@@ -51,8 +97,140 @@ impl Buffer {
             buffer,
             alloc,
             ptr,
+            size,
+            usage: buffer_usage,
+            memory_usage,
         })
     }
+
+    /// Pointer to size bytes from offset, only for the host visible buffers. They are
+    /// mapped as long as they exist, unmap makes the writes visible to the device.
+    ///
+    /// # Safety
+    ///
+    /// The pointer is valid until the buffer is dropped or relocated. The range must not be read or
+    /// written by the gpu while the cpu writes to it.
+    pub unsafe fn map<T>(&self, offset: usize, size: usize) -> Result<*mut T> {
+        if self.ptr.is_null() {
+            return Err(anyhow!("Can't map a buffer in device local memory"));
+        }
+        if offset + size > self.size {
+            return Err(anyhow!(
+                "Range {offset}..{} out of a buffer of {} bytes",
+                offset + size,
+                self.size
+            ));
+        }
+        Ok(self.ptr.add(offset).cast())
+    }
+
+    /// flush the writes through map, only needed if the memory isn't coherent
+    ///
+    /// # Safety
+    ///
+    /// data must be the renderer data the buffer was created with.
+    pub unsafe fn unmap(&self, data: &RendererData, offset: usize) -> Result<()> {
+        let atom = data.physical_device.limits.non_coherent_atom_size;
+        if let Some(offset) = self.flush_offset(atom, offset) {
+            let range = vk::MappedMemoryRange::builder()
+                .memory(self.alloc.memory)
+                .offset(offset)
+                .size(vk::WHOLE_SIZE as u64);
+            data.device.flush_mapped_memory_ranges(&[range])?;
+        }
+        Ok(())
+    }
+
+    // Start in the memory of the range to flush after writing from offset, None if the memory
+    // is coherent. The range must start on an atom, it goes to the end of the memory to not
+    // end in the middle of one.
+    fn flush_offset(&self, atom: u64, offset: usize) -> Option<u64> {
+        (self.memory_usage != AllocUsage::Staging)
+            .then(|| (self.alloc.offset + offset as u64) / atom * atom)
+    }
+
+    /// copy values to the start of a host visible buffer
+    ///
+    /// # Safety
+    ///
+    /// data must be the renderer data the buffer was created with, and no frame in flight may read
+    /// the buffer.
+    pub unsafe fn fill<T: Copy>(&self, data: &RendererData, values: &[T]) -> Result<()> {
+        self.write(values)?;
+        self.unmap(data, 0)
+    }
+
+    unsafe fn write<T: Copy>(&self, values: &[T]) -> Result<()> {
+        let ptr = self.map::<T>(0, std::mem::size_of_val(values))?;
+        std::ptr::copy_nonoverlapping(values.as_ptr(), ptr, values.len());
+        Ok(())
+    }
+
+    /// Move to the new block of a relocation, the old buffer must not be used by the gpu anymore.
+    /// The old block is freed by Allocator::end_defragment.
+    ///
+    /// # Safety
+    ///
+    /// The copies recorded by Allocator::defragment must be done and the old buffer not used by any
+    /// pending command buffer.
+    pub unsafe fn relocate(&mut self, data: &RendererData, relocation: &Relocation) -> Result<()> {
+        let info = vk::BufferCreateInfo::builder()
+            .size(self.size as u64)
+            .usage(self.usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = data.device.create_buffer(&info, None)?;
+        if let Err(e) =
+            data.device
+                .bind_buffer_memory(buffer, relocation.new.memory, relocation.new.offset)
+        {
+            data.device.destroy_buffer(buffer, None);
+            return Err(e.into());
+        }
+        data.device.destroy_buffer(self.buffer, None);
+        self.buffer = buffer;
+        self.alloc = relocation.new;
+        self.ptr = relocation.ptr;
+        Ok(())
+    }
+}
+
+// A chunk buffer written on a transfer queue, the graphics queue waits for the semaphore
+// before drawing it and acquires it if the queue families differ
+pub struct BufferHandoff {
+    pub buffer: vk::Buffer,
+    // family of the queue that wrote the buffer
+    pub src_family: u32,
+    // signaled by the copy
+    pub semaphore: vk::Semaphore,
+}
+
+impl BufferHandoff {
+    #[inline]
+    pub fn ownership_barrier(&self, dst_family: u32) -> vk::BufferMemoryBarrier {
+        ownership_barrier(self.buffer, self.src_family, dst_family)
+    }
+}
+
+// Move a chunk buffer written by a transfer to another queue family, the release on the
+// transfer queue and the acquire on the graphics queue use the same barrier.
+pub fn ownership_barrier(
+    buffer: vk::Buffer,
+    src_family: u32,
+    dst_family: u32,
+) -> vk::BufferMemoryBarrier {
+    vk::BufferMemoryBarrier::builder()
+        .src_queue_family_index(src_family)
+        .dst_queue_family_index(dst_family)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE as u64)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(
+            vk::AccessFlags::VERTEX_ATTRIBUTE_READ
+                | vk::AccessFlags::INDEX_READ
+                | vk::AccessFlags::TRANSFER_READ,
+        )
+        .build()
 }
 
 impl Drop for Buffer {
@@ -64,3 +242,128 @@ impl Drop for Buffer {
         }
     }
 }
+
+const MIN_BUCKET_SIZE: usize = 4 * 1024;
+
+// Keep the freed buffers of power of two sizes to reuse them instead of going through the allocator.
+// All the buffers of a pool should have the same usage. They are movable by the defragmentation.
+pub struct BufferPool {
+    buckets: HashMap<usize, Vec<Buffer>>,
+    max_per_bucket: usize,
+    pub reused: usize,
+    pub created: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_per_bucket: usize) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            max_per_bucket,
+            reused: 0,
+            created: 0,
+        }
+    }
+
+    #[inline]
+    pub fn bucket_size(size: usize) -> usize {
+        size.next_power_of_two().max(MIN_BUCKET_SIZE)
+    }
+
+    /// a buffer of at least size bytes
+    ///
+    /// # Safety
+    ///
+    /// The reused buffers must not be used by the gpu anymore, see give_back.
+    pub unsafe fn get(
+        &mut self,
+        data: &RendererData,
+        size: usize,
+        buffer_usage: vk::BufferUsageFlags,
+        memory_usage: AllocUsage,
+    ) -> Result<Buffer> {
+        if self.max_per_bucket == 0 {
+            self.created += 1;
+            return Buffer::create_movable(data, size, buffer_usage, memory_usage);
+        }
+        let size = Self::bucket_size(size);
+        match self.buckets.get_mut(&size).and_then(|bucket| bucket.pop()) {
+            Some(buffer) => {
+                self.reused += 1;
+                Ok(buffer)
+            }
+            None => {
+                self.created += 1;
+                Buffer::create_movable(data, size, buffer_usage, memory_usage)
+            }
+        }
+    }
+
+    pub fn buffers_mut(&mut self) -> impl Iterator<Item = &mut Buffer> {
+        self.buckets.values_mut().flatten()
+    }
+
+    // the buffer must not be used by the gpu anymore
+    pub fn give_back(&mut self, buffer: Buffer) {
+        if buffer.size != Self::bucket_size(buffer.size) {
+            return;
+        }
+        let bucket = self.buckets.entry(buffer.size).or_default();
+        if bucket.len() < self.max_per_bucket {
+            bucket.push(buffer);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vulkanalia::vk::Handle;
+
+    // a buffer without a device over host memory, at offset in its memory
+    fn test_buffer(memory: &mut [u8], offset: u64, memory_usage: AllocUsage) -> Buffer {
+        Buffer {
+            device: sync::Weak::new(),
+            allocator: sync::Weak::new(),
+            buffer: vk::Buffer::null(),
+            alloc: Block::new(vk::DeviceMemory::null(), 0, offset, memory.len() as u64),
+            ptr: memory.as_mut_ptr(),
+            size: memory.len(),
+            usage: vk::BufferUsageFlags::UNIFORM_BUFFER,
+            memory_usage,
+        }
+    }
+
+    #[test]
+    fn map_and_fill() {
+        let mut memory = [0u8; 64];
+        let buffer = test_buffer(&mut memory, 0, AllocUsage::Staging);
+        unsafe {
+            assert_eq!(buffer.map::<u8>(16, 48).unwrap(), buffer.ptr.add(16));
+            assert!(buffer.map::<u8>(16, 49).is_err());
+            assert!(buffer.map::<u8>(65, 0).is_err());
+            buffer.write(&[1u32, 2, 3]).unwrap();
+            assert!(buffer.write(&[0u32; 17]).is_err());
+        }
+        // coherent, nothing to flush
+        assert_eq!(buffer.flush_offset(64, 8), None);
+        // no device to destroy it
+        std::mem::forget(buffer);
+        assert_eq!(memory[..12], [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+
+        // the flushed range starts on the atom before the write
+        let buffer = test_buffer(&mut memory, 200, AllocUsage::Readback);
+        assert_eq!(buffer.flush_offset(64, 0), Some(192));
+        assert_eq!(buffer.flush_offset(64, 60), Some(256));
+        assert_eq!(buffer.flush_offset(1, 60), Some(260));
+        std::mem::forget(buffer);
+
+        let mut device_local = test_buffer(&mut memory, 0, AllocUsage::DeviceLocal);
+        device_local.ptr = std::ptr::null_mut();
+        assert!(unsafe { device_local.map::<u8>(0, 4) }.is_err());
+        std::mem::forget(device_local);
+    }
+}
@@ -1,134 +1,315 @@
 use anyhow::Result;
 use glm::{vec3, Mat4, Vec3};
+use log::debug;
 use nalgebra_glm as glm;
 
-use crate::inputs::Inputs;
+use crate::{
+    config::{
+        AMBIENT_LIGHT, FOV_SCROLL_STEP, FOV_SPEED_SCALING, MAX_FOV, MAX_SCALED_SPEED, MIN_FOV,
+        MOUSE_SENSITIVITY, MOVE_SPEED, ORIGIN_REBASE_DISTANCE, REVERSE_Z, SPAWN_HEIGHT,
+        SPRINT_FOV_INCREASE, SPRINT_MULTIPLIER, SUN_DIRECTION, VIEW_BOBBING,
+        VIEW_BOBBING_AMPLITUDE, VIEW_BOBBING_FREQUENCY,
+    },
+    inputs::{Action, Inputs},
+    world::Chunk,
+};
 
-use super::renderer::{RendererData, UniformBufferObject};
+use super::{
+    frustum::Frustum,
+    renderer::{RendererData, UniformBufferObject},
+};
+
+const DEFAULT_FOV: f32 = 45.0;
 
 pub struct Camera {
     view: glm::Mat4,
     proj: glm::Mat4,
 
     pub pos: Vec3,
-    fov: f32,
+    // world position the view is relative to, moves with the camera to keep the floats small
+    origin: glm::TVec3<i32>,
+    fov: f32, // without the sprint zoom
+    aspect: f32,
     near: f32,
     far: f32,
 
     yaw: f32,
     pitch: f32,
+
+    move_speed: f32, // in blocks per second
+    sprint_multiplier: f32,
+    sensitivity: f32,
+
+    // horizontal distance moved, drives the view bobbing
+    bob_distance: f32,
+    // fades the bobbing in when moving and out when standing still
+    bob_weight: f32,
+    // fades the sprint zoom in and out
+    sprint_weight: f32,
 }
 
 impl Camera {
-    pub unsafe fn new(data: &mut RendererData) -> Result<Camera> {
+    /// # Safety
+    ///
+    /// data must hold a live device, the projection is computed from its swapchain if there is one.
+    pub unsafe fn new(data: &RendererData) -> Result<Camera> {
         let mut cam = Camera {
             view: Mat4::default(),
             proj: Mat4::default(),
-            pos: vec3(-20.0, 0.0, 0.0),
-            fov: 45.0,
+            pos: vec3(-20.0, SPAWN_HEIGHT, 0.0),
+            origin: glm::TVec3::zeros(),
+            fov: DEFAULT_FOV,
+            aspect: 1.0,
             near: 0.1,
             far: 1000.0,
             yaw: 0.0,
             pitch: 0.0,
+            move_speed: MOVE_SPEED,
+            sprint_multiplier: SPRINT_MULTIPLIER,
+            sensitivity: MOUSE_SENSITIVITY,
+            bob_distance: 0.0,
+            bob_weight: 0.0,
+            sprint_weight: 0.0,
         };
 
         cam.update_view();
-        cam.update_projection(data);
-
-        cam.send_all(data)?;
+        // a headless renderer has no swapchain nor uniforms
+        if data.swapchain.is_some() {
+            cam.update_projection(data);
+            cam.send_all(data)?;
+        }
 
         Ok(cam)
     }
 
+    /// # Safety
+    ///
+    /// The uniforms of data must be created and not read by a frame in flight.
     pub unsafe fn send_all(&self, data: &RendererData) -> Result<()> {
-        let ubo = UniformBufferObject {
-            view: self.view,
-            proj: self.proj,
-        };
-
-        data.uniforms
-            .as_ref()
-            .unwrap()
-            .buffers
-            .iter()
-            .for_each(|b| {
-                *b.lock().unwrap().ptr.cast() = ubo;
-            });
-
+        let ubo = self.ubo();
+        for buffer in &data.uniforms.as_ref().unwrap().buffers {
+            buffer.lock().unwrap().fill(data, &[ubo])?;
+        }
         Ok(())
     }
 
+    /// # Safety
+    ///
+    /// The uniforms of data must be created and the buffer of image_index not read by a frame in
+    /// flight.
     pub unsafe fn send(&self, data: &RendererData, image_index: usize) -> Result<()> {
-        let ptr = data.uniforms.as_ref().unwrap().buffers[image_index]
+        data.uniforms.as_ref().unwrap().buffers[image_index]
             .lock()
             .unwrap()
-            .ptr;
-
-        *ptr.cast() = self.view;
+            .fill(data, &[self.ubo()])
+    }
 
-        Ok(())
+    #[inline]
+    fn ubo(&self) -> UniformBufferObject {
+        UniformBufferObject {
+            view: self.view,
+            proj: self.proj,
+            origin: glm::vec4(self.origin.x, self.origin.y, self.origin.z, 0),
+            sun: glm::normalize(&Vec3::from(SUN_DIRECTION)).push(AMBIENT_LIGHT),
+        }
     }
 
-    pub unsafe fn update(&mut self, inputs: &Inputs, dt: f32) {
-        const SENSITIVITY: f32 = 5.0;
+    // Move the origin to the chunk of the camera when the camera is too far from it.
+    // Only the rendering is relative to the origin, the world still uses absolute positions.
+    fn rebase(&mut self) {
+        let size = Chunk::size();
+        let offset = self.pos - self.origin.map(|c| c as f32);
+        if offset.abs().max() < ORIGIN_REBASE_DISTANCE {
+            return;
+        }
+        self.origin = vec3(
+            (self.pos.x / size[0] as f32).floor() as i32 * size[0],
+            (self.pos.y / size[1] as f32).floor() as i32 * size[1],
+            (self.pos.z / size[2] as f32).floor() as i32 * size[2],
+        );
+        debug!("Rebase origin to {:?}", self.origin);
+    }
 
-        self.yaw += inputs.mouse_delta.0 as f32 * dt * SENSITIVITY;
-        self.pitch -= inputs.mouse_delta.1 as f32 * dt * SENSITIVITY;
+    pub fn update(&mut self, inputs: &Inputs, dt: f32) {
+        self.yaw += inputs.mouse_delta.0 as f32 * dt * self.sensitivity;
+        self.pitch -= inputs.mouse_delta.1 as f32 * dt * self.sensitivity;
 
-        if self.pitch > 89.0 {
-            self.pitch = 89.0;
-        }
-        if self.pitch < -89.0 {
-            self.pitch = -89.0;
-        }
+        self.pitch = self.pitch.clamp(-89.0, 89.0);
 
         let dir =
             Vec3::new(self.yaw.to_radians().cos(), 0., self.yaw.to_radians().sin()).normalize();
         let right = dir.cross(&Vec3::y()).normalize();
         let up = Vec3::y();
 
-        let speed = 400. * dt;
+        let sprinting = inputs.action_pressed(Action::Sprint);
+        let old_pos = self.pos;
 
-        if inputs.is_key_pressed(winit::event::VirtualKeyCode::Z) {
-            self.pos += dir * speed;
+        let mut movement = Vec3::zeros();
+        if inputs.action_pressed(Action::MoveForward) {
+            movement += dir;
+        }
+        if inputs.action_pressed(Action::MoveBackward) {
+            movement -= dir;
         }
-        if inputs.is_key_pressed(winit::event::VirtualKeyCode::S) {
-            self.pos -= dir * speed;
+        if inputs.action_pressed(Action::MoveLeft) {
+            movement -= right;
         }
-        if inputs.is_key_pressed(winit::event::VirtualKeyCode::Q) {
-            self.pos -= right * speed;
+        if inputs.action_pressed(Action::MoveRight) {
+            movement += right;
         }
-        if inputs.is_key_pressed(winit::event::VirtualKeyCode::D) {
-            self.pos += right * speed;
+        if inputs.action_pressed(Action::MoveUp) {
+            movement += up;
         }
-        if inputs.is_key_pressed(winit::event::VirtualKeyCode::Space) {
-            self.pos += up * speed;
+        if inputs.action_pressed(Action::MoveDown) {
+            movement -= up;
         }
-        if inputs.is_key_pressed(winit::event::VirtualKeyCode::LShift) {
-            self.pos -= up * speed;
+        // the same speed in every direction, diagonals included
+        if movement != Vec3::zeros() {
+            self.pos += movement.normalize() * self.speed(sprinting) * dt;
+        }
+
+        if inputs.scroll_delta != 0.0 {
+            self.set_fov(self.fov - inputs.scroll_delta * FOV_SCROLL_STEP);
         }
 
+        let target = if sprinting { 1.0 } else { 0.0 };
+        if self.sprint_weight != target {
+            self.sprint_weight += (target - self.sprint_weight) * (dt * 8.0).min(1.0);
+            if (target - self.sprint_weight).abs() < 0.001 {
+                self.sprint_weight = target;
+            }
+            self.build_projection();
+        }
+
+        if VIEW_BOBBING {
+            let moved = (self.pos.xz() - old_pos.xz()).norm();
+            self.bob_distance += moved;
+            let target = if moved > 0.0 { 1.0 } else { 0.0 };
+            self.bob_weight += (target - self.bob_weight) * (dt * 8.0).min(1.0);
+        }
+
+        self.rebase();
         self.update_view();
     }
 
-    fn update_view(&mut self) {
+    // in blocks per second
+    fn speed(&self, sprinting: bool) -> f32 {
+        let speed = if sprinting {
+            self.move_speed * self.sprint_multiplier
+        } else {
+            self.move_speed
+        };
+        if !FOV_SPEED_SCALING {
+            return speed;
+        }
+        // scale with the width of the visible area
+        let scale =
+            (self.current_fov().to_radians() / 2.0).tan() / (DEFAULT_FOV.to_radians() / 2.0).tan();
+        (speed * scale).min(MAX_SCALED_SPEED)
+    }
+
+    // in blocks per second
+    pub fn set_move_speed(&mut self, speed: f32) {
+        self.move_speed = speed.max(0.0);
+    }
+
+    pub fn set_sprint_multiplier(&mut self, multiplier: f32) {
+        self.sprint_multiplier = multiplier.max(0.0);
+    }
+
+    pub fn set_sensitivity(&mut self, sensitivity: f32) {
+        self.sensitivity = sensitivity;
+    }
+
+    // in degrees, clamped to MIN_FOV..=MAX_FOV
+    pub fn set_fov(&mut self, fov: f32) {
+        self.fov = fov.clamp(MIN_FOV, MAX_FOV);
+        self.build_projection();
+    }
+
+    #[inline]
+    pub fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    // with the sprint zoom
+    fn current_fov(&self) -> f32 {
+        (self.fov + SPRINT_FOV_INCREASE * self.sprint_weight).clamp(MIN_FOV, MAX_FOV)
+    }
+
+    #[inline]
+    pub fn yaw(&self) -> f32 {
+        self.yaw
+    }
+
+    #[inline]
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    // world position the view is relative to
+    #[inline]
+    pub fn origin(&self) -> glm::TVec3<i32> {
+        self.origin
+    }
+
+    #[inline]
+    pub fn view_proj(&self) -> Mat4 {
+        self.proj * self.view
+    }
+
+    // view volume in coordinates relative to the origin
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(&self.view_proj())
+    }
+
+    pub fn front(&self) -> Vec3 {
         let mut front = Vec3::default();
         front.x = self.yaw.to_radians().cos() * self.pitch.to_radians().cos();
         front.y = self.pitch.to_radians().sin();
         front.z = self.yaw.to_radians().sin() * self.pitch.to_radians().cos();
-        let rotation = front.normalize();
+        front.normalize()
+    }
+
+    fn update_view(&mut self) {
+        let rotation = self.front();
 
-        self.view = glm::look_at(&self.pos, &(self.pos + rotation), &glm::vec3(0.0, 1.0, 0.0));
+        // only the view is offset, not the position used by the world
+        let eye = self.pos - self.origin.map(|c| c as f32) + self.bob_offset();
+        self.view = glm::look_at(&eye, &(eye + rotation), &glm::vec3(0.0, 1.0, 0.0));
     }
 
-    pub fn update_projection(&mut self, data: &RendererData) {
-        self.proj = glm::perspective_rh_zo(
-            data.swapchain.as_ref().unwrap().extent.width as f32
-                / data.swapchain.as_ref().unwrap().extent.height as f32,
-            self.fov.to_radians(),
-            self.near,
-            self.far,
+    // one vertical bounce per step and one horizontal sway every two steps
+    fn bob_offset(&self) -> Vec3 {
+        if !VIEW_BOBBING || self.bob_weight == 0.0 {
+            return Vec3::zeros();
+        }
+        let phase = self.bob_distance * VIEW_BOBBING_FREQUENCY * std::f32::consts::PI;
+        let amplitude = VIEW_BOBBING_AMPLITUDE * self.bob_weight;
+        let right = vec3(
+            -self.yaw.to_radians().sin(),
+            0.0,
+            self.yaw.to_radians().cos(),
         );
+        // the eye only goes down so it can't be pushed up into a block
+        vec3(0.0, -phase.sin().abs() * amplitude, 0.0) + right * (phase.cos() * amplitude * 0.5)
+    }
+
+    // the fov is kept when the swapchain is resized
+    pub fn update_projection(&mut self, data: &RendererData) {
+        let extent = data.swapchain.as_ref().unwrap().extent;
+        self.aspect = extent.width as f32 / extent.height as f32;
+        self.build_projection();
+    }
+
+    fn build_projection(&mut self) {
+        // swapping the planes maps the near one to a depth of 1 and the far one to 0
+        let (near, far) = if REVERSE_Z {
+            (self.far, self.near)
+        } else {
+            (self.near, self.far)
+        };
+        self.proj = glm::perspective_rh_zo(self.aspect, self.current_fov().to_radians(), near, far);
         self.proj[(1, 1)] *= -1.0;
     }
 }
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use vulkanalia::vk;
+
+use super::{
+    images::{Image, ImageInfo},
+    renderer::RendererData,
+};
+
+// multisampled color attachment, resolved into the swapchain image at the end of the render pass
+pub struct ColorBuffer {
+    pub image: Image,
+}
+
+impl ColorBuffer {
+    /// None without multisampling, the render pass draws to the swapchain image directly
+    ///
+    /// # Safety
+    ///
+    /// The swapchain of data must be created.
+    pub unsafe fn create(data: &RendererData) -> Result<Option<Self>> {
+        Self::create_sized(data, data.swapchain.as_ref().unwrap().extent)
+    }
+
+    /// Same as create for an offscreen image of extent.
+    ///
+    /// # Safety
+    ///
+    /// The swapchain of data must be created, its format is used. Neither dimension of extent
+    /// may be zero.
+    pub unsafe fn create_sized(data: &RendererData, extent: vk::Extent2D) -> Result<Option<Self>> {
+        if data.msaa_samples == vk::SampleCountFlags::_1 {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            image: Image::create(
+                data,
+                &ImageInfo {
+                    size: (extent.width, extent.height),
+                    mip_levels: 1,
+                    format: data.swapchain.as_ref().unwrap().format,
+                    tiling: vk::ImageTiling::OPTIMAL,
+                    // only used during the render pass
+                    usage: vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                    aspects: vk::ImageAspectFlags::COLOR,
+                    samples: data.msaa_samples,
+                },
+            )?,
+        }))
+    }
+}
@@ -4,7 +4,7 @@ use vulkanalia::{
     Device,
 };
 
-use std::sync::{self, Arc};
+use std::sync::{self, Arc, Mutex};
 
 use super::renderer::RendererData;
 
@@ -14,13 +14,35 @@ pub struct CommandPool {
 }
 
 impl CommandPool {
+    /// # Safety
+    ///
+    /// queue_family must be a queue family of the device of data.
     pub unsafe fn create(data: &RendererData, queue_family: u32) -> Result<Self> {
+        Self::create_with_flags(
+            data,
+            queue_family,
+            vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER
+                | vk::CommandPoolCreateFlags::TRANSIENT,
+        )
+    }
+
+    /// the buffers can't be reset one by one, the whole pool is reset before recording again
+    ///
+    /// # Safety
+    ///
+    /// queue_family must be a queue family of the device of data.
+    pub unsafe fn create_resettable(data: &RendererData, queue_family: u32) -> Result<Self> {
+        Self::create_with_flags(data, queue_family, vk::CommandPoolCreateFlags::TRANSIENT)
+    }
+
+    unsafe fn create_with_flags(
+        data: &RendererData,
+        queue_family: u32,
+        flags: vk::CommandPoolCreateFlags,
+    ) -> Result<Self> {
         let info = vk::CommandPoolCreateInfo::builder()
             .queue_family_index(queue_family)
-            .flags(
-                vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER
-                    | vk::CommandPoolCreateFlags::TRANSIENT,
-            );
+            .flags(flags);
 
         let pool = data.device.create_command_pool(&info, None)?;
 
@@ -30,14 +52,18 @@ impl CommandPool {
         })
     }
 
+    /// # Safety
+    ///
+    /// device must be the device the pool was created on.
     pub unsafe fn allocate_command_buffers(
         &self,
         device: &Device,
         count: u32,
+        level: vk::CommandBufferLevel,
     ) -> Result<Vec<CommandBuffer>> {
         let info = vk::CommandBufferAllocateInfo::builder()
             .command_pool(self.pool)
-            .level(vk::CommandBufferLevel::PRIMARY)
+            .level(level)
             .command_buffer_count(count);
 
         let buffers = device.allocate_command_buffers(&info)?;
@@ -50,10 +76,16 @@ impl CommandPool {
         Ok(buffers)
     }
 
-    // pub unsafe fn reset(&mut self, device: &Device) -> Result<()> {
-    //     device.reset_command_pool(self.pool, vk::CommandPoolResetFlags::empty())?;
-    //     Ok(())
-    // }
+    /// All the buffers of the pool go back to the initial state, none may be pending.
+    /// The memory is kept for the next recording.
+    ///
+    /// # Safety
+    ///
+    /// No command buffer of the pool may be pending execution.
+    pub unsafe fn reset(&self, device: &Device) -> Result<()> {
+        device.reset_command_pool(self.pool, vk::CommandPoolResetFlags::empty())?;
+        Ok(())
+    }
 }
 
 impl Drop for CommandPool {
@@ -73,6 +105,10 @@ pub struct CommandBuffer {
 }
 
 impl CommandBuffer {
+    /// # Safety
+    ///
+    /// device must be the device the buffer was allocated on, and the buffer must not be pending
+    /// execution.
     #[inline]
     pub unsafe fn begin(&mut self, device: &Device) -> Result<()> {
         let info = vk::CommandBufferBeginInfo::builder()
@@ -81,9 +117,100 @@ impl CommandBuffer {
         Ok(())
     }
 
+    /// for the buffers submitted again without being recorded again
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be pending execution.
+    #[inline]
+    pub unsafe fn begin_reusable(&mut self, device: &Device) -> Result<()> {
+        let info = vk::CommandBufferBeginInfo::builder();
+        device.begin_command_buffer(self.buffer, &info)?;
+        Ok(())
+    }
+
+    /// secondary buffers executed inside the render pass, reused like begin_reusable
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be pending execution, and the render pass and framebuffer of the
+    /// inheritance info must outlive it.
+    #[inline]
+    pub unsafe fn begin_secondary(
+        &mut self,
+        device: &Device,
+        inheritance: &vk::CommandBufferInheritanceInfo,
+    ) -> Result<()> {
+        let info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
+            .inheritance_info(inheritance);
+        device.begin_command_buffer(self.buffer, &info)?;
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// device must be the device the buffer was allocated on, and the buffer must be recording.
     #[inline]
     pub unsafe fn end(&mut self, device: &Device) -> Result<()> {
         device.end_command_buffer(self.buffer)?;
         Ok(())
     }
 }
+
+// what the draw commands of a chunk were recorded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkCommandsKey {
+    pub generation: u64, // swapchain generation, the render pass and descriptor sets change with it
+    pub pipeline: vk::Pipeline,
+    pub buffer: vk::Buffer,
+    pub vertices_count: usize,
+    pub indices_count: usize,
+    pub transparent_indices_count: usize,
+    // None if the transparent faces are drawn with the others by `pipeline`
+    pub transparent_pipeline: Option<vk::Pipeline>,
+}
+
+// what dropped chunk commands used, the renderer frees it once the frames using it are done
+#[derive(Debug, Default)]
+pub struct RetiredCommands {
+    pub buffers: Vec<vk::CommandBuffer>,
+}
+
+// secondary command buffers drawing a chunk, one per swapchain image
+#[derive(Debug)]
+pub struct ChunkCommands {
+    pub key: ChunkCommandsKey,
+    pub buffers: Vec<CommandBuffer>,
+    // the transparent faces drawn after all the opaque ones, empty if there are none
+    pub transparent: Vec<CommandBuffer>,
+    retired: Arc<Mutex<RetiredCommands>>,
+}
+
+impl ChunkCommands {
+    pub fn new(
+        key: ChunkCommandsKey,
+        buffers: Vec<CommandBuffer>,
+        transparent: Vec<CommandBuffer>,
+        retired: Arc<Mutex<RetiredCommands>>,
+    ) -> Self {
+        Self {
+            key,
+            buffers,
+            transparent,
+            retired,
+        }
+    }
+}
+
+impl Drop for ChunkCommands {
+    fn drop(&mut self) {
+        let mut retired = self.retired.lock().unwrap();
+        retired.buffers.extend(
+            self.buffers
+                .iter()
+                .chain(&self.transparent)
+                .map(|b| b.buffer),
+        );
+    }
+}
@@ -1,28 +1,210 @@
 use anyhow::{anyhow, Result};
-use vulkanalia::vk::{self, InstanceV1_0};
+use log::warn;
+use vulkanalia::vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0};
 
-use super::{images::Image, renderer::RendererData};
+use super::{
+    buffer::Buffer,
+    images::{Image, ImageInfo},
+    memory::AllocUsage,
+    renderer::RendererData,
+    sync,
+};
 
 pub struct DepthBuffer {
     pub image: Image,
+    format: vk::Format,
+    extent: vk::Extent2D,
 }
 
 impl DepthBuffer {
+    /// # Safety
+    ///
+    /// The swapchain of data must be created.
     pub unsafe fn create(data: &RendererData) -> Result<Self> {
+        Self::create_sized(data, data.swapchain.as_ref().unwrap().extent)
+    }
+
+    /// Same as create for an offscreen image of extent.
+    ///
+    /// # Safety
+    ///
+    /// Neither dimension of extent may be zero.
+    pub unsafe fn create_sized(data: &RendererData, extent: vk::Extent2D) -> Result<Self> {
+        let format = get_depth_format(data)?;
+        let mut usage = vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
+        if data.depth_readback && supports_readback(data, format) {
+            usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
         Ok(Self {
             image: Image::create(
                 data,
-                (
-                    data.swapchain.as_ref().unwrap().extent.width,
-                    data.swapchain.as_ref().unwrap().extent.height,
-                ),
-                get_depth_format(data)?,
-                vk::ImageTiling::OPTIMAL,
-                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-                vk::ImageAspectFlags::DEPTH,
+                &ImageInfo {
+                    size: (extent.width, extent.height),
+                    mip_levels: 1,
+                    format,
+                    tiling: vk::ImageTiling::OPTIMAL,
+                    usage,
+                    aspects: vk::ImageAspectFlags::DEPTH,
+                    samples: data.msaa_samples,
+                },
             )?,
+            format,
+            extent,
         })
     }
+
+    /// Copy the depth of the last rendered frame to the cpu, row by row. The depth is
+    /// reversed with REVERSE_Z, 0 is the far plane.
+    /// Returns None if the depth format can't be copied from on this device or if it is
+    /// multisampled. The depth is only kept with Renderer::set_depth_readback.
+    /// This waits for the copy to finish so it should only be used for tests and debugging.
+    ///
+    /// # Safety
+    ///
+    /// No frame may be rendering to the depth buffer, the copy is submitted right away on the
+    /// graphics queue.
+    pub unsafe fn read_back(&self, data: &RendererData) -> Result<Option<Vec<f32>>> {
+        let format = self.format;
+        if !data.depth_readback {
+            return Err(anyhow!(
+                "The depth isn't stored, enable it with Renderer::set_depth_readback"
+            ));
+        }
+        if !supports_readback(data, format) {
+            warn!("Depth readback not supported for format {:?}", format);
+            return Ok(None);
+        }
+
+        let extent = self.extent;
+        let texels = (extent.width * extent.height) as usize;
+        // D24 is packed in 32 bits texels and the depth aspect of D32S8 is copied without the stencil
+        let buffer = Buffer::create(
+            data,
+            texels * 4,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            AllocUsage::Staging,
+        )?;
+
+        let aspects = if format == vk::Format::D32_SFLOAT {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        };
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(aspects)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+
+        let command_pool = data.command_pool.as_ref().unwrap();
+        let mut command_buffer = command_pool.allocate_command_buffers(
+            &data.device,
+            1,
+            vk::CommandBufferLevel::PRIMARY,
+        )?[0];
+        command_buffer.begin(&data.device)?;
+
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.image.image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+        data.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[to_transfer],
+        );
+
+        let region = vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                    .mip_level(0)
+                    .base_array_layer(0)
+                    .layer_count(1),
+            )
+            .image_offset(vk::Offset3D::default())
+            .image_extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            });
+        data.device.cmd_copy_image_to_buffer(
+            command_buffer.buffer,
+            self.image.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            buffer.buffer,
+            &[region],
+        );
+
+        let to_attachment = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.image.image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
+        data.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[to_attachment],
+        );
+
+        command_buffer.end(&data.device)?;
+
+        let fence = sync::create_fences(&data.device, false, 1)?[0];
+        let buffers = &[command_buffer.buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(buffers);
+        let result = {
+            let _queue = data.queue_lock.lock().unwrap();
+            data.device
+                .queue_submit(data.graphics_queue, &[submit_info], fence)
+        }
+        .and_then(|_| data.device.wait_for_fences(&[fence], true, u64::MAX));
+        data.device.destroy_fence(fence, None);
+        data.device
+            .free_command_buffers(command_pool.pool, &[command_buffer.buffer]);
+        result?;
+
+        let raw = std::slice::from_raw_parts(buffer.ptr.cast::<u32>(), texels);
+        let depths = if format == vk::Format::D24_UNORM_S8_UINT {
+            raw.iter()
+                .map(|d| (d & 0x00FF_FFFF) as f32 / 0x00FF_FFFF as f32)
+                .collect()
+        } else {
+            raw.iter().map(|d| f32::from_bits(*d)).collect()
+        };
+
+        Ok(Some(depths))
+    }
+}
+
+// multisampled images can't be copied to a buffer
+unsafe fn supports_readback(data: &RendererData, format: vk::Format) -> bool {
+    data.msaa_samples == vk::SampleCountFlags::_1
+        && data
+            .instance
+            .get_physical_device_format_properties(data.physical_device.device, format)
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::TRANSFER_SRC)
 }
 
 unsafe fn get_supported_format(
@@ -48,6 +230,9 @@ unsafe fn get_supported_format(
         .ok_or_else(|| anyhow!("Failed to find supported format!"))
 }
 
+/// # Safety
+///
+/// data must hold a live instance and physical device.
 pub unsafe fn get_depth_format(data: &RendererData) -> Result<vk::Format> {
     let candidates = &[
         vk::Format::D32_SFLOAT,
@@ -62,3 +247,128 @@ pub unsafe fn get_depth_format(data: &RendererData) -> Result<vk::Format> {
         vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::renderer::Renderer;
+    use vulkanalia::{
+        loader::{LibloadingLoader, LIBRARY},
+        vk::Handle,
+        Entry,
+    };
+
+    // None without a vulkan device
+    unsafe fn headless() -> Option<(Entry, Renderer)> {
+        let entry = Entry::new(LibloadingLoader::new(LIBRARY).ok()?).ok()?;
+        let renderer = Renderer::new_headless(&entry).ok()?;
+        Some((entry, renderer))
+    }
+
+    // clear the depth in a render pass storing it, as the frames do with the readback
+    unsafe fn clear(data: &RendererData, depth: &DepthBuffer, value: f32) -> Result<()> {
+        let attachment = vk::AttachmentDescription::builder()
+            .format(depth.format)
+            .samples(vk::SampleCountFlags::_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+        let attachment_ref = vk::AttachmentReference::builder()
+            .attachment(0)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+        let subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .depth_stencil_attachment(&attachment_ref);
+        let attachments = &[attachment];
+        let subpasses = &[subpass];
+        let info = vk::RenderPassCreateInfo::builder()
+            .attachments(attachments)
+            .subpasses(subpasses);
+        let render_pass = data.device.create_render_pass(&info, None)?;
+
+        let views = &[depth.image.view];
+        let info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(views)
+            .width(depth.extent.width)
+            .height(depth.extent.height)
+            .layers(1);
+        let framebuffer = data.device.create_framebuffer(&info, None)?;
+
+        let command_pool = data.command_pool.as_ref().unwrap();
+        let mut command_buffer = command_pool.allocate_command_buffers(
+            &data.device,
+            1,
+            vk::CommandBufferLevel::PRIMARY,
+        )?[0];
+        command_buffer.begin(&data.device)?;
+        let clear_values = &[vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: value,
+                stencil: 0,
+            },
+        }];
+        let info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: depth.extent,
+            })
+            .clear_values(clear_values);
+        data.device.cmd_begin_render_pass(
+            command_buffer.buffer,
+            &info,
+            vk::SubpassContents::INLINE,
+        );
+        data.device.cmd_end_render_pass(command_buffer.buffer);
+        command_buffer.end(&data.device)?;
+
+        let buffers = &[command_buffer.buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(buffers);
+        data.device
+            .queue_submit(data.graphics_queue, &[submit_info], vk::Fence::null())?;
+        data.device.queue_wait_idle(data.graphics_queue)?;
+        data.device
+            .free_command_buffers(command_pool.pool, &[command_buffer.buffer]);
+        data.device.destroy_framebuffer(framebuffer, None);
+        data.device.destroy_render_pass(render_pass, None);
+        Ok(())
+    }
+
+    #[test]
+    fn read_back_headless() {
+        unsafe {
+            let Some((_entry, renderer)) = headless() else {
+                eprintln!("No vulkan device, depth readback not tested");
+                return;
+            };
+            let mut data = renderer.data.write().unwrap();
+            data.msaa_samples = vk::SampleCountFlags::_1;
+            let extent = vk::Extent2D {
+                width: 5,
+                height: 3,
+            };
+
+            // not stored by default
+            let depth = DepthBuffer::create_sized(&data, extent).unwrap();
+            assert!(depth.read_back(&data).is_err());
+            drop(depth);
+
+            data.depth_readback = true;
+            let depth = DepthBuffer::create_sized(&data, extent).unwrap();
+            clear(&data, &depth, 0.25).unwrap();
+            match depth.read_back(&data).unwrap() {
+                Some(depths) => {
+                    assert_eq!(depths.len(), 5 * 3);
+                    // D24 is rounded to 24 bits
+                    assert!(depths.iter().all(|d| (d - 0.25).abs() < 1e-6), "{depths:?}");
+                }
+                None => eprintln!("Depth readback not supported by the device"),
+            }
+        }
+    }
+}
@@ -1,14 +1,17 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::sync::Arc;
-use vulkanalia::vk::{DeviceV1_0, HasBuilder};
+use vulkanalia::vk::{DeviceV1_0, HasBuilder, InstanceV1_0};
 use vulkanalia::{vk, Device, Instance};
 
-use crate::config::{DEVICE_EXTENSIONS, VALIDATION_ENABLED, VALIDATION_LAYER};
+use crate::config::{DEVICE_EXTENSIONS, VALIDATION_LAYER};
 use crate::render::physical_device::QueueDef;
 
 use super::physical_device::PhysicalDevice;
 
+/// # Safety
+///
+/// physical_device must have been picked from instance, the device must be destroyed before it.
 pub unsafe fn create(
     instance: &Instance,
     physical_device: &PhysicalDevice,
@@ -34,11 +37,19 @@ pub unsafe fn create(
         insert(queue);
     }
 
+    let families = instance.get_physical_device_queue_family_properties(physical_device.device);
     let mut priorities = vec![Vec::new(); max_family as usize + 1];
     for i in 0..=max_family {
-        if let Some(queue_count) = queues.get(&i) {
+        if let Some(max_index) = queues.get(&i) {
             // +1 because we store the max index and vulkan want the count
-            priorities[i as usize].resize(*queue_count as usize + 1, 1.0);
+            let count = *max_index + 1;
+            let available = families[i as usize].queue_count;
+            if count > available {
+                return Err(anyhow!(
+                    "Queue family {i} has {available} queues but {count} are used"
+                ));
+            }
+            priorities[i as usize].resize(count as usize, 1.0);
         }
     }
 
@@ -51,24 +62,37 @@ pub unsafe fn create(
         })
         .collect();
 
-    let layers = if VALIDATION_ENABLED {
+    // same layers as the instance, the validation can be toggled at runtime
+    let layers = if instance.layers().contains(&VALIDATION_LAYER) {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         Vec::new()
     };
 
-    let features = vk::PhysicalDeviceFeatures::builder().sampler_anisotropy(true);
+    let features = vk::PhysicalDeviceFeatures::builder()
+        .sampler_anisotropy(true)
+        .wide_lines(physical_device.features.wide_lines == vk::TRUE);
 
     let extensions = DEVICE_EXTENSIONS
         .iter()
+        .chain(&physical_device.optional_extensions)
         .map(|n| n.as_ptr())
         .collect::<Vec<_>>();
 
-    let info = vk::DeviceCreateInfo::builder()
+    // an array of textures indexed with the texture of each vertex
+    let mut indexing = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+        .runtime_descriptor_array(true)
+        .descriptor_binding_variable_descriptor_count(true)
+        .shader_sampled_image_array_non_uniform_indexing(true);
+
+    let mut info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
         .enabled_layer_names(&layers)
         .enabled_features(&features)
         .enabled_extension_names(&extensions);
+    if physical_device.bindless_textures {
+        info = info.push_next(&mut indexing);
+    }
 
     let device = instance.create_device(physical_device.device, &info, None)?;
 
@@ -84,6 +108,9 @@ pub unsafe fn create(
     Ok((device, graphics_queue, present_queue))
 }
 
+/// # Safety
+///
+/// Every object created from the device must be destroyed before, and the device not used after.
 #[inline]
 pub unsafe fn destroy(device: &mut Arc<Device>) {
     device.destroy_device(None);
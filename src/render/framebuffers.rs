@@ -14,6 +14,10 @@ pub struct Framebuffers {
 }
 
 impl Framebuffers {
+    /// # Safety
+    ///
+    /// The swapchain, depth buffer and pipeline of data must be created, and the color buffer too
+    /// when multisampled.
     pub unsafe fn create(data: &RendererData) -> Result<Self> {
         let framebuffers = data
             .swapchain
@@ -22,10 +26,15 @@ impl Framebuffers {
             .image_views
             .iter()
             .map(|i| {
-                let attachments = &[*i, data.depth_buffer.as_ref().unwrap().image.view];
+                let depth = data.depth_buffer.as_ref().unwrap().image.view;
+                // the swapchain image is the resolve attachment when multisampled
+                let attachments = match &data.color_buffer {
+                    Some(color) => vec![color.image.view, depth, *i],
+                    None => vec![*i, depth],
+                };
                 let create_info = vk::FramebufferCreateInfo::builder()
                     .render_pass(data.pipeline.as_ref().unwrap().render_pass)
-                    .attachments(attachments)
+                    .attachments(&attachments)
                     .width(data.swapchain.as_ref().unwrap().extent.width)
                     .height(data.swapchain.as_ref().unwrap().extent.height)
                     .layers(1);
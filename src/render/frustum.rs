@@ -0,0 +1,113 @@
+use glm::{Mat4, Vec3, Vec4};
+use nalgebra_glm as glm;
+
+// the six planes of a view volume, the normals point inside
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    // Extract the planes from a view projection matrix with a 0 to 1 depth range, reversed
+    // or not: the near and far planes are just swapped.
+    pub fn from_matrix(m: &Mat4) -> Self {
+        let row = |i: usize| m.row(i).transpose();
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let planes = [w + x, w - x, w + y, w - y, z, w - z].map(|plane| {
+            let length = plane.xyz().norm();
+            if length > 0.0 {
+                plane / length
+            } else {
+                plane
+            }
+        });
+        Self { planes }
+    }
+
+    // False if the box is fully outside of a plane. Boxes near the corners of the frustum
+    // may be outside of it and still intersect.
+    pub fn intersects_aabb(&self, min: &Vec3, max: &Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner of the box the furthest along the normal
+            let corner = Vec3::new(
+                if plane.x >= 0.0 { max.x } else { min.x },
+                if plane.y >= 0.0 { max.y } else { min.y },
+                if plane.z >= 0.0 { max.z } else { min.z },
+            );
+            plane.xyz().dot(&corner) + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // camera at the origin looking along +x, as set up by Camera
+    fn frustum(near: f32, far: f32) -> Frustum {
+        let mut proj = glm::perspective_rh_zo(16.0 / 9.0, 45f32.to_radians(), near, far);
+        proj[(1, 1)] *= -1.0;
+        let view = glm::look_at(
+            &Vec3::zeros(),
+            &Vec3::new(1.0, 0.0, 0.0),
+            &Vec3::new(0.0, 1.0, 0.0),
+        );
+        Frustum::from_matrix(&(proj * view))
+    }
+
+    // box of size 2 around a point
+    fn intersects(frustum: &Frustum, x: f32, y: f32, z: f32) -> bool {
+        let center = Vec3::new(x, y, z);
+        frustum.intersects_aabb(&center.add_scalar(-1.0), &center.add_scalar(1.0))
+    }
+
+    #[test]
+    fn intersects_aabb() {
+        let frustum = frustum(0.1, 1000.0);
+        // inside
+        assert!(intersects(&frustum, 10.0, 0.0, 0.0));
+        assert!(intersects(&frustum, 500.0, 50.0, -50.0));
+        // straddling the camera, the near plane, a side plane and the far plane
+        assert!(intersects(&frustum, 0.0, 0.0, 0.0));
+        assert!(intersects(&frustum, 10.0, 4.5, 0.0));
+        assert!(intersects(&frustum, 1000.5, 0.0, 0.0));
+        // outside, behind, on the sides and past the far plane
+        assert!(!intersects(&frustum, -10.0, 0.0, 0.0));
+        assert!(!intersects(&frustum, 10.0, 100.0, 0.0));
+        assert!(!intersects(&frustum, 10.0, -100.0, 0.0));
+        assert!(!intersects(&frustum, 10.0, 0.0, 100.0));
+        assert!(!intersects(&frustum, 10.0, 0.0, -100.0));
+        assert!(!intersects(&frustum, 2000.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn reverse_z() {
+        // the planes swapped as done by Camera, the near one at a depth of 1
+        let proj = glm::perspective_rh_zo(16.0 / 9.0, 45f32.to_radians(), 1000.0, 0.1);
+        let depth = |distance: f32| {
+            let clip = proj * Vec4::new(0.0, 0.0, -distance, 1.0);
+            clip.z / clip.w
+        };
+        assert!((depth(0.1) - 1.0).abs() < 1e-4);
+        assert!(depth(1000.0).abs() < 1e-4);
+        assert!(depth(10.0) > depth(20.0));
+
+        // the same volume as without reverse z
+        let reversed = frustum(1000.0, 0.1);
+        let frustum = frustum(0.1, 1000.0);
+        for (x, y, z) in [
+            (10.0, 0.0, 0.0),
+            (0.0, 0.0, 0.0),
+            (10.0, 4.5, 0.0),
+            (1000.5, 0.0, 0.0),
+            (-10.0, 0.0, 0.0),
+            (10.0, 0.0, 100.0),
+            (2000.0, 0.0, 0.0),
+        ] {
+            assert_eq!(
+                intersects(&reversed, x, y, z),
+                intersects(&frustum, x, y, z)
+            );
+        }
+    }
+}
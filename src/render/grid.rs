@@ -0,0 +1,119 @@
+use std::mem::size_of;
+
+use anyhow::Result;
+use nalgebra_glm::{vec2, vec3, TVec3, Vec3};
+use vulkanalia::vk::{self, DeviceV1_0};
+
+use crate::{config::CHUNK_GRID_RADIUS, world::Chunk};
+
+use super::{
+    buffer::Buffer,
+    memory::AllocUsage,
+    renderer::RendererData,
+    vertex::{DrawConstants, Vertex},
+};
+
+// lines along the 3 axis, on each chunk boundary of a (2 * radius + 1)^3 chunks cube
+const GRID_LINES_PER_AXIS: usize = (2 * CHUNK_GRID_RADIUS + 2) * (2 * CHUNK_GRID_RADIUS + 2);
+const GRID_VERTICES_COUNT: usize = GRID_LINES_PER_AXIS * 3 * 2;
+
+// world space lines on the chunk boundaries around the camera
+pub struct ChunkGrid {
+    // one buffer per swapchain image so we never write in a buffer used by a frame in flight
+    buffers: Vec<Buffer>,
+}
+
+impl ChunkGrid {
+    /// # Safety
+    ///
+    /// The swapchain of data must be created.
+    pub unsafe fn create(data: &RendererData) -> Result<Self> {
+        let images_count = data.swapchain.as_ref().unwrap().images.len();
+        let mut buffers = Vec::with_capacity(images_count);
+        for _ in 0..images_count {
+            buffers.push(Buffer::create(
+                data,
+                GRID_VERTICES_COUNT * size_of::<Vertex>(),
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                AllocUsage::Staging,
+            )?);
+        }
+        Ok(Self { buffers })
+    }
+
+    /// the lines pipeline and the descriptor sets should already be bound
+    ///
+    /// # Safety
+    ///
+    /// command_buffer must be recording inside the render pass, and the buffer of image_index must
+    /// not be read by a frame in flight.
+    pub unsafe fn record(
+        &self,
+        data: &RendererData,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+        camera_pos: Vec3,
+    ) {
+        let buffer = &self.buffers[image_index];
+        let vertices = std::slice::from_raw_parts_mut(buffer.ptr.cast(), GRID_VERTICES_COUNT);
+        let min = Self::fill_vertices(vertices, camera_pos);
+
+        DrawConstants::new(min).push(
+            &data.device,
+            command_buffer,
+            data.pipeline.as_ref().unwrap().layout,
+        );
+
+        data.device
+            .cmd_set_line_width(command_buffer, data.line_width);
+        data.device
+            .cmd_bind_vertex_buffers(command_buffer, 0, &[buffer.buffer], &[0]);
+        data.device
+            .cmd_draw(command_buffer, GRID_VERTICES_COUNT as u32, 1, 0, 0);
+    }
+
+    // returns the corner the vertices are relative to
+    fn fill_vertices(vertices: &mut [Vertex], camera_pos: Vec3) -> TVec3<i32> {
+        let size = Chunk::size();
+        let size = vec3(size[0], size[1], size[2]);
+        let camera_chunk = vec3(
+            (camera_pos.x / size.x as f32).floor() as i32,
+            (camera_pos.y / size.y as f32).floor() as i32,
+            (camera_pos.z / size.z as f32).floor() as i32,
+        );
+        let radius = CHUNK_GRID_RADIUS as i32;
+
+        let min = camera_chunk.add_scalar(-radius).component_mul(&size);
+        let max = camera_chunk.add_scalar(radius + 1).component_mul(&size);
+
+        let mut i = 0;
+        for axis in 0..3 {
+            let u = (axis + 1) % 3;
+            let v = (axis + 2) % 3;
+            for a in 0..=(2 * radius + 1) {
+                for b in 0..=(2 * radius + 1) {
+                    let mut start = vec3(0, 0, 0);
+                    start[u] = min[u] + a * size[u];
+                    start[v] = min[v] + b * size[v];
+                    start[axis] = min[axis];
+                    let mut end = start;
+                    end[axis] = max[axis];
+
+                    for pos in [start, end] {
+                        vertices[i] = Vertex::new(
+                            pos - min,
+                            vec3(255, 255, 0),
+                            Vertex::UNLIT,
+                            3,
+                            vec2(0, 0),
+                            0,
+                        );
+                        i += 1;
+                    }
+                }
+            }
+        }
+        debug_assert_eq!(i, GRID_VERTICES_COUNT);
+        min
+    }
+}
@@ -1,15 +1,22 @@
-use std::sync::{self, Arc};
+use std::sync::{Arc, Weak};
 
 use super::{
+    buffer::Buffer,
     memory::{AllocRequirements, AllocUsage, Allocator, Block},
     renderer::RendererData,
+    sync,
 };
 use anyhow::Result;
+use log::warn;
 use vulkanalia::{
-    vk::{self, DeviceV1_0, HasBuilder},
+    vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0},
     Device,
 };
 
+/// # Safety
+///
+/// image must be an image of device with format and mip_levels levels, the view must be destroyed
+/// before it.
 pub unsafe fn create_image_view(
     device: &Device,
     image: vk::Image,
@@ -40,23 +47,57 @@ pub unsafe fn create_image_view(
     Ok(device.create_image_view(&info, None)?)
 }
 
+// levels down to a single pixel
+pub fn mip_levels(size: (u32, u32)) -> u32 {
+    u32::BITS - size.0.max(size.1).max(1).leading_zeros()
+}
+
+pub(super) unsafe fn supports_linear_blit(data: &RendererData, format: vk::Format) -> bool {
+    data.instance
+        .get_physical_device_format_properties(data.physical_device.device, format)
+        .optimal_tiling_features
+        .contains(
+            vk::FormatFeatureFlags::BLIT_SRC
+                | vk::FormatFeatureFlags::BLIT_DST
+                | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        )
+}
+
+// a 2D image with a view of the aspects
+#[derive(Clone, Copy, Debug)]
+pub struct ImageInfo {
+    pub size: (u32, u32),
+    pub mip_levels: u32,
+    pub format: vk::Format,
+    pub tiling: vk::ImageTiling,
+    pub usage: vk::ImageUsageFlags,
+    pub aspects: vk::ImageAspectFlags,
+    pub samples: vk::SampleCountFlags,
+}
+
 pub struct Image {
-    device: sync::Weak<Device>,
-    allocator: sync::Weak<Allocator>,
+    device: Weak<Device>,
+    allocator: Weak<Allocator>,
     pub image: vk::Image,
     pub alloc: Block,
     pub view: vk::ImageView,
+    pub mip_levels: u32,
 }
 
 impl Image {
-    pub unsafe fn create(
-        data: &RendererData,
-        size: (u32, u32),
-        format: vk::Format,
-        tiling: vk::ImageTiling,
-        usage: vk::ImageUsageFlags,
-        aspects: vk::ImageAspectFlags,
-    ) -> Result<Self> {
+    /// # Safety
+    ///
+    /// The device and allocator of data must outlive the image, it is freed on drop.
+    pub unsafe fn create(data: &RendererData, info: &ImageInfo) -> Result<Self> {
+        let ImageInfo {
+            size,
+            mip_levels,
+            format,
+            tiling,
+            usage,
+            aspects,
+            samples,
+        } = *info;
         let info = vk::ImageCreateInfo::builder()
             .image_type(vk::ImageType::_2D)
             .extent(vk::Extent3D {
@@ -64,37 +105,284 @@ impl Image {
                 height: size.1,
                 depth: 1,
             })
-            .mip_levels(1)
+            .mip_levels(mip_levels)
             .array_layers(1)
             .format(format)
             .tiling(tiling)
             .initial_layout(vk::ImageLayout::UNDEFINED)
             .usage(usage)
-            .samples(vk::SampleCountFlags::_1)
+            .samples(samples)
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
         let image = data.device.create_image(&info, None)?;
 
         let requirements = data.device.get_image_memory_requirements(image);
 
-        let (alloc, _) = data.allocator.alloc(AllocRequirements::new(
+        let (alloc, _) = match data.allocator.alloc(AllocRequirements::new(
             requirements,
             AllocUsage::DeviceLocal,
-        ))?;
+        )) {
+            Ok(alloc) => alloc,
+            Err(e) => {
+                data.device.destroy_image(image, None);
+                return Err(e);
+            }
+        };
 
-        data.device
-            .bind_image_memory(image, alloc.memory, alloc.offset)?;
+        if let Err(e) = data
+            .device
+            .bind_image_memory(image, alloc.memory, alloc.offset)
+        {
+            data.device.destroy_image(image, None);
+            data.allocator.free(alloc);
+            return Err(e.into());
+        }
 
-        let view = create_image_view(&data.device, image, format, aspects, 1)?;
+        let view = create_image_view(&data.device, image, format, aspects, mip_levels)?;
 
         Ok(Self {
             image,
+            mip_levels,
             allocator: Arc::downgrade(&data.allocator),
             alloc,
             view,
             device: Arc::downgrade(&data.device),
         })
     }
+
+    /// Sampled color image filled with the pixels through a staging buffer, the smaller
+    /// mip levels are downsampled from the first one with blits.
+    /// This waits for the upload to finish so it should only be used at startup.
+    ///
+    /// # Safety
+    ///
+    /// The command pool of data must be created and pixels must hold the whole first mip level.
+    pub unsafe fn create_with_pixels(
+        data: &RendererData,
+        size: (u32, u32),
+        mip_levels: u32,
+        format: vk::Format,
+        pixels: &[u8],
+    ) -> Result<Self> {
+        let mip_levels = if mip_levels > 1 && !supports_linear_blit(data, format) {
+            warn!("Linear blit not supported for format {format:?}: no mipmaps");
+            1
+        } else {
+            mip_levels
+        };
+        let image = Self::create(
+            data,
+            &ImageInfo {
+                size,
+                mip_levels,
+                format,
+                tiling: vk::ImageTiling::OPTIMAL,
+                usage: vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::SAMPLED,
+                aspects: vk::ImageAspectFlags::COLOR,
+                samples: vk::SampleCountFlags::_1,
+            },
+        )?;
+
+        let staging = Buffer::create(
+            data,
+            pixels.len(),
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            AllocUsage::Staging,
+        )?;
+        std::ptr::copy_nonoverlapping(pixels.as_ptr(), staging.ptr, pixels.len());
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(mip_levels)
+            .base_array_layer(0)
+            .layer_count(1);
+
+        let command_pool = data.command_pool.as_ref().unwrap();
+        let mut command_buffer = command_pool.allocate_command_buffers(
+            &data.device,
+            1,
+            vk::CommandBufferLevel::PRIMARY,
+        )?[0];
+        command_buffer.begin(&data.device)?;
+
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+        data.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[to_transfer],
+        );
+
+        let region = vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(0)
+                    .base_array_layer(0)
+                    .layer_count(1),
+            )
+            .image_offset(vk::Offset3D::default())
+            .image_extent(vk::Extent3D {
+                width: size.0,
+                height: size.1,
+                depth: 1,
+            });
+        data.device.cmd_copy_buffer_to_image(
+            command_buffer.buffer,
+            staging.buffer,
+            image.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[region],
+        );
+
+        // each level is blitted from the previous one, which is then ready for the shaders
+        let (mut width, mut height) = (size.0 as i32, size.1 as i32);
+        for level in 1..mip_levels {
+            let previous = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(level - 1)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+            let to_source = vk::ImageMemoryBarrier::builder()
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.image)
+                .subresource_range(previous)
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+            data.device.cmd_pipeline_barrier(
+                command_buffer.buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[] as &[vk::BufferMemoryBarrier],
+                &[to_source],
+            );
+
+            let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+            let layers = |level| {
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(level)
+                    .base_array_layer(0)
+                    .layer_count(1)
+                    .build()
+            };
+            let blit = vk::ImageBlit::builder()
+                .src_subresource(layers(level - 1))
+                .src_offsets([
+                    vk::Offset3D::default(),
+                    vk::Offset3D {
+                        x: width,
+                        y: height,
+                        z: 1,
+                    },
+                ])
+                .dst_subresource(layers(level))
+                .dst_offsets([
+                    vk::Offset3D::default(),
+                    vk::Offset3D {
+                        x: next_width,
+                        y: next_height,
+                        z: 1,
+                    },
+                ]);
+            data.device.cmd_blit_image(
+                command_buffer.buffer,
+                image.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                image.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit],
+                vk::Filter::LINEAR,
+            );
+
+            let to_shader = vk::ImageMemoryBarrier::builder()
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.image)
+                .subresource_range(previous)
+                .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            data.device.cmd_pipeline_barrier(
+                command_buffer.buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[] as &[vk::BufferMemoryBarrier],
+                &[to_shader],
+            );
+            (width, height) = (next_width, next_height);
+        }
+
+        // the last level is only written to
+        let last = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(mip_levels - 1)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let to_shader = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.image)
+            .subresource_range(last)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        data.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[to_shader],
+        );
+
+        command_buffer.end(&data.device)?;
+
+        let fence = sync::create_fences(&data.device, false, 1)?[0];
+        let buffers = &[command_buffer.buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(buffers);
+        let result = {
+            let _queue = data.queue_lock.lock().unwrap();
+            data.device
+                .queue_submit(data.graphics_queue, &[submit_info], fence)
+        }
+        .and_then(|_| data.device.wait_for_fences(&[fence], true, u64::MAX));
+        data.device.destroy_fence(fence, None);
+        data.device
+            .free_command_buffers(command_pool.pool, &[command_buffer.buffer]);
+        result?;
+
+        Ok(image)
+    }
 }
 
 impl Drop for Image {
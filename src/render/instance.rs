@@ -1,21 +1,53 @@
 use std::collections::HashSet;
 use std::ffi::{c_void, CStr};
+use std::sync::OnceLock;
 
 use anyhow::anyhow;
 use anyhow::Result;
 use log::*;
 use vulkanalia::vk::{ExtDebugUtilsExtension, InstanceV1_0};
-use vulkanalia::Instance;
 use vulkanalia::{
     vk::{self, EntryV1_0, HasBuilder},
     Entry,
 };
+use vulkanalia::{Instance, Version};
 use winit::window::Window;
 
-use crate::config::{VALIDATION_ENABLED, VALIDATION_LAYER};
+use crate::config::{
+    API_VERSION, MUTED_DEBUG_MESSAGES, MUTED_DEBUG_MESSAGES_ENV_VAR, VALIDATION_ENABLED,
+    VALIDATION_ENV_VAR, VALIDATION_LAYER,
+};
 
 use super::renderer::RendererData;
 
+// ids of the messages debug_callback doesn't log, set by create
+static MUTED_MESSAGES: OnceLock<HashSet<i32>> = OnceLock::new();
+
+// MUTED_DEBUG_MESSAGES and the ids of MUTED_DEBUG_MESSAGES_ENV_VAR, the invalid ones are skipped
+fn muted_messages() -> HashSet<i32> {
+    let mut muted: HashSet<_> = MUTED_DEBUG_MESSAGES.iter().copied().collect();
+    let Ok(value) = std::env::var(MUTED_DEBUG_MESSAGES_ENV_VAR) else {
+        return muted;
+    };
+    for id in value.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        match parse_message_id(id) {
+            Some(id) => {
+                muted.insert(id);
+            }
+            None => warn!("Invalid message id `{id}` in {MUTED_DEBUG_MESSAGES_ENV_VAR}"),
+        }
+    }
+    muted
+}
+
+// the validation layer prints the ids in hex, as unsigned numbers
+fn parse_message_id(id: &str) -> Option<i32> {
+    match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).map(|id| id as i32).ok(),
+        None => id.parse().ok(),
+    }
+}
+
 extern "system" fn debug_callback(
     severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     type_: vk::DebugUtilsMessageTypeFlagsEXT,
@@ -23,31 +55,74 @@ extern "system" fn debug_callback(
     _: *mut c_void,
 ) -> vk::Bool32 {
     let data = unsafe { *data };
+    let id = data.message_id_number;
+    if MUTED_MESSAGES
+        .get()
+        .is_some_and(|muted| muted.contains(&id))
+    {
+        return vk::FALSE;
+    }
     let message = unsafe { CStr::from_ptr(data.message) }.to_string_lossy();
 
     if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
-        error!("({:?}) {}", type_, message);
+        error!("({:?}, {:#x}) {}", type_, id, message);
     } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
-        warn!("({:?}) {}", type_, message);
+        warn!("({:?}, {:#x}) {}", type_, id, message);
     } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
-        debug!("({:?}) {}", type_, message);
+        debug!("({:?}, {:#x}) {}", type_, id, message);
     } else {
-        trace!("({:?}) {}", type_, message);
+        trace!("({:?}, {:#x}) {}", type_, id, message);
     }
 
     vk::FALSE
 }
 
+// VALIDATION_ENABLED unless VALIDATION_ENV_VAR is set
+fn validation_enabled() -> bool {
+    let Ok(value) = std::env::var(VALIDATION_ENV_VAR) else {
+        return VALIDATION_ENABLED;
+    };
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" => true,
+        "0" | "false" | "off" => false,
+        _ => {
+            warn!("Invalid {VALIDATION_ENV_VAR} value `{value}`: expected 1 or 0");
+            VALIDATION_ENABLED
+        }
+    }
+}
+
+// API_VERSION if the loader supports it, the highest version it supports otherwise
+fn instance_version(entry: &Entry) -> Version {
+    // the 1.0 loaders don't have vkEnumerateInstanceVersion and refuse any other version
+    let supported = entry.version().unwrap_or_else(|e| {
+        warn!("Failed to query the Vulkan instance version: {e}");
+        Version::V1_0_0
+    });
+    Version::new(supported.major, supported.minor, 0).min(API_VERSION)
+}
+
+// Without a window the instance can't create surfaces, see Renderer::new_headless.
+// Also returns the version the instance was created with.
 pub fn create(
-    window: &Window,
+    window: Option<&Window>,
     entry: &Entry,
-) -> Result<(Instance, Option<vk::DebugUtilsMessengerEXT>)> {
+) -> Result<(Instance, Option<vk::DebugUtilsMessengerEXT>, Version)> {
+    let version = instance_version(entry);
+    let validation = validation_enabled();
+    if validation != VALIDATION_ENABLED {
+        info!(
+            "Validation layer {} by {VALIDATION_ENV_VAR}",
+            if validation { "enabled" } else { "disabled" }
+        );
+    }
+    info!("Vulkan instance version {version}");
     let application_info = vk::ApplicationInfo::builder()
         .application_name(b"Vulkan Voxels\0")
         .application_version(vk::make_version(1, 0, 0))
         .engine_name(b"No Engine\0")
         .engine_version(vk::make_version(1, 0, 0))
-        .api_version(vk::make_version(1, 0, 0));
+        .api_version(version.into());
 
     let available_layers = unsafe {
         entry
@@ -57,25 +132,46 @@ pub fn create(
             .collect::<HashSet<_>>()
     };
 
-    if VALIDATION_ENABLED && !available_layers.contains(&VALIDATION_LAYER) {
+    if validation && !available_layers.contains(&VALIDATION_LAYER) {
         return Err(anyhow!("Validation layer requested but not supported."));
     }
 
-    let layers = if VALIDATION_ENABLED {
+    let layers = if validation {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         Vec::new()
     };
 
-    let mut extensions = vulkanalia::window::get_required_instance_extensions(window)
+    let mut extensions = window
+        .map_or(&[][..], |w| {
+            vulkanalia::window::get_required_instance_extensions(w)
+        })
         .iter()
         .map(|e| e.as_ptr())
         .collect::<Vec<_>>();
 
-    if VALIDATION_ENABLED {
+    if validation {
         extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr());
     }
 
+    // needed by the optional device extensions, core since vulkan 1.1
+    let available_extensions = unsafe {
+        entry
+            .enumerate_instance_extension_properties(None)?
+            .iter()
+            .map(|e| e.extension_name)
+            .collect::<HashSet<_>>()
+    };
+    if version < Version::V1_1_0
+        && available_extensions.contains(&vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name)
+    {
+        extensions.push(
+            vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION
+                .name
+                .as_ptr(),
+        );
+    }
+
     let mut info = vk::InstanceCreateInfo::builder()
         .application_info(&application_info)
         .enabled_layer_names(&layers)
@@ -86,7 +182,12 @@ pub fn create(
         .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
         .user_callback(Some(debug_callback));
 
-    if VALIDATION_ENABLED {
+    if validation {
+        // before the instance, the creation messages go through the callback too
+        let muted = MUTED_MESSAGES.get_or_init(muted_messages);
+        if !muted.is_empty() {
+            info!("{} debug message ids muted", muted.len());
+        }
         info = info.push_next(&mut debug_info);
     }
 
@@ -94,7 +195,7 @@ pub fn create(
 
     let mut messenger = None;
 
-    if VALIDATION_ENABLED {
+    if validation {
         let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
             .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
             .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
@@ -103,9 +204,12 @@ pub fn create(
         messenger = Some(unsafe { instance.create_debug_utils_messenger_ext(&debug_info, None)? });
     }
 
-    Ok((instance, messenger))
+    Ok((instance, messenger, version))
 }
 
+/// # Safety
+///
+/// The device and every object created from the instance must be destroyed before.
 pub unsafe fn destroy(data: &mut RendererData) {
     if let Some(messenger) = data.messenger {
         data.instance
@@ -113,3 +217,19 @@ pub unsafe fn destroy(data: &mut RendererData) {
     }
     data.instance.destroy_instance(None);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids() {
+        assert_eq!(parse_message_id("0xe8616bf2"), Some(0xe8616bf2u32 as i32));
+        assert_eq!(parse_message_id("0X10"), Some(16));
+        assert_eq!(parse_message_id("12"), Some(12));
+        assert_eq!(parse_message_id("-5"), Some(-5));
+        for invalid in ["nope", "0x", "0x1ffffffff", "1.5", ""] {
+            assert_eq!(parse_message_id(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -1,25 +1,41 @@
 use std::{
-    ptr,
+    fmt, ptr,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc, Mutex, RwLock, Weak,
     },
+    time::{Duration, Instant},
 };
 
+use super::commands::CommandBuffer;
 use anyhow::{anyhow, Result};
-use log::trace;
+use log::{debug, info, trace};
+
 use vulkanalia::{
-    vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0},
-    Device, Instance,
+    vk::{
+        self, DeviceV1_0, HasBuilder, InstanceV1_0, InstanceV1_1,
+        KhrGetPhysicalDeviceProperties2Extension,
+    },
+    Device, Instance, Version,
 };
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum AllocUsage {
     Staging,
     DeviceLocal,
+    // cached for fast cpu reads, may not be coherent: invalidate the range before reading it
+    Readback,
 }
 
-const MIN_ALLOC_SIZE: usize = 1024 * 1024 * 16;
+// how a chunk picks the free block to allocate from
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AllocStrategy {
+    // the first block large enough, fast
+    #[default]
+    FirstFit,
+    // the smallest block large enough, slower but fragments less
+    BestFit,
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct AllocRequirements {
@@ -27,6 +43,8 @@ pub struct AllocRequirements {
     pub alignment: u64,
     pub usage: AllocUsage,
     pub memory_type_bits: u32,
+    // can be moved by Allocator::defragment, the owner must handle the relocations
+    pub movable: bool,
 }
 
 impl AllocRequirements {
@@ -36,32 +54,110 @@ impl AllocRequirements {
             alignment: requirements.alignment,
             usage,
             memory_type_bits: requirements.memory_type_bits,
+            movable: false,
         }
     }
 }
 
+// a block moved by Allocator::defragment
+#[derive(Copy, Clone, Debug)]
+pub struct Relocation {
+    pub old: Block,
+    pub new: Block,
+    pub ptr: *mut u8, // null if the memory is not host visible
+}
+
+// chunks using less than this fraction of their size are emptied into the others
+const DEFRAGMENT_MAX_USAGE: f64 = 0.5;
+
 #[derive(Debug)]
 pub struct Allocator {
+    device: Weak<Device>,
     memory_properties: vk::PhysicalDeviceMemoryProperties,
     pools: Vec<Pool>,
+    strategy: AllocStrategy,
+    // size the pools start from, they grow by powers of two
+    chunk_size: u64,
+    // larger allocations get their own memory instead of growing a pool to the next power of two
+    dedicated_threshold: u64,
+    dedicated: Mutex<Vec<Block>>,
+    // None if VK_EXT_memory_budget is not supported
+    budget: Option<MemoryBudget>,
+    // whole chunks buffers used by the defragmentation copies
+    copy_buffers: Mutex<Vec<vk::Buffer>>,
 }
 
 impl Allocator {
+    /// # Safety
+    ///
+    /// physical_device must be the physical device of device, picked from instance.
     pub unsafe fn new(
         device: &Arc<Device>,
         instance: &Instance,
         physical_device: vk::PhysicalDevice,
-    ) -> Self {
+        api_version: Version,
+        strategy: AllocStrategy,
+        chunk_size: u64,
+        dedicated_threshold: u64,
+    ) -> Result<Self> {
         trace!("Allocator::new");
+        // blocks of any kind of resource must be able to start at the beginning of a chunk
+        let limits = instance
+            .get_physical_device_properties(physical_device)
+            .limits;
+        let alignment = limits
+            .buffer_image_granularity
+            .max(limits.non_coherent_atom_size);
+        if chunk_size == 0 || !chunk_size.is_multiple_of(alignment) {
+            return Err(anyhow!(
+                "Invalid allocator chunk size {chunk_size}, should be a non zero multiple of {alignment}."
+            ));
+        }
+
         let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+        let budget = MemoryBudget::supported(device).then(|| {
+            MemoryBudget::new(
+                instance,
+                physical_device,
+                api_version,
+                memory_properties.memory_heap_count,
+            )
+        });
+        if budget.is_none() {
+            info!("Memory budget not supported, the pools growth is not limited");
+        }
+
         let mut pools = Vec::with_capacity(memory_properties.memory_type_count as usize);
         for i in 0..memory_properties.memory_type_count {
-            pools.push(Pool::new(device, i as u32));
+            let memory_type = memory_properties.memory_types[i as usize];
+            // the usages share the pools, map all the host visible memory types
+            let map = memory_type
+                .property_flags
+                .contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+            pools.push(Pool::new(
+                device,
+                i,
+                memory_type.heap_index,
+                chunk_size,
+                map,
+            ));
         }
-        Self {
+        Ok(Self {
+            device: Arc::downgrade(device),
             memory_properties,
             pools,
-        }
+            strategy,
+            chunk_size,
+            dedicated_threshold,
+            dedicated: Mutex::new(Vec::new()),
+            budget,
+            copy_buffers: Mutex::new(Vec::new()),
+        })
+    }
+
+    #[inline]
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
     }
 
     fn get_memory_properties(
@@ -73,6 +169,9 @@ impl Allocator {
                 vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT
             }
             AllocUsage::DeviceLocal => vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            AllocUsage::Readback => {
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_CACHED
+            }
         }
     }
 
@@ -90,145 +189,407 @@ impl Allocator {
             .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
     }
 
+    /// # Safety
+    ///
+    /// The block must be given back to free before the allocator is dropped, and ptr only used
+    /// while it is allocated.
     pub unsafe fn alloc(&self, requirements: AllocRequirements) -> Result<(Block, *mut u8)> {
         let properties =
             Allocator::get_memory_properties(self.memory_properties, requirements.usage);
+        let memory_requirements = vk::MemoryRequirements {
+            size: requirements.size,
+            alignment: requirements.alignment,
+            memory_type_bits: requirements.memory_type_bits,
+        };
         let memory_type_index = Allocator::get_memory_type_index(
             self.memory_properties,
             properties,
-            vk::MemoryRequirements {
-                size: requirements.size,
-                alignment: requirements.alignment,
-                memory_type_bits: requirements.memory_type_bits,
-            },
-        )?;
+            memory_requirements,
+        )
+        .or_else(|e| match requirements.usage {
+            // uncached reads are slow but still work
+            AllocUsage::Readback => Allocator::get_memory_type_index(
+                self.memory_properties,
+                vk::MemoryPropertyFlags::HOST_VISIBLE,
+                memory_requirements,
+            ),
+            _ => Err(e),
+        })?;
+
+        if requirements.size > self.dedicated_threshold {
+            return self.alloc_dedicated(requirements.size, memory_type_index);
+        }
 
         let pool = &self.pools[memory_type_index as usize];
         pool.alloc(
             requirements.size,
             requirements.alignment,
-            requirements.usage == AllocUsage::Staging,
+            self.strategy,
+            self.budget.as_ref(),
+            requirements.movable,
         )
     }
 
+    fn is_host_visible(&self, memory_type_index: u32) -> bool {
+        self.memory_properties.memory_types[memory_type_index as usize]
+            .property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+    }
+
+    unsafe fn alloc_dedicated(
+        &self,
+        size: u64,
+        memory_type_index: u32,
+    ) -> Result<(Block, *mut u8)> {
+        trace!("Allocating {} bytes of dedicated memory", size);
+        if let Some(budget) = &self.budget {
+            let heap_index =
+                self.memory_properties.memory_types[memory_type_index as usize].heap_index;
+            budget.reserve(heap_index, size)?;
+        }
+        let device = self
+            .device
+            .upgrade()
+            .ok_or_else(|| anyhow!("Device destroyed."))?;
+        let info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+        let memory = device.allocate_memory(&info, None)?;
+        let ptr = if self.is_host_visible(memory_type_index) {
+            match device.map_memory(
+                memory,
+                0,
+                vk::WHOLE_SIZE as u64,
+                vk::MemoryMapFlags::empty(),
+            ) {
+                Ok(ptr) => ptr.cast(),
+                Err(e) => {
+                    device.free_memory(memory, None);
+                    return Err(e.into());
+                }
+            }
+        } else {
+            ptr::null_mut()
+        };
+
+        let mut block = Block::new(memory, memory_type_index, 0, size);
+        block.is_free = false;
+        block.dedicated = true;
+        self.dedicated.lock().unwrap().push(block);
+        Ok((block, ptr))
+    }
+
+    /// # Safety
+    ///
+    /// block must have been allocated by this allocator and must not be used by the gpu anymore.
     pub unsafe fn free(&self, block: Block) {
+        if block.dedicated {
+            trace!("Freeing dedicated block {:?}", block);
+            self.dedicated
+                .lock()
+                .unwrap()
+                .retain(|b| b.memory != block.memory);
+            // also unmaps it
+            self.device
+                .upgrade()
+                .unwrap()
+                .free_memory(block.memory, None);
+            return;
+        }
         let pool = &self.pools[block.memory_type_index as usize];
         pool.free(block);
     }
 
+    /// Record the copies moving the movable blocks of the mostly empty chunks into the others.
+    /// Once the commands are executed the owners must move to the new blocks (see
+    /// Buffer::relocate) and end_defragment must be called. The old blocks must not be used
+    /// or freed until then.
+    ///
+    /// # Safety
+    ///
+    /// command_buffer must be recording on a queue of the device, and the movable blocks must not
+    /// be used by the gpu until end_defragment.
+    pub unsafe fn defragment(
+        &self,
+        device: &Device,
+        command_buffer: &mut CommandBuffer,
+    ) -> Result<Vec<Relocation>> {
+        let mut relocations = Vec::new();
+        let mut copy_buffers = self.copy_buffers.lock().unwrap();
+        for pool in &self.pools {
+            pool.defragment(
+                device,
+                command_buffer.buffer,
+                &mut relocations,
+                &mut copy_buffers,
+            )?;
+        }
+        debug!("Defragmentation moves {} blocks", relocations.len());
+        Ok(relocations)
+    }
+
+    /// the copies must be done
+    ///
+    /// # Safety
+    ///
+    /// The commands recorded by defragment must have finished and the owners moved to the new
+    /// blocks.
+    pub unsafe fn end_defragment(&self, device: &Device, relocations: Vec<Relocation>) {
+        for buffer in self.copy_buffers.lock().unwrap().drain(..) {
+            device.destroy_buffer(buffer, None);
+        }
+        for relocation in relocations {
+            self.free(relocation.old);
+        }
+        for pool in &self.pools {
+            pool.free_empty_chunks();
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No block of the allocator may be used after, by the cpu or the gpu.
     pub unsafe fn free_all(&mut self) {
         self.pools.clear();
+        for block in self.dedicated.get_mut().unwrap().drain(..) {
+            self.device
+                .upgrade()
+                .unwrap()
+                .free_memory(block.memory, None);
+        }
     }
 
-    #[cfg(debug_assertions)]
-    #[allow(unused)]
-    pub fn snapchot(&self) {
-        #[inline(always)]
-        fn size(size: u64) -> String {
-            if size < 1024 {
-                format!("{}B", size)
-            } else {
-                let s = size as f32;
-                if size < 1024 * 1024 {
-                    format!("{}KB", s / 1024.)
-                } else if size < 1024 * 1024 * 1024 {
-                    format!("{}MB", s / 1024. / 1024.)
-                } else {
-                    format!("{}GB", s / 1024. / 1024. / 1024.)
+    // cheap enough to be called every few frames, only takes the read locks
+    pub fn stats(&self) -> AllocatorStats {
+        let dedicated = self.dedicated.lock().unwrap();
+        let pools = self
+            .pools
+            .iter()
+            .map(|pool| {
+                let chunks: Vec<_> = pool
+                    .chunks
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|chunk| {
+                        let mut stats = ChunkStats {
+                            size: chunk.size,
+                            ..Default::default()
+                        };
+                        for block in chunk.blocks.read().unwrap().iter() {
+                            if block.is_free {
+                                stats.free += block.size;
+                                stats.free_blocks += 1;
+                            } else {
+                                stats.used += block.size;
+                                stats.used_blocks += 1;
+                            }
+                        }
+                        stats
+                    })
+                    .collect();
+                let dedicated = dedicated
+                    .iter()
+                    .filter(|b| b.memory_type_index == pool.memory_type_index)
+                    .map(|b| b.size)
+                    .sum();
+                PoolStats {
+                    memory_type_index: pool.memory_type_index,
+                    alloc_size: pool.size.load(Ordering::Relaxed),
+                    size: chunks.iter().map(|c| c.size).sum(),
+                    used: chunks.iter().map(|c| c.used).sum(),
+                    free: chunks.iter().map(|c| c.free).sum(),
+                    dedicated,
+                    chunks,
                 }
-            }
+            })
+            .collect();
+        AllocatorStats { pools }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AllocatorStats {
+    // one per memory type, the unused ones have no chunk
+    pub pools: Vec<PoolStats>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PoolStats {
+    pub memory_type_index: u32,
+    // size of the next chunk
+    pub alloc_size: u64,
+    pub size: u64,
+    pub used: u64,
+    pub free: u64,
+    // allocated outside of the chunks
+    pub dedicated: u64,
+    pub chunks: Vec<ChunkStats>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChunkStats {
+    pub size: u64,
+    pub used: u64,
+    pub free: u64,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+}
+
+impl AllocatorStats {
+    pub fn size(&self) -> u64 {
+        self.pools.iter().map(|p| p.size + p.dedicated).sum()
+    }
+
+    pub fn used(&self) -> u64 {
+        self.pools.iter().map(|p| p.used + p.dedicated).sum()
+    }
+}
+
+pub fn format_size(size: u64) -> String {
+    if size < 1024 {
+        format!("{}B", size)
+    } else {
+        let s = size as f32;
+        if size < 1024 * 1024 {
+            format!("{:.1}KB", s / 1024.)
+        } else if size < 1024 * 1024 * 1024 {
+            format!("{:.1}MB", s / 1024. / 1024.)
+        } else {
+            format!("{:.2}GB", s / 1024. / 1024. / 1024.)
         }
-        use std::io::{stdout, Write};
-        let mut handle = stdout().lock();
-        for pool in &self.pools {
-            let pool_chunks = pool.chunks.write().unwrap();
-            #[derive(Clone)]
-            struct ChunkInfo {
-                size: u64,
-                free: u64,
-                used: u64,
-                blocks_info: Vec<Block>,
-            }
-            struct PoolInfo {
-                alloc_size: u64,
-                size: u64,
-                free: u64,
-                used: u64,
-                chunks_infos: Vec<ChunkInfo>,
-            }
+    }
+}
 
-            let mut pool_info = PoolInfo {
-                alloc_size: pool.size.load(Ordering::Relaxed),
-                size: 0,
-                free: 0,
-                used: 0,
-                chunks_infos: Vec::with_capacity(pool_chunks.len()),
-            };
-            for chunk in pool_chunks.iter() {
-                let mut chunk_info = ChunkInfo {
-                    size: chunk.size,
-                    free: 0,
-                    used: 0,
-                    blocks_info: Vec::new(),
-                };
-                for block in chunk.blocks.write().unwrap().iter() {
-                    if block.is_free {
-                        chunk_info.free += block.size;
-                    } else {
-                        chunk_info.used += block.size;
-                    }
-                    chunk_info.blocks_info.push(*block);
-                }
-                pool_info.free += chunk_info.free;
-                pool_info.used += chunk_info.used;
-                pool_info.size += chunk_info.size;
-                pool_info.chunks_infos.push(chunk_info);
-            }
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.
+    } else {
+        part as f64 / total as f64 * 100.
+    }
+}
 
-            writeln!(handle, "Pool: ").unwrap();
-            writeln!(handle, "  Alloc size: {}", size(pool_info.alloc_size)).unwrap();
-            writeln!(handle, "  size: {:?}", size(pool_info.size)).unwrap();
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pool in self
+            .pools
+            .iter()
+            .filter(|p| !p.chunks.is_empty() || p.dedicated > 0)
+        {
+            writeln!(f, "Pool (memory type {}):", pool.memory_type_index)?;
+            writeln!(f, "  Alloc size: {}", format_size(pool.alloc_size))?;
+            writeln!(f, "  size: {}", format_size(pool.size))?;
             writeln!(
-                handle,
-                "  free: {:?} ({}%)",
-                size(pool_info.free),
-                pool_info.free as f64 / pool_info.size as f64 * 100.
-            )
-            .unwrap();
+                f,
+                "  free: {} ({:.1}%)",
+                format_size(pool.free),
+                percent(pool.free, pool.size)
+            )?;
             writeln!(
-                handle,
-                "  used: {:?} ({}%)",
-                size(pool_info.used),
-                pool_info.used as f64 / pool_info.size as f64 * 100.
-            )
-            .unwrap();
-            writeln!(handle, "  chunks:").unwrap();
-
-            for chunk in pool_info.chunks_infos {
-                writeln!(handle, "    size: {:?}", size(chunk.size)).unwrap();
+                f,
+                "  used: {} ({:.1}%)",
+                format_size(pool.used),
+                percent(pool.used, pool.size)
+            )?;
+            writeln!(f, "  dedicated: {}", format_size(pool.dedicated))?;
+            writeln!(f, "  chunks:")?;
+            for chunk in &pool.chunks {
+                writeln!(f, "    size: {}", format_size(chunk.size))?;
                 writeln!(
-                    handle,
-                    "    free: {:?} ({}%)",
-                    size(chunk.free),
-                    chunk.free as f64 / chunk.size as f64 * 100.
-                )
-                .unwrap();
+                    f,
+                    "    free: {} ({:.1}%) in {} blocks",
+                    format_size(chunk.free),
+                    percent(chunk.free, chunk.size),
+                    chunk.free_blocks
+                )?;
                 writeln!(
-                    handle,
-                    "    used: {:?} ({}%)",
-                    size(chunk.used),
-                    chunk.used as f64 / chunk.size as f64 * 100.
-                )
-                .unwrap();
-                writeln!(handle, "    blocks:").unwrap();
-                for block in chunk.blocks_info {
-                    write!(handle, "      size: {:?}", size(block.size)).unwrap();
-                    writeln!(handle, "  free: {:?}", block.is_free).unwrap();
-                }
+                    f,
+                    "    used: {} ({:.1}%) in {} blocks",
+                    format_size(chunk.used),
+                    percent(chunk.used, chunk.size),
+                    chunk.used_blocks
+                )?;
             }
+        }
+        Ok(())
+    }
+}
+
+// the other applications allocate too, so the budget is queried again when it gets old
+const BUDGET_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct MemoryBudget {
+    instance: Instance,
+    physical_device: vk::PhysicalDevice,
+    // the query is core since vulkan 1.1, an instance extension before
+    core_query: bool,
+    // per heap, lowered by our allocations until the next query
+    remaining: Vec<AtomicU64>,
+    last_query: Mutex<Instant>,
+}
+
+impl MemoryBudget {
+    // the extension is only enabled if it can be queried, see PhysicalDevice::pick
+    fn supported(device: &Device) -> bool {
+        device
+            .extensions()
+            .contains(&vk::EXT_MEMORY_BUDGET_EXTENSION.name)
+    }
 
-            writeln!(handle).unwrap();
+    unsafe fn new(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        api_version: Version,
+        heap_count: u32,
+    ) -> Self {
+        let budget = Self {
+            instance: instance.clone(),
+            physical_device,
+            core_query: api_version >= Version::V1_1_0,
+            remaining: (0..heap_count).map(|_| AtomicU64::new(0)).collect(),
+            last_query: Mutex::new(Instant::now()),
+        };
+        budget.query();
+        budget
+    }
+
+    unsafe fn query(&self) {
+        let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut properties = vk::PhysicalDeviceMemoryProperties2::builder().push_next(&mut budget);
+        if self.core_query {
+            self.instance
+                .get_physical_device_memory_properties2(self.physical_device, &mut properties);
+        } else {
+            self.instance
+                .get_physical_device_memory_properties2_khr(self.physical_device, &mut properties);
+        }
+        for (i, remaining) in self.remaining.iter().enumerate() {
+            remaining.store(
+                budget.heap_budget[i].saturating_sub(budget.heap_usage[i]),
+                Ordering::Relaxed,
+            );
         }
+        *self.last_query.lock().unwrap() = Instant::now();
+    }
+
+    unsafe fn remaining(&self, heap_index: u32) -> u64 {
+        if self.last_query.lock().unwrap().elapsed() > BUDGET_QUERY_INTERVAL {
+            self.query();
+        }
+        self.remaining[heap_index as usize].load(Ordering::Relaxed)
+    }
+
+    unsafe fn reserve(&self, heap_index: u32, size: u64) -> Result<()> {
+        let remaining = self.remaining(heap_index);
+        self.remaining[heap_index as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| r.checked_sub(size))
+            .map(|_| ())
+            .map_err(|_| {
+                anyhow!(
+                    "Memory heap {heap_index} budget exceeded: {size} bytes requested, {remaining} remaining."
+                )
+            })
     }
 }
 
@@ -236,27 +597,47 @@ impl Allocator {
 struct Pool {
     device: Weak<Device>,
     memory_type_index: u32,
+    heap_index: u32,
     chunks: RwLock<Vec<Chunk>>,
     size: AtomicU64,
     growth_lock: Mutex<()>,
+    // host visible memory type, the chunks stay mapped
+    map: bool,
 }
 
 impl Pool {
-    fn new(device: &Arc<Device>, memory_type_index: u32) -> Self {
+    fn new(
+        device: &Arc<Device>,
+        memory_type_index: u32,
+        heap_index: u32,
+        chunk_size: u64,
+        map: bool,
+    ) -> Self {
         trace!("Creating memory pool for memory type {}", memory_type_index);
         Self {
             device: Arc::downgrade(device),
             memory_type_index,
+            heap_index,
             chunks: RwLock::new(Vec::new()),
-            size: AtomicU64::new(MIN_ALLOC_SIZE as u64),
+            size: AtomicU64::new(chunk_size),
             growth_lock: Mutex::new(()),
+            map,
         }
     }
 
-    unsafe fn alloc(&self, size: u64, alignment: u64, map: bool) -> Result<(Block, *mut u8)> {
+    unsafe fn alloc(
+        &self,
+        size: u64,
+        alignment: u64,
+        strategy: AllocStrategy,
+        budget: Option<&MemoryBudget>,
+        movable: bool,
+    ) -> Result<(Block, *mut u8)> {
         trace!("Allocating {} bytes from memory pool", size);
         for chunk in self.chunks.read().unwrap().iter() {
-            if let Some(block) = chunk.alloc(size, alignment, self.memory_type_index) {
+            if let Some(block) =
+                chunk.alloc(size, alignment, self.memory_type_index, strategy, movable)
+            {
                 return Ok(block);
             }
         }
@@ -268,7 +649,7 @@ impl Pool {
                 // wait other thread to finish growth and retry alloc
                 let l = self.growth_lock.lock().unwrap();
                 drop(l);
-                return self.alloc(size, alignment, map);
+                return self.alloc(size, alignment, strategy, budget, movable);
             }
         };
 
@@ -291,17 +672,23 @@ impl Pool {
             new_size *= 2;
         }
 
-        self.size.store(new_size, Ordering::Relaxed);
+        if let Some(budget) = budget {
+            // grow less rather than fail while the request still fits
+            new_size = new_size.min(budget.remaining(self.heap_index)).max(size);
+            budget.reserve(self.heap_index, new_size)?;
+        }
 
-        let chunk = Chunk::new(
-            &self.device.upgrade().unwrap(),
-            new_size,
-            self.memory_type_index,
-            map,
-        )?;
+        let device = self
+            .device
+            .upgrade()
+            .ok_or_else(|| anyhow!("Device destroyed."))?;
+        let chunk = Chunk::new(&device, new_size, self.memory_type_index, self.map)?;
+        self.size.store(new_size, Ordering::Relaxed);
         let block = chunk
-            .alloc(size, alignment, self.memory_type_index)
-            .expect("New chunk should have space.");
+            .alloc(size, alignment, self.memory_type_index, strategy, movable)
+            .ok_or_else(|| {
+                anyhow!("New chunk of {new_size} bytes has no space for {size} bytes.")
+            })?;
 
         self.chunks.write().unwrap().push(chunk);
 
@@ -316,21 +703,157 @@ impl Pool {
             .unwrap();
         chunk.free(block);
     }
+
+    unsafe fn defragment(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        relocations: &mut Vec<Relocation>,
+        copy_buffers: &mut Vec<vk::Buffer>,
+    ) -> Result<()> {
+        let chunks = self.chunks.read().unwrap();
+        let usage = |chunk: &Chunk| {
+            let blocks = chunk.blocks.read().unwrap();
+            let used: u64 = blocks.iter().filter(|b| !b.is_free).map(|b| b.size).sum();
+            let movable = blocks.iter().all(|b| b.is_free || b.movable);
+            (used as f64 / chunk.size as f64, movable)
+        };
+
+        // the emptiest first, they are the cheapest to empty
+        let mut sources: Vec<(usize, f64)> = chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, chunk)| match usage(chunk) {
+                (usage, true) if usage > 0.0 && usage < DEFRAGMENT_MAX_USAGE => Some((i, usage)),
+                _ => None,
+            })
+            .collect();
+        if sources.is_empty() || sources.len() == chunks.len() {
+            return Ok(());
+        }
+        sources.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        // a buffer covering each chunk to copy between them
+        let mut chunk_buffers = Vec::with_capacity(chunks.len());
+        for chunk in chunks.iter() {
+            let info = vk::BufferCreateInfo::builder()
+                .size(chunk.size)
+                .usage(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE);
+            let buffer = device.create_buffer(&info, None)?;
+            copy_buffers.push(buffer);
+            let requirements = device.get_buffer_memory_requirements(buffer);
+            if requirements.memory_type_bits & (1 << self.memory_type_index) == 0
+                || requirements.size > chunk.size
+            {
+                debug!(
+                    "Memory type {} can't be copied with buffers, not defragmented",
+                    self.memory_type_index
+                );
+                return Ok(());
+            }
+            device.bind_buffer_memory(buffer, chunk.memory, 0)?;
+            chunk_buffers.push(buffer);
+        }
+
+        // the chunks to empty don't receive blocks, a block is moved at most once
+        let mut is_source = vec![false; chunks.len()];
+        for (source, _) in &sources {
+            is_source[*source] = true;
+        }
+        for (source, _) in sources {
+            let blocks: Vec<Block> = chunks[source]
+                .blocks
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|b| !b.is_free)
+                .copied()
+                .collect();
+
+            // a chunk only partially emptied can't be freed, move all its blocks or none
+            let count = blocks.len();
+            let mut moves = Vec::with_capacity(count);
+            for old in blocks {
+                let new = chunks.iter().enumerate().find_map(|(i, chunk)| {
+                    if is_source[i] {
+                        return None;
+                    }
+                    chunk
+                        .alloc(
+                            old.size,
+                            old.alignment,
+                            self.memory_type_index,
+                            AllocStrategy::BestFit,
+                            true,
+                        )
+                        .map(|(new, _)| (i, new))
+                });
+                match new {
+                    Some(new) => moves.push((old, new)),
+                    None => break,
+                }
+            }
+            if moves.len() < count {
+                for (_, (i, new)) in moves {
+                    chunks[i].free(new);
+                }
+                continue;
+            }
+
+            for (old, (i, new)) in moves {
+                let region = vk::BufferCopy::builder()
+                    .src_offset(old.offset)
+                    .dst_offset(new.offset)
+                    .size(old.size);
+                device.cmd_copy_buffer(
+                    command_buffer,
+                    chunk_buffers[source],
+                    chunk_buffers[i],
+                    &[region],
+                );
+                let ptr = if chunks[i].ptr.is_null() {
+                    ptr::null_mut()
+                } else {
+                    chunks[i].ptr.add(new.offset as usize)
+                };
+                relocations.push(Relocation { old, new, ptr });
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn free_empty_chunks(&self) {
+        let device = self.device.upgrade().unwrap();
+        self.chunks.write().unwrap().retain(|chunk| {
+            let blocks = chunk.blocks.read().unwrap();
+            let empty = blocks.len() == 1 && blocks[0].is_free;
+            if empty {
+                trace!("Freeing empty chunk of {} bytes", chunk.size);
+                if !chunk.ptr.is_null() {
+                    device.unmap_memory(chunk.memory);
+                }
+                device.free_memory(chunk.memory, None);
+            }
+            !empty
+        });
+    }
 }
 
 impl Drop for Pool {
     fn drop(&mut self) {
         trace!("Dropping memory pool");
-        let chunks = self.chunks.write().unwrap();
+        let chunks = self.chunks.get_mut().unwrap();
+        // only the pools of the tests have no device, their chunks have no memory
+        let Some(device) = self.device.upgrade() else {
+            return;
+        };
         for chunk in chunks.iter() {
             unsafe {
                 if !chunk.ptr.is_null() {
-                    self.device.upgrade().unwrap().unmap_memory(chunk.memory);
+                    device.unmap_memory(chunk.memory);
                 }
-                self.device
-                    .upgrade()
-                    .unwrap()
-                    .free_memory(chunk.memory, None);
+                device.free_memory(chunk.memory, None);
             }
         }
     }
@@ -386,39 +909,51 @@ impl Chunk {
         size: u64,
         alignment: u64,
         memory_type_index: u32,
+        strategy: AllocStrategy,
+        movable: bool,
     ) -> Option<(Block, *mut u8)> {
         if size > self.size {
             return None;
         }
 
         let mut blocks = self.blocks.write().unwrap(); // possible optimization: rwlock on each block and read lock only here
-        let mut block_out_index = None;
+        let mut block_out = None;
         {
             for (i, block) in blocks.iter().enumerate() {
                 if block.is_free {
-                    let mut block_size = block.size;
-                    if block.offset % alignment != 0 {
-                        block_size -= alignment - block.offset % alignment;
-                    }
+                    // a block smaller than its alignment padding has no usable space
+                    let before_size = Self::padding(block.offset, alignment);
+                    let Some(block_size) = block.size.checked_sub(before_size) else {
+                        continue;
+                    };
 
                     if block_size >= size {
-                        block_out_index = Some(i);
-                        break;
+                        match strategy {
+                            AllocStrategy::FirstFit => {
+                                block_out = Some((i, before_size, block_size));
+                                break;
+                            }
+                            AllocStrategy::BestFit => {
+                                if block_out.is_none_or(|(_, _, best_size)| block_size < best_size)
+                                {
+                                    block_out = Some((i, before_size, block_size));
+                                }
+                                // can't do better
+                                if block_size == size {
+                                    break;
+                                }
+                            }
+                        }
                     }
                 }
             }
         }
 
-        if let Some(i) = block_out_index {
+        if let Some((i, before_size, block_size)) = block_out {
             trace!("Alloc {} bytes from chunk in block {:?}", size, blocks[i]);
 
-            let before_size = if blocks[i].offset % alignment != 0 {
-                alignment - blocks[i].offset % alignment
-            } else {
-                0
-            };
-
-            let after_size = blocks[i].size - (size + before_size);
+            // same sizes as the fit check, block_size >= size
+            let after_size = block_size - size;
 
             if after_size > 0 {
                 let new_block = Block::new(
@@ -434,6 +969,8 @@ impl Chunk {
             blocks[i].is_free = false;
             blocks[i].size = size;
             blocks[i].offset += before_size;
+            blocks[i].alignment = alignment;
+            blocks[i].movable = movable;
             let return_block = blocks[i]; // copy here because if we insert a new block before, we should return blocks[i + 1] instead of blocks[i]
 
             if before_size > 0 {
@@ -446,20 +983,33 @@ impl Chunk {
                 blocks.insert(i, new_block);
             }
 
-            Some((return_block, self.ptr.add(before_block_offset as usize)))
+            let ptr = if self.ptr.is_null() {
+                ptr::null_mut()
+            } else {
+                self.ptr.add(return_block.offset as usize)
+            };
+            Some((return_block, ptr))
         } else {
             None
         }
     }
 
+    // bytes to skip from offset to be aligned
+    #[inline]
+    fn padding(offset: u64, alignment: u64) -> u64 {
+        match offset % alignment {
+            0 => 0,
+            rem => alignment - rem,
+        }
+    }
+
     unsafe fn free(&self, block: Block) {
         trace!("Freeing block {:?}", block);
         let mut blocks = self.blocks.write().unwrap();
-        // FIXME binary search
+        // the blocks are sorted by offset
         let i = blocks
-            .iter_mut()
-            .position(|b| b.offset == block.offset)
-            .unwrap();
+            .binary_search_by_key(&block.offset, |b| b.offset)
+            .expect("Freed block not in chunk");
         blocks[i].is_free = true;
         if i + 1 < blocks.len() && blocks[i + 1].is_free {
             blocks[i].size += blocks[i + 1].size;
@@ -480,10 +1030,19 @@ pub struct Block {
     pub offset: u64,
     pub size: u64,
     is_free: bool,
+    // owns its memory, not part of a chunk
+    dedicated: bool,
+    alignment: u64,
+    movable: bool,
 }
 
 impl Block {
-    fn new(memory: vk::DeviceMemory, memory_type_index: u32, offset: u64, size: u64) -> Self {
+    pub(super) fn new(
+        memory: vk::DeviceMemory,
+        memory_type_index: u32,
+        offset: u64,
+        size: u64,
+    ) -> Self {
         trace!("Creating block at offset {} of {} bytes", offset, size);
         Self {
             memory,
@@ -491,6 +1050,256 @@ impl Block {
             offset,
             size,
             is_free: true,
+            dedicated: false,
+            alignment: 1,
+            movable: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vulkanalia::vk::Handle;
+
+    // a chunk without memory made of blocks of the given sizes, true if free
+    fn test_chunk(layout: &[(u64, bool)]) -> Chunk {
+        let mut offset = 0;
+        let mut blocks = Vec::new();
+        for &(size, free) in layout {
+            let mut block = Block::new(vk::DeviceMemory::null(), 0, offset, size);
+            block.is_free = free;
+            blocks.push(block);
+            offset += size;
+        }
+        Chunk {
+            memory: vk::DeviceMemory::null(),
+            blocks: RwLock::new(blocks),
+            size: offset,
+            ptr: ptr::null_mut(),
+        }
+    }
+
+    // an allocator without a device, with a device local memory type whose pool has a free
+    // chunk of the given size, growing the pool or allocating memory fails
+    fn test_allocator(chunk_size: u64, dedicated_threshold: u64) -> Allocator {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 1,
+            memory_heap_count: 1,
+            ..Default::default()
+        };
+        memory_properties.memory_types[0].property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let pool = Pool {
+            device: Weak::new(),
+            memory_type_index: 0,
+            heap_index: 0,
+            chunks: RwLock::new(vec![test_chunk(&[(chunk_size, true)])]),
+            size: AtomicU64::new(chunk_size),
+            growth_lock: Mutex::new(()),
+            map: false,
+        };
+        Allocator {
+            device: Weak::new(),
+            memory_properties,
+            pools: vec![pool],
+            strategy: AllocStrategy::FirstFit,
+            chunk_size,
+            dedicated_threshold,
+            dedicated: Mutex::new(Vec::new()),
+            budget: None,
+            copy_buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn requirements(size: u64, usage: AllocUsage) -> AllocRequirements {
+        let requirements = vk::MemoryRequirements {
+            size,
+            alignment: 16,
+            memory_type_bits: !0,
+        };
+        AllocRequirements::new(requirements, usage)
+    }
+
+    fn layout(chunk: &Chunk) -> Vec<(u64, u64, bool)> {
+        let blocks = chunk.blocks.read().unwrap();
+        assert!(blocks
+            .windows(2)
+            .all(|w| w[0].offset + w[0].size == w[1].offset));
+        blocks
+            .iter()
+            .map(|b| (b.offset, b.size, b.is_free))
+            .collect()
+    }
+
+    #[test]
+    fn alloc_best_fit() {
+        let blocks = [
+            (256, true),
+            (64, false),
+            (96, true),
+            (64, false),
+            (128, true),
+        ];
+
+        let chunk = test_chunk(&blocks);
+        let (block, _) = unsafe { chunk.alloc(80, 16, 0, AllocStrategy::BestFit, false) }.unwrap();
+        assert_eq!(block.offset, 320);
+        assert_eq!(
+            layout(&chunk),
+            [
+                (0, 256, true),
+                (256, 64, false),
+                (320, 80, false),
+                (400, 16, true),
+                (416, 64, false),
+                (480, 128, true)
+            ]
+        );
+
+        let chunk = test_chunk(&blocks);
+        let (block, _) = unsafe { chunk.alloc(80, 16, 0, AllocStrategy::FirstFit, false) }.unwrap();
+        assert_eq!(block.offset, 0);
+
+        // only the first block is large enough
+        let chunk = test_chunk(&blocks);
+        let (block, _) = unsafe { chunk.alloc(200, 16, 0, AllocStrategy::BestFit, false) }.unwrap();
+        assert_eq!(block.offset, 0);
+
+        let chunk = test_chunk(&blocks);
+        assert!(unsafe { chunk.alloc(300, 16, 0, AllocStrategy::BestFit, false) }.is_none());
+    }
+
+    #[test]
+    fn free_coalesces() {
+        let blocks = [
+            (64, false),
+            (64, false),
+            (64, false),
+            (64, false),
+            (64, false),
+        ];
+        let chunk = test_chunk(&blocks);
+        let block = |offset| {
+            chunk
+                .blocks
+                .read()
+                .unwrap()
+                .iter()
+                .find(|b| b.offset == offset)
+                .copied()
+                .unwrap()
+        };
+
+        // middle, nothing free around it
+        unsafe { chunk.free(block(128)) };
+        assert_eq!(
+            layout(&chunk),
+            [
+                (0, 64, false),
+                (64, 64, false),
+                (128, 64, true),
+                (192, 64, false),
+                (256, 64, false)
+            ]
+        );
+        // start and end, no neighbor on one side
+        unsafe { chunk.free(block(0)) };
+        unsafe { chunk.free(block(256)) };
+        assert_eq!(
+            layout(&chunk),
+            [
+                (0, 64, true),
+                (64, 64, false),
+                (128, 64, true),
+                (192, 64, false),
+                (256, 64, true)
+            ]
+        );
+        // merges with the previous block
+        unsafe { chunk.free(block(192)) };
+        assert_eq!(
+            layout(&chunk),
+            [(0, 64, true), (64, 64, false), (128, 192, true)]
+        );
+        // merges with both neighbors
+        unsafe { chunk.free(block(64)) };
+        assert_eq!(layout(&chunk), [(0, 320, true)]);
+
+        // merges with the next block
+        let chunk = test_chunk(&[(64, false), (64, true), (64, false)]);
+        let first = chunk.blocks.read().unwrap()[0];
+        unsafe { chunk.free(first) };
+        assert_eq!(layout(&chunk), [(0, 128, true), (128, 64, false)]);
+    }
+
+    #[test]
+    fn alloc_padding() {
+        // the free block starts at 8, aligned to 16 its usable size is 64
+        let chunk = test_chunk(&[(8, false), (72, true), (16, false)]);
+        let (block, _) = unsafe { chunk.alloc(64, 16, 0, AllocStrategy::FirstFit, false) }.unwrap();
+        assert_eq!((block.offset, block.size), (16, 64));
+        assert_eq!(
+            layout(&chunk),
+            [
+                (0, 8, false),
+                (8, 8, true),
+                (16, 64, false),
+                (80, 16, false)
+            ]
+        );
+
+        // a block smaller than its padding is skipped instead of underflowing
+        let chunk = test_chunk(&[(8, false), (4, true), (4, false), (64, true)]);
+        let (block, _) = unsafe { chunk.alloc(32, 16, 0, AllocStrategy::BestFit, false) }.unwrap();
+        assert_eq!(block.offset, 16);
+        let chunk = test_chunk(&[(8, false), (4, true), (4, false)]);
+        assert!(unsafe { chunk.alloc(1, 16, 0, AllocStrategy::FirstFit, false) }.is_none());
+    }
+
+    #[test]
+    fn dedicated_allocations() {
+        let allocator = test_allocator(1024, 256);
+        let pool_layout = || layout(&allocator.pools[0].chunks.read().unwrap()[0]);
+        let (block, _) =
+            unsafe { allocator.alloc(requirements(256, AllocUsage::DeviceLocal)) }.unwrap();
+        assert!(!block.dedicated);
+        assert_eq!(pool_layout(), [(0, 256, false), (256, 768, true)]);
+
+        // fits in the pool but goes to its own memory, which needs the device
+        let dedicated = unsafe { allocator.alloc(requirements(257, AllocUsage::DeviceLocal)) };
+        assert!(dedicated.is_err());
+        assert_eq!(pool_layout(), [(0, 256, false), (256, 768, true)]);
+
+        // counted in the stats of the pool of its memory type
+        let mut block = Block::new(vk::DeviceMemory::null(), 0, 0, 4096);
+        block.is_free = false;
+        block.dedicated = true;
+        allocator.dedicated.lock().unwrap().push(block);
+        let stats = allocator.stats();
+        assert_eq!(stats.pools[0].dedicated, 4096);
+        assert_eq!(stats.size(), 1024 + 4096);
+        assert_eq!(stats.used(), 256 + 4096);
+        assert!(stats.to_string().contains("dedicated: "));
+    }
+
+    #[test]
+    fn alloc_errors() {
+        let allocator = test_allocator(1024, 4096);
+        // no host visible memory type
+        assert!(unsafe { allocator.alloc(requirements(16, AllocUsage::Staging)) }.is_err());
+        assert!(unsafe { allocator.alloc(requirements(16, AllocUsage::Readback)) }.is_err());
+        let mut unsupported = requirements(16, AllocUsage::DeviceLocal);
+        unsupported.memory_type_bits = 0b10;
+        assert!(unsafe { allocator.alloc(unsupported) }.is_err());
+
+        // the pool is full and can't grow without the device
+        let (block, _) =
+            unsafe { allocator.alloc(requirements(1024, AllocUsage::DeviceLocal)) }.unwrap();
+        let error = unsafe { allocator.alloc(requirements(16, AllocUsage::DeviceLocal)) };
+        assert!(error.is_err());
+        assert_eq!(allocator.pools[0].size.load(Ordering::Relaxed), 1024);
+        // and works again once there is space
+        unsafe { allocator.free(block) };
+        assert!(unsafe { allocator.alloc(requirements(16, AllocUsage::DeviceLocal)) }.is_ok());
+    }
+}
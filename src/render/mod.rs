@@ -1,17 +1,26 @@
 pub mod buffer;
 pub mod camera;
+pub mod color;
 pub mod commands;
 pub mod depth;
 pub mod device;
 pub mod framebuffers;
+pub mod frustum;
+pub mod grid;
 pub mod images;
 pub mod instance;
 pub mod memory;
+pub mod occlusion;
 pub mod physical_device;
 pub mod pipeline;
+pub mod png;
 pub mod renderer;
+pub mod screenshot;
+pub mod stats;
 pub mod swapchain;
 pub mod sync;
+pub mod texture;
+pub mod timestamps;
 pub mod uniforms;
 pub mod vertex;
 
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashSet,
+    mem::size_of,
+    sync::{
+        self,
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use anyhow::Result;
+use nalgebra_glm::{vec2, vec3, Vec3};
+use vulkanalia::{
+    vk::{self, DeviceV1_0, HasBuilder},
+    Device,
+};
+
+use crate::{
+    config::MAX_OCCLUSION_QUERIES,
+    world::{Chunk, ChunkPos},
+};
+
+use super::{
+    buffer::Buffer,
+    commands::CommandBuffer,
+    memory::AllocUsage,
+    renderer::RendererData,
+    vertex::{DrawConstants, Vertex},
+};
+
+// two triangles per face of the box
+const BOX_VERTICES_COUNT: usize = 36;
+
+// Finds the chunks hidden behind the terrain. The bounding boxes of the chunks in the
+// view are drawn after the opaque faces, without writing anything, each in an occlusion
+// query. The results are read back one frame per image late and the chunks whose box
+// had no visible sample are skipped until a later query sees them again.
+pub struct OcclusionCulling {
+    device: sync::Weak<Device>,
+    // MAX_OCCLUSION_QUERIES queries per swapchain image
+    pool: vk::QueryPool,
+    // box of a chunk grown by a block on each side so it isn't hidden by the faces of the
+    // chunk itself or of its neighbors, drawn relative to the chunk origin minus a block
+    boxes: Buffer,
+    // secondaries drawing the boxes, allocated from the frame command pools
+    command_buffers: Vec<Mutex<CommandBuffer>>,
+    // chunks queried by the last recording of each image, in the order of the queries
+    queried: Mutex<Vec<Vec<ChunkPos>>>,
+    // chunks without any visible sample in the last results read
+    occluded: Mutex<HashSet<ChunkPos>>,
+    // incremented each time occluded changes, the commands must be recorded again
+    version: AtomicU64,
+}
+
+impl OcclusionCulling {
+    /// # Safety
+    ///
+    /// The swapchain and the pipelines of data must be created.
+    pub unsafe fn create(data: &RendererData) -> Result<Self> {
+        let images = data.swapchain.as_ref().unwrap().images.len();
+        let info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::OCCLUSION)
+            .query_count((MAX_OCCLUSION_QUERIES * images) as u32);
+        let pool = data.device.create_query_pool(&info, None)?;
+
+        let boxes = match Self::create_boxes(data) {
+            Ok(boxes) => boxes,
+            Err(e) => {
+                data.device.destroy_query_pool(pool, None);
+                return Err(e);
+            }
+        };
+
+        let mut command_buffers = Vec::with_capacity(images);
+        for pool in &data.frame_command_pools {
+            let buffer =
+                pool.allocate_command_buffers(&data.device, 1, vk::CommandBufferLevel::SECONDARY)?;
+            command_buffers.push(Mutex::new(buffer[0]));
+        }
+
+        Ok(Self {
+            device: Arc::downgrade(&data.device),
+            pool,
+            boxes,
+            command_buffers,
+            queried: Mutex::new(vec![Vec::new(); images]),
+            occluded: Mutex::new(HashSet::new()),
+            version: AtomicU64::new(0),
+        })
+    }
+
+    unsafe fn create_boxes(data: &RendererData) -> Result<Buffer> {
+        let size = Chunk::size();
+        let max = vec3(size[0] + 2, size[1] + 2, size[2] + 2);
+        let corner = |i: usize| {
+            vec3(
+                if i & 1 != 0 { max.x } else { 0 },
+                if i & 2 != 0 { max.y } else { 0 },
+                if i & 4 != 0 { max.z } else { 0 },
+            )
+        };
+        // both sides of the faces are drawn, the winding doesn't matter
+        let faces = [
+            [0, 1, 3, 2],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 3, 7, 6],
+            [0, 2, 6, 4],
+            [1, 3, 7, 5],
+        ];
+        let vertices: Vec<_> = faces
+            .iter()
+            .flat_map(|face| [face[0], face[1], face[2], face[2], face[3], face[0]])
+            .map(|i| Vertex::new(corner(i), vec3(0, 0, 0), Vertex::UNLIT, 3, vec2(0, 0), 0))
+            .collect();
+        debug_assert_eq!(vertices.len(), BOX_VERTICES_COUNT);
+
+        let buffer = Buffer::create(
+            data,
+            BOX_VERTICES_COUNT * size_of::<Vertex>(),
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            AllocUsage::Staging,
+        )?;
+        buffer.fill(data, &vertices)?;
+        Ok(buffer)
+    }
+
+    // the chunks hidden in the last results read, to skip while recording
+    #[inline]
+    pub fn occluded(&self) -> MutexGuard<'_, HashSet<ChunkPos>> {
+        self.occluded.lock().unwrap()
+    }
+
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    // Whether the box of a chunk can be tested. The box of a chunk around the camera
+    // may be clipped by the near plane or only have its back faces hidden by the chunk
+    // itself, the chunk is always drawn instead. Positions are relative to the origin.
+    pub fn can_query(chunk_min: &Vec3, camera_pos: &Vec3) -> bool {
+        let size = Chunk::size();
+        (0..3).any(|axis| {
+            camera_pos[axis] < chunk_min[axis] - 2.0
+                || camera_pos[axis] > chunk_min[axis] + size[axis] as f32 + 2.0
+        })
+    }
+
+    /// outside of the render pass, before it begins
+    ///
+    /// # Safety
+    ///
+    /// command_buffer must be recording outside of a render pass, and the queries of image_index
+    /// must not be in use by a frame in flight.
+    pub unsafe fn record_reset(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+    ) {
+        device.cmd_reset_query_pool(
+            command_buffer,
+            self.pool,
+            (image_index * MAX_OCCLUSION_QUERIES) as u32,
+            MAX_OCCLUSION_QUERIES as u32,
+        );
+    }
+
+    /// Secondary testing the boxes of the chunks, executed after the opaque faces of all
+    /// the chunks. The chunks after MAX_OCCLUSION_QUERIES aren't tested. None if there is
+    /// no chunk to test.
+    ///
+    /// # Safety
+    ///
+    /// The render pass of data must be created, and the queries of image_index reset by
+    /// record_reset before the secondary runs.
+    pub unsafe fn record(
+        &self,
+        data: &RendererData,
+        inheritance: &vk::CommandBufferInheritanceInfo,
+        image_index: usize,
+        mut chunks: Vec<ChunkPos>,
+    ) -> Result<Option<vk::CommandBuffer>> {
+        chunks.truncate(MAX_OCCLUSION_QUERIES);
+        let mut queried = self.queried.lock().unwrap();
+        if chunks.is_empty() {
+            queried[image_index].clear();
+            return Ok(None);
+        }
+
+        let command_buffer = &mut self.command_buffers[image_index].lock().unwrap();
+        command_buffer.begin_secondary(&data.device, inheritance)?;
+        let pipeline = data.pipeline.as_ref().unwrap();
+        data.device.cmd_bind_pipeline(
+            command_buffer.buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            pipeline.occlusion,
+        );
+        data.device.cmd_bind_descriptor_sets(
+            command_buffer.buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            pipeline.layout,
+            0,
+            &[data.uniforms.as_ref().unwrap().descriptor_sets[image_index]],
+            &[0], // the chunk uniforms aren't used by the boxes
+        );
+        data.device
+            .cmd_bind_vertex_buffers(command_buffer.buffer, 0, &[self.boxes.buffer], &[0]);
+
+        let first = image_index * MAX_OCCLUSION_QUERIES;
+        for (i, pos) in chunks.iter().enumerate() {
+            DrawConstants::new(pos.origin().add_scalar(-1)).push(
+                &data.device,
+                command_buffer.buffer,
+                pipeline.layout,
+            );
+            // Not precise: only zero or not zero matters, which doesn't need the
+            // occlusionQueryPrecise feature.
+            data.device.cmd_begin_query(
+                command_buffer.buffer,
+                self.pool,
+                (first + i) as u32,
+                vk::QueryControlFlags::empty(),
+            );
+            data.device
+                .cmd_draw(command_buffer.buffer, BOX_VERTICES_COUNT as u32, 1, 0, 0);
+            data.device
+                .cmd_end_query(command_buffer.buffer, self.pool, (first + i) as u32);
+        }
+        command_buffer.end(&data.device)?;
+
+        queried[image_index] = chunks;
+        Ok(Some(command_buffer.buffer))
+    }
+
+    /// Update the occluded chunks with the results of the last submission of the image's
+    /// commands. They must have finished executing (the image fence waited) so this
+    /// doesn't stall.
+    ///
+    /// # Safety
+    ///
+    /// The commands of image_index must have finished executing.
+    pub unsafe fn read(&self, device: &Device, image_index: usize) -> Result<()> {
+        let queried = self.queried.lock().unwrap();
+        let chunks = &queried[image_index];
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let mut samples = vec![0u64; chunks.len()];
+        device.get_query_pool_results(
+            self.pool,
+            (image_index * MAX_OCCLUSION_QUERIES) as u32,
+            chunks.len() as u32,
+            std::slice::from_raw_parts_mut(samples.as_mut_ptr().cast(), samples.len() * 8),
+            8,
+            vk::QueryResultFlags::_64 | vk::QueryResultFlags::WAIT,
+        )?;
+
+        // the chunks out of the view aren't kept, they are drawn when they come back
+        let occluded: HashSet<_> = chunks
+            .iter()
+            .zip(samples)
+            .filter(|(_, samples)| *samples == 0)
+            .map(|(pos, _)| *pos)
+            .collect();
+        let mut current = self.occluded.lock().unwrap();
+        if *current != occluded {
+            *current = occluded;
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // Forget the occluded chunks while the culling is disabled, they would be outdated
+    // once it is enabled again.
+    pub fn clear(&self) {
+        let mut occluded = self.occluded.lock().unwrap();
+        if !occluded.is_empty() {
+            occluded.clear();
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for OcclusionCulling {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .upgrade()
+                .unwrap()
+                .destroy_query_pool(self.pool, None);
+        }
+    }
+}
@@ -2,13 +2,16 @@ use std::collections::HashSet;
 
 use anyhow::{anyhow, Result};
 use vulkanalia::{
-    vk::{self, InstanceV1_0, KhrSurfaceExtension, QueueFlags},
-    Instance,
+    vk::{
+        self, HasBuilder, InstanceV1_0, InstanceV1_1, KhrGetPhysicalDeviceProperties2Extension,
+        KhrSurfaceExtension, QueueFlags,
+    },
+    Instance, Version,
 };
 
 use log::*;
 
-use crate::{config::DEVICE_EXTENSIONS};
+use crate::config::{DEVICE_EXTENSIONS, OPTIONAL_DEVICE_EXTENSIONS};
 
 use super::swapchain::SwapchainSupport;
 
@@ -29,17 +32,60 @@ pub struct PhysicalDevice {
     pub graphics_queue: QueueDef,
     pub present_queue: QueueDef,
     pub transfer_queues: Vec<QueueDef>,
+    pub features: vk::PhysicalDeviceFeatures,
+    pub limits: vk::PhysicalDeviceLimits,
+    // of the graphics queue family, 0 if it doesn't support timestamps
+    pub timestamp_valid_bits: u32,
+    // the supported ones from OPTIONAL_DEVICE_EXTENSIONS
+    pub optional_extensions: Vec<vk::ExtensionName>,
+    // the lowest of the instance version and the device one, without the patch version
+    pub api_version: Version,
+    // with the bindless-textures feature, if the descriptor indexing features it needs are
+    // supported, their extensions are then in optional_extensions
+    pub bindless_textures: bool,
 }
 
 impl PhysicalDevice {
-    pub fn pick(instance: &Instance, surface: vk::SurfaceKHR) -> Result<Self> {
+    // the suitable device with the best score is selected, unless a suitable one has the
+    // preferred name (case insensitive)
+    pub fn pick(
+        instance: &Instance,
+        instance_version: Version,
+        surface: Option<vk::SurfaceKHR>,
+        preferred_device_name: Option<String>,
+    ) -> Result<Self> {
+        let mut best: Option<(u64, PhysicalDevice)> = None;
+        let mut preferred = None;
         for physical_device in unsafe { instance.enumerate_physical_devices()? } {
             let properties = unsafe { instance.get_physical_device_properties(physical_device) };
 
-            match unsafe { check_physical_device(instance, surface, physical_device) } {
+            match unsafe {
+                check_physical_device(instance, instance_version, surface, physical_device)
+            } {
                 Ok(device) => {
-                    info!("Selected physical device (`{}`).", properties.device_name);
-                    return Ok(device);
+                    if preferred.is_none()
+                        && preferred_device_name.as_ref().is_some_and(|name| {
+                            name.eq_ignore_ascii_case(&properties.device_name.to_string())
+                        })
+                    {
+                        info!(
+                            "Physical device (`{}`) has the preferred name.",
+                            properties.device_name
+                        );
+                        preferred = Some(device);
+                        continue;
+                    }
+                    let memory_properties =
+                        unsafe { instance.get_physical_device_memory_properties(physical_device) };
+                    let score = device_score(&properties, &memory_properties);
+                    info!(
+                        "Physical device (`{}`, {:?}) scored {}.",
+                        properties.device_name, properties.device_type, score
+                    );
+                    // the first one is kept on a tie
+                    if best.as_ref().is_none_or(|(best, _)| score > *best) {
+                        best = Some((score, device));
+                    }
                 }
                 Err(e) => {
                     warn!(
@@ -49,51 +95,235 @@ impl PhysicalDevice {
                 }
             }
         }
-        Err(anyhow!("Failed to find suitable physical device."))
+
+        let device = match preferred {
+            Some(device) => device,
+            None => {
+                if let Some(name) = preferred_device_name {
+                    warn!(
+                        "No suitable physical device named `{name}`: falling back to the scoring."
+                    );
+                }
+                best.ok_or_else(|| anyhow!("Failed to find suitable physical device."))?
+                    .1
+            }
+        };
+        let properties = unsafe { instance.get_physical_device_properties(device.device) };
+        info!("Selected physical device (`{}`).", properties.device_name);
+        Ok(device)
+    }
+
+    // clamp a line width to what the device supports, 1.0 is always supported
+    pub fn supported_line_width(&self, width: f32) -> f32 {
+        if width != 1.0 && self.features.wide_lines != vk::TRUE {
+            warn!("Wide lines not supported: line width set to 1.0 instead of {width}");
+            return 1.0;
+        }
+        let [min, max] = self.limits.line_width_range;
+        if width < min || width > max {
+            warn!("Line width {width} out of the supported range [{min}, {max}]");
+        }
+        width.clamp(min, max)
+    }
+
+    // the highest supported sample count not above samples, for both the color and the depth
+    pub fn supported_sample_count(&self, samples: u32) -> vk::SampleCountFlags {
+        let supported = self.limits.framebuffer_color_sample_counts
+            & self.limits.framebuffer_depth_sample_counts;
+        let count = [
+            vk::SampleCountFlags::_64,
+            vk::SampleCountFlags::_32,
+            vk::SampleCountFlags::_16,
+            vk::SampleCountFlags::_8,
+            vk::SampleCountFlags::_4,
+            vk::SampleCountFlags::_2,
+        ]
+        .into_iter()
+        .find(|count| count.bits() <= samples && supported.contains(*count))
+        .unwrap_or(vk::SampleCountFlags::_1);
+        if count.bits() != samples {
+            warn!(
+                "{samples} samples per pixel not supported: using {} instead",
+                count.bits()
+            );
+        }
+        count
     }
 }
 
+// discrete gpus first, then the one with the most device local memory
+fn device_score(
+    properties: &vk::PhysicalDeviceProperties,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+) -> u64 {
+    let mut score = 0;
+    if properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU {
+        score += 1000;
+    }
+    let device_local: u64 = memory_properties.memory_heaps
+        [..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum();
+    // one point per GiB
+    score + device_local / (1024 * 1024 * 1024)
+}
+
+// the features device::create enables unconditionally
+fn missing_feature(features: &vk::PhysicalDeviceFeatures) -> Option<&'static str> {
+    let required = [("sampler_anisotropy", features.sampler_anisotropy)];
+    required
+        .iter()
+        .find(|(_, supported)| *supported != vk::TRUE)
+        .map(|(name, _)| *name)
+}
+
 unsafe fn check_physical_device(
     instance: &Instance,
-    surface: vk::SurfaceKHR,
+    instance_version: Version,
+    surface: Option<vk::SurfaceKHR>,
     physical_device: vk::PhysicalDevice,
 ) -> Result<PhysicalDevice> {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let device_version = Version::from(properties.api_version);
+    let api_version =
+        Version::new(device_version.major, device_version.minor, 0).min(instance_version);
     let queues = get_queues(instance, surface, physical_device)?;
-    {
-        let extensions = instance
-            .enumerate_device_extension_properties(physical_device, None)?
-            .iter()
-            .map(|e| e.extension_name)
-            .collect::<HashSet<_>>();
-        if DEVICE_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
-            Ok(())
-        } else {
-            Err(anyhow!("Missing required device extensions."))
+    let extensions = instance
+        .enumerate_device_extension_properties(physical_device, None)?
+        .iter()
+        .map(|e| e.extension_name)
+        .collect::<HashSet<_>>();
+    if !DEVICE_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
+        return Err(anyhow!("Missing required device extensions."));
+    }
+    // the memory budget and the extension features are queried with an instance extension
+    // before vulkan 1.1
+    let properties2_usable = api_version >= Version::V1_1_0
+        || instance
+            .extensions()
+            .contains(&vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name);
+    let mut optional_extensions: Vec<_> = OPTIONAL_DEVICE_EXTENSIONS
+        .iter()
+        .filter(|e| extensions.contains(e))
+        .filter(|e| **e != vk::EXT_MEMORY_BUDGET_EXTENSION.name || properties2_usable)
+        .copied()
+        .collect();
+    let mut bindless_textures = false;
+    if cfg!(feature = "bindless-textures") && properties2_usable {
+        if let Some(needed) = descriptor_indexing_extensions(api_version, &extensions) {
+            bindless_textures =
+                descriptor_indexing_supported(instance, physical_device, api_version);
+            if bindless_textures {
+                optional_extensions.extend(needed);
+            }
+        }
+    }
+
+    if let Some(surface) = surface {
+        let support = SwapchainSupport::get(instance, surface, physical_device)?;
+        if support.formats.is_empty() || support.present_modes.is_empty() {
+            return Err(anyhow!("Insufficient swapchain support."));
         }
-    }?;
+    }
 
-    let support = SwapchainSupport::get(instance, surface, physical_device)?;
-    if support.formats.is_empty() || support.present_modes.is_empty() {
-        return Err(anyhow!("Insufficient swapchain support."));
+    let features = instance.get_physical_device_features(physical_device);
+    if let Some(feature) = missing_feature(&features) {
+        return Err(anyhow!("Missing required device feature `{}`.", feature));
     }
+    let limits = properties.limits;
+    let timestamp_valid_bits = instance
+        .get_physical_device_queue_family_properties(physical_device)[queues.0.family as usize]
+        .timestamp_valid_bits;
 
     let device = PhysicalDevice {
         device: physical_device,
         graphics_queue: queues.0,
         present_queue: queues.1,
         transfer_queues: queues.2,
+        features,
+        limits,
+        timestamp_valid_bits,
+        optional_extensions,
+        api_version,
+        bindless_textures,
     };
 
     Ok(device)
 }
 
+// the extensions to enable for the descriptor indexing, None if the device doesn't have them
+fn descriptor_indexing_extensions(
+    api_version: Version,
+    extensions: &HashSet<vk::ExtensionName>,
+) -> Option<Vec<vk::ExtensionName>> {
+    // core from vulkan 1.2, the extension needs maintenance3 which is core from 1.1
+    let needed = if api_version >= Version::V1_2_0 {
+        vec![]
+    } else if api_version >= Version::V1_1_0 {
+        vec![vk::EXT_DESCRIPTOR_INDEXING_EXTENSION.name]
+    } else {
+        vec![
+            vk::EXT_DESCRIPTOR_INDEXING_EXTENSION.name,
+            vk::KHR_MAINTENANCE3_EXTENSION.name,
+        ]
+    };
+    needed
+        .iter()
+        .all(|e| extensions.contains(e))
+        .then_some(needed)
+}
+
+// the features device::create enables for the bindless textures
+unsafe fn descriptor_indexing_supported(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    api_version: Version,
+) -> bool {
+    let mut indexing = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut indexing);
+    if api_version >= Version::V1_1_0 {
+        instance.get_physical_device_features2(physical_device, &mut features);
+    } else {
+        instance.get_physical_device_features2_khr(physical_device, &mut features);
+    }
+    indexing.runtime_descriptor_array == vk::TRUE
+        && indexing.descriptor_binding_variable_descriptor_count == vk::TRUE
+        && indexing.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+}
+
+/// without a surface nothing is presented, the graphics queue stands in for the present queue
+///
+/// # Safety
+///
+/// physical_device must have been enumerated from instance, and surface created with it.
 pub unsafe fn get_queues(
     instance: &Instance,
-    surface: vk::SurfaceKHR,
+    surface: Option<vk::SurfaceKHR>,
     physical_device: vk::PhysicalDevice,
 ) -> Result<(QueueDef, QueueDef, Vec<QueueDef>)> {
     let properties = instance.get_physical_device_queue_family_properties(physical_device);
+    let present_support = (0..properties.len() as u32)
+        .map(|family| match surface {
+            Some(surface) => Ok(instance.get_physical_device_surface_support_khr(
+                physical_device,
+                family,
+                surface,
+            )?),
+            None => Ok(properties[family as usize]
+                .queue_flags
+                .contains(QueueFlags::GRAPHICS)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    select_queues(&properties, &present_support)
+}
 
+// present_support tells for each family if it can present to the surface
+fn select_queues(
+    properties: &[vk::QueueFamilyProperties],
+    present_support: &[bool],
+) -> Result<(QueueDef, QueueDef, Vec<QueueDef>)> {
     let mut graphics = None;
     let mut present = None;
     let mut transfer = Vec::new();
@@ -106,13 +336,7 @@ pub unsafe fn get_queues(
             graphics = Some(QueueDef::new(index as u32, 0));
             min_queue_index = 1;
         }
-        if present.is_none()
-            && instance.get_physical_device_surface_support_khr(
-                physical_device,
-                index as u32,
-                surface,
-            )?
-        {
+        if present.is_none() && present_support[index] {
             present = Some(QueueDef::new(index as u32, 0));
             min_queue_index = 1;
         }
@@ -124,14 +348,135 @@ pub unsafe fn get_queues(
         }
     }
 
-    if transfer.len() == 0 {
-        Err(anyhow!("No transfer queue found"))
-    } else {
-        let r = (
-            graphics.expect("No graphics queue found"),
-            present.expect("No present queue family found"),
+    let graphics = graphics.ok_or_else(|| anyhow!("No graphics queue found"))?;
+    let present = present.ok_or_else(|| anyhow!("No present queue family found"))?;
+
+    if transfer.is_empty() {
+        // graphics queues support transfers even if the family doesn't advertise it
+        let queue_count = properties[graphics.family as usize].queue_count;
+        let index = if queue_count > 1 { 1 } else { 0 };
+        warn!(
+            "No transfer queue found: using the queue {} of the graphics family",
+            index
+        );
+        transfer.push(QueueDef::new(graphics.family, index));
+    }
+
+    Ok((graphics, present, transfer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discrete_first_then_device_local_memory() {
+        let mut properties = vk::PhysicalDeviceProperties::default();
+        let mut memory = vk::PhysicalDeviceMemoryProperties {
+            memory_heap_count: 2,
+            ..Default::default()
+        };
+        memory.memory_heaps[0] = vk::MemoryHeap {
+            size: 8 << 30,
+            flags: vk::MemoryHeapFlags::DEVICE_LOCAL,
+        };
+        memory.memory_heaps[1] = vk::MemoryHeap {
+            size: 16 << 30,
+            flags: vk::MemoryHeapFlags::empty(),
+        };
+        // past memory_heap_count, ignored
+        memory.memory_heaps[2] = vk::MemoryHeap {
+            size: 16 << 30,
+            flags: vk::MemoryHeapFlags::DEVICE_LOCAL,
+        };
+
+        properties.device_type = vk::PhysicalDeviceType::INTEGRATED_GPU;
+        let integrated = device_score(&properties, &memory);
+        assert_eq!(integrated, 8);
+        properties.device_type = vk::PhysicalDeviceType::DISCRETE_GPU;
+        let discrete = device_score(&properties, &memory);
+        assert_eq!(discrete, 1008);
+
+        // more memory doesn't make an integrated gpu better than a discrete one
+        memory.memory_heaps[0].size = 64 << 30;
+        properties.device_type = vk::PhysicalDeviceType::INTEGRATED_GPU;
+        assert!(device_score(&properties, &memory) < discrete);
+    }
+
+    fn family(queue_flags: QueueFlags, queue_count: u32) -> vk::QueueFamilyProperties {
+        vk::QueueFamilyProperties {
+            queue_flags,
+            queue_count,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn descriptor_indexing_extensions_by_version() {
+        let indexing = vk::EXT_DESCRIPTOR_INDEXING_EXTENSION.name;
+        let maintenance3 = vk::KHR_MAINTENANCE3_EXTENSION.name;
+        let none = HashSet::new();
+        let both = HashSet::from([indexing, maintenance3]);
+
+        assert_eq!(
+            descriptor_indexing_extensions(Version::V1_2_0, &none),
+            Some(vec![])
+        );
+        assert_eq!(descriptor_indexing_extensions(Version::V1_1_0, &none), None);
+        assert_eq!(
+            descriptor_indexing_extensions(Version::V1_1_0, &both),
+            Some(vec![indexing])
+        );
+        assert_eq!(
+            descriptor_indexing_extensions(Version::V1_0_0, &HashSet::from([indexing])),
+            None
+        );
+        assert_eq!(
+            descriptor_indexing_extensions(Version::V1_0_0, &both),
+            Some(vec![indexing, maintenance3])
+        );
+    }
+
+    #[test]
+    fn select_queue_families() {
+        // a single queue does everything
+        let families = [family(QueueFlags::GRAPHICS | QueueFlags::COMPUTE, 1)];
+        let (graphics, present, transfer) = select_queues(&families, &[true]).unwrap();
+        assert_eq!(graphics, QueueDef::new(0, 0));
+        assert_eq!(present, QueueDef::new(0, 0));
+        assert_eq!(transfer, [QueueDef::new(0, 0)]);
+
+        // graphics family without the transfer flag, its second queue is used
+        let families = [family(QueueFlags::GRAPHICS, 4)];
+        let (_, _, transfer) = select_queues(&families, &[true]).unwrap();
+        assert_eq!(transfer, [QueueDef::new(0, 1)]);
+
+        // the other queues of the graphics family and the whole transfer family
+        let families = [
+            family(QueueFlags::GRAPHICS | QueueFlags::TRANSFER, 3),
+            family(QueueFlags::TRANSFER, 2),
+        ];
+        let (_, present, transfer) = select_queues(&families, &[true, false]).unwrap();
+        assert_eq!(present, QueueDef::new(0, 0));
+        assert_eq!(
             transfer,
+            [
+                QueueDef::new(0, 1),
+                QueueDef::new(0, 2),
+                QueueDef::new(1, 0),
+                QueueDef::new(1, 1)
+            ]
         );
-        Ok(r)
+
+        // present only on another family
+        let families = [
+            family(QueueFlags::GRAPHICS, 1),
+            family(QueueFlags::TRANSFER, 1),
+        ];
+        let (graphics, present, _) = select_queues(&families, &[false, true]).unwrap();
+        assert_eq!((graphics.family, present.family), (0, 1));
+
+        assert!(select_queues(&[family(QueueFlags::TRANSFER, 1)], &[true]).is_err());
+        assert!(select_queues(&[family(QueueFlags::GRAPHICS, 1)], &[false]).is_err());
     }
 }
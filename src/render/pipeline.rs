@@ -6,24 +6,62 @@ use vulkanalia::{
     Device,
 };
 
-use super::vertex::Vertex;
+use crate::config::{CAVE_VIEW_OPACITY, REVERSE_Z};
+
+use super::vertex::{DrawConstants, Vertex};
 use super::{depth::get_depth_format, renderer::RendererData};
 
 #[derive(Default)]
 pub struct Pipeline {
     device: sync::Weak<Device>,
     pub pipeline: vk::Pipeline,
+    pub lines: vk::Pipeline,
+    pub points: vk::Pipeline,
+    pub see_through: vk::Pipeline,
+    // transparent blocks, drawn after the opaque ones
+    pub transparent: vk::Pipeline,
+    // bounding boxes of the chunks for the occlusion queries, nothing is written
+    pub occlusion: vk::Pipeline,
     pub layout: vk::PipelineLayout,
     pub render_pass: vk::RenderPass,
 }
 
 impl Pipeline {
+    /// # Safety
+    ///
+    /// The swapchain of data must be created, the pipelines must be destroyed before the device.
     pub unsafe fn create(data: &RendererData) -> Result<Self> {
-        let vert = include_bytes!("../../assets/shaders/vert.spv");
-        let frag = include_bytes!("../../assets/shaders/frag.spv");
+        Self::create_for(
+            data,
+            data.swapchain.as_ref().unwrap().extent,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+        )
+    }
 
-        let vert_shader_module = create_shader_module(&data.device, &vert[..])?;
-        let frag_shader_module = create_shader_module(&data.device, &frag[..])?;
+    /// Pipelines drawing to an image of extent, the swapchain format and the samples of data,
+    /// copied from after the render pass instead of being presented.
+    ///
+    /// # Safety
+    ///
+    /// The swapchain and the uniforms of data must be created.
+    pub unsafe fn create_offscreen(data: &RendererData, extent: vk::Extent2D) -> Result<Self> {
+        Self::create_for(data, extent, vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+    }
+
+    // color_layout is the layout of the drawn image after the render pass
+    unsafe fn create_for(
+        data: &RendererData,
+        extent: vk::Extent2D,
+        color_layout: vk::ImageLayout,
+    ) -> Result<Self> {
+        let bindless = data.atlas.as_ref().is_some_and(|atlas| atlas.bindless);
+        let (vert, frag) = match &data.reloaded_shaders {
+            Some(shaders) => shaders.clone(),
+            None => shaders(bindless)?,
+        };
+
+        let vert_shader_module = create_shader_module(&data.device, &vert)?;
+        let frag_shader_module = create_shader_module(&data.device, &frag)?;
 
         let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::VERTEX)
@@ -35,95 +73,83 @@ impl Pipeline {
             .module(frag_shader_module)
             .name(b"main\0");
 
-        let binding_descriptions = &[Vertex::binding_description()];
-        let attribute_descriptions = Vertex::attribute_descriptions();
-        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_binding_descriptions(binding_descriptions)
-            .vertex_attribute_descriptions(&attribute_descriptions);
-
-        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .primitive_restart_enable(false);
-
-        let viewport = vk::Viewport::builder()
-            .x(0.0)
-            .y(0.0)
-            .width(data.swapchain.as_ref().unwrap().extent.width as f32)
-            .height(data.swapchain.as_ref().unwrap().extent.height as f32)
-            .min_depth(0.0)
-            .max_depth(1.0);
-
-        let scissor = vk::Rect2D::builder()
-            .offset(vk::Offset2D { x: 0, y: 0 })
-            .extent(data.swapchain.as_ref().unwrap().extent);
-
-        let viewports = &[viewport];
-        let scissors = &[scissor];
-        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(viewports)
-            .scissors(scissors);
-
-        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::FRONT)
-            .front_face(vk::FrontFace::CLOCKWISE)
-            .depth_bias_enable(false);
-
-        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-            .sample_shading_enable(false)
-            .rasterization_samples(vk::SampleCountFlags::_1);
-
-        let attachment = vk::PipelineColorBlendAttachmentState::builder()
-            .color_write_mask(vk::ColorComponentFlags::all())
-            .blend_enable(false);
-
-        let attachments = &[attachment];
-        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
-            .logic_op_enable(false)
-            .logic_op(vk::LogicOp::COPY)
-            .attachments(attachments)
-            .blend_constants([0.0, 0.0, 0.0, 0.0]);
-
         let set_layouts = &[data.uniforms.as_ref().unwrap().descriptor_set_layout];
-        let layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(set_layouts);
+        let push_constant_ranges = &[DrawConstants::push_constant_range()];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(set_layouts)
+            .push_constant_ranges(push_constant_ranges);
         let layout = data.device.create_pipeline_layout(&layout_info, None)?;
 
-        let render_pass = create_render_pass(data)?;
-
-        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS)
-            .depth_bounds_test_enable(false)
-            .stencil_test_enable(false);
+        let render_pass = create_render_pass(data, color_layout)?;
+        let target = Target {
+            layout,
+            render_pass,
+            extent,
+        };
 
         let stages = &[vert_stage, frag_stage];
-        let info = vk::GraphicsPipelineCreateInfo::builder()
-            .stages(stages)
-            .vertex_input_state(&vertex_input_state)
-            .input_assembly_state(&input_assembly_state)
-            .viewport_state(&viewport_state)
-            .rasterization_state(&rasterization_state)
-            .multisample_state(&multisample_state)
-            .color_blend_state(&color_blend_state)
-            .layout(layout)
-            .render_pass(render_pass)
-            .subpass(0)
-            .depth_stencil_state(&depth_stencil_state);
-
-        let pipeline = data
-            .device
-            .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
-            .0;
+        let pipeline = create_pipeline(
+            data,
+            stages,
+            &target,
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &[],
+            Blending::Opaque,
+        )?;
+        // debug lines, the width is set with `cmd_set_line_width` before drawing
+        let lines = create_pipeline(
+            data,
+            stages,
+            &target,
+            vk::PrimitiveTopology::LINE_LIST,
+            &[vk::DynamicState::LINE_WIDTH],
+            Blending::Opaque,
+        )?;
+        // chunks vertices as points, to debug the meshing
+        let points = create_pipeline(
+            data,
+            stages,
+            &target,
+            vk::PrimitiveTopology::POINT_LIST,
+            &[],
+            Blending::Opaque,
+        )?;
+        // cave view, all the faces are blended so the inside of the terrain can be seen
+        let see_through = create_pipeline(
+            data,
+            stages,
+            &target,
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &[],
+            Blending::SeeThrough,
+        )?;
+        let transparent = create_pipeline(
+            data,
+            stages,
+            &target,
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &[],
+            Blending::Alpha,
+        )?;
+        let occlusion = create_pipeline(
+            data,
+            stages,
+            &target,
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &[],
+            Blending::Hidden,
+        )?;
 
         data.device.destroy_shader_module(vert_shader_module, None);
         data.device.destroy_shader_module(frag_shader_module, None);
 
         Ok(Self {
             pipeline,
+            lines,
+            points,
+            see_through,
+            transparent,
+            occlusion,
             layout,
             render_pass,
             device: Arc::downgrade(&data.device),
@@ -136,12 +162,220 @@ impl Drop for Pipeline {
         let device = self.device.upgrade().unwrap();
         unsafe {
             device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline(self.lines, None);
+            device.destroy_pipeline(self.points, None);
+            device.destroy_pipeline(self.see_through, None);
+            device.destroy_pipeline(self.transparent, None);
+            device.destroy_pipeline(self.occlusion, None);
             device.destroy_pipeline_layout(self.layout, None);
             device.destroy_render_pass(self.render_pass, None);
         }
     }
 }
 
+// the fragment shader indexing the texture array, compiled with the bindless-textures feature
+#[cfg(all(feature = "bindless-textures", not(feature = "shaders-from-disk")))]
+const BINDLESS_FRAG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/frag_bindless.spv"));
+// never bindless without the feature
+#[cfg(not(any(feature = "bindless-textures", feature = "shaders-from-disk")))]
+const BINDLESS_FRAG: &[u8] = &[];
+
+// compiled by build.rs and embedded in the executable
+#[cfg(not(feature = "shaders-from-disk"))]
+fn shaders(bindless: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    let vert = include_bytes!(concat!(env!("OUT_DIR"), "/vert.spv"));
+    let frag = if bindless {
+        BINDLESS_FRAG
+    } else {
+        include_bytes!(concat!(env!("OUT_DIR"), "/frag.spv"))
+    };
+    Ok((vert.to_vec(), frag.to_vec()))
+}
+
+// relative to the working directory, to change the shaders without rebuilding
+#[cfg(feature = "shaders-from-disk")]
+fn shaders(bindless: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    let read = |path| std::fs::read(path).map_err(|e| anyhow!("Failed to read {path}: {e}"));
+    Ok((
+        read("assets/shaders/vert.spv")?,
+        read(if bindless {
+            "assets/shaders/frag_bindless.spv"
+        } else {
+            "assets/shaders/frag.spv"
+        })?,
+    ))
+}
+
+// compile the GLSL sources relative to the working directory for the hot reload, with the
+// same glslc as build.rs (there is no in-process compiler)
+pub fn compile_shaders(bindless: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    let compile = |path: &str, defines: &[&str]| -> Result<Vec<u8>> {
+        let output = std::process::Command::new("glslc")
+            .args(defines)
+            .args(["-o", "-", path])
+            .output()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => anyhow!(
+                    "glslc not found, install the Vulkan SDK or add glslc to the PATH to reload the shaders"
+                ),
+                _ => anyhow!("Failed to run glslc: {e}"),
+            })?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Failed to compile {path}:\n{}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ));
+        }
+        Ok(output.stdout)
+    };
+    Ok((
+        compile("assets/shaders/shader.vert", &[])?,
+        compile(
+            "assets/shaders/shader.frag",
+            if bindless {
+                &["-DBINDLESS_TEXTURES"]
+            } else {
+                &[]
+            },
+        )?,
+    ))
+}
+
+// what the pipelines draw to
+struct Target {
+    layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    extent: vk::Extent2D,
+}
+
+// the blended pipelines don't cull the faces nor write the depth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Blending {
+    Opaque,
+    SeeThrough, // with a constant opacity
+    Alpha,      // with the alpha of the texture
+    Hidden,     // only depth tested, the color isn't written either
+}
+
+unsafe fn create_pipeline(
+    data: &RendererData,
+    stages: &[vk::PipelineShaderStageCreateInfoBuilder],
+    target: &Target,
+    topology: vk::PrimitiveTopology,
+    dynamic_states: &[vk::DynamicState],
+    blending: Blending,
+) -> Result<vk::Pipeline> {
+    let blended = blending != Blending::Opaque;
+    let binding_descriptions = &[Vertex::binding_description()];
+    let attribute_descriptions = Vertex::attribute_descriptions();
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_binding_descriptions(binding_descriptions)
+        .vertex_attribute_descriptions(&attribute_descriptions);
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(topology)
+        .primitive_restart_enable(false);
+
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(target.extent.width as f32)
+        .height(target.extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D { x: 0, y: 0 })
+        .extent(target.extent);
+
+    let viewports = &[viewport];
+    let scissors = &[scissor];
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewports(viewports)
+        .scissors(scissors);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(if blended {
+            vk::CullModeFlags::NONE
+        } else {
+            vk::CullModeFlags::FRONT
+        })
+        .front_face(vk::FrontFace::CLOCKWISE)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(data.msaa_samples);
+
+    let (src_factor, dst_factor) = match blending {
+        Blending::Alpha => (
+            vk::BlendFactor::SRC_ALPHA,
+            vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+        ),
+        _ => (
+            vk::BlendFactor::CONSTANT_ALPHA,
+            vk::BlendFactor::ONE_MINUS_CONSTANT_ALPHA,
+        ),
+    };
+    let attachment = vk::PipelineColorBlendAttachmentState::builder()
+        .color_write_mask(if blending == Blending::Hidden {
+            vk::ColorComponentFlags::empty()
+        } else {
+            vk::ColorComponentFlags::all()
+        })
+        .blend_enable(blended)
+        .src_color_blend_factor(src_factor)
+        .dst_color_blend_factor(dst_factor)
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::ONE)
+        .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
+        .alpha_blend_op(vk::BlendOp::ADD);
+
+    let attachments = &[attachment];
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(attachments)
+        .blend_constants([0.0, 0.0, 0.0, CAVE_VIEW_OPACITY]);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(!blended)
+        .depth_compare_op(if REVERSE_Z {
+            vk::CompareOp::GREATER_OR_EQUAL
+        } else {
+            vk::CompareOp::LESS
+        })
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let dynamic_state =
+        vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(dynamic_states);
+
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(target.layout)
+        .render_pass(target.render_pass)
+        .subpass(0)
+        .depth_stencil_state(&depth_stencil_state);
+
+    Ok(data
+        .device
+        .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
+        .0)
+}
+
 unsafe fn create_shader_module(device: &Device, bytecode: &[u8]) -> Result<vk::ShaderModule> {
     let bytecode = Vec::<u8>::from(bytecode);
     let (prefix, code, suffix) = bytecode.align_to::<u32>();
@@ -156,16 +390,31 @@ unsafe fn create_shader_module(device: &Device, bytecode: &[u8]) -> Result<vk::S
     Ok(device.create_shader_module(&info, None)?)
 }
 
-unsafe fn create_render_pass(data: &RendererData) -> Result<vk::RenderPass> {
+// With multisampling the color attachment is the ColorBuffer, resolved into the
+// swapchain image as the third attachment. Otherwise it is the swapchain image.
+// The swapchain image, or the offscreen one drawn instead, ends in color_layout.
+unsafe fn create_render_pass(
+    data: &RendererData,
+    color_layout: vk::ImageLayout,
+) -> Result<vk::RenderPass> {
+    let multisampled = data.msaa_samples != vk::SampleCountFlags::_1;
     let color_attachment = vk::AttachmentDescription::builder()
         .format(data.swapchain.as_ref().unwrap().format)
-        .samples(vk::SampleCountFlags::_1)
+        .samples(data.msaa_samples)
         .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::STORE)
+        .store_op(if multisampled {
+            vk::AttachmentStoreOp::DONT_CARE
+        } else {
+            vk::AttachmentStoreOp::STORE
+        })
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
+        .final_layout(if multisampled {
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        } else {
+            color_layout
+        });
 
     let color_attachment_ref = vk::AttachmentReference::builder()
         .attachment(0)
@@ -173,9 +422,14 @@ unsafe fn create_render_pass(data: &RendererData) -> Result<vk::RenderPass> {
 
     let depth_stencil_attachment = vk::AttachmentDescription::builder()
         .format(get_depth_format(data)?)
-        .samples(vk::SampleCountFlags::_1)
+        .samples(data.msaa_samples)
         .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        // only needed after the render pass by `DepthBuffer::read_back`
+        .store_op(if data.depth_readback {
+            vk::AttachmentStoreOp::STORE
+        } else {
+            vk::AttachmentStoreOp::DONT_CARE
+        })
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
@@ -185,11 +439,29 @@ unsafe fn create_render_pass(data: &RendererData) -> Result<vk::RenderPass> {
         .attachment(1)
         .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
 
+    let resolve_attachment = vk::AttachmentDescription::builder()
+        .format(data.swapchain.as_ref().unwrap().format)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(color_layout);
+
+    let resolve_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(2)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
     let color_attachments = &[color_attachment_ref];
-    let subpass = vk::SubpassDescription::builder()
+    let resolve_attachments = &[resolve_attachment_ref];
+    let mut subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
         .color_attachments(color_attachments)
         .depth_stencil_attachment(&depth_stencil_attachment_ref);
+    if multisampled {
+        subpass = subpass.resolve_attachments(resolve_attachments);
+    }
 
     let dependency = vk::SubpassDependency::builder()
         .src_subpass(vk::SUBPASS_EXTERNAL)
@@ -208,11 +480,14 @@ unsafe fn create_render_pass(data: &RendererData) -> Result<vk::RenderPass> {
                 | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
         );
 
-    let attachments = &[color_attachment, depth_stencil_attachment];
+    let mut attachments = vec![color_attachment, depth_stencil_attachment];
+    if multisampled {
+        attachments.push(resolve_attachment);
+    }
     let subpasses = &[subpass];
     let dependencies = &[dependency];
     let info = vk::RenderPassCreateInfo::builder()
-        .attachments(attachments)
+        .attachments(&attachments)
         .subpasses(subpasses)
         .dependencies(dependencies);
 
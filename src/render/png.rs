@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// Decode a non interlaced PNG with 8 bits RGB or RGBA pixels to RGBA pixels.
+// Returns the width, the height and the pixels row by row.
+pub fn decode(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+    let mut rest = bytes
+        .strip_prefix(SIGNATURE)
+        .ok_or_else(|| anyhow!("Not a PNG"))?;
+
+    let mut header = None;
+    let mut compressed = Vec::new();
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let kind = &rest[4..8];
+        let chunk = rest
+            .get(8..8 + len)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk"))?;
+        match kind {
+            b"IHDR" if len == 13 => header = Some(chunk),
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+        // the crc is not checked
+        rest = rest
+            .get(12 + len..)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk"))?;
+    }
+
+    let header = header.ok_or_else(|| anyhow!("PNG without header"))?;
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let (bit_depth, color_type, interlace) = (header[8], header[9], header[12]);
+    let channels = match color_type {
+        2 => 3,
+        6 => 4,
+        _ => return Err(anyhow!("Unsupported PNG color type {color_type}")),
+    };
+    if bit_depth != 8 || interlace != 0 {
+        return Err(anyhow!(
+            "Unsupported PNG with {bit_depth} bits depth and interlace {interlace}"
+        ));
+    }
+
+    let filtered = miniz_oxide::inflate::decompress_to_vec_zlib(&compressed)
+        .map_err(|e| anyhow!("Invalid PNG data: {e:?}"))?;
+    let stride = width as usize * channels;
+    if filtered.len() != (stride + 1) * height as usize {
+        return Err(anyhow!("PNG data doesn't match its size"));
+    }
+
+    // each row starts with its filter type and is predicted from the previous row
+    let mut pixels = vec![0u8; stride * height as usize];
+    for y in 0..height as usize {
+        let row = &filtered[y * (stride + 1)..(y + 1) * (stride + 1)];
+        let (previous, current) = pixels.split_at_mut(y * stride);
+        let above = previous
+            .get(previous.len().saturating_sub(stride)..)
+            .filter(|_| y > 0);
+        let current = &mut current[..stride];
+        for i in 0..stride {
+            let a = if i >= channels {
+                current[i - channels]
+            } else {
+                0
+            };
+            let b = above.map_or(0, |above| above[i]);
+            let c = match above {
+                Some(above) if i >= channels => above[i - channels],
+                _ => 0,
+            };
+            let predicted = match row[0] {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                filter => return Err(anyhow!("Invalid PNG filter {filter}")),
+            };
+            current[i] = row[i + 1].wrapping_add(predicted);
+        }
+    }
+
+    if channels == 3 {
+        pixels = pixels
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect();
+    }
+    Ok((width, height, pixels))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Encode 8 bits RGBA pixels, row by row from the top, to a PNG.
+pub fn encode(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(pixels.len(), width as usize * height as usize * 4);
+    // each row is stored as is (filter type 0)
+    let filtered = pixels
+        .chunks_exact(width as usize * 4)
+        .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+        .collect::<Vec<_>>();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits RGBA, deflate, no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut bytes = SIGNATURE.to_vec();
+    write_chunk(&mut bytes, b"IHDR", &header);
+    write_chunk(
+        &mut bytes,
+        b"IDAT",
+        &miniz_oxide::deflate::compress_to_vec_zlib(&filtered, 6),
+    );
+    write_chunk(&mut bytes, b"IEND", &[]);
+    bytes
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = bytes.len();
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+    let crc = crc32(&bytes[start..]);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+}
+
+// crc of the chunk type and data
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -1,32 +1,54 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    ops::Range,
+    path::Path,
     sync::{Arc, Mutex, RwLock, Weak},
+    time::Instant,
 };
 
 use anyhow::{anyhow, Result};
-use log::{debug, trace};
+use log::{debug, info, trace, warn};
 use nalgebra_glm as glm;
 use vulkanalia::{
     self,
     vk::{self, DeviceV1_0, Handle, HasBuilder, KhrSurfaceExtension, KhrSwapchainExtension},
-    Device, Entry, Instance,
+    Device, Entry, Instance, Version,
 };
 use winit::window::Window;
 
-use crate::{config::MAX_FRAMES_IN_FLIGHT, inputs::Inputs, world::Chunk, render::vertex::Vertex};
+use crate::{
+    config::{
+        ALLOC_CHUNK_SIZE, ALLOC_DEDICATED_THRESHOLD, ALLOC_STRATEGY, DEBUG_LINE_WIDTH,
+        LATENCY_PROBE_FRAMES, LATENCY_PROBE_SIZE, MAX_DRAWN_CHUNKS, MAX_FRAMES_IN_FLIGHT,
+        MSAA_SAMPLES, OCCLUSION_CULLING, REVERSE_Z, VSYNC,
+    },
+    inputs::Inputs,
+    render::vertex::{DrawConstants, Vertex},
+    world::{Chunk, ChunkPos},
+};
 
 use super::{
+    buffer::BufferHandoff,
     camera::Camera,
-    commands::{CommandBuffer, CommandPool},
+    color::ColorBuffer,
+    commands::{ChunkCommands, ChunkCommandsKey, CommandBuffer, CommandPool, RetiredCommands},
     depth::DepthBuffer,
     device,
     framebuffers::Framebuffers,
+    frustum::Frustum,
+    grid::ChunkGrid,
     instance,
     memory::Allocator,
+    occlusion::OcclusionCulling,
     physical_device::PhysicalDevice,
-    pipeline::Pipeline,
+    pipeline::{self, Pipeline},
+    screenshot::{self, ScaledTarget, Screenshot},
+    stats::FrameStats,
     swapchain::Swapchain,
     sync,
+    texture::TextureAtlas,
+    timestamps::GpuTimer,
     uniforms::Uniforms,
 };
 
@@ -35,6 +57,24 @@ use super::{
 pub struct UniformBufferObject {
     pub view: glm::Mat4,
     pub proj: glm::Mat4,
+    pub origin: glm::IVec4, // subtracted from the vertices positions, w unused
+    pub sun: glm::Vec4,     // normalized direction towards the sun, w is the ambient light
+}
+
+// everything the draw commands depend on besides the uniforms
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RecordState {
+    draw_list_version: u64,
+    view_proj: glm::Mat4,
+    origin: glm::TVec3<i32>,
+    camera_pos: glm::Vec3,
+    draw_points: bool,
+    cave_view: bool,
+    show_chunk_grid: bool,
+    max_drawn_chunks: Option<usize>,
+    latency_probe: bool,
+    // version of the occluded chunks, None if the occlusion culling is disabled
+    occlusion_version: Option<u64>,
 }
 
 pub struct Renderer {
@@ -42,68 +82,209 @@ pub struct Renderer {
     frame: usize,
     pub resized: bool,
     pub camera: RefCell<Camera>,
+    pub show_chunk_grid: bool,
+    pub draw_points: bool,
+    pub cave_view: bool,
+    // not with the debug views, they show the hidden chunks
+    pub occlusion_culling: bool,
+    // debug cap on the number of chunks drawn, the nearest ones are kept
+    pub max_drawn_chunks: Option<usize>,
+    // chunks without any face skipped by the last recording
+    pub skipped_empty_chunks: Cell<usize>,
+    pub drawn_chunks: Cell<usize>,
+    // chunks outside of the view skipped by the last recording
+    pub culled_chunks: Cell<usize>,
+    // chunks hidden behind the terrain skipped by the last recording
+    pub occluded_chunks: Cell<usize>,
+    // the chunks drawn by the last recording, drawn again by the scaled screenshots
+    last_drawn: RefCell<Vec<Weak<Mutex<Chunk>>>>,
+    // frames rendered since the start
+    frame_count: u64,
+    pub frame_stats: FrameStats,
+    // what the commands of each swapchain image were recorded with
+    recorded: RefCell<Vec<Option<RecordState>>>,
+    latency_probe: Option<LatencyProbe>,
+    // swapchain image presented last, None until one is presented with the current swapchain
+    last_presented: Cell<Option<usize>>,
+    // the window has no area, the swapchain is kept as is and nothing is rendered
+    minimized: Cell<bool>,
+    // chunk commands retired at a frame_count, freed when no frame can use them anymore
+    retired_commands: RefCell<VecDeque<(u64, RetiredCommands)>>,
+    // upload semaphores waited by the last submit of each frame in flight
+    handoff_semaphores: Vec<Vec<vk::Semaphore>>,
 }
 
 impl Renderer {
-    pub unsafe fn new(window: &Window, entry: &Entry) -> Self {
-        let (instance, messenger) = instance::create(window, entry).unwrap();
-        let surface = vulkanalia::window::create_surface(&instance, window).unwrap();
-        let physical_device = PhysicalDevice::pick(&instance, surface).unwrap();
-        let (device, graphics_queue, present_queue) =
-            device::create(&instance, &physical_device).unwrap();
+    /// # Safety
+    ///
+    /// entry must stay loaded as long as the renderer exists, and window must outlive its surface.
+    pub unsafe fn new(
+        window: &Window,
+        entry: &Entry,
+        preferred_device_name: Option<String>,
+    ) -> Result<Self> {
+        let (instance, messenger, version) = instance::create(Some(window), entry)?;
+        let surface = vulkanalia::window::create_surface(&instance, window)?;
+        let mut data = Renderer::create_data(
+            instance,
+            version,
+            messenger,
+            Some(surface),
+            preferred_device_name,
+        )?;
+
+        data.swapchain = Some(Swapchain::create(window, &data)?);
+        let images = data.swapchain.as_ref().unwrap().images.len();
+        if images < MAX_FRAMES_IN_FLIGHT {
+            warn!(
+                "{MAX_FRAMES_IN_FLIGHT} frames in flight for {images} swapchain images, \
+                 the extra frames wait for an image"
+            );
+        }
+        data.command_pool = Some(CommandPool::create(
+            &data,
+            data.physical_device.graphics_queue.family,
+        )?);
+        // uploaded with the command pool, kept when the swapchain is recreated
+        data.atlas = Some(TextureAtlas::create(&data)?);
+        data.uniforms = Some(Uniforms::create(&data)?);
+        data.color_buffer = ColorBuffer::create(&data)?;
+        data.depth_buffer = Some(DepthBuffer::create(&data)?);
+        data.pipeline = Some(Pipeline::create(&data)?);
+        data.framebuffers = Some(Framebuffers::create(&data)?);
+        data.chunk_grid = Some(ChunkGrid::create(&data)?);
+        Renderer::allocate_command_buffers(&mut data)?;
+        data.gpu_timer = GpuTimer::create(&data)?;
+        data.occlusion = Some(OcclusionCulling::create(&data)?);
+
+        let camera = Camera::new(&data)?;
+
+        Renderer::create_sync_objects(&mut data)?;
+
+        Ok(Self::with_data(data, camera))
+    }
+
+    /// Device, allocator and command pool only, without a window: enough to upload
+    /// and mesh chunks (benchmarks, tests) but nothing can be rendered.
+    ///
+    /// # Safety
+    ///
+    /// entry must stay loaded as long as the renderer exists.
+    pub unsafe fn new_headless(entry: &Entry) -> Result<Self> {
+        let (instance, messenger, version) = instance::create(None, entry)?;
+        let mut data = Renderer::create_data(instance, version, messenger, None, None)?;
+        data.command_pool = Some(CommandPool::create(
+            &data,
+            data.physical_device.graphics_queue.family,
+        )?);
+        let camera = Camera::new(&data)?;
+        Ok(Self::with_data(data, camera))
+    }
+
+    unsafe fn create_data(
+        instance: Instance,
+        instance_version: Version,
+        messenger: Option<vk::DebugUtilsMessengerEXT>,
+        surface: Option<vk::SurfaceKHR>,
+        preferred_device_name: Option<String>,
+    ) -> Result<RendererData> {
+        let physical_device =
+            PhysicalDevice::pick(&instance, instance_version, surface, preferred_device_name)?;
+        info!("Vulkan version {}", physical_device.api_version);
+        let (device, graphics_queue, present_queue) = device::create(&instance, &physical_device)?;
         let device = Arc::new(device);
 
-        let allocator = Arc::new(Allocator::new(&device, &instance, physical_device.device));
+        let allocator = Arc::new(Allocator::new(
+            &device,
+            &instance,
+            physical_device.device,
+            physical_device.api_version,
+            ALLOC_STRATEGY,
+            ALLOC_CHUNK_SIZE,
+            ALLOC_DEDICATED_THRESHOLD,
+        )?);
 
-        let mut data = RendererData::new(
+        Ok(RendererData::new(
             instance,
             messenger,
             surface,
             physical_device,
             device,
-            graphics_queue,
-            present_queue,
+            (graphics_queue, present_queue),
             allocator,
-        );
-
-        data.swapchain = Some(Swapchain::create(window, &data).unwrap());
-        data.uniforms = Some(Uniforms::create(&data).unwrap());
-        data.depth_buffer = Some(DepthBuffer::create(&data).unwrap());
-        data.pipeline = Some(Pipeline::create(&data).unwrap());
-        data.framebuffers = Some(Framebuffers::create(&data).unwrap());
-        data.command_pool =
-            Some(CommandPool::create(&data, data.physical_device.graphics_queue.family).unwrap());
-        data.command_buffers = data
-            .command_pool
-            .as_mut()
-            .unwrap()
-            .allocate_command_buffers(
-                &data.device,
-                data.swapchain.as_ref().unwrap().images.len() as u32,
-            )
-            .unwrap()
-            .iter()
-            .map(|b| Mutex::new(*b))
-            .collect();
-
-        let camera = RefCell::new(Camera::new(&mut data).unwrap());
-
-        Renderer::create_sync_objects(&mut data).unwrap();
+        ))
+    }
 
+    fn with_data(data: RendererData, camera: Camera) -> Self {
         Self {
             data: Arc::new(RwLock::new(data)),
             frame: 0,
             resized: false,
-            camera,
+            camera: RefCell::new(camera),
+            show_chunk_grid: false,
+            cave_view: false,
+            occlusion_culling: OCCLUSION_CULLING,
+            max_drawn_chunks: MAX_DRAWN_CHUNKS,
+            skipped_empty_chunks: Cell::new(0),
+            drawn_chunks: Cell::new(0),
+            culled_chunks: Cell::new(0),
+            occluded_chunks: Cell::new(0),
+            last_drawn: RefCell::new(Vec::new()),
+            frame_count: 0,
+            frame_stats: FrameStats::new(),
+            latency_probe: None,
+            last_presented: Cell::new(None),
+            minimized: Cell::new(false),
+            recorded: RefCell::new(Vec::new()),
+            retired_commands: RefCell::new(VecDeque::new()),
+            handoff_semaphores: vec![Vec::new(); MAX_FRAMES_IN_FLIGHT],
+            draw_points: false,
         }
     }
 
+    // One primary and one secondary for the overlays per swapchain image, each image
+    // has its own pool reset before recording its commands again.
+    unsafe fn allocate_command_buffers(data: &mut RendererData) -> Result<()> {
+        let count = data.swapchain.as_ref().unwrap().images.len();
+        for _ in 0..count {
+            let pool =
+                CommandPool::create_resettable(data, data.physical_device.graphics_queue.family)?;
+            let primary =
+                pool.allocate_command_buffers(&data.device, 1, vk::CommandBufferLevel::PRIMARY)?;
+            let overlay =
+                pool.allocate_command_buffers(&data.device, 1, vk::CommandBufferLevel::SECONDARY)?;
+            data.command_buffers.push(Mutex::new(primary[0]));
+            data.overlay_command_buffers.push(Mutex::new(overlay[0]));
+            data.frame_command_pools.push(pool);
+        }
+        Ok(())
+    }
+
+    // the buffers are freed with their pools
+    unsafe fn free_command_buffers(data: &mut RendererData) {
+        data.command_buffers.clear();
+        data.overlay_command_buffers.clear();
+        data.frame_command_pools.clear();
+    }
+
     unsafe fn create_sync_objects(data: &mut RendererData) -> Result<()> {
         data.image_available_semaphore =
             sync::create_semaphores(&data.device, MAX_FRAMES_IN_FLIGHT)?;
         data.render_finished_semaphore =
             sync::create_semaphores(&data.device, MAX_FRAMES_IN_FLIGHT)?;
         data.in_flight_fences = sync::create_fences(&data.device, true, MAX_FRAMES_IN_FLIGHT)?;
+        data.handoff_command_buffers = data
+            .command_pool
+            .as_ref()
+            .unwrap()
+            .allocate_command_buffers(
+                &data.device,
+                MAX_FRAMES_IN_FLIGHT as u32,
+                vk::CommandBufferLevel::PRIMARY,
+            )?
+            .into_iter()
+            .map(Mutex::new)
+            .collect();
         data.images_in_flight = Mutex::new(
             data.swapchain
                 .as_ref()
@@ -132,6 +313,9 @@ impl Renderer {
             .for_each(|f| data.device.destroy_fence(*f, None));
         data.in_flight_fences.clear();
 
+        // freed with the command pool
+        data.handoff_command_buffers.clear();
+
         Ok(())
     }
 
@@ -145,9 +329,24 @@ impl Renderer {
         debug!("Recording commands");
 
         let data = self.data.read().unwrap();
+        if data.swapchain.is_none() {
+            return Err(anyhow!("Can't record the commands of a headless renderer"));
+        }
         let command_buffer = &mut data.command_buffers[image_index].lock().unwrap();
 
-        command_buffer.begin(&data.device)?;
+        // render waited for the last frame drawn to this image, its commands aren't pending
+        debug_assert!({
+            let fence = data.images_in_flight.lock().unwrap()[image_index];
+            fence.is_null() || data.device.get_fence_status(fence) == Ok(vk::SuccessCode::SUCCESS)
+        });
+        data.frame_command_pools[image_index].reset(&data.device)?;
+        command_buffer.begin_reusable(&data.device)?;
+        if let Some(timer) = &data.gpu_timer {
+            timer.record_start(&data.device, command_buffer.buffer, image_index);
+        }
+        if let Some(occlusion) = &data.occlusion {
+            occlusion.record_reset(&data.device, command_buffer.buffer, image_index);
+        }
 
         let render_area = vk::Rect2D::builder()
             .offset(vk::Offset2D::default())
@@ -161,7 +360,8 @@ impl Renderer {
 
         let depth_clear_value = vk::ClearValue {
             depth_stencil: vk::ClearDepthStencilValue {
-                depth: 1.0,
+                // the farthest depth
+                depth: if REVERSE_Z { 0.0 } else { 1.0 },
                 stencil: 0,
             },
         };
@@ -176,97 +376,563 @@ impl Renderer {
         data.device.cmd_begin_render_pass(
             command_buffer.buffer,
             &info,
-            vk::SubpassContents::INLINE,
-        );
-        data.device.cmd_bind_pipeline(
-            command_buffer.buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            data.pipeline.as_ref().unwrap().pipeline,
+            vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
         );
+        let (pipeline, transparent_pipeline) =
+            self.scene_pipelines(data.pipeline.as_ref().unwrap());
+        let camera_pos = self.camera.borrow().pos;
 
-        data.device.cmd_bind_descriptor_sets(
-            command_buffer.buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            data.pipeline.as_ref().unwrap().layout,
-            0,
-            &[data.uniforms.as_ref().unwrap().descriptor_sets[image_index]],
-            &[],
-        );
+        if let Some(max) = self.max_drawn_chunks {
+            if chunks.len() > max {
+                profiling::scope!("sort chunks by distance");
+                chunks.sort_by_cached_key(|chunk| {
+                    chunk.upgrade().map_or(u64::MAX, |chunk| {
+                        distance2_to_chunk(chunk.lock().unwrap().pos, &camera_pos) as u64
+                    })
+                });
+            }
+        }
 
         let mut to_remove = Vec::new();
+        let mut drawn_chunks = Vec::new();
+        let mut secondaries = Vec::new();
+        // with the distance of their chunk to sort them back to front
+        let mut transparents = Vec::new();
+        let mut drawn = 0;
+        let mut recorded = 0;
+        let mut skipped_empty = 0;
+        let mut culled = 0;
+        let mut occluded_count = 0;
+        let (frustum, origin) = {
+            let camera = self.camera.borrow();
+            (camera.frustum(), camera.origin())
+        };
+        let relative_camera_pos = camera_pos - origin.map(|c| c as f32);
+        let occlusion = data.occlusion.as_ref();
+        if let Some(occlusion) = occlusion.filter(|_| !self.occlusion_enabled()) {
+            occlusion.clear();
+        }
+        // the frustum culled chunks aren't tested
+        let mut queries = Vec::new();
+        let occluded = occlusion
+            .filter(|_| self.occlusion_enabled())
+            .map(|occlusion| occlusion.occluded());
 
         for (i, chunk) in chunks.iter().enumerate() {
             if let Some(chunk) = chunk.upgrade() {
-                let chunk = chunk.lock().unwrap();
-                data.device.cmd_bind_vertex_buffers(
-                    command_buffer.buffer,
-                    0,
-                    &[chunk
-                        .buffer
-                        .as_ref()
-                        .expect("Chunk not meshed")
-                        .buffer],
-                    &[0],
-                );
-                data.device.cmd_bind_index_buffer(
-                    command_buffer.buffer,
-                    chunk
-                        .buffer
-                        .as_ref()
-                        .expect("Chunk not meshed")
-                        .buffer,
-                    (chunk.vertices_count * std::mem::size_of::<Vertex>()) as u64,
-                    vk::IndexType::UINT32,
-                );
-                data.device.cmd_draw_indexed(
-                    command_buffer.buffer,
-                    chunk.indices_count as u32,
-                    1,
-                    0,
-                    0,
-                    0,
-                );
+                let mut chunk = chunk.lock().unwrap();
+                let min = match chunk_visibility(&chunk, &frustum, &origin) {
+                    ChunkVisibility::NoMesh => continue,
+                    ChunkVisibility::Empty => {
+                        skipped_empty += 1;
+                        continue;
+                    }
+                    ChunkVisibility::Culled => {
+                        culled += 1;
+                        continue;
+                    }
+                    ChunkVisibility::Visible(min) => min,
+                };
+                if let Some(occluded) = &occluded {
+                    if OcclusionCulling::can_query(&min, &relative_camera_pos) {
+                        queries.push(chunk.pos);
+                        // tested again to be drawn once it is visible
+                        if occluded.contains(&chunk.pos) {
+                            occluded_count += 1;
+                            continue;
+                        }
+                    }
+                }
+                // keep going to find the dropped chunks
+                if self.max_drawn_chunks.is_some_and(|max| drawn >= max) {
+                    continue;
+                }
+                drawn += 1;
+                drawn_chunks.push(chunks[i].clone());
+                let key = ChunkCommandsKey {
+                    generation: data.swapchain_generation,
+                    pipeline,
+                    buffer: chunk.buffer.as_ref().expect("Chunk not meshed").buffer,
+                    vertices_count: chunk.vertices_count,
+                    indices_count: chunk.indices_count,
+                    transparent_indices_count: chunk.transparent_indices_count,
+                    transparent_pipeline,
+                };
+                if chunk.commands.as_ref().map(|c| c.key) != Some(key) {
+                    // the previous ones are retired when dropped
+                    chunk.commands = Some(self.record_chunk_commands(&data, &chunk, key)?);
+                    recorded += 1;
+                }
+                let commands = chunk.commands.as_ref().unwrap();
+                secondaries.push(commands.buffers[image_index].buffer);
+                if let Some(transparent) = commands.transparent.get(image_index) {
+                    transparents.push((
+                        distance2_to_chunk(chunk.pos, &camera_pos),
+                        transparent.buffer,
+                    ));
+                }
             } else {
                 to_remove.push(i);
             }
         }
 
+        drop(occluded);
         to_remove.reverse();
 
         for i in to_remove {
             chunks.swap_remove(i);
         }
 
+        // against the depth of the opaque faces, the transparent ones don't hide anything
+        if let Some(occlusion) = occlusion {
+            let inheritance = Self::inheritance_info(&data, image_index);
+            if let Some(boxes) = occlusion.record(&data, &inheritance, image_index, queries)? {
+                secondaries.push(boxes);
+            }
+        }
+
+        // blended over the opaque faces of all the chunks, the farthest first
+        transparents.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        secondaries.extend(transparents.into_iter().map(|(_, buffer)| buffer));
+
+        if let Some(overlay) = self.record_overlay_commands(&data, image_index)? {
+            secondaries.push(overlay);
+        }
+
+        if !secondaries.is_empty() {
+            data.device
+                .cmd_execute_commands(command_buffer.buffer, &secondaries);
+        }
+
         data.device.cmd_end_render_pass(command_buffer.buffer);
 
+        if let Some(timer) = &data.gpu_timer {
+            timer.record_end(&data.device, command_buffer.buffer, image_index);
+        }
         command_buffer.end(&data.device)?;
 
-        debug!("Recording commands took {:?}", t.elapsed());
+        self.skipped_empty_chunks.set(skipped_empty);
+        self.drawn_chunks.set(drawn);
+        self.last_drawn.replace(drawn_chunks);
+        self.culled_chunks.set(culled);
+        self.occluded_chunks.set(occluded_count);
+        trace!(
+            "Recording commands took {:?}, {} empty chunks skipped, {} chunks recorded",
+            t.elapsed(),
+            skipped_empty,
+            recorded
+        );
         Ok(())
     }
 
-    pub unsafe fn update(&mut self, inputs: &Inputs, dt: f32) -> Result<()> {
+    // the debug views draw the transparent faces with the others
+    fn scene_pipelines(&self, pipeline: &Pipeline) -> (vk::Pipeline, Option<vk::Pipeline>) {
+        if self.draw_points {
+            (pipeline.points, None)
+        } else if self.cave_view {
+            (pipeline.see_through, None)
+        } else {
+            (pipeline.pipeline, Some(pipeline.transparent))
+        }
+    }
+
+    fn inheritance_info<'a>(
+        data: &RendererData,
+        image_index: usize,
+    ) -> vk::CommandBufferInheritanceInfoBuilder<'a> {
+        vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(data.pipeline.as_ref().unwrap().render_pass)
+            .subpass(0)
+            .framebuffer(data.framebuffers.as_ref().unwrap()[image_index])
+    }
+
+    // the draws of a chunk for every swapchain image, reused until the key changes
+    unsafe fn record_chunk_commands(
+        &self,
+        data: &RendererData,
+        chunk: &Chunk,
+        key: ChunkCommandsKey,
+    ) -> Result<ChunkCommands> {
+        let buffer = chunk.buffer.as_ref().expect("Chunk not meshed");
+        // the counts must be the ones the buffer was uploaded with
+        debug_assert!(
+            key.vertices_count * std::mem::size_of::<Vertex>()
+                + key.indices_count * std::mem::size_of::<u32>()
+                <= buffer.size,
+            "Chunk {:?} counts don't match its buffer",
+            chunk.pos
+        );
+
+        let draws =
+            |pipeline, indices| self.record_chunk_draws(data, chunk, &key, pipeline, indices);
+        let (buffers, transparent) = match key.transparent_pipeline {
+            Some(transparent_pipeline) => {
+                let opaque_count = key.indices_count - key.transparent_indices_count;
+                let transparent = if key.transparent_indices_count > 0 {
+                    draws(transparent_pipeline, opaque_count..key.indices_count)?
+                } else {
+                    Vec::new()
+                };
+                match draws(key.pipeline, 0..opaque_count) {
+                    Ok(buffers) => (buffers, transparent),
+                    Err(e) => {
+                        Self::retire_command_buffers(data, &transparent);
+                        return Err(e);
+                    }
+                }
+            }
+            None => (draws(key.pipeline, 0..key.indices_count)?, Vec::new()),
+        };
+
+        Ok(ChunkCommands::new(
+            key,
+            buffers,
+            transparent,
+            data.retired_commands.clone(),
+        ))
+    }
+
+    // draw a range of the indices of a chunk, or all its vertices as points
+    unsafe fn record_chunk_draws(
+        &self,
+        data: &RendererData,
+        chunk: &Chunk,
+        key: &ChunkCommandsKey,
+        pipeline: vk::Pipeline,
+        indices: std::ops::Range<usize>,
+    ) -> Result<Vec<CommandBuffer>> {
+        let uniforms = data.uniforms.as_ref().unwrap();
+        let mut buffers = data
+            .command_pool
+            .as_ref()
+            .unwrap()
+            .allocate_command_buffers(
+                &data.device,
+                data.command_buffers.len() as u32,
+                vk::CommandBufferLevel::SECONDARY,
+            )?;
+        let recorded =
+            buffers
+                .iter_mut()
+                .enumerate()
+                .try_for_each(|(image_index, command_buffer)| {
+                    let inheritance = Self::inheritance_info(data, image_index);
+                    command_buffer.begin_secondary(&data.device, &inheritance)?;
+                    // nothing is inherited from the primary buffer
+                    data.device.cmd_bind_pipeline(
+                        command_buffer.buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        pipeline,
+                    );
+                    data.device.cmd_bind_descriptor_sets(
+                        command_buffer.buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        data.pipeline.as_ref().unwrap().layout,
+                        0,
+                        &[uniforms.descriptor_sets[image_index]],
+                        &[uniforms.dynamic_offset(None)],
+                    );
+                    self.record_draw(
+                        data,
+                        command_buffer.buffer,
+                        data.pipeline.as_ref().unwrap().layout,
+                        chunk.pos,
+                        key,
+                        indices.clone(),
+                    );
+                    command_buffer.end(&data.device)
+                });
+        if let Err(e) = recorded {
+            Self::retire_command_buffers(data, &buffers);
+            return Err(e);
+        }
+        Ok(buffers)
+    }
+
+    // buffers that won't be submitted, freed with the retired chunk commands
+    fn retire_command_buffers(data: &RendererData, buffers: &[CommandBuffer]) {
+        data.retired_commands
+            .lock()
+            .unwrap()
+            .buffers
+            .extend(buffers.iter().map(|b| b.buffer));
+    }
+
+    // the pipeline and the descriptor sets must be bound
+    unsafe fn record_draw(
+        &self,
+        data: &RendererData,
+        command_buffer: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+        pos: ChunkPos,
+        key: &ChunkCommandsKey,
+        indices: Range<usize>,
+    ) {
+        DrawConstants::new(pos.origin()).push(&data.device, command_buffer, layout);
+        data.device
+            .cmd_bind_vertex_buffers(command_buffer, 0, &[key.buffer], &[0]);
+        if self.draw_points {
+            // each vertex is drawn once, no need for the indices
+            data.device
+                .cmd_draw(command_buffer, key.vertices_count as u32, 1, 0, 0);
+        } else {
+            data.device.cmd_bind_index_buffer(
+                command_buffer,
+                key.buffer,
+                (key.vertices_count * std::mem::size_of::<Vertex>()) as u64,
+                vk::IndexType::UINT32,
+            );
+            data.device.cmd_draw_indexed(
+                command_buffer,
+                indices.len() as u32,
+                1,
+                indices.start as u32,
+                0,
+                0,
+            );
+        }
+    }
+
+    // The chunks of the last recording drawn inline with the pipelines of another target,
+    // with the uniforms of the image. The overlays aren't drawn.
+    unsafe fn record_scene(
+        &self,
+        data: &RendererData,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &Pipeline,
+        image_index: usize,
+    ) {
+        let (opaque_pipeline, transparent_pipeline) = self.scene_pipelines(pipeline);
+        let uniforms = data.uniforms.as_ref().unwrap();
+        let descriptor_set = uniforms.descriptor_sets[image_index];
+        let draw = |draw_pipeline, pos, key: &ChunkCommandsKey, indices| {
+            data.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                draw_pipeline,
+            );
+            data.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &[descriptor_set],
+                &[uniforms.dynamic_offset(None)],
+            );
+            self.record_draw(data, command_buffer, pipeline.layout, pos, key, indices);
+        };
+
+        let camera_pos = self.camera.borrow().pos;
+        let mut transparents = Vec::new();
+        for chunk in self.last_drawn.borrow().iter().filter_map(Weak::upgrade) {
+            let chunk = chunk.lock().unwrap();
+            // only the buffers a frame drew, the ones uploaded since may not be acquired yet
+            let Some(commands) = chunk.commands.as_ref().filter(|commands| {
+                chunk
+                    .buffer
+                    .as_ref()
+                    .is_some_and(|buffer| buffer.buffer == commands.key.buffer)
+            }) else {
+                continue;
+            };
+            let key = commands.key;
+            let opaque_count = match transparent_pipeline {
+                Some(_) => key.indices_count - key.transparent_indices_count,
+                None => key.indices_count,
+            };
+            draw(opaque_pipeline, chunk.pos, &key, 0..opaque_count);
+            if transparent_pipeline.is_some() && key.transparent_indices_count > 0 {
+                let distance = distance2_to_chunk(chunk.pos, &camera_pos);
+                transparents.push((distance, chunk.pos, key, opaque_count));
+            }
+        }
+
+        // blended over the opaque faces of all the chunks, the farthest first
+        if let Some(transparent_pipeline) = transparent_pipeline {
+            transparents.sort_by(|(a, ..), (b, ..)| b.total_cmp(a));
+            for (_, pos, key, opaque_count) in transparents {
+                draw(
+                    transparent_pipeline,
+                    pos,
+                    &key,
+                    opaque_count..key.indices_count,
+                );
+            }
+        }
+    }
+
+    // chunk grid and latency probe, None if there is nothing to draw
+    unsafe fn record_overlay_commands(
+        &self,
+        data: &RendererData,
+        image_index: usize,
+    ) -> Result<Option<vk::CommandBuffer>> {
+        let latency_probe = self.latency_probe_shown();
+        if !self.show_chunk_grid && !latency_probe {
+            return Ok(None);
+        }
+
+        let command_buffer = &mut data.overlay_command_buffers[image_index].lock().unwrap();
+        let inheritance = Self::inheritance_info(data, image_index);
+        command_buffer.begin_secondary(&data.device, &inheritance)?;
+
+        if self.show_chunk_grid {
+            data.device.cmd_bind_pipeline(
+                command_buffer.buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                data.pipeline.as_ref().unwrap().lines,
+            );
+            data.device.cmd_bind_descriptor_sets(
+                command_buffer.buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                data.pipeline.as_ref().unwrap().layout,
+                0,
+                &[data.uniforms.as_ref().unwrap().descriptor_sets[image_index]],
+                &[0], // the chunk uniforms aren't used by the overlays
+            );
+            data.chunk_grid.as_ref().unwrap().record(
+                data,
+                command_buffer.buffer,
+                image_index,
+                self.camera.borrow().pos,
+            );
+        }
+
+        if latency_probe {
+            let attachment = vk::ClearAttachment::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .color_attachment(0)
+                .clear_value(vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: [1.0, 1.0, 1.0, 1.0],
+                    },
+                });
+            let extent = data.swapchain.as_ref().unwrap().extent;
+            let rect = vk::ClearRect::builder()
+                .rect(vk::Rect2D {
+                    offset: vk::Offset2D::default(),
+                    extent: vk::Extent2D {
+                        width: LATENCY_PROBE_SIZE.min(extent.width),
+                        height: LATENCY_PROBE_SIZE.min(extent.height),
+                    },
+                })
+                .base_array_layer(0)
+                .layer_count(1);
+            data.device
+                .cmd_clear_attachments(command_buffer.buffer, &[attachment], &[rect]);
+        }
+
+        command_buffer.end(&data.device)?;
+        Ok(Some(command_buffer.buffer))
+    }
+
+    // free the chunk commands no frame in flight can still use
+    unsafe fn free_retired_commands(&self, data: &RendererData) {
+        let mut queue = self.retired_commands.borrow_mut();
+        let retired = std::mem::take(&mut *data.retired_commands.lock().unwrap());
+        if !retired.buffers.is_empty() {
+            queue.push_back((self.frame_count, retired));
+        }
+        while let Some((frame, _)) = queue.front() {
+            if frame + MAX_FRAMES_IN_FLIGHT as u64 > self.frame_count {
+                break;
+            }
+            let (_, retired) = queue.pop_front().unwrap();
+            data.device
+                .free_command_buffers(data.command_pool.as_ref().unwrap().pool, &retired.buffers);
+        }
+    }
+
+    // lines width is a dynamic state so no need to rebuild the pipelines
+    pub fn set_line_width(&self, width: f32) {
+        let mut data = self.data.write().unwrap();
+        data.line_width = data.physical_device.supported_line_width(width);
+        // the width is set by the recorded commands
+        self.recorded.borrow_mut().clear();
+    }
+
+    pub fn update(&mut self, inputs: &Inputs, dt: f32) -> Result<()> {
         self.camera.get_mut().update(inputs, dt);
         Ok(())
     }
 
+    // frames rendered since the start
+    #[inline]
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // the debug views show the chunks hidden behind the terrain
+    #[inline]
+    fn occlusion_enabled(&self) -> bool {
+        self.occlusion_culling && !self.draw_points && !self.cave_view
+    }
+
+    fn record_state(&self, data: &RendererData, draw_list_version: u64) -> RecordState {
+        let camera = self.camera.borrow();
+        RecordState {
+            draw_list_version,
+            view_proj: camera.view_proj(),
+            origin: camera.origin(),
+            camera_pos: camera.pos,
+            draw_points: self.draw_points,
+            cave_view: self.cave_view,
+            show_chunk_grid: self.show_chunk_grid,
+            max_drawn_chunks: self.max_drawn_chunks,
+            latency_probe: self.latency_probe_shown(),
+            occlusion_version: data
+                .occlusion
+                .as_ref()
+                .filter(|_| self.occlusion_enabled())
+                .map(OcclusionCulling::version),
+        }
+    }
+
+    // flash the latency probe square from the next frame
+    pub fn probe_latency(&mut self) {
+        self.latency_probe = Some(LatencyProbe {
+            pressed: Instant::now(),
+            frame: self.frame_count,
+        });
+    }
+
+    #[inline]
+    fn latency_probe_shown(&self) -> bool {
+        self.latency_probe
+            .as_ref()
+            .is_some_and(|probe| probe.shown(self.frame_count))
+    }
+
+    // draw_list_version must change when the chunks to draw or their buffers change
     #[profiling::function]
     pub unsafe fn render(
         &mut self,
         window: &Window,
         chunks: &mut Vec<Weak<Mutex<Chunk>>>,
-        _dt: f32,
+        draw_list_version: u64,
+        dt: f32,
     ) -> Result<()> {
+        if self.minimized.get() {
+            // recreated once the window is restored
+            self.recreate_swapchain(window)?;
+            if self.minimized.get() {
+                return Ok(());
+            }
+        }
+        self.frame_stats.push(dt);
         let data = self.data.read().unwrap();
-        data.device.wait_for_fences(
-            &[data.in_flight_fences[self.frame]],
-            true,
-            u64::max_value(),
-        )?;
+        if data.swapchain.is_none() {
+            return Err(anyhow!("Can't render with a headless renderer"));
+        }
+        data.device
+            .wait_for_fences(&[data.in_flight_fences[self.frame]], true, u64::MAX)?;
+        for semaphore in self.handoff_semaphores[self.frame].drain(..) {
+            data.device.destroy_semaphore(semaphore, None);
+        }
 
         let result = data.device.acquire_next_image_khr(
             data.swapchain.as_ref().unwrap().swapchain,
-            u64::max_value(),
+            u64::MAX,
             data.image_available_semaphore[self.frame],
             vk::Fence::null(),
         );
@@ -288,32 +954,71 @@ impl Renderer {
                 data.device.wait_for_fences(
                     &[images_in_flight[image_index as usize]],
                     true,
-                    u64::max_value(),
+                    u64::MAX,
                 )?;
             }
 
             images_in_flight[image_index as usize] = data.in_flight_fences[self.frame];
         }
 
+        self.free_retired_commands(&data);
+
+        // one frame per image late, the last commands of the image are done
+        if let Some(timer) = &data.gpu_timer {
+            if let Some(time) = timer.read(&data.device, image_index)? {
+                self.frame_stats.push_gpu(time);
+            }
+        }
+        if let Some(occlusion) = &data.occlusion {
+            occlusion.read(&data.device, image_index)?;
+        }
+
         self.camera.get_mut().send(&data, image_index)?;
-        self.record_commands(chunks, image_index)?;
+        // the commands of the image are submitted again if they would be the same
+        let state = self.record_state(&data, draw_list_version);
+        if self.recorded.get_mut().get(image_index) != Some(&Some(state)) {
+            self.record_commands(chunks, image_index)?;
+            let recorded = self.recorded.get_mut();
+            if recorded.len() <= image_index {
+                recorded.resize(image_index + 1, None);
+            }
+            recorded[image_index] = Some(state);
+        }
+        // Chunk buffers uploaded since the last frame, taken after the recording so the
+        // buffers it draws were all handed off by this frame or an earlier one. The frame
+        // waits for their copies and acquires them before its commands.
+        let handoffs = std::mem::take(&mut *data.buffer_handoffs.lock().unwrap());
+        let mut command_buffers = Vec::with_capacity(2);
+        {
+            let acquire = &mut data.handoff_command_buffers[self.frame].lock().unwrap();
+            if data.record_buffer_acquires(
+                acquire,
+                &handoffs,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+            )? {
+                command_buffers.push(acquire.buffer);
+            }
+        }
+        command_buffers.push(data.command_buffers[image_index].lock().unwrap().buffer);
 
-        let wait_semaphores = &[data.image_available_semaphore[self.frame]];
-        let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers = &[data.command_buffers[image_index as usize]
-            .lock()
-            .unwrap()
-            .buffer];
+        let mut wait_semaphores = vec![data.image_available_semaphore[self.frame]];
+        let mut wait_stages = vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        for handoff in &handoffs {
+            wait_semaphores.push(handoff.semaphore);
+            wait_stages.push(vk::PipelineStageFlags::VERTEX_INPUT);
+        }
+        self.handoff_semaphores[self.frame].extend(wait_semaphores[1..].iter().copied());
         let signal_semaphores = &[data.render_finished_semaphore[self.frame]];
         let submit_info = vk::SubmitInfo::builder()
-            .wait_semaphores(wait_semaphores)
-            .wait_dst_stage_mask(wait_stages)
-            .command_buffers(command_buffers)
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers)
             .signal_semaphores(signal_semaphores);
 
         data.device
             .reset_fences(&[data.in_flight_fences[self.frame]])?;
 
+        let queue_guard = data.queue_lock.lock().unwrap();
         data.device.queue_submit(
             data.graphics_queue,
             &[submit_info],
@@ -330,10 +1035,28 @@ impl Renderer {
         let result = data
             .device
             .queue_present_khr(data.present_queue, &present_info);
+        drop(queue_guard);
 
         let changed = result == Ok(vk::SuccessCode::SUBOPTIMAL_KHR)
             || result == Err(vk::ErrorCode::OUT_OF_DATE_KHR);
 
+        if result.is_ok() {
+            self.last_presented.set(Some(image_index));
+            if let Some(probe) = self
+                .latency_probe
+                .as_ref()
+                .filter(|probe| probe.frame == self.frame_count)
+            {
+                info!(
+                    "Latency probe: square first drawn in frame {} to image {}, queued for \
+                     presentation {:?} after the key press",
+                    self.frame_count,
+                    image_index,
+                    probe.pressed.elapsed()
+                );
+            }
+        }
+
         if changed || self.resized {
             drop(data); // deadlock if we don't drop the read lock
             self.recreate_swapchain(window)?;
@@ -343,48 +1066,250 @@ impl Renderer {
         }
 
         self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.frame_count += 1;
 
         profiling::finish_frame!();
 
         Ok(())
     }
 
+    /// copy of the last presented frame
+    ///
+    /// # Safety
+    ///
+    /// The queue lock must not be held by the calling thread, capturing waits for the graphics
+    /// queue.
+    pub unsafe fn capture_frame(&self) -> Result<Screenshot> {
+        let image_index = self
+            .last_presented
+            .get()
+            .ok_or_else(|| anyhow!("No frame presented to capture"))?;
+        Screenshot::capture(&self.data.read().unwrap(), image_index)
+    }
+
+    /// Draw the last presented frame again scale times larger and save it downsampled to the
+    /// size of the window as a PNG at path. The scale is rounded down to a power of two that
+    /// fits in max_image_dimension_2d, and halved while the target doesn't fit in the memory.
+    /// The overlays aren't drawn.
+    ///
+    /// # Safety
+    ///
+    /// The queue lock must not be held by the calling thread, capturing waits for the graphics
+    /// queue.
+    pub unsafe fn capture_screenshot_scaled(
+        &self,
+        path: impl AsRef<Path>,
+        scale: u32,
+    ) -> Result<()> {
+        let image_index = self
+            .last_presented
+            .get()
+            .ok_or_else(|| anyhow!("No frame presented to capture"))?;
+        let data = self.data.read().unwrap();
+        let extent = data
+            .swapchain
+            .as_ref()
+            .ok_or_else(|| anyhow!("No swapchain to capture"))?
+            .extent;
+        let max_dimension = data.physical_device.limits.max_image_dimension_2d;
+        let mut supported = screenshot::supported_scale(scale, extent, max_dimension);
+        if supported != scale {
+            warn!("Screenshot scale {scale} not supported: using {supported} instead");
+        }
+        let target = loop {
+            match ScaledTarget::create(&data, supported) {
+                Ok(target) => break target,
+                Err(e) if supported > 1 => {
+                    warn!(
+                        "Failed to create the screenshot target at scale {supported}: {e}, \
+                         trying {}",
+                        supported / 2
+                    );
+                    supported /= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let screenshot = target.capture(&data, |command_buffer| {
+            self.record_scene(&data, command_buffer, &target.pipeline, image_index);
+            Ok(())
+        })?;
+        info!(
+            "Screenshot drawn at {}x{} and saved at {}x{}",
+            extent.width * supported,
+            extent.height * supported,
+            screenshot.width,
+            screenshot.height
+        );
+        screenshot.save_png(path)
+    }
+
+    /// the swapchain is recreated if it changes
+    ///
+    /// # Safety
+    ///
+    /// window must be the window the surface was created for, the new swapchain takes its size.
+    pub unsafe fn set_vsync(&self, window: &Window, vsync: bool) -> Result<()> {
+        {
+            let mut data = self.data.write().unwrap();
+            if data.vsync == vsync {
+                return Ok(());
+            }
+            data.vsync = vsync;
+        }
+        self.recreate_swapchain(window)?;
+        info!("Present mode: {:?}", self.present_mode());
+        Ok(())
+    }
+
+    /// Samples per pixel, lowered to what the device supports. The swapchain is recreated
+    /// if it changes.
+    ///
+    /// # Safety
+    ///
+    /// window must be the window the surface was created for. The command buffers recorded for the
+    /// old render pass are freed and must not be submitted again.
+    pub unsafe fn set_msaa(&self, window: &Window, samples: u32) -> Result<()> {
+        {
+            let mut data = self.data.write().unwrap();
+            let samples = data.physical_device.supported_sample_count(samples);
+            if data.msaa_samples == samples {
+                return Ok(());
+            }
+            data.msaa_samples = samples;
+        }
+        self.recreate_swapchain(window)
+    }
+
+    /// Keep the depth of the frames to read it back, it isn't stored otherwise. The swapchain
+    /// is recreated if it changes.
+    ///
+    /// # Safety
+    ///
+    /// window must be the window the surface was created for, the depth buffer and the render pass
+    /// are recreated with the swapchain.
+    pub unsafe fn set_depth_readback(&self, window: &Window, enabled: bool) -> Result<()> {
+        {
+            let mut data = self.data.write().unwrap();
+            if data.depth_readback == enabled {
+                return Ok(());
+            }
+            data.depth_readback = enabled;
+        }
+        self.recreate_swapchain(window)
+    }
+
+    #[inline]
+    pub fn msaa(&self) -> u32 {
+        self.data.read().unwrap().msaa_samples.bits()
+    }
+
+    #[inline]
+    pub fn vsync(&self) -> bool {
+        self.data.read().unwrap().vsync
+    }
+
+    // effective present mode, may differ from the requested one if it isn't supported
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.data
+            .read()
+            .unwrap()
+            .swapchain
+            .as_ref()
+            .unwrap()
+            .present_mode
+    }
+
+    /// Compile the shaders again and rebuild the pipelines with them, they are kept if it fails.
+    /// The reloaded shaders are also used when the swapchain is recreated.
+    ///
+    /// # Safety
+    ///
+    /// No other thread may record commands with the old pipelines, they are destroyed once the
+    /// device is idle.
+    pub unsafe fn reload_shaders(&self) -> Result<()> {
+        let bindless = self
+            .data
+            .read()
+            .unwrap()
+            .atlas
+            .as_ref()
+            .is_some_and(|atlas| atlas.bindless);
+        let shaders = pipeline::compile_shaders(bindless)?;
+
+        let mut data = self.data.write().unwrap();
+        data.device.device_wait_idle()?;
+        let previous = data.reloaded_shaders.replace(shaders);
+        match Pipeline::create(&data) {
+            Ok(pipeline) => {
+                // the old pipelines are destroyed when dropped
+                data.pipeline = Some(pipeline);
+                data.swapchain_generation += 1;
+                self.recorded.borrow_mut().clear();
+                Ok(())
+            }
+            Err(e) => {
+                data.reloaded_shaders = previous;
+                Err(e)
+            }
+        }
+    }
+
+    // true while the window is minimized, render does nothing until it is restored
+    #[inline]
+    pub fn minimized(&self) -> bool {
+        self.minimized.get()
+    }
+
+    /// Skipped while the window is minimized: a swapchain, depth buffer and framebuffers
+    /// with no area are invalid. The old ones are kept until the window is restored.
+    ///
+    /// # Safety
+    ///
+    /// window must be the window the surface was created for. The command buffers recorded for the
+    /// old swapchain are freed and must not be submitted again.
     pub unsafe fn recreate_swapchain(&self, window: &Window) -> Result<()> {
+        let size = window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            if !self.minimized.replace(true) {
+                debug!("Window minimized, rendering paused");
+            }
+            return Ok(());
+        }
+        self.minimized.set(false);
         trace!("Recreating swapchain");
 
         let mut data = self.data.write().unwrap();
+        self.recorded.borrow_mut().clear();
+        self.last_presented.set(None);
 
         data.device.queue_wait_idle(data.graphics_queue)?;
         data.device.queue_wait_idle(data.present_queue)?;
 
         data.uniforms = None;
+        data.color_buffer = None;
         data.depth_buffer = None;
         data.framebuffers = None;
-        data.device.free_command_buffers(
-            data.command_pool.as_ref().unwrap().pool,
-            &data
-                .command_buffers
-                .iter()
-                .map(|b| b.lock().unwrap().buffer)
-                .collect::<Vec<vk::CommandBuffer>>(),
-        );
-        data.command_buffers.clear();
+        data.chunk_grid = None;
+        // its secondaries are freed with the frame command pools
+        data.occlusion = None;
+        Renderer::free_command_buffers(&mut data);
         data.pipeline = None;
         data.swapchain = None;
         data.swapchain = Some(Swapchain::create(window, &data)?);
         data.uniforms = Some(Uniforms::create(&data)?);
+        data.color_buffer = ColorBuffer::create(&data)?;
         data.depth_buffer = Some(DepthBuffer::create(&data)?);
         data.pipeline = Some(Pipeline::create(&data)?);
         data.framebuffers = Some(Framebuffers::create(&data)?);
+        data.chunk_grid = Some(ChunkGrid::create(&data)?);
         let swapchain_len = data.swapchain.as_ref().unwrap().images.len();
-        data.command_buffers = data
-            .command_pool
-            .as_ref()
-            .unwrap()
-            .allocate_command_buffers(&data.device, swapchain_len as u32)?
-            .iter()
-            .map(|b| Mutex::new(*b))
-            .collect();
+        Renderer::allocate_command_buffers(&mut data)?;
+        data.gpu_timer = None;
+        data.gpu_timer = GpuTimer::create(&data)?;
+        data.occlusion = Some(OcclusionCulling::create(&data)?);
+        // the chunk commands use the old render pass, they are recorded again when drawn
+        data.swapchain_generation += 1;
         data.images_in_flight
             .get_mut()
             .unwrap()
@@ -403,11 +1328,25 @@ impl Drop for Renderer {
             let mut data = self.data.write().unwrap();
             data.device.device_wait_idle().unwrap();
 
+            let handoffs = std::mem::take(data.buffer_handoffs.get_mut().unwrap());
+            for semaphore in handoffs
+                .iter()
+                .map(|handoff| handoff.semaphore)
+                .chain(self.handoff_semaphores.drain(..).flatten())
+            {
+                data.device.destroy_semaphore(semaphore, None);
+            }
+
             // set all options to None to call Drop in the right order
+            data.color_buffer = None;
             data.depth_buffer = None;
             data.uniforms = None;
+            data.atlas = None;
             data.framebuffers = None;
-            data.command_buffers.clear();
+            data.chunk_grid = None;
+            data.occlusion = None;
+            Renderer::free_command_buffers(&mut data);
+            data.gpu_timer = None;
             data.command_pool = None;
             data.pipeline = None;
             data.swapchain = None;
@@ -417,17 +1356,76 @@ impl Drop for Renderer {
             Renderer::destroy_sync_objects(&mut data).unwrap();
 
             device::destroy(&mut data.device);
-            data.instance.destroy_surface_khr(data.surface, None);
+            if let Some(surface) = data.surface {
+                data.instance.destroy_surface_khr(surface, None);
+            }
             instance::destroy(&mut data);
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
+enum ChunkVisibility {
+    // not meshed yet, the chunk has no buffer
+    NoMesh,
+    // all air or fully enclosed, there is no buffer either
+    Empty,
+    // outside of the frustum
+    Culled,
+    // with the min corner of the chunk relative to the origin
+    Visible(glm::Vec3),
+}
+
+// whether a chunk is drawn, before the occlusion culling
+fn chunk_visibility(chunk: &Chunk, frustum: &Frustum, origin: &glm::TVec3<i32>) -> ChunkVisibility {
+    if !chunk.state.has_mesh() {
+        return ChunkVisibility::NoMesh;
+    }
+    if chunk.indices_count == 0 {
+        return ChunkVisibility::Empty;
+    }
+    let min = (chunk.pos.origin() - origin).map(|c| c as f32);
+    let size = Chunk::size();
+    let max = min + glm::vec3(size[0] as f32, size[1] as f32, size[2] as f32);
+    if !frustum.intersects_aabb(&min, &max) {
+        return ChunkVisibility::Culled;
+    }
+    ChunkVisibility::Visible(min)
+}
+
+struct LatencyProbe {
+    // when the key was pressed
+    pressed: Instant,
+    // frame_count at that time, the first frame with the square
+    frame: u64,
+}
+
+impl LatencyProbe {
+    #[inline]
+    fn shown(&self, frame_count: u64) -> bool {
+        (self.frame..self.frame + LATENCY_PROBE_FRAMES).contains(&frame_count)
+    }
+}
+
+// squared distance from the camera to the center of a chunk
+fn distance2_to_chunk(pos: ChunkPos, camera_pos: &glm::Vec3) -> f32 {
+    let origin = pos.origin();
+    let size = Chunk::size();
+    let center = glm::vec3(
+        origin.x as f32 + size[0] as f32 / 2.0,
+        origin.y as f32 + size[1] as f32 / 2.0,
+        origin.z as f32 + size[2] as f32 / 2.0,
+    );
+    glm::distance2(&center, camera_pos)
+}
+
 pub struct RendererData {
     pub instance: Instance,
     pub messenger: Option<vk::DebugUtilsMessengerEXT>,
-    pub surface: vk::SurfaceKHR,
+    pub surface: Option<vk::SurfaceKHR>, // None when headless
     pub physical_device: PhysicalDevice,
+    // negotiated with the loader and the device, the features of later versions must check it
+    pub api_version: Version,
     pub device: Arc<Device>,
     pub graphics_queue: vk::Queue,
     pub present_queue: vk::Queue,
@@ -437,29 +1435,59 @@ pub struct RendererData {
     pub framebuffers: Option<Framebuffers>,
     pub command_pool: Option<CommandPool>,
     pub command_buffers: Vec<Mutex<CommandBuffer>>,
+    pub overlay_command_buffers: Vec<Mutex<CommandBuffer>>, // secondaries
+    // the pools of command_buffers and overlay_command_buffers, one per swapchain image
+    pub frame_command_pools: Vec<CommandPool>,
+    // None if the device doesn't support timestamps
+    pub gpu_timer: Option<GpuTimer>,
+    // None when headless
+    pub occlusion: Option<OcclusionCulling>,
+    // chunk commands dropped since the last frame
+    pub retired_commands: Arc<Mutex<RetiredCommands>>,
+    // chunk buffers uploaded by the meshing threads since the last frame
+    pub buffer_handoffs: Mutex<Vec<BufferHandoff>>,
+    // acquire the handed off buffers, one per frame in flight
+    pub handoff_command_buffers: Vec<Mutex<CommandBuffer>>,
+    // incremented each time the swapchain or the pipelines are recreated
+    pub swapchain_generation: u64,
+    // SPIR-V used instead of the embedded one once the shaders are hot reloaded
+    pub reloaded_shaders: Option<(Vec<u8>, Vec<u8>)>,
     pub image_available_semaphore: Vec<vk::Semaphore>,
     pub render_finished_semaphore: Vec<vk::Semaphore>,
     pub in_flight_fences: Vec<vk::Fence>,
     pub images_in_flight: Mutex<Vec<vk::Fence>>,
-    pub uniforms: Option<Uniforms<UniformBufferObject>>,
+    // held to use the graphics queue outside of the write lock, a meshing thread may share it
+    pub queue_lock: Mutex<()>,
+    pub uniforms: Option<Uniforms<UniformBufferObject, DrawConstants>>,
+    pub color_buffer: Option<ColorBuffer>, // None without multisampling
+    pub atlas: Option<TextureAtlas>,
     pub depth_buffer: Option<DepthBuffer>,
+    pub chunk_grid: Option<ChunkGrid>,
+    pub line_width: f32,
+    // requested, the swapchain has the present mode actually used
+    pub vsync: bool,
+    pub msaa_samples: vk::SampleCountFlags,
+    // the depth is kept after the render pass for DepthBuffer::read_back
+    pub depth_readback: bool,
 }
 
 impl RendererData {
     pub fn new(
         instance: Instance,
         messenger: Option<vk::DebugUtilsMessengerEXT>,
-        surface: vk::SurfaceKHR,
+        surface: Option<vk::SurfaceKHR>,
         physical_device: PhysicalDevice,
         device: Arc<Device>,
-        graphics_queue: vk::Queue,
-        present_queue: vk::Queue,
+        (graphics_queue, present_queue): (vk::Queue, vk::Queue),
         allocator: Arc<Allocator>,
     ) -> Self {
+        let line_width = physical_device.supported_line_width(DEBUG_LINE_WIDTH);
+        let msaa_samples = physical_device.supported_sample_count(MSAA_SAMPLES);
         Self {
             instance,
             messenger,
             surface,
+            api_version: physical_device.api_version,
             physical_device,
             device,
             graphics_queue,
@@ -470,12 +1498,147 @@ impl RendererData {
             framebuffers: None,
             command_pool: None,
             command_buffers: Vec::new(),
+            overlay_command_buffers: Vec::new(),
+            frame_command_pools: Vec::new(),
+            gpu_timer: None,
+            occlusion: None,
+            retired_commands: Arc::new(Mutex::new(RetiredCommands::default())),
+            buffer_handoffs: Mutex::new(Vec::new()),
+            handoff_command_buffers: Vec::new(),
+            swapchain_generation: 0,
+            reloaded_shaders: None,
             image_available_semaphore: Vec::new(),
             render_finished_semaphore: Vec::new(),
             in_flight_fences: Vec::new(),
             images_in_flight: Mutex::new(Vec::new()),
+            queue_lock: Mutex::new(()),
             uniforms: None,
+            color_buffer: None,
+            atlas: None,
             depth_buffer: None,
+            chunk_grid: None,
+            line_width,
+            vsync: VSYNC,
+            msaa_samples,
+            depth_readback: false,
+        }
+    }
+
+    /// Record the acquire barriers of the buffers written by a transfer queue of another
+    /// family, dst_stage is where they are used next. Returns false if there is none, the
+    /// command buffer isn't recorded then.
+    ///
+    /// # Safety
+    ///
+    /// command_buffer must not be pending execution, and the handoffs must come from submitted
+    /// transfers of this device.
+    pub unsafe fn record_buffer_acquires(
+        &self,
+        command_buffer: &mut CommandBuffer,
+        handoffs: &[BufferHandoff],
+        dst_stage: vk::PipelineStageFlags,
+    ) -> Result<bool> {
+        let family = self.physical_device.graphics_queue.family;
+        let barriers: Vec<_> = handoffs
+            .iter()
+            .filter(|handoff| handoff.src_family != family)
+            .map(|handoff| handoff.ownership_barrier(family))
+            .collect();
+        if barriers.is_empty() {
+            return Ok(false);
         }
+        command_buffer.begin(&self.device)?;
+        self.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &barriers,
+            &[] as &[vk::ImageMemoryBarrier],
+        );
+        command_buffer.end(&self.device)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{generation::TERRAIN, ChunkState};
+
+    // meshed like the meshing threads do, without the upload
+    fn meshed(pos: ChunkPos, blocks: &[(usize, usize, usize)]) -> Chunk {
+        let mut chunk = Chunk::new(pos);
+        for &(x, y, z) in blocks {
+            chunk.set_block(x, y, z, TERRAIN);
+        }
+        let zero = Vertex::new(
+            glm::vec3(0, 0, 0),
+            glm::vec3(0, 0, 0),
+            0,
+            0,
+            glm::vec2(0, 0),
+            0,
+        );
+        let (mut vertices, mut indices) = (vec![zero; 1000], vec![0u32; 1000]);
+        let (vertices_count, indices_count, transparent_indices_count) =
+            chunk.mesh(&mut vertices, &mut indices, 0).unwrap();
+        chunk.vertices_count = vertices_count;
+        chunk.indices_count = indices_count;
+        chunk.transparent_indices_count = transparent_indices_count;
+        chunk.state = ChunkState::Ready;
+        chunk
+    }
+
+    #[test]
+    fn empty_chunks_not_drawn() {
+        // camera at the origin looking along +x
+        let mut proj = glm::perspective_rh_zo(16.0 / 9.0, 45f32.to_radians(), 0.1, 1000.0);
+        proj[(1, 1)] *= -1.0;
+        let view = glm::look_at(&glm::Vec3::zeros(), &glm::Vec3::x(), &glm::Vec3::y());
+        let frustum = Frustum::from_matrix(&(proj * view));
+        let origin = glm::vec3(0, 0, 0);
+        let pos = ChunkPos { x: 1, y: 0, z: 0 };
+
+        let unmeshed = Chunk::new(pos);
+        assert_eq!(
+            chunk_visibility(&unmeshed, &frustum, &origin),
+            ChunkVisibility::NoMesh
+        );
+        // all air, no face and so no buffer to draw
+        let air = meshed(pos, &[]);
+        assert_eq!(air.indices_count, 0);
+        assert!(air.buffer.is_none());
+        assert_eq!(
+            chunk_visibility(&air, &frustum, &origin),
+            ChunkVisibility::Empty
+        );
+
+        let block = meshed(pos, &[(1, 2, 3)]);
+        assert_eq!(block.indices_count, 6 * 6);
+        assert_eq!(
+            chunk_visibility(&block, &frustum, &origin),
+            ChunkVisibility::Visible(glm::vec3(16.0, 0.0, 0.0))
+        );
+        // behind the camera
+        let behind = meshed(ChunkPos { x: -2, y: 0, z: 0 }, &[(1, 2, 3)]);
+        assert_eq!(
+            chunk_visibility(&behind, &frustum, &origin),
+            ChunkVisibility::Culled
+        );
+    }
+
+    #[test]
+    fn latency_probe_frames() {
+        let probe = LatencyProbe {
+            pressed: Instant::now(),
+            frame: 5,
+        };
+        // from the frame after the key press
+        assert!(!probe.shown(4));
+        assert!(probe.shown(5));
+        assert!(probe.shown(5 + LATENCY_PROBE_FRAMES - 1));
+        assert!(!probe.shown(5 + LATENCY_PROBE_FRAMES));
     }
 }
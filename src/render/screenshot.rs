@@ -0,0 +1,538 @@
+use std::{
+    path::Path,
+    sync::{Arc, Weak},
+};
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{
+    vk::{self, DeviceV1_0, HasBuilder},
+    Device,
+};
+
+use crate::config::REVERSE_Z;
+
+use super::{
+    buffer::Buffer,
+    color::ColorBuffer,
+    depth::DepthBuffer,
+    images::{self, Image, ImageInfo},
+    memory::AllocUsage,
+    pipeline::Pipeline,
+    png,
+    renderer::RendererData,
+    sync,
+};
+
+// RGBA pixels of a frame, row by row from the top
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    /// Copy a presented swapchain image back to the cpu. The queue is idle when it returns.
+    ///
+    /// # Safety
+    ///
+    /// image_index must be a presented image of the current swapchain, the graphics queue must not
+    /// be used concurrently without the queue lock.
+    pub unsafe fn capture(data: &RendererData, image_index: usize) -> Result<Self> {
+        let swapchain = data
+            .swapchain
+            .as_ref()
+            .ok_or_else(|| anyhow!("No swapchain to capture"))?;
+        if !swapchain.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+            return Err(anyhow!("The swapchain images can't be copied"));
+        }
+        let source = Source {
+            image: swapchain.images[image_index],
+            extent: swapchain.extent,
+            layout: vk::ImageLayout::PRESENT_SRC_KHR,
+            stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            access: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        };
+        Self::read_back(data, source, swizzle(swapchain.format)?, |_| Ok(()))
+    }
+
+    // Copy the source image to the cpu after the commands of record, in the same submit.
+    // The queue is idle when it returns.
+    unsafe fn read_back(
+        data: &RendererData,
+        source: Source,
+        swizzle: [usize; 3],
+        record: impl FnOnce(vk::CommandBuffer) -> Result<()>,
+    ) -> Result<Self> {
+        let Source { image, extent, .. } = source;
+        let size = extent.width as usize * extent.height as usize * 4;
+
+        let readback = Buffer::create(
+            data,
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            AllocUsage::Readback,
+        )?;
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+
+        let command_pool = data.command_pool.as_ref().unwrap();
+        let mut command_buffer = command_pool.allocate_command_buffers(
+            &data.device,
+            1,
+            vk::CommandBufferLevel::PRIMARY,
+        )?[0];
+        command_buffer.begin(&data.device)?;
+        if let Err(e) = record(command_buffer.buffer) {
+            data.device
+                .free_command_buffers(command_pool.pool, &[command_buffer.buffer]);
+            return Err(e);
+        }
+
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .old_layout(source.layout)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(source.access)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+        data.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            source.stage,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[to_transfer],
+        );
+
+        // tightly packed rows
+        let region = vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(0)
+                    .base_array_layer(0)
+                    .layer_count(1),
+            )
+            .image_offset(vk::Offset3D::default())
+            .image_extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            });
+        data.device.cmd_copy_image_to_buffer(
+            command_buffer.buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            readback.buffer,
+            &[region],
+        );
+
+        // back to the layout the image had, a presented one is expected by the render pass
+        let restore = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(source.layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty());
+        let to_host = vk::BufferMemoryBarrier::builder()
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(readback.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE as u64)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ);
+        data.device.cmd_pipeline_barrier(
+            command_buffer.buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::HOST,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[to_host],
+            &[restore],
+        );
+
+        command_buffer.end(&data.device)?;
+
+        // the frame rendered to a swapchain image must be finished before copying it
+        let fence = sync::create_fences(&data.device, false, 1)?[0];
+        let buffers = &[command_buffer.buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(buffers);
+        let result = {
+            let _queue = data.queue_lock.lock().unwrap();
+            data.device
+                .queue_wait_idle(data.graphics_queue)
+                .and_then(|_| {
+                    data.device
+                        .queue_submit(data.graphics_queue, &[submit_info], fence)
+                })
+        }
+        .and_then(|_| data.device.wait_for_fences(&[fence], true, u64::MAX));
+        data.device.destroy_fence(fence, None);
+        data.device
+            .free_command_buffers(command_pool.pool, &[command_buffer.buffer]);
+        result?;
+
+        // the readback memory may not be coherent, the range must start on an atom
+        let atom = data.physical_device.limits.non_coherent_atom_size;
+        let range = vk::MappedMemoryRange::builder()
+            .memory(readback.alloc.memory)
+            .offset(readback.alloc.offset / atom * atom)
+            .size(vk::WHOLE_SIZE as u64);
+        data.device.invalidate_mapped_memory_ranges(&[range])?;
+
+        // the projection flips y so the first row of the image is already the top of the
+        // frame, as in a PNG
+        let copied = std::slice::from_raw_parts(readback.ptr, size);
+        let pixels = copied
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[swizzle[0]], pixel[swizzle[1]], pixel[swizzle[2]], 255])
+            .collect();
+
+        Ok(Self {
+            width: extent.width,
+            height: extent.height,
+            pixels,
+        })
+    }
+
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, png::encode(self.width, self.height, &self.pixels))?;
+        Ok(())
+    }
+}
+
+// an image to copy and how it was last written, it is left in the same layout
+#[derive(Debug, Clone, Copy)]
+struct Source {
+    image: vk::Image,
+    extent: vk::Extent2D,
+    layout: vk::ImageLayout,
+    stage: vk::PipelineStageFlags,
+    access: vk::AccessFlags,
+}
+
+// the channels of each pixel of the format in the RGBA order
+fn swizzle(format: vk::Format) -> Result<[usize; 3]> {
+    match format {
+        vk::Format::B8G8R8A8_SRGB | vk::Format::B8G8R8A8_UNORM => Ok([2, 1, 0]),
+        vk::Format::R8G8B8A8_SRGB | vk::Format::R8G8B8A8_UNORM => Ok([0, 1, 2]),
+        format => Err(anyhow!("Can't capture the swapchain format {format:?}")),
+    }
+}
+
+// The largest power of two up to scale for which the target fits in max_dimension, the
+// halves of the target then end at the swapchain size.
+pub fn supported_scale(scale: u32, extent: vk::Extent2D, max_dimension: u32) -> u32 {
+    let mut supported = 1 << (u32::BITS - 1 - scale.max(1).leading_zeros());
+    let side = extent.width.max(extent.height);
+    while supported > 1
+        && side
+            .checked_mul(supported)
+            .is_none_or(|size| size > max_dimension)
+    {
+        supported /= 2;
+    }
+    supported
+}
+
+// Offscreen target scale times the size of the swapchain images, with its own pipelines.
+// The frame drawn to it is halved with linear blits down to the swapchain size, a box
+// filter over the pixels of each final pixel.
+pub struct ScaledTarget {
+    device: Weak<Device>,
+    pub pipeline: Pipeline,
+    framebuffer: vk::Framebuffer,
+    extent: vk::Extent2D,
+    // the drawn image then each half, the last one has the size of the swapchain images
+    levels: Vec<Image>,
+    _color_buffer: Option<ColorBuffer>,
+    _depth_buffer: DepthBuffer,
+    swizzle: [usize; 3],
+}
+
+impl ScaledTarget {
+    /// scale must be a power of two, see supported_scale.
+    ///
+    /// # Safety
+    ///
+    /// The swapchain and the uniforms of data must be created.
+    pub unsafe fn create(data: &RendererData, scale: u32) -> Result<Self> {
+        debug_assert!(scale.is_power_of_two());
+        let swapchain = data
+            .swapchain
+            .as_ref()
+            .ok_or_else(|| anyhow!("No swapchain to capture"))?;
+        let swizzle = swizzle(swapchain.format)?;
+        if scale > 1 && !images::supports_linear_blit(data, swapchain.format) {
+            return Err(anyhow!(
+                "Can't downsample the swapchain format {:?}",
+                swapchain.format
+            ));
+        }
+        let extent = vk::Extent2D {
+            width: swapchain.extent.width * scale,
+            height: swapchain.extent.height * scale,
+        };
+
+        let levels = (0..=scale.trailing_zeros())
+            .map(|level| {
+                let usage = if level == 0 {
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC
+                } else {
+                    vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC
+                };
+                Image::create(
+                    data,
+                    &ImageInfo {
+                        size: (extent.width >> level, extent.height >> level),
+                        mip_levels: 1,
+                        format: swapchain.format,
+                        tiling: vk::ImageTiling::OPTIMAL,
+                        usage,
+                        aspects: vk::ImageAspectFlags::COLOR,
+                        samples: vk::SampleCountFlags::_1,
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let color_buffer = ColorBuffer::create_sized(data, extent)?;
+        let depth_buffer = DepthBuffer::create_sized(data, extent)?;
+        let pipeline = Pipeline::create_offscreen(data, extent)?;
+
+        // same attachments as the Framebuffers
+        let drawn = levels[0].view;
+        let depth = depth_buffer.image.view;
+        let attachments = match &color_buffer {
+            Some(color) => vec![color.image.view, depth, drawn],
+            None => vec![drawn, depth],
+        };
+        let info = vk::FramebufferCreateInfo::builder()
+            .render_pass(pipeline.render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = data.device.create_framebuffer(&info, None)?;
+
+        Ok(Self {
+            device: Arc::downgrade(&data.device),
+            pipeline,
+            framebuffer,
+            extent,
+            levels,
+            _color_buffer: color_buffer,
+            _depth_buffer: depth_buffer,
+            swizzle,
+        })
+    }
+
+    /// Draw a frame with the commands of draw, recorded inside the render pass of the target
+    /// with the pipelines of the target, and copy it downsampled to the cpu. The queue is idle
+    /// when it returns.
+    ///
+    /// # Safety
+    ///
+    /// The graphics queue must not be used concurrently without the queue lock, draw must only
+    /// use buffers and descriptor sets no other submit writes.
+    pub unsafe fn capture(
+        &self,
+        data: &RendererData,
+        draw: impl FnOnce(vk::CommandBuffer) -> Result<()>,
+    ) -> Result<Screenshot> {
+        let last = self.levels.len() - 1;
+        let source = if last == 0 {
+            // left by the render pass
+            Source {
+                image: self.levels[0].image,
+                extent: self.extent,
+                layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                access: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            }
+        } else {
+            Source {
+                image: self.levels[last].image,
+                extent: self.level_extent(last),
+                layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                stage: vk::PipelineStageFlags::TRANSFER,
+                access: vk::AccessFlags::TRANSFER_WRITE,
+            }
+        };
+        Screenshot::read_back(data, source, self.swizzle, |command_buffer| {
+            let clear_values = &[
+                vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: [0.0, 0.0, 0.0, 1.0],
+                    },
+                },
+                vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        // the farthest depth
+                        depth: if REVERSE_Z { 0.0 } else { 1.0 },
+                        stencil: 0,
+                    },
+                },
+            ];
+            let info = vk::RenderPassBeginInfo::builder()
+                .render_pass(self.pipeline.render_pass)
+                .framebuffer(self.framebuffer)
+                .render_area(vk::Rect2D {
+                    offset: vk::Offset2D::default(),
+                    extent: self.extent,
+                })
+                .clear_values(clear_values);
+            data.device
+                .cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+            let drawn = draw(command_buffer);
+            data.device.cmd_end_render_pass(command_buffer);
+            drawn?;
+            self.record_downsample(data, command_buffer);
+            Ok(())
+        })
+    }
+
+    #[inline]
+    fn level_extent(&self, level: usize) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.extent.width >> level,
+            height: self.extent.height >> level,
+        }
+    }
+
+    // each level is blitted from the previous one, the last one is left to be copied
+    unsafe fn record_downsample(&self, data: &RendererData, command_buffer: vk::CommandBuffer) {
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let layers = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+        let corner = |extent: vk::Extent2D| vk::Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: 1,
+        };
+
+        for level in 1..self.levels.len() {
+            // the drawn image is left in the transfer layout by the render pass
+            let (src_layout, src_stage, src_access) = if level == 1 {
+                (
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                    vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                )
+            } else {
+                (
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                )
+            };
+            let to_source = vk::ImageMemoryBarrier::builder()
+                .old_layout(src_layout)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(self.levels[level - 1].image)
+                .subresource_range(subresource_range)
+                .src_access_mask(src_access)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+            let to_destination = vk::ImageMemoryBarrier::builder()
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(self.levels[level].image)
+                .subresource_range(subresource_range)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+            data.device.cmd_pipeline_barrier(
+                command_buffer,
+                src_stage | vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[] as &[vk::BufferMemoryBarrier],
+                &[to_source, to_destination],
+            );
+
+            // the linear filter averages the 2x2 pixels of each destination pixel
+            let blit = vk::ImageBlit::builder()
+                .src_subresource(layers)
+                .src_offsets([
+                    vk::Offset3D::default(),
+                    corner(self.level_extent(level - 1)),
+                ])
+                .dst_subresource(layers)
+                .dst_offsets([vk::Offset3D::default(), corner(self.level_extent(level))]);
+            data.device.cmd_blit_image(
+                command_buffer,
+                self.levels[level - 1].image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                self.levels[level].image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit],
+                vk::Filter::LINEAR,
+            );
+        }
+    }
+}
+
+impl Drop for ScaledTarget {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .upgrade()
+                .unwrap()
+                .destroy_framebuffer(self.framebuffer, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_clamped_to_the_max_dimension() {
+        let extent = vk::Extent2D {
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(supported_scale(4, extent, 16384), 4);
+        assert_eq!(supported_scale(4, extent, 4096), 2);
+        // the width decides, not the height
+        assert_eq!(supported_scale(2, extent, 2048), 1);
+        // down to a power of two
+        assert_eq!(supported_scale(3, extent, 16384), 2);
+        assert_eq!(supported_scale(0, extent, 16384), 1);
+        assert_eq!(supported_scale(1, extent, 1024), 1);
+        // no overflow
+        assert_eq!(supported_scale(1 << 31, extent, u32::MAX), 1 << 21);
+    }
+}
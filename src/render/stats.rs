@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+
+use crate::config::FRAME_STATS_WINDOW;
+
+// the last FRAME_STATS_WINDOW times, in seconds
+struct TimeWindow {
+    times: VecDeque<f32>,
+    sum: f32,
+}
+
+impl TimeWindow {
+    fn new() -> Self {
+        Self {
+            times: VecDeque::with_capacity(FRAME_STATS_WINDOW),
+            sum: 0.0,
+        }
+    }
+
+    fn push(&mut self, dt: f32) {
+        if self.times.len() == FRAME_STATS_WINDOW {
+            self.sum -= self.times.pop_front().unwrap();
+        }
+        self.times.push_back(dt);
+        self.sum += dt;
+    }
+
+    fn avg(&self) -> Option<f32> {
+        if self.times.is_empty() {
+            return None;
+        }
+        // the running sum drifts a bit, it can't go below 0
+        Some(self.sum.max(0.0) / self.times.len() as f32)
+    }
+
+    fn p99(&self) -> Option<f32> {
+        if self.times.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f32> = self.times.iter().copied().collect();
+        sorted.sort_by(f32::total_cmp);
+        let index = (sorted.len() * 99).div_ceil(100) - 1;
+        Some(sorted[index])
+    }
+}
+
+// frame times of the last FRAME_STATS_WINDOW frames, in seconds
+pub struct FrameStats {
+    cpu: TimeWindow,
+    // measured with timestamp queries, empty if the device doesn't support them
+    gpu: TimeWindow,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        Self {
+            cpu: TimeWindow::new(),
+            gpu: TimeWindow::new(),
+        }
+    }
+
+    pub fn push(&mut self, dt: f32) {
+        self.cpu.push(dt);
+    }
+
+    // time the gpu took to execute the commands of a frame
+    pub fn push_gpu(&mut self, dt: f32) {
+        self.gpu.push(dt);
+    }
+
+    // 0 until a frame is pushed
+    pub fn fps(&self) -> f32 {
+        let avg = self.avg_frame_time();
+        if avg > 0.0 {
+            1.0 / avg
+        } else {
+            0.0
+        }
+    }
+
+    pub fn avg_frame_time(&self) -> f32 {
+        self.cpu.avg().unwrap_or(0.0)
+    }
+
+    // the slowest frames show the stutters the average hides
+    pub fn p99_frame_time(&self) -> f32 {
+        self.cpu.p99().unwrap_or(0.0)
+    }
+
+    // None until a gpu time is pushed
+    pub fn avg_gpu_frame_time(&self) -> Option<f32> {
+        self.gpu.avg()
+    }
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_window() {
+        let mut stats = FrameStats::new();
+        assert_eq!((stats.fps(), stats.p99_frame_time()), (0.0, 0.0));
+        assert_eq!(stats.avg_gpu_frame_time(), None);
+
+        // older frames are dropped
+        for _ in 0..FRAME_STATS_WINDOW {
+            stats.push(1.0);
+        }
+        // just few enough slow frames to stay above the 99th percentile
+        let fast = (FRAME_STATS_WINDOW * 99).div_ceil(100);
+        let slow = FRAME_STATS_WINDOW - fast;
+        for _ in 0..fast {
+            stats.push(0.01);
+        }
+        for _ in 0..slow {
+            stats.push(0.1);
+        }
+        assert_eq!(stats.cpu.times.len(), FRAME_STATS_WINDOW);
+        let avg = (0.01 * fast as f32 + 0.1 * slow as f32) / FRAME_STATS_WINDOW as f32;
+        // up to the drift of the running sum
+        assert!((stats.avg_frame_time() / avg - 1.0).abs() < 1e-3);
+        assert!((stats.fps() * avg - 1.0).abs() < 1e-3);
+        assert_eq!(stats.p99_frame_time(), 0.01);
+        // one more and the 99th percentile is slow
+        stats.push(0.1);
+        assert_eq!(stats.p99_frame_time(), 0.1);
+
+        stats.push_gpu(0.004);
+        stats.push_gpu(0.006);
+        assert!((stats.avg_gpu_frame_time().unwrap() - 0.005).abs() < 1e-6);
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::{self, Arc};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use vulkanalia::{
     vk::{self, DeviceV1_0, Handle, HasBuilder, KhrSurfaceExtension, KhrSwapchainExtension},
     Device, Instance,
@@ -16,6 +16,9 @@ pub struct SwapchainSupport {
 }
 
 impl SwapchainSupport {
+    /// # Safety
+    ///
+    /// surface and physical_device must have been created from instance.
     pub unsafe fn get(
         instance: &Instance,
         surface: vk::SurfaceKHR,
@@ -42,16 +45,23 @@ fn get_swapchain_surface_format(formats: &[vk::SurfaceFormatKHR]) -> vk::Surface
         .unwrap_or_else(|| formats[0])
 }
 
-fn get_swapchain_present_mode(present_modes: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
-    present_modes
-        .iter()
-        .cloned()
-        .find(|m| *m == vk::PresentModeKHR::MAILBOX)
+// FIFO is always supported and is the only mode waiting for the vertical blank
+fn get_swapchain_present_mode(
+    present_modes: &[vk::PresentModeKHR],
+    vsync: bool,
+) -> vk::PresentModeKHR {
+    if vsync {
+        return vk::PresentModeKHR::FIFO;
+    }
+    // mailbox doesn't tear
+    [vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE]
+        .into_iter()
+        .find(|m| present_modes.contains(m))
         .unwrap_or(vk::PresentModeKHR::FIFO)
 }
 
 fn get_swapchain_extent(window: &Window, capabilities: vk::SurfaceCapabilitiesKHR) -> vk::Extent2D {
-    if capabilities.current_extent.width != u32::max_value() {
+    if capabilities.current_extent.width != u32::MAX {
         capabilities.current_extent
     } else {
         let size = window.inner_size();
@@ -78,16 +88,26 @@ pub struct Swapchain {
     pub swapchain: vk::SwapchainKHR,
     pub format: vk::Format,
     pub extent: vk::Extent2D,
+    pub present_mode: vk::PresentModeKHR,
+    // TRANSFER_SRC when supported, to copy the screenshots from the images
+    pub usage: vk::ImageUsageFlags,
     pub images: Vec<vk::Image>,
     pub image_views: Vec<vk::ImageView>,
 }
 
 impl Swapchain {
+    /// # Safety
+    ///
+    /// window must be the window the surface of data was created for, and the old swapchain
+    /// destroyed before.
     pub unsafe fn create(window: &Window, data: &RendererData) -> Result<Self> {
-        let support = SwapchainSupport::get(&data.instance, data.surface, data.physical_device.device)?;
+        let surface = data
+            .surface
+            .ok_or_else(|| anyhow!("No surface to create the swapchain for"))?;
+        let support = SwapchainSupport::get(&data.instance, surface, data.physical_device.device)?;
 
         let surface_format = get_swapchain_surface_format(&support.formats);
-        let present_mode = get_swapchain_present_mode(&support.present_modes);
+        let present_mode = get_swapchain_present_mode(&support.present_modes, data.vsync);
         let extent = get_swapchain_extent(window, support.capabilities);
 
         let mut image_count = support.capabilities.min_image_count + 1;
@@ -98,7 +118,9 @@ impl Swapchain {
         }
 
         let mut queue_family_indices = vec![];
-        let image_sharing_mode = if data.physical_device.graphics_queue.family != data.physical_device.present_queue.family {
+        let image_sharing_mode = if data.physical_device.graphics_queue.family
+            != data.physical_device.present_queue.family
+        {
             queue_family_indices.push(data.physical_device.graphics_queue.family);
             queue_family_indices.push(data.physical_device.present_queue.family);
             vk::SharingMode::CONCURRENT
@@ -106,14 +128,17 @@ impl Swapchain {
             vk::SharingMode::EXCLUSIVE
         };
 
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | (support.capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
         let info = vk::SwapchainCreateInfoKHR::builder()
-            .surface(data.surface)
+            .surface(surface)
             .min_image_count(image_count)
             .image_format(surface_format.format)
             .image_color_space(surface_format.color_space)
             .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(usage)
             .image_sharing_mode(image_sharing_mode)
             .queue_family_indices(&queue_family_indices)
             .pre_transform(support.capabilities.current_transform)
@@ -141,6 +166,8 @@ impl Swapchain {
             swapchain,
             format: surface_format.format,
             extent,
+            present_mode,
+            usage,
             images,
             image_views,
             device: Arc::downgrade(&data.device),
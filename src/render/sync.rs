@@ -4,12 +4,15 @@ use vulkanalia::{
     Device,
 };
 
-// #[inline]
-// pub unsafe fn create_semaphore(device: &Device) -> Result<vk::Semaphore> {
-//     let info = vk::SemaphoreCreateInfo::builder();
-//     let semaphore = device.create_semaphore(&info, None)?;
-//     Ok(semaphore)
-// }
+/// # Safety
+///
+/// The semaphore must be destroyed before the device.
+#[inline]
+pub unsafe fn create_semaphore(device: &Device) -> Result<vk::Semaphore> {
+    let info = vk::SemaphoreCreateInfo::builder();
+    let semaphore = device.create_semaphore(&info, None)?;
+    Ok(semaphore)
+}
 
 // #[inline]
 // pub unsafe fn create_fence(device: &Device, signaled: bool) -> Result<vk::Fence> {
@@ -22,6 +25,9 @@ use vulkanalia::{
 //     Ok(fence)
 // }
 
+/// # Safety
+///
+/// The semaphores must be destroyed before the device.
 #[inline]
 pub unsafe fn create_semaphores(device: &Device, count: usize) -> Result<Vec<vk::Semaphore>> {
     let info = vk::SemaphoreCreateInfo::builder();
@@ -32,6 +38,9 @@ pub unsafe fn create_semaphores(device: &Device, count: usize) -> Result<Vec<vk:
     Ok(semaphores)
 }
 
+/// # Safety
+///
+/// The fences must be destroyed before the device.
 #[inline]
 pub unsafe fn create_fences(
     device: &Device,
@@ -0,0 +1,167 @@
+use std::sync::{self, Arc};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use vulkanalia::{
+    vk::{self, DeviceV1_0, HasBuilder},
+    Device,
+};
+
+use crate::config::{ATLAS_TILES_PER_ROW, MAX_ANISOTROPY, MAX_BINDLESS_TEXTURES};
+
+use super::{
+    images::{self, Image},
+    png,
+    renderer::RendererData,
+};
+
+const ATLAS: &[u8] = include_bytes!("../../assets/textures/atlas.png");
+
+// Textures of the blocks, square tiles in rows of ATLAS_TILES_PER_ROW.
+// The tile of a block is given by the block registry.
+// With the bindless textures each tile is its own image, indexed by the tile in the shader.
+pub struct TextureAtlas {
+    device: sync::Weak<Device>,
+    // the whole atlas, or one per tile with the bindless textures
+    pub images: Vec<Image>,
+    pub sampler: vk::Sampler,
+    pub bindless: bool,
+}
+
+impl TextureAtlas {
+    /// # Safety
+    ///
+    /// The command pool of data must be created, the atlas is uploaded on the graphics queue.
+    pub unsafe fn create(data: &RendererData) -> Result<Self> {
+        let (width, height, pixels) = png::decode(ATLAS)?;
+        if width != height || width % ATLAS_TILES_PER_ROW != 0 {
+            return Err(anyhow!(
+                "Texture atlas of {width}x{height} pixels can't have {ATLAS_TILES_PER_ROW} square tiles per row"
+            ));
+        }
+        let tile = width / ATLAS_TILES_PER_ROW;
+        let tiles = ATLAS_TILES_PER_ROW * ATLAS_TILES_PER_ROW;
+        let bindless = data.physical_device.bindless_textures
+            && tiles <= max_bindless_textures(&data.physical_device.limits);
+        if cfg!(feature = "bindless-textures") && !bindless {
+            warn!("Bindless textures not supported for {tiles} tiles: using the texture atlas");
+        }
+
+        // down to one pixel per tile, the smaller levels would mix the tiles
+        let mip_levels = images::mip_levels((tile, tile));
+        let images = if bindless {
+            (0..tiles)
+                .map(|index| {
+                    Image::create_with_pixels(
+                        data,
+                        (tile, tile),
+                        mip_levels,
+                        vk::Format::R8G8B8A8_UNORM,
+                        &tile_pixels(&pixels, width, tile, index),
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![Image::create_with_pixels(
+                data,
+                (width, height),
+                mip_levels,
+                vk::Format::R8G8B8A8_UNORM,
+                &pixels,
+            )?]
+        };
+
+        // the tiles repeat over the merged faces, the atlas is clamped by the shader
+        let address_mode = if bindless {
+            vk::SamplerAddressMode::REPEAT
+        } else {
+            vk::SamplerAddressMode::CLAMP_TO_EDGE
+        };
+        // nearest when magnified to keep the pixels sharp, the anisotropic filtering
+        // keeps the faces seen at grazing angles from shimmering
+        let info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(address_mode)
+            .address_mode_v(address_mode)
+            .address_mode_w(address_mode)
+            .anisotropy_enable(true)
+            .max_anisotropy(MAX_ANISOTROPY.min(data.physical_device.limits.max_sampler_anisotropy))
+            .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+            .unnormalized_coordinates(false)
+            .compare_enable(false)
+            .compare_op(vk::CompareOp::ALWAYS)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .mip_lod_bias(0.0)
+            .min_lod(0.0)
+            .max_lod(images[0].mip_levels as f32);
+        let sampler = data.device.create_sampler(&info, None)?;
+
+        Ok(Self {
+            device: Arc::downgrade(&data.device),
+            images,
+            sampler,
+            bindless,
+        })
+    }
+}
+
+// size of the texture array of the descriptor sets, the atlas tiles must fit in it
+pub fn max_bindless_textures(limits: &vk::PhysicalDeviceLimits) -> u32 {
+    MAX_BINDLESS_TEXTURES
+        .min(limits.max_per_stage_descriptor_samplers)
+        .min(limits.max_per_stage_descriptor_sampled_images)
+        .min(limits.max_descriptor_set_samplers)
+        .min(limits.max_descriptor_set_sampled_images)
+}
+
+// RGBA pixels of the tile at index in an atlas width pixels wide
+fn tile_pixels(pixels: &[u8], width: u32, tile: u32, index: u32) -> Vec<u8> {
+    let (column, row) = (index % ATLAS_TILES_PER_ROW, index / ATLAS_TILES_PER_ROW);
+    let row_bytes = tile as usize * 4;
+    (row * tile..(row + 1) * tile)
+        .flat_map(|y| {
+            let start = (y * width + column * tile) as usize * 4;
+            &pixels[start..start + row_bytes]
+        })
+        .copied()
+        .collect()
+}
+
+impl Drop for TextureAtlas {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .upgrade()
+                .unwrap()
+                .destroy_sampler(self.sampler, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cut_from_the_atlas() {
+        // 2x2 pixels per tile, each pixel is its x and y in the atlas
+        let tile = 2;
+        let width = tile * ATLAS_TILES_PER_ROW;
+        let pixels: Vec<u8> = (0..width)
+            .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 0, 255]))
+            .collect();
+
+        let first = tile_pixels(&pixels, width, tile, 0);
+        assert_eq!(
+            first,
+            [0, 0, 0, 255, 1, 0, 0, 255, 0, 1, 0, 255, 1, 1, 0, 255]
+        );
+        // second column of the second row
+        let index = ATLAS_TILES_PER_ROW + 1;
+        let pixels = tile_pixels(&pixels, width, tile, index);
+        assert_eq!(pixels.len(), (tile * tile * 4) as usize);
+        assert_eq!(&pixels[..4], [2, 2, 0, 255]);
+        assert_eq!(&pixels[12..], [3, 3, 0, 255]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::{self, Arc, Mutex};
+
+use anyhow::Result;
+use log::warn;
+use vulkanalia::{
+    vk::{self, DeviceV1_0, HasBuilder},
+    Device,
+};
+
+use super::renderer::RendererData;
+
+// Measures how long the gpu takes to execute the render pass of each swapchain image
+// with a timestamp before and after it.
+pub struct GpuTimer {
+    device: sync::Weak<Device>,
+    // two queries per swapchain image
+    pool: vk::QueryPool,
+    // the images whose queries were written, the others have no result to read
+    written: Mutex<Vec<bool>>,
+    // the bits of the timestamps the graphics queue sets
+    mask: u64,
+    // nanoseconds per timestamp tick
+    period: f32,
+}
+
+impl GpuTimer {
+    /// None if the graphics queue doesn't support timestamps
+    ///
+    /// # Safety
+    ///
+    /// The swapchain of data must be created, there are two queries per image.
+    pub unsafe fn create(data: &RendererData) -> Result<Option<Self>> {
+        let bits = data.physical_device.timestamp_valid_bits;
+        if bits == 0 {
+            warn!("Timestamps not supported by the graphics queue: no gpu frame time");
+            return Ok(None);
+        }
+        let images = data.swapchain.as_ref().unwrap().images.len();
+        let info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(2 * images as u32);
+        let pool = data.device.create_query_pool(&info, None)?;
+
+        Ok(Some(Self {
+            device: Arc::downgrade(&data.device),
+            pool,
+            written: Mutex::new(vec![false; images]),
+            mask: u64::MAX >> (64 - bits.min(64)),
+            period: data.physical_device.limits.timestamp_period,
+        }))
+    }
+
+    /// outside of the render pass, before it begins
+    ///
+    /// # Safety
+    ///
+    /// command_buffer must be recording outside of a render pass, and the queries of image_index
+    /// not be in use by a frame in flight.
+    pub unsafe fn record_start(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+    ) {
+        let first = 2 * image_index as u32;
+        device.cmd_reset_query_pool(command_buffer, self.pool, first, 2);
+        device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            self.pool,
+            first,
+        );
+    }
+
+    /// outside of the render pass, after it ends
+    ///
+    /// # Safety
+    ///
+    /// command_buffer must be recording outside of a render pass, after record_start for the same
+    /// image.
+    pub unsafe fn record_end(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+    ) {
+        device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            self.pool,
+            2 * image_index as u32 + 1,
+        );
+        self.written.lock().unwrap()[image_index] = true;
+    }
+
+    /// Seconds the last submission of the image's commands took, None if they have never
+    /// been recorded. The commands must have finished executing (the image fence waited)
+    /// so this doesn't stall.
+    ///
+    /// # Safety
+    ///
+    /// The commands of image_index must have finished executing.
+    pub unsafe fn read(&self, device: &Device, image_index: usize) -> Result<Option<f32>> {
+        if !self.written.lock().unwrap()[image_index] {
+            return Ok(None);
+        }
+        let mut timestamps = [0u64; 2];
+        device.get_query_pool_results(
+            self.pool,
+            2 * image_index as u32,
+            2,
+            std::slice::from_raw_parts_mut(timestamps.as_mut_ptr().cast(), 16),
+            8,
+            vk::QueryResultFlags::_64 | vk::QueryResultFlags::WAIT,
+        )?;
+        let ticks = (timestamps[1].wrapping_sub(timestamps[0])) & self.mask;
+        Ok(Some(ticks as f32 * self.period / 1e9))
+    }
+}
+
+impl Drop for GpuTimer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .upgrade()
+                .unwrap()
+                .destroy_query_pool(self.pool, None);
+        }
+    }
+}
@@ -1,37 +1,107 @@
 use std::{
     marker::PhantomData,
     mem::size_of,
-    sync::{self, Arc, Mutex},
+    sync::{
+        self,
+        atomic::{self, AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
 };
 
-use super::{buffer::Buffer, memory::AllocUsage, renderer::RendererData};
+use super::{
+    buffer::Buffer, memory::AllocUsage, renderer::RendererData, texture::max_bindless_textures,
+};
+use crate::config::CHUNK_UNIFORM_SLOTS;
 use anyhow::Result;
+use log::warn;
 use vulkanalia::{
     vk::{self, DeviceV1_0, HasBuilder},
     Device,
 };
 
-pub struct Uniforms<T> {
+// the uniforms are recreated with the swapchain, the slots of the old ones are ignored
+static NEXT_UNIFORMS_ID: AtomicU64 = AtomicU64::new(0);
+
+// a C in the dynamic uniform buffer of the Uniforms with the id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UniformSlot {
+    index: u32,
+    uniforms: u64,
+}
+
+// T is shared by all the draws of a frame, each swapchain image has its own.
+// C lives in a dynamic uniform buffer shared by the images, a slot is written once when
+// it is allocated and selected by the dynamic offset of a draw. the chunks don't allocate
+// slots, their origin is pushed with the draw, so they bind the zeroed slot 0.
+pub struct Uniforms<T, C> {
     device: sync::Weak<Device>,
+    id: u64,
 
     pub descriptor_set_layout: vk::DescriptorSetLayout,
     pub buffers: Vec<Mutex<Buffer>>,
     pub descriptor_pool: vk::DescriptorPool,
     pub descriptor_sets: Vec<vk::DescriptorSet>,
-    _marker: PhantomData<T>,
+    // CHUNK_UNIFORM_SLOTS C, each aligned on min_uniform_buffer_offset_alignment
+    chunk_buffer: Buffer,
+    chunk_stride: u64,
+    // the slot 0 is never allocated, it is bound for the draws without a slot
+    free_slots: Mutex<Vec<u32>>,
+    // warned once when all the slots are used
+    slots_exhausted: AtomicBool,
+    _marker: PhantomData<(T, C)>,
 }
 
-impl<T> Uniforms<T> {
+// Binding of the texture array with the bindless textures, instead of the atlas at 1.
+// Its size is only known when the sets are allocated, so it must be the last binding.
+const TEXTURES_BINDING: u32 = 3;
+
+impl<T, C> Uniforms<T, C> {
+    /// # Safety
+    ///
+    /// The swapchain and texture atlas of data must be created, the uniforms must be dropped before
+    /// them.
     pub unsafe fn create(data: &RendererData) -> Result<Self> {
+        let atlas = data.atlas.as_ref().unwrap();
+        let textures_count = atlas.images.len() as u32;
         let descriptor_set_layout = {
             let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
                 .binding(0)
                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
                 .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
+
+            let atlas_binding = if atlas.bindless {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(TEXTURES_BINDING)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .descriptor_count(max_bindless_textures(&data.physical_device.limits))
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            } else {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(1)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            };
+
+            let chunk_binding = vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                .descriptor_count(1)
                 .stage_flags(vk::ShaderStageFlags::VERTEX);
 
-            let bindings = &[ubo_binding];
-            let info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(bindings);
+            let bindings = &[ubo_binding, atlas_binding, chunk_binding];
+            let binding_flags = &[
+                vk::DescriptorBindingFlags::empty(),
+                vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT,
+                vk::DescriptorBindingFlags::empty(),
+            ];
+            let mut flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+                .binding_flags(binding_flags);
+            let mut info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(bindings);
+            if atlas.bindless {
+                info = info.push_next(&mut flags_info);
+            }
 
             data.device.create_descriptor_set_layout(&info, None)?
         };
@@ -46,12 +116,36 @@ impl<T> Uniforms<T> {
             )?));
         }
 
+        let alignment = data
+            .physical_device
+            .limits
+            .min_uniform_buffer_offset_alignment
+            .max(1);
+        let chunk_stride = (size_of::<C>() as u64).div_ceil(alignment) * alignment;
+        let chunk_buffer = Buffer::create(
+            data,
+            chunk_stride as usize * CHUNK_UNIFORM_SLOTS,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            AllocUsage::Staging,
+        )?;
+        std::ptr::write_bytes(chunk_buffer.ptr, 0, chunk_stride as usize);
+
         let descriptor_pool = {
             let ubo_size = vk::DescriptorPoolSize::builder()
                 .type_(vk::DescriptorType::UNIFORM_BUFFER)
                 .descriptor_count(data.swapchain.as_ref().unwrap().images.len() as u32);
 
-            let pool_sizes = &[ubo_size];
+            let atlas_size = vk::DescriptorPoolSize::builder()
+                .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(
+                    data.swapchain.as_ref().unwrap().images.len() as u32 * textures_count,
+                );
+
+            let chunk_size = vk::DescriptorPoolSize::builder()
+                .type_(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                .descriptor_count(data.swapchain.as_ref().unwrap().images.len() as u32);
+
+            let pool_sizes = &[ubo_size, atlas_size, chunk_size];
             let info = vk::DescriptorPoolCreateInfo::builder()
                 .pool_sizes(pool_sizes)
                 .max_sets(data.swapchain.as_ref().unwrap().images.len() as u32);
@@ -62,11 +156,27 @@ impl<T> Uniforms<T> {
         let descriptor_sets = {
             let layouts =
                 vec![descriptor_set_layout; data.swapchain.as_ref().unwrap().images.len()];
-            let info = vk::DescriptorSetAllocateInfo::builder()
+            let counts = vec![textures_count; layouts.len()];
+            let mut count_info = vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+                .descriptor_counts(&counts);
+            let mut info = vk::DescriptorSetAllocateInfo::builder()
                 .descriptor_pool(descriptor_pool)
                 .set_layouts(&layouts);
+            if atlas.bindless {
+                info = info.push_next(&mut count_info);
+            }
 
             let sets = data.device.allocate_descriptor_sets(&info)?;
+            let image_info: Vec<_> = atlas
+                .images
+                .iter()
+                .map(|image| {
+                    vk::DescriptorImageInfo::builder()
+                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                        .image_view(image.view)
+                        .sampler(atlas.sampler)
+                })
+                .collect();
 
             for i in 0..data.swapchain.as_ref().unwrap().images.len() {
                 let info = vk::DescriptorBufferInfo::builder()
@@ -82,8 +192,30 @@ impl<T> Uniforms<T> {
                     .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
                     .buffer_info(buffer_info);
 
-                data.device
-                    .update_descriptor_sets(&[ubo_write], &[] as &[vk::CopyDescriptorSet]);
+                let atlas_write = vk::WriteDescriptorSet::builder()
+                    .dst_set(sets[i])
+                    .dst_binding(if atlas.bindless { TEXTURES_BINDING } else { 1 })
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&image_info);
+
+                let info = vk::DescriptorBufferInfo::builder()
+                    .buffer(chunk_buffer.buffer)
+                    .offset(0)
+                    .range(size_of::<C>() as u64);
+
+                let chunk_info = &[info];
+                let chunk_write = vk::WriteDescriptorSet::builder()
+                    .dst_set(sets[i])
+                    .dst_binding(2)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                    .buffer_info(chunk_info);
+
+                data.device.update_descriptor_sets(
+                    &[ubo_write, atlas_write, chunk_write],
+                    &[] as &[vk::CopyDescriptorSet],
+                );
             }
 
             sets
@@ -91,16 +223,59 @@ impl<T> Uniforms<T> {
 
         Ok(Self {
             device: Arc::downgrade(&data.device),
+            id: NEXT_UNIFORMS_ID.fetch_add(1, atomic::Ordering::Relaxed),
             descriptor_set_layout,
             buffers,
             descriptor_pool,
             descriptor_sets,
+            chunk_buffer,
+            chunk_stride,
+            // the lowest slots are allocated first
+            free_slots: Mutex::new((1..CHUNK_UNIFORM_SLOTS as u32).rev().collect()),
+            slots_exhausted: AtomicBool::new(false),
             _marker: PhantomData,
         })
     }
+
+    /// Store value in a free slot of the dynamic buffer, None if they are all used.
+    /// The slot must be freed once no frame in flight can read it anymore.
+    ///
+    /// # Safety
+    ///
+    /// The slot must not be freed while a frame in flight can read it.
+    pub unsafe fn allocate_slot(&self, value: C) -> Option<UniformSlot> {
+        let Some(index) = self.free_slots.lock().unwrap().pop() else {
+            if !self.slots_exhausted.swap(true, atomic::Ordering::Relaxed) {
+                warn!("All the {CHUNK_UNIFORM_SLOTS} chunk uniform slots are used");
+            }
+            return None;
+        };
+        let ptr = self
+            .chunk_buffer
+            .ptr
+            .add((index as u64 * self.chunk_stride) as usize);
+        ptr.cast::<C>().write(value);
+        Some(UniformSlot {
+            index,
+            uniforms: self.id,
+        })
+    }
+
+    // the slots of the uniforms recreated since are ignored
+    pub fn free_slot(&self, slot: UniformSlot) {
+        if slot.uniforms == self.id {
+            self.free_slots.lock().unwrap().push(slot.index);
+        }
+    }
+
+    // offset to bind the descriptor sets with, the zeroed slot 0 without a slot
+    pub fn dynamic_offset(&self, slot: Option<UniformSlot>) -> u32 {
+        slot.filter(|slot| slot.uniforms == self.id)
+            .map_or(0, |slot| (slot.index as u64 * self.chunk_stride) as u32)
+    }
 }
 
-impl<T> Drop for Uniforms<T> {
+impl<T, C> Drop for Uniforms<T, C> {
     fn drop(&mut self) {
         unsafe {
             let device = self.device.upgrade().unwrap();
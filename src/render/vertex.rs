@@ -1,17 +1,71 @@
 use std::{hash::Hash, mem::size_of};
 
 use nalgebra_glm as glm;
-use vulkanalia::vk::{self, HasBuilder};
+use vulkanalia::{
+    vk::{self, DeviceV1_0, HasBuilder},
+    Device,
+};
 
+use crate::config::{CHUNK_GRID_RADIUS, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+// bits of each coordinate in the packed position
+const POS_BITS: u32 = 10;
+const POS_MASK: u32 = (1 << POS_BITS) - 1;
+
+// the chunk corners and the grid around the camera must fit in the packed coordinates
+const _: () = assert!(
+    (2 * CHUNK_GRID_RADIUS + 1) * CHUNK_SIZE_X <= POS_MASK as usize
+        && (2 * CHUNK_GRID_RADIUS + 1) * CHUNK_SIZE_Y <= POS_MASK as usize
+        && (2 * CHUNK_GRID_RADIUS + 1) * CHUNK_SIZE_Z <= POS_MASK as usize
+);
+
+// 12 bytes per vertex, the position is relative to the origin pushed with each draw
+// (see `DrawConstants`) so it fits in a few bits per axis
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Vertex {
-    pub pos: glm::TVec3<i32>,
+    // x | y << 10 | z << 20 | ao << 30
+    pub pos_ao: u32,
     pub color: glm::TVec3<u8>,
-    pub light_modifier: u8,
+    // normal of the face: +x, +y, +z, -x, -y, -z or UNLIT
+    pub face: u8,
+    // u | v << 10 | tile << 20, the uv repeat the tile once per block
+    pub uv_tile: u32,
 }
 
 impl Vertex {
+    // drawn with its color as is, without the sun light nor texture
+    pub const UNLIT: u8 = 6;
+
+    // pos relative to the draw origin, ao from 0 (occluded) to 3, uv in blocks
+    #[inline]
+    pub fn new(
+        pos: glm::TVec3<i32>,
+        color: glm::TVec3<u8>,
+        face: u8,
+        ao: u8,
+        uv: glm::TVec2<i32>,
+        tile: u16,
+    ) -> Self {
+        debug_assert!(
+            pos.iter()
+                .chain(uv.iter())
+                .all(|c| (0..=POS_MASK as i32).contains(c)),
+            "Vertex position {pos:?} or uv {uv:?} out of the packed range"
+        );
+        debug_assert!(tile < 1 << (32 - 2 * POS_BITS));
+        debug_assert!(ao <= 3 && face <= Self::UNLIT);
+        Self {
+            pos_ao: pos.x as u32
+                | (pos.y as u32) << POS_BITS
+                | (pos.z as u32) << (2 * POS_BITS)
+                | (ao as u32) << (3 * POS_BITS),
+            color,
+            face,
+            uv_tile: uv.x as u32 | (uv.y as u32) << POS_BITS | (tile as u32) << (2 * POS_BITS),
+        }
+    }
+
     pub fn binding_description() -> vk::VertexInputBindingDescription {
         vk::VertexInputBindingDescription::builder()
             .binding(0)
@@ -20,26 +74,75 @@ impl Vertex {
             .build()
     }
 
-    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 4] {
         [
             vk::VertexInputAttributeDescription::builder()
                 .binding(0)
                 .location(0)
-                .format(vk::Format::R32G32B32_SINT)
+                .format(vk::Format::R32_UINT)
                 .offset(0)
                 .build(),
             vk::VertexInputAttributeDescription::builder()
                 .binding(0)
                 .location(1)
                 .format(vk::Format::R8G8B8_UINT)
-                .offset(size_of::<glm::TVec3<i32>>() as u32)
+                .offset(size_of::<u32>() as u32)
                 .build(),
             vk::VertexInputAttributeDescription::builder()
                 .binding(0)
                 .location(2)
                 .format(vk::Format::R8_UINT)
-                .offset(size_of::<glm::TVec3<i32>>() as u32 + size_of::<glm::TVec3<u8>>() as u32)
+                .offset(size_of::<u32>() as u32 + size_of::<glm::TVec3<u8>>() as u32)
+                .build(),
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(3)
+                .format(vk::Format::R32_UINT)
+                .offset(2 * size_of::<u32>() as u32)
                 .build(),
         ]
     }
 }
+
+// pushed before each draw, the vertices positions are relative to the origin
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DrawConstants {
+    pub origin: glm::IVec4, // w unused
+}
+
+impl DrawConstants {
+    pub fn new(origin: glm::TVec3<i32>) -> Self {
+        Self {
+            origin: glm::vec4(origin.x, origin.y, origin.z, 0),
+        }
+    }
+
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .offset(0)
+            .size(size_of::<Self>() as u32)
+            .build()
+    }
+
+    /// # Safety
+    ///
+    /// command_buffer must be recording with a pipeline of layout bound.
+    pub unsafe fn push(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+    ) {
+        let bytes =
+            std::slice::from_raw_parts((self as *const Self).cast::<u8>(), size_of::<Self>());
+        device.cmd_push_constants(
+            command_buffer,
+            layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            bytes,
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::{num::NonZeroUsize, sync::Arc, thread};
+
+use crossbeam_channel::{Receiver, Sender, TryIter};
+use log::{info, trace};
+
+use crate::world::{generation::TerrainGenerator, Chunk, ChunkPos, ChunkState};
+
+use super::queue::ChunkQueue;
+
+// positions to generate with the generator to use
+type GenerationQueue = ChunkQueue<(ChunkPos, Arc<dyn TerrainGenerator>)>;
+
+// half of the cores, the others mesh and render
+#[inline]
+fn get_threads_count() -> usize {
+    let parallelism: usize = thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(4);
+    (parallelism / 2).max(1)
+}
+
+// Fills new chunks off the main thread. The generated chunks are returned in
+// the Generated state, the main thread places their features and queues them
+// for meshing.
+pub struct GenerationThreadPool {
+    threads: Vec<thread::JoinHandle<()>>,
+
+    queue: Arc<GenerationQueue>,
+
+    out_sender: Sender<Chunk>,
+    out_receiver: Receiver<Chunk>,
+}
+
+impl GenerationThreadPool {
+    pub fn new() -> Self {
+        let (out_sender, out_receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            threads: Vec::new(),
+            queue: Arc::new(GenerationQueue::new()),
+            out_sender,
+            out_receiver,
+        }
+    }
+
+    pub fn start_threads(&mut self) {
+        let threads_count = get_threads_count();
+        info!("Starting {} generation threads", threads_count);
+
+        for i in 0..threads_count {
+            let sender = self.out_sender.clone();
+            let queue = self.queue.clone();
+            let thread = thread::Builder::new()
+                .name(format!("Generation Thread {i}"))
+                .spawn(move || Self::thread_main(sender, queue));
+            self.threads.push(thread.unwrap());
+        }
+    }
+
+    pub fn exit_all(&mut self) {
+        // the threads waiting for a chunk are woken up
+        self.queue.close();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+
+    pub fn generate(&self, pos: ChunkPos, generator: Arc<dyn TerrainGenerator>) {
+        // bounded by the render distance, the world doesn't queue a position twice
+        self.queue.push((pos, generator), pos);
+    }
+
+    // the chunks nearest to the player chunk are generated first
+    pub fn set_center(&self, player_chunk: ChunkPos) {
+        self.queue.set_center(player_chunk);
+    }
+
+    // Drop the queued chunks that aren't needed anymore. The chunks already
+    // being generated are still returned.
+    pub fn cancel(&self, mut cancelled: impl FnMut(ChunkPos) -> bool) {
+        self.queue.retain(|pos| !cancelled(pos));
+    }
+
+    pub fn try_iter(&self) -> TryIter<'_, Chunk> {
+        self.out_receiver.try_iter()
+    }
+
+    fn thread_main(sender: Sender<Chunk>, queue: Arc<GenerationQueue>) {
+        profiling::register_thread!();
+        trace!("{} started", thread::current().name().unwrap());
+
+        while let Some((pos, generator)) = queue.pop() {
+            let mut chunk = Chunk::new(pos);
+            generator.generate(pos, &mut chunk);
+            chunk.state = ChunkState::Generated;
+            // the receiver is only dropped with the pool, after the threads exited
+            sender.send(chunk).unwrap();
+        }
+
+        trace!("{} exited", thread::current().name().unwrap());
+    }
+}
+
+impl Default for GenerationThreadPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::generation::NoiseGenerator;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn generate_queued_chunks() {
+        let mut pool = GenerationThreadPool::new();
+        let generator: Arc<dyn TerrainGenerator> = Arc::new(NoiseGenerator::new(1));
+        for x in 0..10 {
+            pool.generate(ChunkPos { x, y: 3, z: 0 }, generator.clone());
+        }
+        // cancelled before a thread could take them
+        pool.cancel(|pos| pos.x % 2 == 1);
+        pool.start_threads();
+
+        let mut generated = Vec::new();
+        let start = Instant::now();
+        while generated.len() < 5 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            generated.extend(pool.try_iter().map(|chunk| (chunk.pos.x, chunk.state)));
+            thread::sleep(Duration::from_millis(1));
+        }
+        pool.exit_all();
+        generated.extend(pool.try_iter().map(|chunk| (chunk.pos.x, chunk.state)));
+        generated.sort_by_key(|(x, _)| *x);
+        assert_eq!(
+            generated,
+            [0, 2, 4, 6, 8].map(|x| (x, ChunkState::Generated))
+        );
+    }
+}
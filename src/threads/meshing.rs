@@ -1,30 +1,176 @@
 use std::{
+    collections::{HashMap, VecDeque},
     mem::size_of,
     num::NonZeroUsize,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex, RwLock, Weak,
+        atomic::{self, AtomicU64},
+        Arc, Mutex, MutexGuard, RwLock, Weak,
     },
     thread,
 };
 
+use anyhow::Result;
 use crossbeam_channel::{Receiver, Sender, TryIter};
-use log::{info, trace, warn};
+use log::{debug, info, trace, warn};
 use vulkanalia::vk::{self, DeviceV1_0, Handle, HasBuilder};
 
 use crate::{
-    config::CHUNK_SIZE,
+    config::{
+        CHUNK_BUFFER_POOL_SIZE, CHUNK_VOLUME, MAX_FRAMES_IN_FLIGHT, MESH_QUEUE_CHUNKS_PER_THREAD,
+    },
     render::{
-        buffer::Buffer, commands::CommandPool, memory::AllocUsage, physical_device::PhysicalDevice,
-        renderer::RendererData, vertex::Vertex,
+        buffer::{ownership_barrier, Buffer, BufferHandoff, BufferPool},
+        commands::{CommandBuffer, CommandPool},
+        memory::AllocUsage,
+        physical_device::PhysicalDevice,
+        renderer::RendererData,
+        sync,
+        vertex::Vertex,
     },
-    world::Chunk,
+    world::{Chunk, ChunkPos, ChunkState, MeshOverflow},
 };
 
+use super::queue::ChunkQueue;
+
+// the most faces a mesh can have, every block showing its 6 faces like in a checkerboard of
+// two different transparent blocks
+const MAX_MESH_FACES: usize = CHUNK_VOLUME * 6;
+// the staging buffer doubles when a mesh doesn't fit, up to the worst case at this scale
+const MAX_STAGING_SCALE: usize = 4;
+// in bytes, a quarter of the worst case is enough for most chunks
 pub const STAGING_BUFFER_SIZE_VERTICES: usize =
-    ((CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize * size_of::<Vertex>() * 36) / 5;
+    MAX_MESH_FACES * 4 / MAX_STAGING_SCALE * size_of::<Vertex>();
 pub const STAGING_BUFFER_SIZE_INDICES: usize =
-    ((CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize * 36) * 2;
+    MAX_MESH_FACES * 6 / MAX_STAGING_SCALE * size_of::<u32>();
+// uploads in flight per thread
+const UPLOAD_SLOTS: usize = 2;
+
+// staging buffer with the vertices followed by the indices
+struct MeshStaging {
+    buffer: Buffer,
+    scale: usize,
+}
+
+impl MeshStaging {
+    unsafe fn create(data: &RendererData, scale: usize) -> Result<Self> {
+        Ok(Self {
+            buffer: Buffer::create(
+                data,
+                (STAGING_BUFFER_SIZE_VERTICES + STAGING_BUFFER_SIZE_INDICES) * scale,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                AllocUsage::Staging,
+            )?,
+            scale,
+        })
+    }
+
+    #[inline]
+    fn indices_offset(&self) -> usize {
+        STAGING_BUFFER_SIZE_VERTICES * self.scale
+    }
+
+    unsafe fn slices(&mut self) -> (&mut [Vertex], &mut [u32]) {
+        let ptr = self.buffer.ptr;
+        (
+            std::slice::from_raw_parts_mut(ptr.cast(), self.indices_offset() / size_of::<Vertex>()),
+            std::slice::from_raw_parts_mut(
+                ptr.add(self.indices_offset()).cast(),
+                STAGING_BUFFER_SIZE_INDICES * self.scale / size_of::<u32>(),
+            ),
+        )
+    }
+}
+
+// a mesh being copied to the chunk buffer, the chunk gets it once the copy is done
+struct PendingUpload {
+    chunk: Weak<Mutex<Chunk>>,
+    pos: ChunkPos,
+    mesh_id: u64,
+    buffer: Option<Buffer>,
+    vertices_count: usize,
+    indices_count: usize,
+    transparent_indices_count: usize,
+    state: ChunkState,
+    first_mesh: bool,
+    // given to the renderer with the buffer
+    handoff: Option<BufferHandoff>,
+}
+
+impl PendingUpload {
+    // drawn as empty and remeshed on the next change
+    fn clear(&mut self) {
+        self.vertices_count = 0;
+        self.indices_count = 0;
+        self.transparent_indices_count = 0;
+        self.state = ChunkState::Dirty;
+    }
+
+    // the copy to the buffer must be done
+    unsafe fn apply(self, data: &RendererData, shared: &ThreadShared) {
+        let ThreadShared {
+            sender,
+            mesh_queue,
+            mesh_version,
+            old_buffers,
+            buffer_pool,
+            ..
+        } = shared;
+        // the buffer isn't drawn, nothing waits for the semaphore
+        let discard = |buffer: Option<Buffer>, handoff: Option<BufferHandoff>| {
+            if let Some(buffer) = buffer {
+                buffer_pool.lock().unwrap().give_back(buffer);
+            }
+            if let Some(handoff) = handoff {
+                data.device.destroy_semaphore(handoff.semaphore, None);
+            }
+        };
+        let Some(chunk) = self.chunk.upgrade() else {
+            // unloaded during the copy
+            discard(self.buffer, self.handoff);
+            return;
+        };
+        let mut chunk = chunk.lock().unwrap();
+        // a newer mesh was made by another thread, it replaces this one
+        if chunk.mesh_id() != self.mesh_id {
+            discard(self.buffer, self.handoff);
+            return;
+        }
+        // handed off before the buffer can be drawn
+        if let Some(handoff) = self.handoff {
+            data.buffer_handoffs.lock().unwrap().push(handoff);
+        }
+        if let Some(buffer) = std::mem::replace(&mut chunk.buffer, self.buffer) {
+            old_buffers.lock().unwrap().push(buffer);
+        }
+        chunk.vertices_count = self.vertices_count;
+        chunk.indices_count = self.indices_count;
+        chunk.transparent_indices_count = self.transparent_indices_count;
+        mesh_version.fetch_add(1, atomic::Ordering::Release);
+        chunk.state = if chunk.is_dirty() {
+            // changed during the copy, the chunks with a mesh were queued again by the change
+            if self.first_mesh {
+                mesh_queue.push(self.chunk.clone(), self.pos);
+            }
+            ChunkState::Dirty
+        } else {
+            self.state
+        };
+        drop(chunk);
+        // remeshed chunks are already in the render list
+        if self.first_mesh {
+            sender.send(self.chunk).unwrap();
+        }
+    }
+}
+
+// what a thread needs to upload a mesh, each thread has UPLOAD_SLOTS of them so
+// it can mesh the next chunk while the previous ones are copied
+struct UploadSlot {
+    staging: MeshStaging,
+    command_buffer: CommandBuffer,
+    fence: vk::Fence,
+    pending: Option<PendingUpload>,
+}
 
 #[inline]
 fn get_threads_count(physical_device: &PhysicalDevice) -> usize {
@@ -39,196 +185,644 @@ fn get_threads_count(physical_device: &PhysicalDevice) -> usize {
         1..=4 => parallelism,
         _ => parallelism - 1,
     };
-    max_meshing_threads.min(physical_device.transfer_queues.len())
+    // one thread per transfer queue, a single one when falling back to the graphics family
+    max_meshing_threads.clamp(1, physical_device.transfer_queues.len())
+}
+
+// the device can't be used anymore, the app has to stop
+pub fn is_device_lost(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<vk::ErrorCode>() == Some(&vk::ErrorCode::DEVICE_LOST)
+}
+
+// chunks waiting to be meshed
+type MeshQueue = ChunkQueue<Weak<Mutex<Chunk>>>;
+
+// what the threads share with the pool, see MeshingThreadPool
+#[derive(Clone)]
+struct ThreadShared {
+    sender: Sender<Weak<Mutex<Chunk>>>,
+    errors: Sender<anyhow::Error>,
+    mesh_queue: Arc<MeshQueue>,
+    old_buffers: Arc<Mutex<Vec<Buffer>>>,
+    buffer_pool: Arc<Mutex<BufferPool>>,
+    mesh_version: Arc<AtomicU64>,
 }
 
 pub struct MeshingThreadPool {
     threads: Vec<thread::JoinHandle<()>>,
 
-    // sender to send chunks to be meshed to the threads
-    in_sender: Sender<Weak<Mutex<Chunk>>>,
-    in_receiver: Receiver<Weak<Mutex<Chunk>>>,
+    // chunks to be meshed by the threads
+    queue: Arc<MeshQueue>,
 
-    // sender to return meshed chunks
+    // sender to return meshed chunks, unbounded but it only gets the chunks of the
+    // bounded queue and is drained each tick
     out_sender: Sender<Weak<Mutex<Chunk>>>,
     out_receiver: Receiver<Weak<Mutex<Chunk>>>,
+    // errors of the threads, they are logged by the main thread
+    errors_sender: Sender<anyhow::Error>,
+    errors_receiver: Receiver<anyhow::Error>,
 
-    exit: Arc<AtomicBool>,
+    // buffers of remeshed or unloaded chunks, they may still be used by a frame in flight
+    old_buffers: Arc<Mutex<Vec<Buffer>>>,
+    // old buffers with the frame count when they were retired, oldest first
+    retired_buffers: Mutex<VecDeque<(u64, Buffer)>>,
+    // freed chunk buffers, reused for the next meshes
+    buffer_pool: Arc<Mutex<BufferPool>>,
+    // incremented when the buffer of a chunk changes
+    mesh_version: Arc<AtomicU64>,
 }
 
 impl MeshingThreadPool {
     pub fn new() -> Self {
-        let (in_sender, in_receiver) = crossbeam_channel::unbounded();
         let (out_sender, out_receiver) = crossbeam_channel::unbounded();
+        let (errors_sender, errors_receiver) = crossbeam_channel::unbounded();
 
         Self {
             threads: Vec::new(),
-            in_sender,
-            in_receiver,
+            queue: Arc::new(MeshQueue::new()),
             out_sender,
             out_receiver,
-            exit: Arc::new(AtomicBool::new(false)),
+            errors_sender,
+            errors_receiver,
+            old_buffers: Arc::new(Mutex::new(Vec::new())),
+            retired_buffers: Mutex::new(VecDeque::new()),
+            mesh_version: Arc::new(AtomicU64::new(0)),
+            buffer_pool: Arc::new(Mutex::new(BufferPool::new(CHUNK_BUFFER_POOL_SIZE))),
         }
     }
 
+    /// # Safety
+    ///
+    /// The threads use the device of data until they are stopped, it must outlive them.
     pub unsafe fn start_threads(&mut self, data: Arc<RwLock<RendererData>>) {
         let threads_count = get_threads_count(&data.read().unwrap().physical_device);
         info!("Starting {} meshing threads", threads_count);
+        self.queue
+            .set_capacity(threads_count * MESH_QUEUE_CHUNKS_PER_THREAD);
 
         for i in 0..threads_count {
             let mut name = "Meshing Thread ".to_string();
             name.push_str(i.to_string().as_str());
 
-            let sender = self.out_sender.clone();
-            let receiver = self.in_receiver.clone();
-
-            let exit = self.exit.clone();
+            let shared = ThreadShared {
+                sender: self.out_sender.clone(),
+                errors: self.errors_sender.clone(),
+                mesh_queue: self.queue.clone(),
+                old_buffers: self.old_buffers.clone(),
+                buffer_pool: self.buffer_pool.clone(),
+                mesh_version: self.mesh_version.clone(),
+            };
 
             let data = data.clone();
 
             let thread = thread::Builder::new().name(name).spawn(move || {
-                MeshingThreadPool::thread_main(i as u32, sender, receiver, exit, data);
+                MeshingThreadPool::thread_main(i as u32, shared, data);
             });
             self.threads.push(thread.unwrap());
         }
     }
 
     pub fn exit_all(&mut self) {
-        self.exit.store(true, Ordering::Relaxed);
-
-        // send a empty weak to all threads to prevent them from blocking on the in_receiver
-        for _ in 0..self.threads.len() {
-            self.in_sender.send(Weak::new()).unwrap();
-        }
+        // the threads waiting for a chunk are woken up
+        self.queue.close();
         for _ in 0..self.threads.len() {
             self.threads.pop().unwrap().join().unwrap();
         }
     }
 
-    pub fn mesh_thread(&self, chunk: Weak<Mutex<Chunk>>) {
-        self.in_sender.send(chunk).unwrap();
+    // Queue a chunk, pos is its position. Returns false when the queue holds more than
+    // MESH_QUEUE_CHUNKS_PER_THREAD chunks per thread, the chunk is queued anyway so a
+    // remesh is never lost but no new chunk should be added until it drains.
+    pub fn mesh_thread(&self, chunk: Weak<Mutex<Chunk>>, pos: ChunkPos) -> bool {
+        self.queue.push(chunk, pos)
     }
 
-    unsafe fn thread_main(
-        i: u32,
-        sender: Sender<Weak<Mutex<Chunk>>>,
-        receiver: Receiver<Weak<Mutex<Chunk>>>,
-        exit: Arc<AtomicBool>,
-        renderer_data: Arc<RwLock<RendererData>>,
-    ) {
-        profiling::register_thread!();
-        trace!("{} started", thread::current().name().unwrap());
-        let (staging_buffer, queue_family, queue) = {
-            let data = renderer_data.read().unwrap();
-            let staging_buffer = Buffer::create(
-                &data,
-                STAGING_BUFFER_SIZE_VERTICES + STAGING_BUFFER_SIZE_INDICES,
-                vk::BufferUsageFlags::TRANSFER_SRC,
-                AllocUsage::Staging,
-            )
-            .unwrap();
+    // the chunks nearest to the player chunk are meshed first
+    pub fn set_center(&self, player_chunk: ChunkPos) {
+        self.queue.set_center(player_chunk);
+    }
 
-            let queue_def = renderer_data
-                .read()
+    // Reuse the old buffers that no frame in flight can use anymore, frame_count is the
+    // number of frames rendered so far. The old buffers may be used by all the frames
+    // before it, so they are kept until MAX_FRAMES_IN_FLIGHT more frames are rendered.
+    pub fn recycle_old_buffers(&self, frame_count: u64) {
+        let mut retired = self.retired_buffers.lock().unwrap();
+        retired.extend(
+            self.old_buffers
+                .lock()
                 .unwrap()
-                .physical_device
-                .transfer_queues[i as usize];
-            let queue = data
-                .device
-                .as_ref()
-                .get_device_queue(queue_def.family, queue_def.index);
-
-            (staging_buffer, queue_def.family, queue)
+                .drain(..)
+                .map(|buffer| (frame_count, buffer)),
+        );
+        let mut pool = self.buffer_pool.lock().unwrap();
+        while retired
+            .front()
+            .is_some_and(|(frame, _)| frame + MAX_FRAMES_IN_FLIGHT as u64 <= frame_count)
+        {
+            pool.give_back(retired.pop_front().unwrap().1);
+        }
+    }
+
+    // the device must be idle
+    pub fn recycle_all_old_buffers(&self) {
+        let mut pool = self.buffer_pool.lock().unwrap();
+        for (_, buffer) in self.retired_buffers.lock().unwrap().drain(..) {
+            pool.give_back(buffer);
+        }
+        for buffer in self.old_buffers.lock().unwrap().drain(..) {
+            pool.give_back(buffer);
+        }
+    }
+
+    // buffer of an unloaded chunk, reused once the frames in flight are done with it
+    pub fn recycle_buffer(&self, buffer: Buffer) {
+        self.old_buffers.lock().unwrap().push(buffer);
+    }
+
+    /// Move the chunk buffers out of the mostly empty memory chunks, returns the number of moved buffers.
+    /// The chunks must be locked so the threads can't use their buffers, and the device idle.
+    ///
+    /// # Safety
+    ///
+    /// The device must be idle and the chunks locked, no buffer they own may be used during the
+    /// copies.
+    pub unsafe fn defragment(
+        &self,
+        data: &RendererData,
+        chunks: &mut [MutexGuard<Chunk>],
+    ) -> Result<usize> {
+        self.recycle_all_old_buffers();
+        let mut pool = self.buffer_pool.lock().unwrap();
+
+        let command_pool = data.command_pool.as_ref().unwrap();
+        let allocated = command_pool.allocate_command_buffers(
+            &data.device,
+            2,
+            vk::CommandBufferLevel::PRIMARY,
+        )?;
+        let (mut acquire, mut command_buffer) = (allocated[0], allocated[1]);
+        // the buffers uploaded since the last frame are acquired before being copied, the
+        // device is idle so their copies are done and the semaphores can be destroyed
+        let handoffs = std::mem::take(&mut *data.buffer_handoffs.lock().unwrap());
+        for handoff in &handoffs {
+            data.device.destroy_semaphore(handoff.semaphore, None);
+        }
+        let acquired =
+            data.record_buffer_acquires(&mut acquire, &handoffs, vk::PipelineStageFlags::TRANSFER);
+        command_buffer.begin(&data.device)?;
+        let relocations = data.allocator.defragment(&data.device, &mut command_buffer);
+        command_buffer.end(&data.device)?;
+        let buffers: Vec<_> = match acquired {
+            Ok(true) => vec![acquire.buffer, command_buffer.buffer],
+            _ => vec![command_buffer.buffer],
         };
+        let submitted = acquired.and(relocations).and_then(|relocations| {
+            let submit_info = vk::SubmitInfo::builder().command_buffers(&buffers);
+            let _queue = data.queue_lock.lock().unwrap();
+            data.device
+                .queue_submit(data.graphics_queue, &[submit_info], vk::Fence::null())?;
+            data.device.queue_wait_idle(data.graphics_queue)?;
+            Ok(relocations)
+        });
+        data.device
+            .free_command_buffers(command_pool.pool, &[acquire.buffer, command_buffer.buffer]);
+        let relocations = submitted?;
+
+        let mut pending: HashMap<_, _> = relocations
+            .into_iter()
+            .map(|r| ((r.old.memory.as_raw(), r.old.offset), r))
+            .collect();
+        let mut moved = Vec::with_capacity(pending.len());
+        for buffer in chunks
+            .iter_mut()
+            .filter_map(|chunk| chunk.buffer.as_mut())
+            .chain(pool.buffers_mut())
+        {
+            let key = (buffer.alloc.memory.as_raw(), buffer.alloc.offset);
+            if let Some(relocation) = pending.remove(&key) {
+                match buffer.relocate(data, &relocation) {
+                    Ok(()) => moved.push(relocation),
+                    Err(e) => {
+                        warn!("Failed to relocate a chunk buffer: {}", e);
+                        data.allocator.free(relocation.new);
+                    }
+                }
+            }
+        }
+        // the old blocks of the buffers not found are still used
+        for relocation in pending.into_values() {
+            warn!("Chunk buffer not found for {:?}", relocation.old);
+            data.allocator.free(relocation.new);
+        }
 
-        let command_pool =
-            CommandPool::create(&renderer_data.read().unwrap(), queue_family).unwrap();
-        let mut command_buffer = command_pool
-            .allocate_command_buffers(&renderer_data.read().unwrap().device, 1)
-            .unwrap()[0];
+        let count = moved.len();
+        if count > 0 {
+            self.mesh_version.fetch_add(1, atomic::Ordering::Release);
+        }
+        data.allocator.end_defragment(&data.device, moved);
+        Ok(count)
+    }
 
-        loop {
-            if exit.load(Ordering::Relaxed) {
-                break;
+    #[inline]
+    pub fn mesh_version(&self) -> u64 {
+        self.mesh_version.load(atomic::Ordering::Acquire)
+    }
+
+    pub fn free_buffers(&self) {
+        self.old_buffers.lock().unwrap().clear();
+        self.retired_buffers.lock().unwrap().clear();
+        let mut pool = self.buffer_pool.lock().unwrap();
+        debug!(
+            "Chunk buffers: {} created, {} reused",
+            pool.created, pool.reused
+        );
+        pool.clear();
+    }
+
+    // the resources of a thread to upload the meshes on its transfer queue
+    unsafe fn create_slots(
+        i: u32,
+        data: &RendererData,
+    ) -> Result<(vk::Queue, CommandPool, Vec<UploadSlot>)> {
+        let queue_def = data.physical_device.transfer_queues[i as usize];
+        let queue = data
+            .device
+            .as_ref()
+            .get_device_queue(queue_def.family, queue_def.index);
+
+        let command_pool = CommandPool::create(data, queue_def.family)?;
+        let command_buffers = command_pool.allocate_command_buffers(
+            &data.device,
+            UPLOAD_SLOTS as u32,
+            vk::CommandBufferLevel::PRIMARY,
+        )?;
+        let fences = sync::create_fences(&data.device, false, UPLOAD_SLOTS)?;
+        let slots = command_buffers
+            .into_iter()
+            .zip(fences)
+            .map(|(command_buffer, fence)| {
+                Ok(UploadSlot {
+                    staging: MeshStaging::create(data, 1)?,
+                    command_buffer,
+                    fence,
+                    pending: None,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((queue, command_pool, slots))
+    }
+
+    // Copy the mesh from the staging buffer of the slot to the chunk buffer, the graphics
+    // queue waits for the copy with the semaphore of the returned handoff. The buffer is
+    // released to the graphics family if family is another one.
+    unsafe fn submit_upload(
+        data: &RendererData,
+        queue: vk::Queue,
+        family: u32,
+        slot: &mut UploadSlot,
+        buffer: &Buffer,
+        vertices_count: usize,
+        indices_count: usize,
+    ) -> Result<BufferHandoff> {
+        let device = &data.device;
+        let command_buffer = &mut slot.command_buffer;
+        {
+            profiling::scope!("recording");
+            command_buffer.begin(device)?;
+            let regions = [
+                vk::BufferCopy::builder()
+                    .size((vertices_count * std::mem::size_of::<Vertex>()) as u64),
+                vk::BufferCopy::builder()
+                    .src_offset(slot.staging.indices_offset() as u64)
+                    .dst_offset((vertices_count * std::mem::size_of::<Vertex>()) as u64)
+                    .size((indices_count * std::mem::size_of::<u32>()) as u64),
+            ];
+            device.cmd_copy_buffer(
+                command_buffer.buffer,
+                slot.staging.buffer.buffer,
+                buffer.buffer,
+                &regions,
+            );
+
+            let graphics_family = data.physical_device.graphics_queue.family;
+            if family != graphics_family {
+                device.cmd_pipeline_barrier(
+                    command_buffer.buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[] as &[vk::MemoryBarrier],
+                    &[ownership_barrier(buffer.buffer, family, graphics_family)],
+                    &[] as &[vk::ImageMemoryBarrier],
+                );
             }
-            let recv_chunk = receiver.recv().unwrap();
-            if let Some(chunk) = recv_chunk.upgrade() {
-                {
-                    let mut chunk = chunk.lock().unwrap();
-                    {
-                        profiling::scope!("meshing");
-                        chunk
-                            .mesh(
-                                std::slice::from_raw_parts_mut(
-                                    staging_buffer.ptr.cast(),
-                                    STAGING_BUFFER_SIZE_VERTICES,
-                                ),
-                                std::slice::from_raw_parts_mut(
-                                    staging_buffer.ptr.add(STAGING_BUFFER_SIZE_VERTICES).cast(),
-                                    STAGING_BUFFER_SIZE_INDICES,
-                                ),
-                            )
-                            .unwrap();
-                        chunk.buffer = Some(Buffer::create(
-                            &renderer_data.read().unwrap(),
-                            chunk.vertices_count * size_of::<Vertex>()
-                                + chunk.indices_count * size_of::<u32>(),
-                            vk::BufferUsageFlags::VERTEX_BUFFER
-                                | vk::BufferUsageFlags::INDEX_BUFFER
-                                | vk::BufferUsageFlags::TRANSFER_DST,
-                            AllocUsage::DeviceLocal,
-                        ).unwrap());
+
+            command_buffer.end(device)?;
+        }
+
+        let handoff = BufferHandoff {
+            buffer: buffer.buffer,
+            src_family: family,
+            semaphore: sync::create_semaphore(device)?,
+        };
+
+        profiling::scope!("submitting");
+        let buffers = &[command_buffer.buffer];
+        let signal_semaphores = &[handoff.semaphore];
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(buffers)
+            .signal_semaphores(signal_semaphores);
+        // the transfer queue is the graphics one without a dedicated queue
+        let _queue = data.queue_lock.lock().unwrap();
+        if let Err(e) = device.queue_submit(queue, &[submit_info], slot.fence) {
+            device.destroy_semaphore(handoff.semaphore, None);
+            return Err(e.into());
+        }
+        Ok(handoff)
+    }
+
+    // The errors that stop a thread (failed setup, lost device) and the failed uploads
+    // are sent to the main thread, see errors.
+    unsafe fn thread_main(i: u32, shared: ThreadShared, renderer_data: Arc<RwLock<RendererData>>) {
+        let ThreadShared {
+            errors,
+            mesh_queue,
+            buffer_pool,
+            ..
+        } = &shared;
+        profiling::register_thread!();
+        let name = thread::current().name().unwrap().to_string();
+        trace!("{} started", name);
+        let family = renderer_data
+            .read()
+            .unwrap()
+            .physical_device
+            .transfer_queues[i as usize]
+            .family;
+        let (queue, _command_pool, mut slots) =
+            match Self::create_slots(i, &renderer_data.read().unwrap()) {
+                Ok(resources) => resources,
+                Err(e) => {
+                    let _ = errors.send(e.context(format!("{name} failed to start")));
+                    return;
+                }
+            };
+
+        let finish = |slot: &mut UploadSlot| -> Result<()> {
+            if let Some(upload) = slot.pending.take() {
+                profiling::scope!("waiting");
+                let data = renderer_data.read().unwrap();
+                data.device.wait_for_fences(&[slot.fence], true, u64::MAX)?;
+                data.device.reset_fences(&[slot.fence])?;
+                upload.apply(&data, &shared);
+            }
+            Ok(())
+        };
+
+        let mut current = 0;
+        let mut run = || -> Result<()> {
+            loop {
+                let recv_chunk = match mesh_queue.try_pop() {
+                    Some(chunk) => chunk,
+                    // nothing else to do, the uploads in flight are done before waiting
+                    None => {
+                        slots.iter_mut().try_for_each(finish)?;
+                        match mesh_queue.pop() {
+                            Some(chunk) => chunk,
+                            None => return Ok(()),
+                        }
                     }
+                };
+                // the slot is reused once its previous copy is done
+                let slot = &mut slots[current];
+                finish(slot)?;
 
+                let Some(chunk) = recv_chunk.upgrade() else {
+                    continue;
+                };
+                let mut chunk = chunk.lock().unwrap();
+                // already remeshed if it was queued several times
+                if chunk.state == ChunkState::Ready {
+                    continue;
+                }
+                let first_mesh = chunk.state == ChunkState::Meshing;
+                let lod = chunk.lod();
+
+                let meshed = {
+                    profiling::scope!("meshing");
+                    let mut result = {
+                        let (vertices, indices) = slot.staging.slices();
+                        chunk.mesh(vertices, indices, lod)
+                    };
+                    while slot.staging.scale < MAX_STAGING_SCALE
+                        && result.as_ref().is_err_and(|e| e.is::<MeshOverflow>())
                     {
-                        profiling::scope!("uploading");
-                        let device = &renderer_data.read().unwrap().device;
-                        {
-                            profiling::scope!("recording");
-                            command_buffer.begin(device).unwrap();
-                            let regions = [
-                                vk::BufferCopy::builder().size(
-                                    (chunk.vertices_count * std::mem::size_of::<Vertex>()) as u64,
-                                ),
-                                vk::BufferCopy::builder()
-                                    .src_offset(STAGING_BUFFER_SIZE_VERTICES as u64)
-                                    .dst_offset(
-                                        (chunk.vertices_count * std::mem::size_of::<Vertex>())
-                                            as u64,
-                                    )
-                                    .size(
-                                        (chunk.indices_count * std::mem::size_of::<u32>()) as u64,
-                                    ),
-                            ];
-                            device.cmd_copy_buffer(
-                                command_buffer.buffer,
-                                staging_buffer.buffer,
-                                chunk.buffer.as_ref().unwrap().buffer,
-                                &regions,
-                            );
-
-                            command_buffer.end(device).unwrap();
+                        let scale = slot.staging.scale * 2;
+                        warn!(
+                            "Mesh of chunk {:?} too big for the staging buffer, growing it to {}x",
+                            chunk.pos, scale
+                        );
+                        match MeshStaging::create(&renderer_data.read().unwrap(), scale) {
+                            Ok(new_staging) => slot.staging = new_staging,
+                            Err(e) => {
+                                warn!("Failed to grow the staging buffer: {}", e);
+                                break;
+                            }
+                        }
+                        let (vertices, indices) = slot.staging.slices();
+                        result = chunk.mesh(vertices, indices, lod);
+                    }
+                    // like a failed buffer creation, the chunk is drawn empty until its next change
+                    match result {
+                        Ok(counts) => Some(counts),
+                        Err(e) => {
+                            let _ = errors
+                                .send(e.context(format!("Failed to mesh chunk {:?}", chunk.pos)));
+                            None
+                        }
+                    }
+                };
+                let (vertices_count, indices_count, transparent_indices_count) =
+                    meshed.unwrap_or_default();
+                let mesh_id = chunk.mesh_id();
+                let pos = chunk.pos;
+                // the chunk keeps drawing its previous mesh while the new one is uploaded
+                drop(chunk);
+
+                // an empty mesh has no buffer and nothing to upload
+                let mut upload = PendingUpload {
+                    chunk: recv_chunk,
+                    pos,
+                    mesh_id,
+                    buffer: None,
+                    vertices_count,
+                    indices_count,
+                    transparent_indices_count,
+                    state: ChunkState::Ready,
+                    first_mesh,
+                    handoff: None,
+                };
+                if meshed.is_none() {
+                    upload.clear();
+                }
+                if indices_count > 0 {
+                    let buffer = buffer_pool.lock().unwrap().get(
+                        &renderer_data.read().unwrap(),
+                        vertices_count * size_of::<Vertex>() + indices_count * size_of::<u32>(),
+                        vk::BufferUsageFlags::VERTEX_BUFFER
+                            | vk::BufferUsageFlags::INDEX_BUFFER
+                            | vk::BufferUsageFlags::TRANSFER_DST,
+                        AllocUsage::DeviceLocal,
+                    );
+                    match buffer {
+                        Ok(buffer) => upload.buffer = Some(buffer),
+                        Err(e) => {
+                            warn!("Failed to create the buffer of chunk {:?}: {}", pos, e);
+                            upload.clear();
                         }
+                    }
+                }
 
-                        {
-                            profiling::scope!("submitting");
-                            let buffers = &[command_buffer.buffer];
-                            let submit_info = vk::SubmitInfo::builder().command_buffers(buffers);
-                            device
-                                .queue_submit(queue, &[submit_info], vk::Fence::null())
-                                .unwrap();
+                let submitted = match &upload.buffer {
+                    Some(buffer) => {
+                        profiling::scope!("uploading");
+                        Self::submit_upload(
+                            &renderer_data.read().unwrap(),
+                            queue,
+                            family,
+                            slot,
+                            buffer,
+                            vertices_count,
+                            indices_count,
+                        )
+                        .map(Some)
+                    }
+                    None => Ok(None),
+                };
+                match submitted {
+                    Ok(Some(handoff)) => {
+                        upload.handoff = Some(handoff);
+                        slot.pending = Some(upload);
+                        current = (current + 1) % UPLOAD_SLOTS;
+                    }
+                    Ok(None) => upload.apply(&renderer_data.read().unwrap(), &shared),
+                    Err(e) if is_device_lost(&e) => return Err(e),
+                    // like a failed buffer creation, the thread goes on with the next chunk
+                    Err(e) => {
+                        let _ = errors.send(e.context(format!("Failed to upload chunk {pos:?}")));
+                        if let Some(buffer) = upload.buffer.take() {
+                            buffer_pool.lock().unwrap().give_back(buffer);
                         }
-                        profiling::scope!("waiting");
-                        device.queue_wait_idle(queue).unwrap();
+                        upload.clear();
+                        upload.apply(&renderer_data.read().unwrap(), &shared);
                     }
                 }
-                sender.send(recv_chunk).unwrap();
             }
+        };
+        if let Err(e) = run() {
+            let _ = errors.send(e.context(format!("{name} stopped")));
         }
-        trace!("{} exited", thread::current().name().unwrap());
+
+        // waiting on a lost device returns right away
+        for slot in &mut slots {
+            if let Err(e) = finish(slot) {
+                trace!("Failed to finish an upload: {e}");
+            }
+        }
+        let device = &renderer_data.read().unwrap().device;
+        for slot in &slots {
+            device.destroy_fence(slot.fence, None);
+        }
+        trace!("{} exited", name);
     }
 
     pub fn try_iter(&self) -> TryIter<'_, Weak<Mutex<Chunk>>> {
         self.out_receiver.try_iter()
     }
+
+    // A thread stops after a failed start or a lost device (see is_device_lost),
+    // the other errors only lose the mesh or the upload of a chunk, drawn empty instead.
+    pub fn errors(&self) -> TryIter<'_, anyhow::Error> {
+        self.errors_receiver.try_iter()
+    }
+}
+
+impl Default for MeshingThreadPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z},
+        world::{
+            generation::{GLASS, TERRAIN, WATER},
+            Block,
+        },
+    };
+    use nalgebra_glm::{vec2, vec3};
+
+    // the slices of a staging buffer of that scale, see MeshStaging::slices
+    fn staging(scale: usize) -> (Vec<Vertex>, Vec<u32>) {
+        let zero = Vertex::new(vec3(0, 0, 0), vec3(0, 0, 0), 0, 0, vec2(0, 0), 0);
+        (
+            vec![zero; STAGING_BUFFER_SIZE_VERTICES * scale / size_of::<Vertex>()],
+            vec![0; STAGING_BUFFER_SIZE_INDICES * scale / size_of::<u32>()],
+        )
+    }
+
+    // no face can be merged nor culled, the other blocks are left as air
+    fn checkerboard(block: Block, other: Option<Block>) -> Chunk {
+        let mut chunk = Chunk::new(ChunkPos { x: 0, y: 0, z: 0 });
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..CHUNK_SIZE_Y {
+                for z in 0..CHUNK_SIZE_Z {
+                    if (x + y + z) % 2 == 0 {
+                        chunk.set_block(x, y, z, block);
+                    } else if let Some(other) = other {
+                        chunk.set_block(x, y, z, other);
+                    }
+                }
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn mesh_overflow() {
+        let mut chunk = checkerboard(TERRAIN, None);
+        let (mut vertices, mut indices) = staging(1);
+        let error = chunk.mesh(&mut vertices, &mut indices, 0).unwrap_err();
+        assert!(error.is::<MeshOverflow>());
+        let error = chunk
+            .mesh_sections(&mut vertices, &mut indices)
+            .unwrap_err();
+        assert!(error.is::<MeshOverflow>());
+
+        let (mut vertices, mut indices) = staging(MAX_STAGING_SCALE);
+        let (vertices_count, indices_count, _) =
+            chunk.mesh(&mut vertices, &mut indices, 0).unwrap();
+        assert_eq!(indices_count, CHUNK_VOLUME / 2 * 6 * 6);
+        assert_eq!(vertices_count, CHUNK_VOLUME / 2 * 6 * 4);
+        // the sections kept after an overflow are still valid
+        let counts = chunk.mesh_sections(&mut vertices, &mut indices).unwrap();
+        assert_eq!((counts.0, counts.1), (vertices_count, indices_count));
+    }
+    #[test]
+    fn worst_case_mesh() {
+        // both blocks show all their faces
+        let mut chunk = checkerboard(GLASS, Some(WATER));
+        let (mut vertices, mut indices) = staging(MAX_STAGING_SCALE / 2);
+        let error = chunk.mesh(&mut vertices, &mut indices, 0).unwrap_err();
+        assert!(error.is::<MeshOverflow>());
+
+        let (mut vertices, mut indices) = staging(MAX_STAGING_SCALE);
+        let (vertices_count, indices_count, _) =
+            chunk.mesh(&mut vertices, &mut indices, 0).unwrap();
+        assert_eq!(vertices_count, MAX_MESH_FACES * 4);
+        assert_eq!(indices_count, MAX_MESH_FACES * 6);
+    }
 }
@@ -1,3 +1,6 @@
+mod generation;
 mod meshing;
+mod queue;
 
+pub use generation::*;
 pub use meshing::*;
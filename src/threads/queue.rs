@@ -0,0 +1,201 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    sync::{Condvar, Mutex},
+};
+
+use crate::world::ChunkPos;
+
+struct QueuedChunk<T> {
+    distance: i32,
+    // submission order, the chunks at the same distance are handled first in first out
+    order: u64,
+    pos: ChunkPos,
+    item: T,
+}
+
+// the nearest chunk is the greatest so it is popped first from the heap
+impl<T> Ord for QueuedChunk<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance
+            .cmp(&self.distance)
+            .then(other.order.cmp(&self.order))
+    }
+}
+
+impl<T> PartialOrd for QueuedChunk<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for QueuedChunk<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for QueuedChunk<T> {}
+
+struct ChunkQueueState<T> {
+    heap: BinaryHeap<QueuedChunk<T>>,
+    // chunk of the player, the distances are relative to it
+    center: ChunkPos,
+    next_order: u64,
+    // soft limit on the queued chunks, see push
+    capacity: usize,
+    closed: bool,
+}
+
+// work on chunks waiting for a thread, the nearest to the player first
+pub(super) struct ChunkQueue<T> {
+    state: Mutex<ChunkQueueState<T>>,
+    available: Condvar,
+}
+
+impl<T> ChunkQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ChunkQueueState {
+                heap: BinaryHeap::new(),
+                center: ChunkPos { x: 0, y: 0, z: 0 },
+                next_order: 0,
+                capacity: usize::MAX,
+                closed: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    // The chunk is always queued, returns false if the queue is now over its capacity
+    // so the caller can stop adding chunks.
+    pub fn push(&self, item: T, pos: ChunkPos) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let queued = QueuedChunk {
+            distance: pos.distance_squared(state.center),
+            order: state.next_order,
+            pos,
+            item,
+        };
+        state.next_order += 1;
+        state.heap.push(queued);
+        self.available.notify_one();
+        state.heap.len() <= state.capacity
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.state.lock().unwrap().capacity = capacity;
+    }
+
+    // None if the queue is empty or closed
+    pub fn try_pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        state.heap.pop().map(|queued| queued.item)
+    }
+
+    // Block until a chunk is queued, returns None once the queue is closed.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(queued) = state.heap.pop() {
+                return Some(queued.item);
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    // the chunks already queued are sorted again when the player changes of chunk
+    pub fn set_center(&self, center: ChunkPos) {
+        let mut state = self.state.lock().unwrap();
+        if state.center == center {
+            return;
+        }
+        state.center = center;
+        let mut queued = std::mem::take(&mut state.heap).into_vec();
+        for chunk in &mut queued {
+            chunk.distance = chunk.pos.distance_squared(center);
+        }
+        state.heap = queued.into();
+    }
+
+    // drop the queued chunks whose position doesn't match
+    pub fn retain(&self, mut keep: impl FnMut(ChunkPos) -> bool) {
+        self.state
+            .lock()
+            .unwrap()
+            .heap
+            .retain(|queued| keep(queued.pos));
+    }
+
+    // wake all the threads so they exit
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread, time::Duration};
+
+    fn pos(x: i32) -> ChunkPos {
+        ChunkPos { x, y: 0, z: 0 }
+    }
+
+    #[test]
+    fn nearest_first() {
+        let queue = ChunkQueue::new();
+        for (item, x) in [(0, 5), (1, 1), (2, 3), (3, 1)] {
+            queue.push(item, pos(x));
+        }
+        // same distance in submission order
+        assert_eq!(queue.try_pop(), Some(1));
+        assert_eq!(queue.try_pop(), Some(3));
+        // sorted again around the new center
+        queue.set_center(pos(6));
+        assert_eq!(queue.try_pop(), Some(0));
+        assert_eq!(queue.try_pop(), Some(2));
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn close_wakes_pop() {
+        let queue = Arc::new(ChunkQueue::<()>::new());
+        let thread = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.pop())
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert_eq!(thread.join().unwrap(), None);
+        queue.push((), pos(0));
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn capacity() {
+        let queue = ChunkQueue::new();
+        assert!(queue.push(0, pos(0)));
+        queue.set_capacity(3);
+        assert!(queue.push(1, pos(5)));
+        assert!(queue.push(2, pos(-1)));
+        // over capacity, queued anyway
+        assert!(!queue.push(3, pos(2)));
+        assert_eq!(queue.try_pop(), Some(0));
+        assert_eq!(queue.try_pop(), Some(2));
+        assert!(queue.push(4, pos(9)));
+        assert!(!queue.push(5, pos(9)));
+
+        queue.retain(|pos| pos.x != 2 && pos.x != 9);
+        assert_eq!(queue.try_pop(), Some(1));
+        assert_eq!(queue.try_pop(), None);
+    }
+}
@@ -1,308 +1,1045 @@
-use std::fmt::Debug;
+use std::{borrow::Cow, collections::HashMap, fmt::Debug};
 
 use anyhow::{anyhow, Result};
 use log::trace;
-use nalgebra_glm::{vec3, TVec3};
+use nalgebra_glm::{vec2, vec3};
 
 use crate::{
-    config::CHUNK_SIZE,
-    render::{buffer::Buffer, vertex::Vertex},
+    config::{
+        CHUNK_SECTION_HEIGHT, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z, CHUNK_VOLUME,
+        LOD_DISTANCES, SECTION_MESHING,
+    },
+    render::{buffer::Buffer, commands::ChunkCommands, vertex::Vertex},
 };
 
-use super::world::ChunkPos;
+use super::{
+    generation::FeatureBlock, palette::BlockStorage, storage::Reader, world::ChunkPos,
+    BlockRegistry,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Block {
     id: u16,
 }
 
+impl Block {
+    pub const AIR: Block = Block::new(0);
+
+    #[inline]
+    pub const fn new(id: u16) -> Self {
+        Self { id }
+    }
+
+    #[inline]
+    pub fn id(self) -> u16 {
+        self.id
+    }
+
+    #[inline]
+    pub fn is_air(self) -> bool {
+        self.id == 0
+    }
+}
+
+// The mesh doesn't fit in the output buffers, it should be retried with bigger ones.
+#[derive(Debug)]
+pub struct MeshOverflow;
+
+impl std::fmt::Display for MeshOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mesh too big for the output buffers")
+    }
+}
+
+impl std::error::Error for MeshOverflow {}
+
+pub const CHUNK_SECTIONS: usize = CHUNK_SIZE_Y / CHUNK_SECTION_HEIGHT;
+const _: () = assert!(CHUNK_SIZE_Y.is_multiple_of(CHUNK_SECTION_HEIGHT) && CHUNK_SECTIONS <= 64);
+const ALL_SECTIONS: u64 = u64::MAX >> (64 - CHUNK_SECTIONS);
+
+// coarsest level of detail, the voxels of each level are cubes of 2^lod blocks
+pub const MAX_LOD: u32 = LOD_DISTANCES.len() as u32;
+// the biggest voxels must tile the sections
+const _: () = assert!(
+    CHUNK_SIZE_X.is_multiple_of(1 << MAX_LOD)
+        && CHUNK_SECTION_HEIGHT.is_multiple_of(1 << MAX_LOD)
+        && CHUNK_SIZE_Z.is_multiple_of(1 << MAX_LOD)
+);
+
+// version of the format written by Chunk::serialize
+const SAVE_VERSION: u8 = 1;
+
+// biggest slice of a chunk along an axis
+const MAX_FACE_AREA: usize = {
+    let xy = CHUNK_SIZE_X * CHUNK_SIZE_Y;
+    let yz = CHUNK_SIZE_Y * CHUNK_SIZE_Z;
+    let xz = CHUNK_SIZE_X * CHUNK_SIZE_Z;
+    let max = if xy > yz { xy } else { yz };
+    if max > xz {
+        max
+    } else {
+        xz
+    }
+};
+
+// mesh of a horizontal slab of a chunk
+#[derive(Default)]
+struct SectionMesh {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    transparent_indices: usize, // the last ones of indices
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkState {
+    Ungenerated, // not loaded in the world
+    Generating,
+    Generated, // blocks are filled, waiting to be meshed
+    Meshing,   // first mesh in progress
+    Ready,     // mesh uploaded and up to date
+    Dirty,     // mesh uploaded but outdated, waiting to be remeshed
+}
+
+impl ChunkState {
+    // true if the chunk has a mesh on the gpu, even an outdated one
+    #[inline]
+    pub fn has_mesh(self) -> bool {
+        matches!(self, Self::Ready | Self::Dirty)
+    }
+}
+
 pub struct Chunk {
     pub pos: ChunkPos,
-    pub blocks: [Block; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
+    pub state: ChunkState,
+    blocks: BlockStorage,
     pub buffer: Option<Buffer>,
     pub vertices_count: usize,
     pub indices_count: usize,
+    // the last ones of the indices, drawn after the opaque faces of all the chunks
+    pub transparent_indices_count: usize,
+    pub features: Vec<FeatureBlock>, // blocks of the features this chunk owns, may spill in neighbors
+    pub edits: HashMap<(usize, usize, usize), Block>, // blocks changed after the generation
+    dirty_sections: u64,             // bit i set if section i needs to be remeshed
+    sections: Option<Box<[SectionMesh; CHUNK_SECTIONS]>>, // only used with SECTION_MESHING
+    // blocks of the neighbor layer on each side, None if the neighbor isn't loaded
+    borders: [Option<Box<[Block]>>; 6],
+    mesh_id: u64, // incremented by each mesh, tells if a mesh is the latest
+    // level of detail of the next meshes, set by the world from the distance to the player
+    lod: u32,
+    mesh_lod: u32, // level of detail of the last mesh and of the sections
+    // draw commands of the current buffer, recorded by the renderer when first drawn
+    pub commands: Option<ChunkCommands>,
 }
 
 impl Chunk {
-    #[profiling::function]
-    pub fn new(pos: ChunkPos) -> Result<Self> {
-        let mut c = Self {
+    // all air, filled by a TerrainGenerator
+    pub fn new(pos: ChunkPos) -> Self {
+        trace!("Create chunk {:?}", pos);
+        Self {
             pos,
-            blocks: [Block { id: 0 }; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
+            state: ChunkState::Generating,
+            blocks: BlockStorage::default(),
             buffer: None,
             vertices_count: 0,
             indices_count: 0,
+            transparent_indices_count: 0,
+            features: Vec::new(),
+            edits: HashMap::new(),
+            dirty_sections: ALL_SECTIONS,
+            sections: None,
+            borders: Default::default(),
+            mesh_id: 0,
+            lod: 0,
+            mesh_lod: 0,
+            commands: None,
+        }
+    }
+
+    // Blocks as a palette of the block ids and runs of palette indices, followed by the edits.
+    // The mesh, features and borders are not saved.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut palette: Vec<u16> = Vec::new();
+        let mut runs: Vec<(u16, u32)> = Vec::new();
+        for block in self.blocks.iter() {
+            let index = match palette.iter().position(|id| *id == block.id) {
+                Some(index) => index,
+                None => {
+                    palette.push(block.id);
+                    palette.len() - 1
+                }
+            } as u16;
+            match runs.last_mut() {
+                Some((last, len)) if *last == index => *len += 1,
+                _ => runs.push((index, 1)),
+            }
+        }
+
+        let mut data = vec![SAVE_VERSION];
+        data.extend_from_slice(&(palette.len() as u16).to_le_bytes());
+        for id in palette {
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+        for (index, len) in runs {
+            data.extend_from_slice(&index.to_le_bytes());
+            data.extend_from_slice(&len.to_le_bytes());
+        }
+        data.extend_from_slice(&(self.edits.len() as u32).to_le_bytes());
+        for ((x, y, z), block) in &self.edits {
+            for value in [*x as u16, *y as u16, *z as u16, block.id] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    // the chunk is Generated, without features
+    pub fn deserialize(pos: ChunkPos, data: &[u8]) -> Result<Chunk> {
+        let mut reader = Reader::new(data);
+        let version = reader.u8()?;
+        if version != SAVE_VERSION {
+            return Err(anyhow!("Unsupported chunk version {version}"));
+        }
+
+        let palette = (0..reader.u16()?)
+            .map(|_| reader.u16().map(Block::new))
+            .collect::<Result<Vec<_>>>()?;
+        let mut blocks = Vec::with_capacity(CHUNK_VOLUME);
+        for _ in 0..reader.u32()? {
+            let index = reader.u16()? as usize;
+            let len = reader.u32()? as usize;
+            let block = *palette
+                .get(index)
+                .ok_or_else(|| anyhow!("Palette index {index} out of the palette"))?;
+            if blocks.len() + len > CHUNK_VOLUME {
+                return Err(anyhow!("Too many blocks"));
+            }
+            blocks.resize(blocks.len() + len, block);
+        }
+        if blocks.len() != CHUNK_VOLUME {
+            return Err(anyhow!("{} blocks instead of {CHUNK_VOLUME}", blocks.len()));
+        }
+        let mut chunk = Self::new(pos);
+        chunk.blocks = blocks.into_iter().collect();
+
+        for _ in 0..reader.u32()? {
+            let (x, y, z) = (
+                reader.u16()? as usize,
+                reader.u16()? as usize,
+                reader.u16()? as usize,
+            );
+            let block = Block::new(reader.u16()?);
+            if !Self::contains(x, y, z) {
+                return Err(anyhow!("Edit {:?} out of the chunk", (x, y, z)));
+            }
+            chunk.edits.insert((x, y, z), block);
+        }
+        if !reader.is_empty() {
+            return Err(anyhow!("Unexpected data after the chunk"));
+        }
+
+        chunk.state = ChunkState::Generated;
+        Ok(chunk)
+    }
+
+    // Returns the vertices, indices and transparent indices counts of the mesh, the
+    // transparent indices are the last ones. The counts of the chunk are those of its
+    // buffer and are only updated once the mesh is uploaded.
+    // With a level of detail, the blocks are merged by cubes of 2^lod blocks which are
+    // meshed as single voxels, see Voxels.
+    pub fn mesh(
+        &mut self,
+        vertices: &mut [Vertex],
+        indices: &mut [u32],
+        lod: u32,
+    ) -> Result<(usize, usize, usize)> {
+        debug_assert!(lod <= MAX_LOD, "Level of detail {lod} above {MAX_LOD}");
+        if lod != self.mesh_lod {
+            // the sections kept are of the previous level
+            self.dirty_sections = ALL_SECTIONS;
+            self.mesh_lod = lod;
+        }
+        let counts = if SECTION_MESHING {
+            self.mesh_sections(vertices, indices)
+        } else {
+            self.mesh_full(vertices, indices)
         };
+        // a failed mesh replaces the previous ones too, the chunk is drawn empty instead
+        self.mesh_id += 1;
+        counts
+    }
+
+    // mesh the whole chunk at once
+    pub fn mesh_full(
+        &mut self,
+        vertices: &mut [Vertex],
+        indices: &mut [u32],
+    ) -> Result<(usize, usize, usize)> {
+        trace!("Mesh chunk {:?} at level {}", self.pos, self.mesh_lod);
+
+        let voxels = Voxels::new(&self.blocks, &self.borders, self.mesh_lod);
+        let counts = Self::mesh_region(&voxels, [0, 0, 0], voxels.size, vertices, indices)?;
+        self.dirty_sections = 0;
+
+        Ok(counts)
+    }
+
+    // Only remesh the dirty sections and keep a copy of each section mesh for the next time.
+    // The output buffers are also used as scratch space for the sections being meshed.
+    pub fn mesh_sections(
+        &mut self,
+        vertices: &mut [Vertex],
+        indices: &mut [u32],
+    ) -> Result<(usize, usize, usize)> {
+        trace!(
+            "Mesh sections {:#b} of chunk {:?}",
+            self.dirty_sections,
+            self.pos
+        );
 
-        for x in 0..CHUNK_SIZE {
-            for z in 0..CHUNK_SIZE {
-                let height = (x as i32 - z as i32).unsigned_abs() as usize;
-                debug_assert!(height < CHUNK_SIZE);
-                for y in 0..height {
-                    c.blocks[x * CHUNK_SIZE * CHUNK_SIZE + y * CHUNK_SIZE + z].id = 1;
+        let mut sections = self.sections.take().unwrap_or_default();
+        let voxels = Voxels::new(&self.blocks, &self.borders, self.mesh_lod);
+        let [size_x, _, size_z] = voxels.size;
+        let section_height = CHUNK_SECTION_HEIGHT as i32 / voxels.scale;
+
+        for (i, section) in sections.iter_mut().enumerate() {
+            if self.dirty_sections & (1 << i) == 0 {
+                continue;
+            }
+            let min_y = i as i32 * section_height;
+            let max_y = min_y + section_height;
+            let (min, max) = ([0, min_y, 0], [size_x, max_y, size_z]);
+            // keep the sections already done so the retry only does the remaining ones
+            let (vertices_count, indices_count, transparent_indices) =
+                match Self::mesh_region(&voxels, min, max, vertices, indices) {
+                    Ok(counts) => counts,
+                    Err(e) => {
+                        self.sections = Some(sections);
+                        return Err(e);
+                    }
+                };
+            section.vertices = vertices[..vertices_count].to_vec();
+            section.indices = indices[..indices_count].to_vec();
+            section.transparent_indices = transparent_indices;
+            self.dirty_sections &= !(1 << i);
+        }
+
+        let total_vertices: usize = sections.iter().map(|s| s.vertices.len()).sum();
+        let total_indices: usize = sections.iter().map(|s| s.indices.len()).sum();
+        if total_vertices > vertices.len() || total_indices > indices.len() {
+            self.sections = Some(sections);
+            return Err(MeshOverflow.into());
+        }
+
+        // Stitch all the sections together, indices are relative to their section.
+        // The opaque indices of all the sections come first, then the transparent ones.
+        let mut vertices_count = 0;
+        let mut indices_count = 0;
+        let mut first_vertices = [0; CHUNK_SECTIONS];
+        for (section, first_vertex) in sections.iter().zip(&mut first_vertices) {
+            vertices[vertices_count..vertices_count + section.vertices.len()]
+                .copy_from_slice(&section.vertices);
+            *first_vertex = vertices_count;
+            let opaque = &section.indices[..section.indices.len() - section.transparent_indices];
+            for (dst, index) in indices[indices_count..].iter_mut().zip(opaque) {
+                *dst = index + vertices_count as u32;
+            }
+            vertices_count += section.vertices.len();
+            indices_count += opaque.len();
+        }
+        let mut transparent_count = 0;
+        for (section, first_vertex) in sections.iter().zip(first_vertices) {
+            let transparent =
+                &section.indices[section.indices.len() - section.transparent_indices..];
+            for (dst, index) in indices[indices_count..].iter_mut().zip(transparent) {
+                *dst = index + first_vertex as u32;
+            }
+            indices_count += transparent.len();
+            transparent_count += transparent.len();
+        }
+
+        self.sections = Some(sections);
+        self.dirty_sections = 0;
+
+        Ok((vertices_count, indices_count, transparent_count))
+    }
+
+    #[inline]
+    pub fn mesh_id(&self) -> u64 {
+        self.mesh_id
+    }
+
+    // true if blocks or the level of detail changed since the last mesh
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_sections != 0 || self.lod != self.mesh_lod
+    }
+
+    // level of detail the next meshes are made at
+    #[inline]
+    pub fn lod(&self) -> u32 {
+        self.lod
+    }
+
+    // Returns true if the mesh is outdated and the chunk should be queued for meshing.
+    // The first mesh is made at the new level if it isn't done yet.
+    pub fn set_lod(&mut self, lod: u32) -> bool {
+        if lod == self.lod {
+            return false;
+        }
+        self.lod = lod;
+        if self.state == ChunkState::Ready {
+            self.state = ChunkState::Dirty;
+        }
+        self.state == ChunkState::Dirty
+    }
+
+    #[inline]
+    pub fn contains(x: usize, y: usize, z: usize) -> bool {
+        x < CHUNK_SIZE_X && y < CHUNK_SIZE_Y && z < CHUNK_SIZE_Z
+    }
+
+    #[inline]
+    pub fn get_block(&self, x: usize, y: usize, z: usize) -> Block {
+        assert!(
+            Self::contains(x, y, z),
+            "Block {:?} out of the chunk",
+            (x, y, z)
+        );
+        self.blocks.get(x, y, z)
+    }
+
+    // the sections around the block are marked for a remesh if it changed
+    pub fn set_block(&mut self, x: usize, y: usize, z: usize, block: Block) {
+        assert!(
+            Self::contains(x, y, z),
+            "Block {:?} out of the chunk",
+            (x, y, z)
+        );
+        if self.blocks.get(x, y, z) != block {
+            self.blocks.set(x, y, z, block);
+            self.mark_dirty(y);
+        }
+    }
+
+    // set a block and remember it as an edit so it survives a regeneration
+    pub fn edit_block(&mut self, x: usize, y: usize, z: usize, block: Block) {
+        self.set_block(x, y, z, block);
+        self.edits.insert((x, y, z), block);
+    }
+
+    // edited chunks are saved when unloaded
+    #[inline]
+    pub fn is_edited(&self) -> bool {
+        !self.edits.is_empty()
+    }
+
+    // take the blocks and edits of a saved chunk at the same position
+    pub fn restore_from(&mut self, mut saved: Chunk) {
+        debug_assert_eq!(self.pos, saved.pos);
+        std::mem::swap(&mut self.blocks, &mut saved.blocks);
+        self.edits = std::mem::take(&mut saved.edits);
+        self.dirty_sections = ALL_SECTIONS;
+        self.sections = None;
+    }
+
+    // Take the blocks of a newly generated chunk at the same position.
+    // The current mesh is still drawn until the chunk is remeshed.
+    pub fn regenerate_from(&mut self, mut generated: Chunk, keep_edits: bool) {
+        debug_assert_eq!(self.pos, generated.pos);
+        self.blocks = std::mem::take(&mut generated.blocks);
+        self.features = std::mem::take(&mut generated.features);
+        self.dirty_sections = ALL_SECTIONS;
+        self.sections = None;
+        if self.state.has_mesh() {
+            self.state = ChunkState::Dirty;
+        }
+
+        let edits = std::mem::take(&mut self.edits);
+        if keep_edits {
+            for ((x, y, z), block) in edits {
+                self.edit_block(x, y, z, block);
+            }
+        }
+    }
+
+    // Blocks of the layer of this chunk on a side, for the neighbor on that side.
+    // The sides are the axis for the positive ones and axis + 3 for the negative ones.
+    pub fn border(&self, side: usize) -> Box<[Block]> {
+        let axis = side % 3;
+        let size = Self::size();
+        let mut border = vec![Block::AIR; Self::border_len(axis)].into_boxed_slice();
+        let mut pos = [0i32; 3];
+        pos[axis] = if side < 3 { size[axis] - 1 } else { 0 };
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        for a in 0..size[u] {
+            pos[u] = a;
+            for b in 0..size[v] {
+                pos[v] = b;
+                border[Self::border_index(axis, pos)] =
+                    self.get_block(pos[0] as usize, pos[1] as usize, pos[2] as usize);
+            }
+        }
+        border
+    }
+
+    // Set the layer of the neighbor on a side, the faces on that side are culled against it.
+    // Returns true if the mesh is outdated and the chunk should be queued for meshing.
+    pub fn set_border(&mut self, side: usize, border: Option<Box<[Block]>>) -> bool {
+        if self.borders[side] == border {
+            return false;
+        }
+        self.borders[side] = border;
+        match side {
+            1 => self.mark_dirty(CHUNK_SIZE_Y - 1),
+            4 => self.mark_dirty(0),
+            _ => {
+                self.dirty_sections = ALL_SECTIONS;
+                if self.state == ChunkState::Ready {
+                    self.state = ChunkState::Dirty;
                 }
-                // for y in 0..CHUNK_SIZE {
-                //     c.blocks[x * CHUNK_SIZE * CHUNK_SIZE + y * CHUNK_SIZE + z].id =
-                //         ((x + y + z) % 2) as u16;
-                // }
             }
         }
+        self.state == ChunkState::Dirty
+    }
+
+    // Whether the block is in the layer of one of the sides, the neighbors cull their faces
+    // against it.
+    #[inline]
+    pub fn is_on_border(x: usize, y: usize, z: usize) -> bool {
+        x == 0
+            || y == 0
+            || z == 0
+            || x == CHUNK_SIZE_X - 1
+            || y == CHUNK_SIZE_Y - 1
+            || z == CHUNK_SIZE_Z - 1
+    }
 
-        trace!("Create chunk {:?}", c.pos);
+    #[inline]
+    fn border_len(axis: usize) -> usize {
+        let size = Self::size();
+        (size[(axis + 1) % 3] * size[(axis + 2) % 3]) as usize
+    }
 
-        Ok(c)
+    // index in a border of the position, the coordinate along the axis is ignored
+    #[inline]
+    fn border_index(axis: usize, pos: [i32; 3]) -> usize {
+        let size = Self::size();
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        (pos[u] * size[v] + pos[v]) as usize
     }
 
-    pub fn mesh(&mut self, vertices: &mut [Vertex], indices: &mut [u32]) -> Result<()> {
-        trace!("Mesh chunk {:?}", self.pos);
+    // Mark the sections whose faces depend on the block at height y as needing a remesh.
+    // A block on the edge of a section can hide a face of the neighbor section.
+    pub fn mark_dirty(&mut self, y: usize) {
+        let section = y / CHUNK_SECTION_HEIGHT;
+        self.dirty_sections |= 1 << section;
+        if y.is_multiple_of(CHUNK_SECTION_HEIGHT) && section > 0 {
+            self.dirty_sections |= 1 << (section - 1);
+        }
+        if (y + 1).is_multiple_of(CHUNK_SECTION_HEIGHT) && section + 1 < CHUNK_SECTIONS {
+            self.dirty_sections |= 1 << (section + 1);
+        }
+        if self.state == ChunkState::Ready {
+            self.state = ChunkState::Dirty;
+        }
+    }
 
+    // Greedy mesh the voxels between min (inclusive) and max (exclusive), in voxels.
+    // Faces of voxels outside the region are not emitted, but the visibility of the faces
+    // still depends on voxels outside the region.
+    // Returns the vertices, indices and transparent indices count, the transparent indices
+    // are the last ones.
+    fn mesh_region(
+        voxels: &Voxels,
+        min: [i32; 3],
+        max: [i32; 3],
+        vertices: &mut [Vertex],
+        indices: &mut [u32],
+    ) -> Result<(usize, usize, usize)> {
         // from https://github.com/fesoliveira014/cubeproject/blob/master/CubeProject/tactical/volume/mesher/ChunkMesher.cpp
 
         let mut vertices_index = 0;
         let mut indices_index = 0;
         let mut indices_max = 0;
+        // appended after the opaque indices once they are all emitted
+        let mut transparent_indices = Vec::new();
 
+        // the u8 is the ambient occlusion of the face corners, see face_ao
         #[derive(Debug, Clone, Copy)]
-        enum MaskValue<'a> {
+        enum MaskValue {
             None,
-            Positive(&'a Block),
-            Negative(&'a Block),
+            Positive(Block, u8),
+            Negative(Block, u8),
         }
 
-        impl MaskValue<'_> {
+        impl MaskValue {
             #[inline]
             fn is_none(&self) -> bool {
+                matches!(self, Self::None)
+            }
+
+            #[inline]
+            fn is_positive(&self) -> bool {
+                matches!(self, Self::Positive(..))
+            }
+
+            #[inline]
+            fn block(&self) -> Option<Block> {
                 match self {
-                    Self::None => true,
-                    _ => false,
+                    Self::None => None,
+                    Self::Positive(b, _) | Self::Negative(b, _) => Some(*b),
                 }
             }
 
             #[inline]
-            fn is_positive(&self) -> bool {
+            fn ao(&self) -> u8 {
                 match self {
-                    Self::Positive(_) => true,
-                    _ => false,
+                    Self::None => 0,
+                    Self::Positive(_, ao) | Self::Negative(_, ao) => *ao,
                 }
             }
         }
 
-        impl PartialEq for MaskValue<'_> {
+        impl PartialEq for MaskValue {
             #[inline]
             fn eq(&self, other: &Self) -> bool {
                 match (self, other) {
                     (Self::None, Self::None) => true,
-                    (Self::Positive(a), Self::Positive(b)) => a.id == b.id,
-                    (Self::Negative(a), Self::Negative(b)) => a.id == b.id,
+                    // faces with different occlusion can't be merged
+                    (Self::Positive(a, a_ao), Self::Positive(b, b_ao)) => {
+                        a.id == b.id && a_ao == b_ao
+                    }
+                    (Self::Negative(a, a_ao), Self::Negative(b, b_ao)) => {
+                        a.id == b.id && a_ao == b_ao
+                    }
                     _ => false,
                 }
             }
         }
 
-        let mut mask = [MaskValue::None; CHUNK_SIZE * CHUNK_SIZE];
+        let registry = BlockRegistry::global();
+        // faces toward the positive and the negative side of the axis
+        let mut positive_mask = [MaskValue::None; MAX_FACE_AREA];
+        let mut negative_mask = [MaskValue::None; MAX_FACE_AREA];
 
         for axis in 0..3 {
             let u = (axis + 1) % 3;
             let v = (axis + 2) % 3;
 
-            let mut side = Side::NORTH;
+            // mask dimensions
+            let width = (max[u] - min[u]) as usize;
+            let height = (max[v] - min[v]) as usize;
 
             let mut x = [0i32; 3];
             let mut q = [0i32; 3];
             q[axis] = 1;
-            x[axis] = -1;
+            x[axis] = min[axis] - 1;
 
-            while x[axis] < CHUNK_SIZE as i32 {
+            while x[axis] < max[axis] {
                 let mut n = 0;
-                for i in 0..CHUNK_SIZE {
-                    x[v] = i as i32;
-                    for i in 0..CHUNK_SIZE {
-                        x[u] = i as i32;
-
-                        side = Side::try_from(axis).unwrap();
-
-                        let a = if x[axis] >= 0 {
-                            if self.is_face_visible(x[0], x[1], x[2], side) {
-                                let b = &self.blocks[Self::block_pos_to_index(
-                                    x[0] as u32,
-                                    x[1] as u32,
-                                    x[2] as u32,
-                                )];
-                                if b.id == 0 {
-                                    None
-                                } else {
-                                    Some(b)
-                                }
-                            } else {
-                                None
-                            }
+                for i in 0..height {
+                    x[v] = min[v] + i as i32;
+                    for i in 0..width {
+                        x[u] = min[u] + i as i32;
+
+                        let side = Side::try_from(axis).unwrap();
+
+                        let a = if x[axis] >= min[axis] {
+                            let a = voxels.get(x);
+                            (!a.is_air() && voxels.is_face_visible(a, x[0], x[1], x[2], side))
+                                .then_some(a)
                         } else {
                             None
                         };
 
-                        side = Side::try_from(axis + 3).unwrap();
-                        let b = if x[axis] < CHUNK_SIZE as i32 - 1 {
-                            if self.is_face_visible(x[0] + q[0], x[1] + q[1], x[2] + q[2], side) {
-                                let b = &self.blocks[Self::block_pos_to_index(
-                                    (x[0] + q[0]) as u32,
-                                    (x[1] + q[1]) as u32,
-                                    (x[2] + q[2]) as u32,
-                                )];
-                                if b.id == 0 {
-                                    None
-                                } else {
-                                    Some(b)
-                                }
-                            } else {
-                                None
-                            }
+                        let side = Side::try_from(axis + 3).unwrap();
+                        let b = if x[axis] + 1 < max[axis] {
+                            let b = voxels.get([x[0] + q[0], x[1] + q[1], x[2] + q[2]]);
+                            (!b.is_air()
+                                && voxels.is_face_visible(
+                                    b,
+                                    x[0] + q[0],
+                                    x[1] + q[1],
+                                    x[2] + q[2],
+                                    side,
+                                ))
+                            .then_some(b)
                         } else {
                             None
                         };
 
-                        if a.is_some() == b.is_some() {
-                            mask[n] = MaskValue::None;
-                        } else if a.is_some() {
-                            mask[n] = MaskValue::Positive(a.unwrap());
-                        } else {
-                            mask[n] = MaskValue::Negative(b.unwrap());
-                        }
+                        positive_mask[n] = match a {
+                            Some(a) => {
+                                let front = [x[0] + q[0], x[1] + q[1], x[2] + q[2]];
+                                MaskValue::Positive(a, voxels.face_ao(front, axis))
+                            }
+                            None => MaskValue::None,
+                        };
+                        negative_mask[n] = match b {
+                            Some(b) => MaskValue::Negative(b, voxels.face_ao(x, axis)),
+                            None => MaskValue::None,
+                        };
 
                         n += 1;
                     }
                 }
 
                 x[axis] += 1;
-                let mut n = 0;
 
-                for j in 0..CHUNK_SIZE {
-                    let mut i = 0;
-                    while i < CHUNK_SIZE {
-                        let c = mask[n];
-                        if !c.is_none() {
-                            let mut width = 1;
-                            while i + width < CHUNK_SIZE && c == mask[n + width] {
-                                width += 1;
-                            }
+                // the faces of both masks can be on the same cell between two different
+                // transparent blocks
+                for mask in [&mut positive_mask, &mut negative_mask] {
+                    let mut n = 0;
+
+                    for j in 0..height {
+                        let mut i = 0;
+                        while i < width {
+                            let c = mask[n];
+                            if !c.is_none() {
+                                let mut quad_width = 1;
+                                while i + quad_width < width && c == mask[n + quad_width] {
+                                    quad_width += 1;
+                                }
 
-                            let mut done = false;
-                            let mut height = 1;
-                            while !done && height + j < CHUNK_SIZE {
-                                let mut k = 0;
-                                while k < width {
-                                    if mask[n + k + height * CHUNK_SIZE] != c {
-                                        done = true;
-                                        break;
+                                let mut done = false;
+                                let mut quad_height = 1;
+                                while !done && quad_height + j < height {
+                                    let mut k = 0;
+                                    while k < quad_width {
+                                        if mask[n + k + quad_height * width] != c {
+                                            done = true;
+                                            break;
+                                        }
+                                        k += 1;
+                                    }
+                                    if !done {
+                                        quad_height += 1;
                                     }
-                                    k += 1;
                                 }
-                                if !done {
-                                    height += 1;
+
+                                x[u] = min[u] + i as i32;
+                                x[v] = min[v] + j as i32;
+                                let mut du = [0i32; 3];
+                                let mut dv = [0i32; 3];
+
+                                if c.is_positive() {
+                                    dv[v] = quad_height as i32;
+                                    du[u] = quad_width as i32;
+                                } else {
+                                    du[v] = quad_height as i32;
+                                    dv[u] = quad_width as i32;
                                 }
-                            }
 
-                            x[u] = i as i32;
-                            x[v] = j as i32;
-                            let mut du = [0i32; 3];
-                            let mut dv = [0i32; 3];
+                                // emit quad
+                                {
+                                    let color = registry.color(c.block().unwrap());
+                                    let texture = registry.texture(c.block().unwrap());
+                                    let transparent = registry.is_transparent(c.block().unwrap());
+                                    let side = Side::try_from(if c.is_positive() {
+                                        axis
+                                    } else {
+                                        axis + 3
+                                    })
+                                    .unwrap();
 
-                            if c.is_positive() {
-                                dv[v] = height as i32;
-                                du[u] = width as i32;
-                            } else {
-                                du[v] = height as i32;
-                                dv[u] = width as i32;
-                            }
+                                    if vertices_index + 4 > vertices.len()
+                                        || indices_index + transparent_indices.len() + 6
+                                            > indices.len()
+                                    {
+                                        return Err(MeshOverflow.into());
+                                    }
+                                    // corners of face_ao in the order of the vertices
+                                    let ao_corners = if c.is_positive() {
+                                        [0, 1, 2, 3]
+                                    } else {
+                                        [0, 3, 2, 1]
+                                    };
+                                    let ao = ao_corners.map(|i| (c.ao() >> (i * 2)) & 0b11);
+                                    for (corner, ao) in [
+                                        vec3(x[0], x[1], x[2]),
+                                        vec3(x[0] + du[0], x[1] + du[1], x[2] + du[2]),
+                                        vec3(
+                                            x[0] + du[0] + dv[0],
+                                            x[1] + du[1] + dv[1],
+                                            x[2] + du[2] + dv[2],
+                                        ),
+                                        vec3(x[0] + dv[0], x[1] + dv[1], x[2] + dv[2]),
+                                    ]
+                                    .into_iter()
+                                    .zip(ao)
+                                    {
+                                        // the texture is repeated once per block of the merged quad
+                                        let uv =
+                                            vec2(corner[u] - x[u], corner[v] - x[v]) * voxels.scale;
+                                        // relative to the chunk origin, pushed with the draw
+                                        vertices[vertices_index] = Vertex::new(
+                                            corner * voxels.scale,
+                                            color,
+                                            side as u8,
+                                            ao,
+                                            uv,
+                                            texture,
+                                        );
+                                        vertices_index += 1;
+                                    }
 
-                            // emit quad
-                            {
-                                let color: TVec3<u8> = vec3(255, 255, 255);
-                                let light_modifier = match side {
-                                    Side::NORTH | Side::SOUTH => 8,
-                                    Side::WEST | Side::EAST => 6,
-                                    Side::TOP => 10,
-                                    Side::BOTTOM => 5,
-                                };
-
-                                for corner in [
-                                    vec3(x[0], x[1], x[2]),
-                                    vec3(x[0] + du[0], x[1] + du[1], x[2] + du[2]),
-                                    vec3(
-                                        x[0] + du[0] + dv[0],
-                                        x[1] + du[1] + dv[1],
-                                        x[2] + du[2] + dv[2],
-                                    ),
-                                    vec3(x[0] + dv[0], x[1] + dv[1], x[2] + dv[2]),
-                                ] {
-                                    vertices[vertices_index] = Vertex {
-                                        pos: corner
-                                            + vec3(
-                                                self.pos.x * CHUNK_SIZE as i32,
-                                                self.pos.y as i32 * CHUNK_SIZE as i32,
-                                                self.pos.z * CHUNK_SIZE as i32,
-                                            ),
-                                        color,
-                                        light_modifier,
+                                    // split the quad along the brightest diagonal so the
+                                    // occlusion is interpolated the same way on every face
+                                    let quad_indices = if ao[0] + ao[2] >= ao[1] + ao[3] {
+                                        [0, 1, 2, 2, 3, 0]
+                                    } else {
+                                        [1, 2, 3, 3, 0, 1]
                                     };
-                                    vertices_index += 1;
+                                    for i in quad_indices {
+                                        let index = indices_max + i as u32;
+                                        if transparent {
+                                            transparent_indices.push(index);
+                                        } else {
+                                            indices[indices_index] = index;
+                                            indices_index += 1;
+                                        }
+                                    }
+                                    indices_max += 4;
+                                }
+
+                                for l in 0..quad_height {
+                                    for k in 0..quad_width {
+                                        mask[n + k + l * width] = MaskValue::None;
+                                    }
                                 }
 
-                                [0, 1, 2, 2, 3, 0].iter().for_each(|i| {
-                                    indices[indices_index] = indices_max + *i as u32;
-                                    indices_index += 1;
-                                });
-                                indices_max += 4;
+                                i += quad_width;
+                                n += quad_width;
+                            } else {
+                                n += 1;
+                                i += 1;
                             }
+                        }
+                    }
+                }
+            }
+        }
+
+        indices[indices_index..indices_index + transparent_indices.len()]
+            .copy_from_slice(&transparent_indices);
+        Ok((
+            vertices_index,
+            indices_index + transparent_indices.len(),
+            transparent_indices.len(),
+        ))
+    }
+
+    #[inline]
+    pub const fn size() -> [i32; 3] {
+        [
+            CHUNK_SIZE_X as i32,
+            CHUNK_SIZE_Y as i32,
+            CHUNK_SIZE_Z as i32,
+        ]
+    }
+}
+
+// The blocks seen by the mesher. With a level of detail each voxel is a cube of 2^lod blocks
+// merged together: it is solid if any of its blocks is, with the most common of them.
+// This never makes the terrain of a coarser chunk lower than the blocks of its neighbors see.
+struct Voxels<'a> {
+    blocks: &'a BlockStorage,
+    // the merged voxels at a level of detail, the blocks are used as is at full resolution
+    merged: Option<Vec<Block>>,
+    // layer of voxels of the neighbor on each side, None if the neighbor isn't loaded
+    borders: [Option<Cow<'a, [Block]>>; 6],
+    // blocks on a side of the voxels
+    scale: i32,
+    // in voxels
+    size: [i32; 3],
+}
+
+impl<'a> Voxels<'a> {
+    fn new(blocks: &'a BlockStorage, borders: &'a [Option<Box<[Block]>>; 6], lod: u32) -> Self {
+        let scale = 1 << lod;
+        let size = Chunk::size().map(|size| size / scale);
+        if lod == 0 {
+            return Self {
+                blocks,
+                merged: None,
+                borders: borders.each_ref().map(|b| b.as_deref().map(Cow::Borrowed)),
+                scale,
+                size,
+            };
+        }
 
-                            for l in 0..height {
-                                for k in 0..width {
-                                    mask[n + k + l * CHUNK_SIZE] = MaskValue::None;
+        let mut merged = Vec::with_capacity((size[0] * size[1] * size[2]) as usize);
+        let mut counts: Vec<(Block, u32)> = Vec::new();
+        for x in 0..size[0] {
+            for y in 0..size[1] {
+                for z in 0..size[2] {
+                    counts.clear();
+                    // from the top so the grass wins over the dirt below it on a tie
+                    for dy in (0..scale).rev() {
+                        for dx in 0..scale {
+                            for dz in 0..scale {
+                                let block = blocks.get(
+                                    (x * scale + dx) as usize,
+                                    (y * scale + dy) as usize,
+                                    (z * scale + dz) as usize,
+                                );
+                                if block.is_air() {
+                                    continue;
+                                }
+                                match counts.iter_mut().find(|(b, _)| *b == block) {
+                                    Some((_, count)) => *count += 1,
+                                    None => counts.push((block, 1)),
                                 }
                             }
-
-                            i += width;
-                            n += width;
-                        } else {
-                            n += 1;
-                            i += 1;
                         }
                     }
+                    // max_by_key keeps the last of the equal ones
+                    let block = counts.iter().rev().max_by_key(|(_, count)| *count);
+                    merged.push(block.map_or(Block::AIR, |(block, _)| *block));
                 }
             }
         }
 
-        self.vertices_count = vertices_index;
-        self.indices_count = indices_index;
+        let borders = std::array::from_fn(|side| {
+            let border = borders[side].as_deref()?;
+            let axis = side % 3;
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let mut voxels = Vec::with_capacity((size[u] * size[v]) as usize);
+            for a in 0..size[u] {
+                for b in 0..size[v] {
+                    let mut pos = [0; 3];
+                    let blocks = (0..scale * scale).map(|i| {
+                        pos[u] = a * scale + i / scale;
+                        pos[v] = b * scale + i % scale;
+                        border[Chunk::border_index(axis, pos)]
+                    });
+                    voxels.push(Self::covering(blocks));
+                }
+            }
+            Some(Cow::Owned(voxels))
+        });
+
+        Self {
+            blocks,
+            merged: Some(merged),
+            borders,
+            scale,
+            size,
+        }
+    }
+
+    // Voxel of the neighbor layer made of blocks, it hides a face only if all of them
+    // would. The faces along a finer neighbor are drawn where it doesn't cover them
+    // instead of leaving holes between the chunks.
+    fn covering(mut blocks: impl Iterator<Item = Block>) -> Block {
+        let registry = BlockRegistry::global();
+        let opaque = |block: Block| !block.is_air() && !registry.is_transparent(block);
+        let first = blocks.next().unwrap_or(Block::AIR);
+        let (mut same, mut all_opaque) = (true, opaque(first));
+        for block in blocks {
+            same &= block == first;
+            all_opaque &= opaque(block);
+        }
+        if same || all_opaque {
+            first
+        } else {
+            Block::AIR
+        }
+    }
 
-        Ok(())
+    // pos inside the voxels
+    #[inline]
+    fn get(&self, pos: [i32; 3]) -> Block {
+        match &self.merged {
+            Some(merged) => {
+                merged[((pos[0] * self.size[1] + pos[1]) * self.size[2] + pos[2]) as usize]
+            }
+            None => self
+                .blocks
+                .get(pos[0] as usize, pos[1] as usize, pos[2] as usize),
+        }
     }
 
+    // index in a border of the position, the coordinate along the axis is ignored
     #[inline]
-    fn block_pos_to_index(x: u32, y: u32, z: u32) -> usize {
-        (x as usize) * CHUNK_SIZE * CHUNK_SIZE + (y as usize) * CHUNK_SIZE + (z as usize)
+    fn border_index(&self, axis: usize, pos: [i32; 3]) -> usize {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        (pos[u] * self.size[v] + pos[v]) as usize
+    }
+
+    // Whether the block at the position is solid, voxels outside of the chunk are known only
+    // from the borders and are considered air in the corners.
+    fn is_solid(&self, pos: [i32; 3]) -> bool {
+        let size = self.size;
+        let mut outside = (0..3).filter(|&axis| pos[axis] < 0 || pos[axis] >= size[axis]);
+        match (outside.next(), outside.next()) {
+            (None, _) => !self.get(pos).is_air(),
+            (Some(axis), None) => {
+                let side = if pos[axis] < 0 { axis + 3 } else { axis };
+                match &self.borders[side] {
+                    Some(border) => !border[self.border_index(axis, pos)].is_air(),
+                    None => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    // Ambient occlusion of the four corners of a face, 2 bits per corner from 0 (occluded)
+    // to 3 (not occluded). `air` is the position in front of the face and the corners are
+    // in the order (-u, -v), (+u, -v), (+u, +v), (-u, +v) with u and v the axes after `axis`.
+    fn face_ao(&self, air: [i32; 3], axis: usize) -> u8 {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut ao = 0;
+        for (i, (du, dv)) in [(-1, -1), (1, -1), (1, 1), (-1, 1)].into_iter().enumerate() {
+            let mut side_u = air;
+            side_u[u] += du;
+            let mut side_v = air;
+            side_v[v] += dv;
+            let mut corner = side_u;
+            corner[v] += dv;
+
+            let (side_u, side_v, corner) = (
+                self.is_solid(side_u),
+                self.is_solid(side_v),
+                self.is_solid(corner),
+            );
+            let value = if side_u && side_v {
+                0
+            } else {
+                3 - side_u as u8 - side_v as u8 - corner as u8
+            };
+            ao |= value << (i * 2);
+        }
+        ao
     }
 
-    fn is_face_visible(&self, x: i32, y: i32, z: i32, side: Side) -> bool {
+    // The face of the block on a side is hidden by an opaque neighbor or by the same
+    // transparent block, so the faces inside water or glass aren't drawn.
+    fn is_face_visible(&self, block: Block, x: i32, y: i32, z: i32, side: Side) -> bool {
         let (x, y, z) = match side {
-            Side::NORTH => (x + 1, y, z),
-            Side::SOUTH => (x - 1, y, z),
-            Side::EAST => (x, y, z + 1),
-            Side::WEST => (x, y, z - 1),
-            Side::TOP => (x, y + 1, z),
-            Side::BOTTOM => (x, y - 1, z),
+            Side::North => (x + 1, y, z),
+            Side::South => (x - 1, y, z),
+            Side::East => (x, y, z + 1),
+            Side::West => (x, y, z - 1),
+            Side::Top => (x, y + 1, z),
+            Side::Bottom => (x, y - 1, z),
         };
-        if x < 0
-            || x >= CHUNK_SIZE as i32
-            || y < 0
-            || y >= CHUNK_SIZE as i32
-            || z < 0
-            || z >= CHUNK_SIZE as i32
-        {
-            return true;
-        }
-        let block = self.blocks[Self::block_pos_to_index(x as u32, y as u32, z as u32)];
-        block.id == 0
+        let pos = [x, y, z];
+        let size = self.size;
+        // face neighbors are outside of the chunk on one axis at most
+        let neighbor =
+            if let Some(axis) = (0..3).find(|&axis| pos[axis] < 0 || pos[axis] >= size[axis]) {
+                let side = if pos[axis] < 0 { axis + 3 } else { axis };
+                match &self.borders[side] {
+                    Some(border) => border[self.border_index(axis, pos)],
+                    None => return true,
+                }
+            } else {
+                self.get(pos)
+            };
+        neighbor.is_air() || (neighbor != block && BlockRegistry::global().is_transparent(neighbor))
     }
 }
 
@@ -312,14 +1049,15 @@ impl Drop for Chunk {
     }
 }
 
+// the discriminant is the face index of the vertices, the shader derives the normal from it
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum Side {
-    NORTH,  // x+
-    TOP,    // y+
-    EAST,   // z+
-    SOUTH,  // x-
-    BOTTOM, // y-
-    WEST,   // z-
+    North,  // x+
+    Top,    // y+
+    East,   // z+
+    South,  // x-
+    Bottom, // y-
+    West,   // z-
 }
 
 impl TryFrom<usize> for Side {
@@ -328,13 +1066,250 @@ impl TryFrom<usize> for Side {
     #[inline(always)]
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(Side::NORTH),
-            1 => Ok(Side::TOP),
-            2 => Ok(Side::EAST),
-            3 => Ok(Side::SOUTH),
-            4 => Ok(Side::BOTTOM),
-            5 => Ok(Side::WEST),
+            0 => Ok(Side::North),
+            1 => Ok(Side::Top),
+            2 => Ok(Side::East),
+            3 => Ok(Side::South),
+            4 => Ok(Side::Bottom),
+            5 => Ok(Side::West),
             _ => Err(anyhow!("Invalid side")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::generation::{GLASS, TERRAIN, WATER};
+
+    // chunk with the blocks below height filled with TERRAIN
+    fn filled(pos: ChunkPos, height: usize) -> Chunk {
+        let mut chunk = Chunk::new(pos);
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..height {
+                for z in 0..CHUNK_SIZE_Z {
+                    chunk.set_block(x, y, z, TERRAIN);
+                }
+            }
+        }
+        chunk
+    }
+
+    // vertices and index counts of a mesh
+    fn mesh(chunk: &mut Chunk, lod: u32) -> (Vec<Vertex>, usize) {
+        let zero = Vertex::new(vec3(0, 0, 0), vec3(0, 0, 0), 0, 0, vec2(0, 0), 0);
+        let mut vertices = vec![zero; 60000];
+        let mut indices = vec![0u32; 90000];
+        let (vertices_count, indices_count, _) =
+            chunk.mesh(&mut vertices, &mut indices, lod).unwrap();
+        assert!(indices[..indices_count]
+            .iter()
+            .all(|&i| (i as usize) < vertices_count));
+        vertices.truncate(vertices_count);
+        (vertices, indices_count)
+    }
+
+    #[test]
+    fn borders_cull_faces() {
+        let mut chunk = filled(ChunkPos { x: 0, y: 0, z: 0 }, CHUNK_SIZE_Y);
+        let neighbor = filled(ChunkPos { x: 1, y: 0, z: 0 }, CHUNK_SIZE_Y);
+        assert_eq!(mesh(&mut chunk, 0).1, 6 * 6);
+
+        // not meshed yet, nothing to queue
+        assert!(!chunk.set_border(0, Some(neighbor.border(3))));
+        assert_eq!(mesh(&mut chunk, 0).1, 5 * 6);
+        // the same border doesn't outdate the mesh
+        assert!(!chunk.set_border(0, Some(neighbor.border(3))));
+
+        for side in 1..6 {
+            chunk.set_border(side, Some(neighbor.border((side + 3) % 6)));
+        }
+        assert_eq!(mesh(&mut chunk, 0).1, 0);
+
+        // unloading a neighbor shows the faces on its side again
+        chunk.state = ChunkState::Ready;
+        assert!(chunk.set_border(1, None));
+        assert_eq!(chunk.state, ChunkState::Dirty);
+        assert_eq!(mesh(&mut chunk, 0).1, 6);
+    }
+
+    #[test]
+    fn ambient_occlusion() {
+        let mut chunk = Chunk::new(ChunkPos { x: 0, y: 0, z: 0 });
+        chunk.set_block(5, 5, 5, TERRAIN);
+        chunk.set_block(6, 6, 5, TERRAIN);
+        let voxels = Voxels::new(&chunk.blocks, &chunk.borders, 0);
+        // top face of (5, 5, 5), the corners on the +x side are next to (6, 6, 5)
+        assert_eq!(voxels.face_ao([5, 6, 5], 1), 3 | 3 << 2 | 2 << 4 | 2 << 6);
+        // bottom face, nothing around
+        assert_eq!(voxels.face_ao([5, 4, 5], 1), 0xFF);
+        // both sides solid, fully occluded whatever the corner
+        chunk.set_block(5, 6, 6, TERRAIN);
+        let voxels = Voxels::new(&chunk.blocks, &chunk.borders, 0);
+        assert_eq!(voxels.face_ao([5, 6, 5], 1) >> 4 & 3, 0);
+
+        let (vertices, _) = mesh(&mut chunk, 0);
+        let occluded = vertices.iter().filter(|v| v.pos_ao >> 30 != 3).count();
+        assert!(occluded > 0 && occluded < vertices.len());
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let pos = ChunkPos {
+            x: -17,
+            y: 3,
+            z: 40,
+        };
+        let mut chunk = filled(pos, 5);
+        chunk.set_block(5, 9, 2, Block::new(7));
+        chunk.edit_block(1, 2, 3, Block::new(9));
+        let data = chunk.serialize();
+
+        let loaded = Chunk::deserialize(pos, &data).unwrap();
+        assert_eq!(loaded.state, ChunkState::Generated);
+        assert!(loaded.blocks.iter().eq(chunk.blocks.iter()));
+        assert_eq!(loaded.edits, chunk.edits);
+        assert_eq!(loaded.serialize(), data);
+    }
+
+    #[test]
+    fn deserialize_invalid() {
+        let pos = ChunkPos { x: 0, y: 0, z: 0 };
+        let mut chunk = filled(pos, 5);
+        chunk.edit_block(1, 2, 3, Block::new(9));
+        let data = chunk.serialize();
+
+        for len in [0, 1, data.len() / 2, data.len() - 1] {
+            assert!(
+                Chunk::deserialize(pos, &data[..len]).is_err(),
+                "{len} bytes"
+            );
+        }
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(Chunk::deserialize(pos, &trailing).is_err());
+        let mut version = data.clone();
+        version[0] = SAVE_VERSION + 1;
+        assert!(Chunk::deserialize(pos, &version).is_err());
+    }
+
+    // indices and transparent indices counts, the same meshed whole or by sections
+    fn transparent_counts(blocks: &[((usize, usize, usize), Block)]) -> (usize, usize) {
+        let mut chunk = Chunk::new(ChunkPos { x: 0, y: 0, z: 0 });
+        for &((x, y, z), block) in blocks {
+            chunk.set_block(x, y, z, block);
+        }
+        let zero = Vertex::new(vec3(0, 0, 0), vec3(0, 0, 0), 0, 0, vec2(0, 0), 0);
+        let (mut vertices, mut indices) = (vec![zero; 20000], vec![0u32; 30000]);
+        let (_, full, full_transparent) = chunk.mesh_full(&mut vertices, &mut indices).unwrap();
+        chunk.dirty_sections = ALL_SECTIONS;
+        let (_, sections, sections_transparent) =
+            chunk.mesh_sections(&mut vertices, &mut indices).unwrap();
+        assert_eq!((full, full_transparent), (sections, sections_transparent));
+        (full, full_transparent)
+    }
+
+    #[test]
+    fn transparent_faces() {
+        // 6 indices per face
+        assert_eq!(transparent_counts(&[((5, 1, 5), WATER)]), (36, 36));
+        // no face inside the same transparent block
+        assert_eq!(
+            transparent_counts(&[((5, 1, 5), WATER), ((6, 1, 5), WATER)]),
+            (36, 36)
+        );
+        // but between two different ones
+        assert_eq!(
+            transparent_counts(&[((5, 1, 5), WATER), ((6, 1, 5), GLASS)]),
+            (72, 72)
+        );
+        // the opaque faces behind a transparent block are drawn, not the other way around
+        assert_eq!(
+            transparent_counts(&[((5, 0, 5), TERRAIN), ((5, 1, 5), WATER)]),
+            (36 + 30, 30)
+        );
+        assert_eq!(
+            transparent_counts(&[
+                ((5, 3, 5), TERRAIN),
+                ((5, 4, 5), WATER),
+                ((5, 5, 5), GLASS),
+                ((7, 3, 7), TERRAIN)
+            ]),
+            (72 + 66, 66)
+        );
+        assert_eq!(
+            transparent_counts(&[((5, 1, 5), TERRAIN), ((5, 2, 5), TERRAIN)]),
+            (36, 0)
+        );
+    }
+
+    #[test]
+    fn unequal_dimensions() {
+        // the chunk sizes are all the same, the voxels are indexed with their own
+        let size = [3, 5, 7];
+        let blocks = BlockStorage::default();
+        let zero = Vertex::new(vec3(0, 0, 0), vec3(0, 0, 0), 0, 0, vec2(0, 0), 0);
+        let (mut vertices, mut indices) = (vec![zero; 2000], vec![0u32; 3000]);
+        // boxes filling each axis in turn, the others are a single voxel thick
+        for full in [None, Some(0), Some(1), Some(2)] {
+            let extent: [i32; 3] = std::array::from_fn(|axis| {
+                if full.is_none_or(|f| f == axis) {
+                    size[axis]
+                } else {
+                    1
+                }
+            });
+            let mut merged = Vec::new();
+            for x in 0..size[0] {
+                for y in 0..size[1] {
+                    for z in 0..size[2] {
+                        let inside = x < extent[0] && y < extent[1] && z < extent[2];
+                        merged.push(if inside { TERRAIN } else { Block::AIR });
+                    }
+                }
+            }
+            let voxels = Voxels {
+                blocks: &blocks,
+                merged: Some(merged),
+                borders: Default::default(),
+                scale: 1,
+                size,
+            };
+            let (vertices_count, indices_count, _) =
+                Chunk::mesh_region(&voxels, [0; 3], size, &mut vertices, &mut indices).unwrap();
+            // a single quad per side of the box
+            assert_eq!(indices_count, 6 * 6, "{extent:?}");
+            let max = |shift: u32| {
+                vertices[..vertices_count]
+                    .iter()
+                    .map(|v| (v.pos_ao >> shift & 0x3FF) as i32)
+                    .max()
+            };
+            assert_eq!([max(0), max(10), max(20)], extent.map(Some));
+        }
+    }
+
+    #[test]
+    fn lod_meshes() {
+        let mut chunk = filled(ChunkPos { x: 0, y: 0, z: 0 }, 5);
+        // a voxel is solid if any of its blocks is, so the top rises to the voxel size
+        for (lod, top) in [(0, 5), (1, 6), (2, 8), (0, 5)] {
+            let (vertices, _) = mesh(&mut chunk, lod);
+            let y = |v: &Vertex| (v.pos_ao >> 10) & 0x3FF;
+            assert_eq!(vertices.iter().map(y).max(), Some(top), "lod {lod}");
+            // on the voxel grid
+            assert!(vertices
+                .iter()
+                .all(|v| (v.pos_ao & 0x3FF) % (1 << lod) == 0 && y(v) % (1 << lod) == 0));
+        }
+
+        // a voxel of the neighbor layer with some air doesn't hide the faces against it
+        let mut border = vec![TERRAIN; Chunk::border_len(0)].into_boxed_slice();
+        chunk.set_border(0, Some(border.clone()));
+        let (hidden, _) = mesh(&mut chunk, 1);
+        border[0] = Block::AIR;
+        chunk.set_border(0, Some(border));
+        let (partial, _) = mesh(&mut chunk, 1);
+        assert!(partial.len() > hidden.len());
+    }
+}
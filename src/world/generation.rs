@@ -0,0 +1,373 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+use nalgebra_glm::{vec3, TVec3};
+
+use crate::config::{
+    CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z, TERRAIN_AMPLITUDE, TERRAIN_BASE_HEIGHT,
+    TERRAIN_OCTAVES, TERRAIN_SCALE, WORLD_SEED,
+};
+
+use super::{Block, Chunk, ChunkPos};
+
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+// splitmix64 finalizer
+#[inline]
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// seed used if none is set in the config, different each session
+pub fn session_seed() -> u64 {
+    let seed = WORLD_SEED.unwrap_or_else(|| {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        mix(time.as_nanos() as u64)
+    });
+    info!("World seed: {seed}");
+    seed
+}
+
+// Hash of a world block position, only depends on the seed and the position
+// so it is the same whatever chunk asks for it.
+#[inline]
+pub fn position_hash(seed: u64, x: i32, y: i32, z: i32) -> u64 {
+    let mut h = mix(seed.wrapping_add(GOLDEN_GAMMA));
+    h = mix(h ^ x as u32 as u64);
+    h = mix(h ^ y as u32 as u64);
+    mix(h ^ z as u32 as u64)
+}
+
+// splitmix64 generator seeded from a world block position
+#[derive(Debug, Clone)]
+pub struct PositionRng {
+    state: u64,
+}
+
+impl PositionRng {
+    #[inline]
+    pub fn new(seed: u64, x: i32, y: i32, z: i32) -> Self {
+        Self {
+            state: position_hash(seed, x, y, z),
+        }
+    }
+
+    #[inline]
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix(self.state)
+    }
+
+    // uniform in [0, 1)
+    #[inline]
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // uniform in [min, max)
+    #[inline]
+    pub fn range(&mut self, min: i32, max: i32) -> i32 {
+        debug_assert!(min < max);
+        min + (self.next_u64() % (max - min) as u64) as i32
+    }
+}
+
+pub const TERRAIN: Block = Block::new(1);
+pub const WOOD: Block = Block::new(2);
+pub const LEAVES: Block = Block::new(3);
+pub const GRASS: Block = Block::new(4);
+// transparent, not generated yet
+pub const GLASS: Block = Block::new(5);
+pub const WATER: Block = Block::new(6);
+
+// Fills the blocks of a new chunk. The blocks of a position must only depend on the
+// generator and the position, a chunk is generated again each time it is loaded.
+pub trait TerrainGenerator: Send + Sync {
+    fn generate(&self, pos: ChunkPos, chunk: &mut Chunk);
+}
+
+// hills from a few octaves of value noise
+pub struct NoiseGenerator {
+    seed: u64,
+}
+
+impl NoiseGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    // height of the terrain column in blocks, the top block is at height - 1
+    pub fn height(&self, x: i32, z: i32) -> i32 {
+        let mut noise = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut scale = TERRAIN_SCALE;
+        for octave in 0..TERRAIN_OCTAVES {
+            let seed = mix(self.seed.wrapping_add(octave as u64 + 1));
+            noise += value_noise(seed, x as f32 / scale, z as f32 / scale) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5;
+            scale *= 0.5;
+        }
+        TERRAIN_BASE_HEIGHT + (noise / total * TERRAIN_AMPLITUDE).round() as i32
+    }
+}
+
+impl TerrainGenerator for NoiseGenerator {
+    #[profiling::function]
+    fn generate(&self, pos: ChunkPos, chunk: &mut Chunk) {
+        let origin = pos.origin();
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                let height = self.height(origin.x + x as i32, origin.z + z as i32) - origin.y;
+                for y in 0..height.clamp(0, CHUNK_SIZE_Y as i32) {
+                    let block = if y + 1 == height { GRASS } else { TERRAIN };
+                    chunk.set_block(x, y as usize, z, block);
+                }
+            }
+        }
+    }
+}
+
+// The diagonal valleys the chunks used to have, the same in every chunk.
+// Used by the benchmarks for a mesh that doesn't depend on the noise.
+pub struct DemoGenerator;
+
+impl TerrainGenerator for DemoGenerator {
+    fn generate(&self, _pos: ChunkPos, chunk: &mut Chunk) {
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                let height = ((x as i32 - z as i32).unsigned_abs() as usize).min(CHUNK_SIZE_Y - 1);
+                for y in 0..height {
+                    let block = if y + 1 == height { GRASS } else { TERRAIN };
+                    chunk.set_block(x, y, z, block);
+                }
+            }
+        }
+    }
+}
+
+// Smoothly interpolated random values at the integer positions, in [-1, 1].
+fn value_noise(seed: u64, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let lattice = |dx: i32, dz: i32| {
+        let hash = position_hash(seed, x0 as i32 + dx, 0, z0 as i32 + dz);
+        (hash >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    };
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (tx, tz) = (smooth(x - x0), smooth(z - z0));
+    let top = lattice(0, 0) + (lattice(1, 0) - lattice(0, 0)) * tx;
+    let bottom = lattice(0, 1) + (lattice(1, 1) - lattice(0, 1)) * tx;
+    top + (bottom - top) * tz
+}
+
+// chance for a surface column to get a tree
+const TREE_CHANCE: f32 = 0.01;
+
+// a block placed by a feature, in world coordinates
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureBlock {
+    pub pos: TVec3<i32>,
+    pub block: Block,
+}
+
+// Features only replace air and wood replaces leaves, so the result of
+// overlapping features does not depend on the order they are placed in.
+#[inline]
+fn can_replace(old: Block, new: Block) -> bool {
+    old.is_air() || (old == LEAVES && new == WOOD)
+}
+
+// Place the part of a feature block that is in the chunk, the edited blocks are kept.
+// Return true if the chunk was modified.
+pub fn place_feature_block(chunk: &mut Chunk, feature: &FeatureBlock) -> bool {
+    match ChunkPos::from_block_pos(feature.pos) {
+        Some((pos, (x, y, z)))
+            if pos == chunk.pos
+                && !chunk.edits.contains_key(&(x, y, z))
+                && can_replace(chunk.get_block(x, y, z), feature.block) =>
+        {
+            chunk.set_block(x, y, z, feature.block);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Features whose origin is in the chunk. They only depend on the seed and the
+// chunk terrain so they are the same whenever the chunk is generated.
+pub fn chunk_features(seed: u64, chunk: &Chunk) -> Vec<FeatureBlock> {
+    let mut features = Vec::new();
+    let origin = chunk.pos.origin();
+
+    for x in 0..CHUNK_SIZE_X {
+        for z in 0..CHUNK_SIZE_Z {
+            // the top of the column must be in this chunk, with air above
+            let surface = match (0..CHUNK_SIZE_Y - 1)
+                .rev()
+                .find(|&y| !chunk.get_block(x, y, z).is_air())
+            {
+                Some(y)
+                    if chunk.get_block(x, y, z) == GRASS
+                        && chunk.get_block(x, y + 1, z).is_air() =>
+                {
+                    y
+                }
+                _ => continue,
+            };
+            let base = origin + vec3(x as i32, surface as i32 + 1, z as i32);
+            let mut rng = PositionRng::new(seed, base.x, base.y, base.z);
+            if rng.next_f32() < TREE_CHANCE {
+                tree(&mut rng, base, &mut features);
+            }
+        }
+    }
+
+    features
+}
+
+fn tree(rng: &mut PositionRng, base: TVec3<i32>, features: &mut Vec<FeatureBlock>) {
+    let height = rng.range(4, 7);
+
+    for dy in height - 2..=height {
+        let radius: i32 = if dy == height { 1 } else { 2 };
+        for dx in -radius..=radius {
+            for dz in -radius..=radius {
+                // round the corners of the big layers
+                if radius == 2 && dx.abs() == 2 && dz.abs() == 2 {
+                    continue;
+                }
+                features.push(FeatureBlock {
+                    pos: base + vec3(dx, dy, dz),
+                    block: LEAVES,
+                });
+            }
+        }
+    }
+    for dy in 0..height {
+        features.push(FeatureBlock {
+            pos: base + vec3(0, dy, 0),
+            block: WOOD,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generated(generator: &NoiseGenerator, pos: ChunkPos) -> Chunk {
+        let mut chunk = Chunk::new(pos);
+        generator.generate(pos, &mut chunk);
+        chunk
+    }
+
+    fn feature_blocks(features: &[FeatureBlock]) -> Vec<(TVec3<i32>, Block)> {
+        features.iter().map(|f| (f.pos, f.block)).collect()
+    }
+
+    #[test]
+    fn position_rng_deterministic() {
+        // the blocks on both sides of a chunk boundary, in both orders
+        let positions: Vec<_> = (-20..20)
+            .flat_map(|x| (14..18).map(move |z| (x, CHUNK_SIZE_Y as i32 - 1, z)))
+            .collect();
+        let forward: Vec<_> = positions
+            .iter()
+            .map(|&(x, y, z)| position_hash(42, x, y, z))
+            .collect();
+        let backward: Vec<_> = positions
+            .iter()
+            .rev()
+            .map(|&(x, y, z)| position_hash(42, x, y, z))
+            .collect();
+        assert!(forward.iter().eq(backward.iter().rev()));
+
+        let sequence = |seed, x, y, z| {
+            let mut rng = PositionRng::new(seed, x, y, z);
+            (0..8).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(42, 15, 3, -1), sequence(42, 15, 3, -1));
+        // neighbors across the boundary, and the other seeds, differ
+        assert_ne!(sequence(42, 15, 3, -1), sequence(42, 16, 3, -1));
+        assert_ne!(sequence(42, 15, 3, -1), sequence(42, 15, 3, 0));
+        assert_ne!(sequence(42, 15, 3, -1), sequence(43, 15, 3, -1));
+
+        let mut rng = PositionRng::new(7, 0, 0, 0);
+        for _ in 0..1000 {
+            assert!((0.0..1.0).contains(&rng.next_f32()));
+            assert!((4..7).contains(&rng.range(4, 7)));
+        }
+    }
+
+    #[test]
+    fn features_independent_of_generation_order() {
+        let generator = NoiseGenerator::new(42);
+        let positions: Vec<_> = (0..8).map(|x| ChunkPos { x, y: 3, z: 0 }).collect();
+        let forward: Vec<_> = positions
+            .iter()
+            .map(|&pos| chunk_features(42, &generated(&generator, pos)))
+            .collect();
+        let mut backward: Vec<_> = positions
+            .iter()
+            .rev()
+            .map(|&pos| chunk_features(42, &generated(&generator, pos)))
+            .collect();
+        backward.reverse();
+        assert!(forward.iter().any(|features| !features.is_empty()));
+        for (a, b) in forward.iter().zip(&backward) {
+            assert_eq!(feature_blocks(a), feature_blocks(b));
+        }
+    }
+
+    #[test]
+    fn noise_heights() {
+        let generator = NoiseGenerator::new(42);
+        let (mut min, mut max, mut step) = (i32::MAX, i32::MIN, 0);
+        for x in -500..500 {
+            for z in (-500..500).step_by(7) {
+                let height = generator.height(x, z);
+                min = min.min(height);
+                max = max.max(height);
+                step = step.max((height - generator.height(x + 1, z)).abs());
+            }
+        }
+        // within the amplitude and smooth
+        let amplitude = TERRAIN_AMPLITUDE as i32;
+        assert!(min >= TERRAIN_BASE_HEIGHT - amplitude && max <= TERRAIN_BASE_HEIGHT + amplitude);
+        assert!(max - min > amplitude / 2);
+        assert!(step <= 3);
+
+        // only depends on the seed
+        let heights = |seed| {
+            (0..50)
+                .map(|x| NoiseGenerator::new(seed).height(x, -x))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(heights(42), heights(42));
+        assert_ne!(heights(42), heights(43));
+    }
+
+    #[test]
+    fn generate_fills_below_height() {
+        let generator = NoiseGenerator::new(42);
+        let pos = ChunkPos { x: 3, y: 3, z: -2 };
+        let chunk = generated(&generator, pos);
+        let origin = pos.origin();
+        for (x, z) in [(0, 0), (5, 11), (15, 15)] {
+            let height = generator.height(origin.x + x as i32, origin.z + z as i32) - origin.y;
+            for y in 0..CHUNK_SIZE_Y {
+                let expected = match y as i32 {
+                    y if y + 1 == height => GRASS,
+                    y if y < height => TERRAIN,
+                    _ => Block::AIR,
+                };
+                assert_eq!(chunk.get_block(x, y, z), expected);
+            }
+        }
+    }
+}
@@ -1,5 +1,12 @@
 mod chunk;
+pub mod generation;
+mod palette;
+mod registry;
+mod storage;
+#[allow(clippy::module_inception)]
 mod world;
 
 pub use chunk::*;
+pub use registry::{BlockInfo, BlockRegistry};
+pub use storage::{LoadMode, Storage};
 pub use world::{ChunkPos, World};
@@ -0,0 +1,173 @@
+use crate::config::{CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z, CHUNK_VOLUME};
+
+use super::Block;
+
+// Blocks of a chunk as a palette of the different blocks and the palette index of each
+// block, packed with just enough bits for the palette. Entries don't span two words.
+// A chunk of a single block has no indices at all.
+// The palette only grows, a block no longer used keeps its entry until the chunk is dropped.
+#[derive(Clone)]
+pub struct BlockStorage {
+    palette: Vec<Block>,
+    bits: u32, // bits per index, 0 with a single block in the palette
+    data: Vec<u64>,
+}
+
+impl BlockStorage {
+    // chunk filled with a single block
+    pub fn filled(block: Block) -> Self {
+        Self {
+            palette: vec![block],
+            bits: 0,
+            data: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, x: usize, y: usize, z: usize) -> Block {
+        self.palette[self.palette_index(Self::index(x, y, z))]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block: Block) {
+        let palette_index = match self.palette.iter().position(|b| *b == block) {
+            Some(palette_index) => palette_index,
+            None => {
+                self.palette.push(block);
+                let bits = usize::BITS - (self.palette.len() - 1).leading_zeros();
+                if bits > self.bits {
+                    self.repack(bits);
+                }
+                self.palette.len() - 1
+            }
+        };
+        self.set_palette_index(Self::index(x, y, z), palette_index);
+    }
+
+    // blocks in x, y, z order, z changing first
+    pub fn iter(&self) -> impl Iterator<Item = Block> + '_ {
+        (0..CHUNK_VOLUME).map(|i| self.palette[self.palette_index(i)])
+    }
+
+    #[inline]
+    fn index(x: usize, y: usize, z: usize) -> usize {
+        debug_assert!(x < CHUNK_SIZE_X && y < CHUNK_SIZE_Y && z < CHUNK_SIZE_Z);
+        x * CHUNK_SIZE_Y * CHUNK_SIZE_Z + y * CHUNK_SIZE_Z + z
+    }
+
+    #[inline]
+    fn per_word(bits: u32) -> usize {
+        (u64::BITS / bits) as usize
+    }
+
+    #[inline]
+    fn palette_index(&self, i: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+        let per_word = Self::per_word(self.bits);
+        let shift = (i % per_word) as u32 * self.bits;
+        ((self.data[i / per_word] >> shift) & ((1 << self.bits) - 1)) as usize
+    }
+
+    #[inline]
+    fn set_palette_index(&mut self, i: usize, palette_index: usize) {
+        if self.bits == 0 {
+            return;
+        }
+        let per_word = Self::per_word(self.bits);
+        let shift = (i % per_word) as u32 * self.bits;
+        let word = &mut self.data[i / per_word];
+        *word &= !(((1 << self.bits) - 1) << shift);
+        *word |= (palette_index as u64) << shift;
+    }
+
+    // repack the indices with more bits per index
+    fn repack(&mut self, bits: u32) {
+        let mut repacked = Self {
+            palette: Vec::new(),
+            bits,
+            data: vec![0; CHUNK_VOLUME.div_ceil(Self::per_word(bits))],
+        };
+        for i in 0..CHUNK_VOLUME {
+            repacked.set_palette_index(i, self.palette_index(i));
+        }
+        self.bits = bits;
+        self.data = repacked.data;
+    }
+}
+
+// blocks in the order of iter
+impl FromIterator<Block> for BlockStorage {
+    fn from_iter<T: IntoIterator<Item = Block>>(blocks: T) -> Self {
+        let mut blocks = blocks.into_iter();
+        let mut storage = Self::filled(blocks.next().unwrap_or(Block::AIR));
+        for (i, block) in blocks.enumerate() {
+            let i = i + 1;
+            let (x, y, z) = (
+                i / (CHUNK_SIZE_Y * CHUNK_SIZE_Z),
+                i / CHUNK_SIZE_Z % CHUNK_SIZE_Y,
+                i % CHUNK_SIZE_Z,
+            );
+            storage.set(x, y, z, block);
+        }
+        storage
+    }
+}
+
+impl Default for BlockStorage {
+    fn default() -> Self {
+        Self::filled(Block::AIR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_matches_reference() {
+        let mut storage = BlockStorage::default();
+        assert!(storage.data.is_empty());
+        let mut reference = vec![Block::AIR; CHUNK_VOLUME];
+        // linear congruential generator, the palette grows up to 21 blocks
+        let mut seed = 12345u64;
+        for n in 0..20000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let (x, y, z) = (
+                (seed >> 33) as usize % CHUNK_SIZE_X,
+                (seed >> 40) as usize % CHUNK_SIZE_Y,
+                (seed >> 50) as usize % CHUNK_SIZE_Z,
+            );
+            let block = Block::new(((seed >> 20) % (1 + n / 1000)) as u16);
+            storage.set(x, y, z, block);
+            reference[BlockStorage::index(x, y, z)] = block;
+            assert_eq!(storage.get(x, y, z), block);
+            if n % 997 == 0 {
+                assert!(storage.iter().eq(reference.iter().copied()));
+            }
+        }
+        assert!(storage.iter().eq(reference.iter().copied()));
+        assert_eq!(storage.bits, 5);
+
+        let collected: BlockStorage = reference.iter().copied().collect();
+        assert!(collected.iter().eq(reference.iter().copied()));
+    }
+
+    #[test]
+    fn single_block_has_no_indices() {
+        let storage: BlockStorage = std::iter::repeat_n(Block::new(3), CHUNK_VOLUME).collect();
+        assert_eq!((storage.bits, storage.data.len()), (0, 0));
+        assert!(storage.iter().all(|block| block == Block::new(3)));
+
+        // the same block again doesn't grow the palette
+        let mut storage = BlockStorage::filled(Block::AIR);
+        storage.set(1, 2, 3, Block::AIR);
+        assert_eq!(storage.bits, 0);
+        storage.set(1, 2, 3, Block::new(1));
+        assert_eq!(storage.bits, 1);
+        assert_eq!(storage.get(1, 2, 3), Block::new(1));
+        assert_eq!(storage.get(3, 2, 1), Block::AIR);
+    }
+}
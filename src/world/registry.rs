@@ -0,0 +1,117 @@
+use std::sync::OnceLock;
+
+use nalgebra_glm::{vec3, TVec3};
+
+use super::{
+    generation::{GLASS, GRASS, LEAVES, TERRAIN, WATER, WOOD},
+    Block,
+};
+
+#[derive(Debug, Clone)]
+pub struct BlockInfo {
+    pub name: &'static str,
+    pub color: TVec3<u8>, // tints the texture
+    pub texture: u16,     // tile in the texture atlas
+    // blended over what is behind it, the faces of the blocks behind aren't culled
+    pub transparent: bool,
+}
+
+#[derive(Debug)]
+pub struct BlockRegistry {
+    blocks: Vec<BlockInfo>,
+}
+
+static REGISTRY: OnceLock<BlockRegistry> = OnceLock::new();
+
+impl BlockRegistry {
+    pub fn empty() -> Self {
+        Self { blocks: Vec::new() }
+    }
+
+    #[inline]
+    pub fn global() -> &'static BlockRegistry {
+        REGISTRY.get_or_init(BlockRegistry::default)
+    }
+
+    // Register a block at the next free id.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        color: TVec3<u8>,
+        texture: u16,
+        transparent: bool,
+    ) -> Block {
+        let id = u16::try_from(self.blocks.len()).expect("Too many blocks registered");
+        self.blocks.push(BlockInfo {
+            name,
+            color,
+            texture,
+            transparent,
+        });
+        Block::new(id)
+    }
+
+    #[inline]
+    pub fn get(&self, block: Block) -> Option<&BlockInfo> {
+        self.blocks.get(block.id() as usize)
+    }
+
+    // unregistered blocks are magenta so they stand out
+    #[inline]
+    pub fn color(&self, block: Block) -> TVec3<u8> {
+        self.get(block).map_or(vec3(255, 0, 255), |info| info.color)
+    }
+
+    #[inline]
+    pub fn texture(&self, block: Block) -> u16 {
+        self.get(block).map_or(0, |info| info.texture)
+    }
+
+    #[inline]
+    pub fn is_transparent(&self, block: Block) -> bool {
+        self.get(block).is_some_and(|info| info.transparent)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        // the ids must match the constants the generation uses
+        assert_eq!(
+            registry.register("air", vec3(0, 0, 0), 0, false),
+            Block::AIR
+        );
+        assert_eq!(
+            registry.register("stone", vec3(125, 125, 125), 0, false),
+            TERRAIN
+        );
+        assert_eq!(registry.register("wood", vec3(110, 80, 45), 1, false), WOOD);
+        assert_eq!(
+            registry.register("leaves", vec3(50, 130, 40), 2, false),
+            LEAVES
+        );
+        assert_eq!(
+            registry.register("grass", vec3(95, 160, 60), 3, false),
+            GRASS
+        );
+        assert_eq!(
+            registry.register("glass", vec3(255, 255, 255), 4, true),
+            GLASS
+        );
+        assert_eq!(
+            registry.register("water", vec3(40, 90, 200), 5, true),
+            WATER
+        );
+        registry
+    }
+}
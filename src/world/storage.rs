@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+
+use super::{Chunk, ChunkPos};
+
+// chunks per side of a region
+const REGION_SIZE: i32 = 16;
+const REGION_MAGIC: &[u8; 4] = b"VXRG";
+const REGION_VERSION: u8 = 1;
+const SEED_FILE: &str = "seed";
+
+type RegionPos = (i32, u32, i32);
+
+// what is done with the chunks already saved in the directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadMode {
+    Continue,
+    // The saved chunks are ignored and the new world is generated from the saved seed.
+    // They are deleted when the new world is first written, not before.
+    Fresh,
+}
+
+// serialized chunks of a region file
+#[derive(Default)]
+struct Region {
+    chunks: HashMap<ChunkPos, Vec<u8>>,
+    dirty: bool, // changed since it was written
+}
+
+// Edited chunks saved in region files of REGION_SIZE^3 chunks. A region file is
+// read the first time one of its chunks is needed and written back by flush.
+pub struct Storage {
+    dir: PathBuf,
+    regions: HashMap<RegionPos, Region>,
+    // the region files on disk are from the previous world
+    stale_regions: bool,
+}
+
+impl Storage {
+    pub fn open(dir: impl AsRef<Path>, mode: LoadMode) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        info!("World saved in {} ({:?})", dir.display(), mode);
+        Ok(Self {
+            dir,
+            regions: HashMap::new(),
+            stale_regions: mode == LoadMode::Fresh,
+        })
+    }
+
+    // if starting a fresh world in dir would overwrite edited chunks
+    pub fn has_saved_chunks(dir: impl AsRef<Path>) -> Result<bool> {
+        match region_files(dir.as_ref()) {
+            Ok(files) => Ok(!files.is_empty()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // the chunks are generated from it, so it must be kept with them
+    pub fn load_seed(&self) -> Result<Option<u64>> {
+        match fs::read_to_string(self.dir.join(SEED_FILE)) {
+            Ok(seed) => Ok(Some(seed.trim().parse()?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_seed(&self, seed: u64) -> Result<()> {
+        write_atomic(&self.dir.join(SEED_FILE), seed.to_string().as_bytes())
+    }
+
+    pub fn load(&mut self, pos: ChunkPos) -> Result<Option<Chunk>> {
+        let region = self.region(pos)?;
+        match region.chunks.get(&pos) {
+            Some(data) => Ok(Some(Chunk::deserialize(pos, data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(&mut self, chunk: &Chunk) -> Result<()> {
+        let data = chunk.serialize();
+        let region = self.region(chunk.pos)?;
+        region.chunks.insert(chunk.pos, data);
+        region.dirty = true;
+        Ok(())
+    }
+
+    // for the chunks that are no longer edited
+    pub fn remove(&mut self, pos: ChunkPos) -> Result<()> {
+        let region = self.region(pos)?;
+        if region.chunks.remove(&pos).is_some() {
+            region.dirty = true;
+        }
+        Ok(())
+    }
+
+    // write the changed regions
+    pub fn flush(&mut self) -> Result<()> {
+        if self.stale_regions && self.regions.values().any(|region| region.dirty) {
+            for path in region_files(&self.dir)? {
+                fs::remove_file(path)?;
+            }
+            info!("Deleted the previous world in {}", self.dir.display());
+            self.stale_regions = false;
+        }
+        for (pos, region) in &mut self.regions {
+            if !region.dirty {
+                continue;
+            }
+            let path = region_path(&self.dir, *pos);
+            if region.chunks.is_empty() {
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            } else {
+                write_atomic(&path, &encode_region(&region.chunks))?;
+            }
+            debug!("Saved region {:?}, {} chunks", pos, region.chunks.len());
+            region.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn region(&mut self, pos: ChunkPos) -> Result<&mut Region> {
+        let region_pos = (
+            pos.x.div_euclid(REGION_SIZE),
+            pos.y / REGION_SIZE as u32,
+            pos.z.div_euclid(REGION_SIZE),
+        );
+        if !self.regions.contains_key(&region_pos) {
+            // the chunks of the previous world are not loaded
+            let region = if self.stale_regions {
+                Region::default()
+            } else {
+                match fs::read(region_path(&self.dir, region_pos)) {
+                    Ok(data) => Region {
+                        chunks: decode_region(&data)?,
+                        dirty: false,
+                    },
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Region::default(),
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            self.regions.insert(region_pos, region);
+        }
+        Ok(self.regions.get_mut(&region_pos).unwrap())
+    }
+}
+
+fn region_path(dir: &Path, (x, y, z): RegionPos) -> PathBuf {
+    dir.join(format!("r.{x}.{y}.{z}.bin"))
+}
+
+fn region_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if name.starts_with("r.") && name.ends_with(".bin") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+// a crash while writing leaves the previous file intact
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn encode_region(chunks: &HashMap<ChunkPos, Vec<u8>>) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(REGION_MAGIC);
+    data.push(REGION_VERSION);
+    data.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+    for (pos, chunk) in chunks {
+        data.extend_from_slice(&pos.x.to_le_bytes());
+        data.extend_from_slice(&pos.y.to_le_bytes());
+        data.extend_from_slice(&pos.z.to_le_bytes());
+        data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        data.extend_from_slice(chunk);
+    }
+    data
+}
+
+fn decode_region(data: &[u8]) -> Result<HashMap<ChunkPos, Vec<u8>>> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(REGION_MAGIC.len())? != REGION_MAGIC {
+        return Err(anyhow!("Not a region file"));
+    }
+    let version = reader.u8()?;
+    if version != REGION_VERSION {
+        return Err(anyhow!("Unsupported region version {version}"));
+    }
+    let count = reader.u32()?;
+    let mut chunks = HashMap::new();
+    for _ in 0..count {
+        let pos = ChunkPos {
+            x: reader.u32()? as i32,
+            y: reader.u32()?,
+            z: reader.u32()? as i32,
+        };
+        let len = reader.u32()? as usize;
+        chunks.insert(pos, reader.bytes(len)?.to_vec());
+    }
+    Ok(chunks)
+}
+
+// little endian values read from the start of a buffer
+pub(super) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!("Unexpected end of data"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::Block;
+
+    // empty directory removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("voxels-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn edited_chunk(pos: ChunkPos, block: Block) -> Chunk {
+        let mut chunk = Chunk::new(pos);
+        chunk.edit_block(1, 2, 3, block);
+        chunk
+    }
+
+    fn region() -> HashMap<ChunkPos, Vec<u8>> {
+        HashMap::from([
+            (ChunkPos { x: -1, y: 0, z: 15 }, vec![1, 2, 3]),
+            (
+                ChunkPos {
+                    x: 0,
+                    y: 15,
+                    z: -16,
+                },
+                vec![],
+            ),
+            (ChunkPos { x: 7, y: 3, z: 7 }, vec![0xFF; 300]),
+        ])
+    }
+
+    #[test]
+    fn region_round_trip() {
+        let chunks = region();
+        assert_eq!(decode_region(&encode_region(&chunks)).unwrap(), chunks);
+        assert!(decode_region(&encode_region(&HashMap::new()))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn decode_invalid_region() {
+        let data = encode_region(&region());
+        for len in [0, 3, REGION_MAGIC.len() + 1, data.len() / 2, data.len() - 1] {
+            assert!(decode_region(&data[..len]).is_err(), "{len} bytes");
+        }
+        let mut magic = data.clone();
+        magic[0] = b'X';
+        assert!(decode_region(&magic).is_err());
+        let mut version = data.clone();
+        version[REGION_MAGIC.len()] = REGION_VERSION + 1;
+        assert!(decode_region(&version).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new("save");
+        let pos = ChunkPos {
+            x: -17,
+            y: 3,
+            z: 40,
+        };
+        let mut storage = Storage::open(&dir.0, LoadMode::Continue).unwrap();
+        assert_eq!(storage.load_seed().unwrap(), None);
+        storage.save_seed(42).unwrap();
+        storage.save(&edited_chunk(pos, Block::new(9))).unwrap();
+        storage.flush().unwrap();
+
+        let mut storage = Storage::open(&dir.0, LoadMode::Continue).unwrap();
+        assert_eq!(storage.load_seed().unwrap(), Some(42));
+        let loaded = storage.load(pos).unwrap().unwrap();
+        assert_eq!(loaded.get_block(1, 2, 3), Block::new(9));
+        assert!(storage
+            .load(ChunkPos { x: 0, y: 0, z: 0 })
+            .unwrap()
+            .is_none());
+
+        // the region file of a region without chunks is deleted
+        storage.remove(pos).unwrap();
+        storage.flush().unwrap();
+        assert!(!Storage::has_saved_chunks(&dir.0).unwrap());
+    }
+
+    #[test]
+    fn fresh_replaces_on_first_write() {
+        let dir = TempDir::new("fresh");
+        assert!(!Storage::has_saved_chunks(&dir.0).unwrap());
+        let old = ChunkPos { x: 0, y: 0, z: 0 };
+        let new = ChunkPos { x: 100, y: 0, z: 0 };
+        let mut storage = Storage::open(&dir.0, LoadMode::Continue).unwrap();
+        storage.save(&edited_chunk(old, Block::new(9))).unwrap();
+        storage.flush().unwrap();
+        assert!(Storage::has_saved_chunks(&dir.0).unwrap());
+
+        // hidden from the fresh world
+        let mut storage = Storage::open(&dir.0, LoadMode::Fresh).unwrap();
+        assert!(storage.load(old).unwrap().is_none());
+        // nothing written yet, the old world is kept
+        storage.flush().unwrap();
+        let mut previous = Storage::open(&dir.0, LoadMode::Continue).unwrap();
+        assert!(previous.load(old).unwrap().is_some());
+
+        // deleted by the first write
+        storage.save(&edited_chunk(new, Block::new(1))).unwrap();
+        storage.flush().unwrap();
+        let mut storage = Storage::open(&dir.0, LoadMode::Continue).unwrap();
+        assert!(storage.load(old).unwrap().is_none());
+        assert!(storage.load(new).unwrap().is_some());
+    }
+}
@@ -1,19 +1,29 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    ops::Range,
+    path::Path,
     sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use nalgebra_glm::Vec3;
-use vulkanalia::vk::DeviceV1_0;
+use log::{debug, error, info, trace, warn};
+use nalgebra_glm::{vec3, TVec3, Vec3};
 
 use crate::{
-    config::{CHUNK_SIZE, RENDER_DISTANCE},
-    render::renderer::RendererData,
-    threads::MeshingThreadPool,
+    config::{
+        CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z, LOD_DISTANCES, MAX_RENDER_DISTANCE,
+        RENDER_DISTANCE, VERTICAL_RENDER_DISTANCE, WORLD_MAX_CHUNK_Y, WORLD_MIN_CHUNK_Y,
+        WORLD_TICK_BUDGET_MS,
+    },
+    threads::{GenerationThreadPool, MeshingThreadPool},
 };
 
-use super::Chunk;
+use super::{
+    generation::{self, NoiseGenerator, TerrainGenerator},
+    Block, Chunk, ChunkState, LoadMode, Storage,
+};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct ChunkPos {
@@ -22,109 +32,809 @@ pub struct ChunkPos {
     pub z: i32,
 }
 
+impl ChunkPos {
+    // neighbor sharing the side of a chunk, see Chunk::border for the sides
+    pub fn neighbor(self, side: usize) -> Option<Self> {
+        let mut pos = [self.x, self.y as i32, self.z];
+        pos[side % 3] += if side < 3 { 1 } else { -1 };
+        (pos[1] >= 0).then(|| Self {
+            x: pos[0],
+            y: pos[1] as u32,
+            z: pos[2],
+        })
+    }
+
+    // position of the chunk containing a world block, and of the block in the chunk
+    pub fn from_block_pos(pos: TVec3<i32>) -> Option<(Self, (usize, usize, usize))> {
+        if pos.y < 0 {
+            return None;
+        }
+        let [size_x, size_y, size_z] = Chunk::size();
+        Some((
+            Self {
+                x: pos.x.div_euclid(size_x),
+                y: (pos.y / size_y) as u32,
+                z: pos.z.div_euclid(size_z),
+            },
+            (
+                pos.x.rem_euclid(size_x) as usize,
+                (pos.y % size_y) as usize,
+                pos.z.rem_euclid(size_z) as usize,
+            ),
+        ))
+    }
+
+    #[inline]
+    pub fn distance_squared(self, other: Self) -> i32 {
+        let dx = self.x - other.x;
+        let dy = self.y as i32 - other.y as i32;
+        let dz = self.z - other.z;
+        dx * dx + dy * dy + dz * dz
+    }
+
+    // world position of the block at the corner of the chunk
+    #[inline]
+    pub fn origin(self) -> TVec3<i32> {
+        let [size_x, size_y, size_z] = Chunk::size();
+        vec3(self.x * size_x, self.y as i32 * size_y, self.z * size_z)
+    }
+}
+
 pub struct World {
     pub chunks: HashMap<ChunkPos, Arc<Mutex<Chunk>>>,
     pub chunks_to_render: Vec<Weak<Mutex<Chunk>>>,
+    pub seed: u64,
+    // time allowed for a tick, the chunks that don't fit are generated in the next ticks
+    pub tick_budget: Duration,
+    pub last_tick_time: Duration,
+    // incremented when chunks are added to the render list or unloaded
+    pub render_list_version: u64,
+    // edited chunks saved on disk, None if the world isn't saved
+    storage: Option<Storage>,
+    // fills the new chunks, replacing it only changes the chunks generated afterwards
+    pub generator: Arc<dyn TerrainGenerator>,
+    // chunks queued in the generation pool, the others it returns are dropped
+    generating: HashSet<ChunkPos>,
+    // player chunk of the last tick
+    center: Option<ChunkPos>,
+    // horizontal, in chunks, see set_render_distance
+    render_distance: usize,
 }
 
 impl World {
-    pub unsafe fn new() -> Result<Self> {
+    // save_dir is None to not save the world
+    pub fn new(save_dir: Option<&Path>, load_mode: LoadMode) -> Result<Self> {
+        let storage = save_dir
+            .map(|dir| Storage::open(dir, load_mode))
+            .transpose()?;
+        // the saved chunks were edited on the terrain of the saved seed
+        let seed = match storage
+            .as_ref()
+            .map(Storage::load_seed)
+            .transpose()?
+            .flatten()
+        {
+            Some(seed) => {
+                info!("World seed: {seed} (saved)");
+                seed
+            }
+            None => {
+                let seed = generation::session_seed();
+                if let Some(storage) = &storage {
+                    storage.save_seed(seed)?;
+                }
+                seed
+            }
+        };
         Ok(Self {
             chunks: HashMap::new(),
             chunks_to_render: Vec::new(),
+            seed,
+            tick_budget: Duration::from_secs_f32(WORLD_TICK_BUDGET_MS / 1000.0),
+            last_tick_time: Duration::ZERO,
+            render_list_version: 0,
+            storage,
+            generator: Arc::new(NoiseGenerator::new(seed)),
+            generating: HashSet::new(),
+            center: None,
+            render_distance: RENDER_DISTANCE,
         })
     }
 
+    fn generate(&self, pos: ChunkPos) -> Chunk {
+        let mut chunk = Chunk::new(pos);
+        self.generator.generate(pos, &mut chunk);
+        chunk.state = ChunkState::Generated;
+        chunk
+    }
+
+    // a saved chunk that can't be read is generated again
+    fn load_saved(&mut self, pos: ChunkPos) -> Option<Chunk> {
+        match self.storage.as_mut()?.load(pos) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Failed to load chunk {:?}, it is generated: {e}", pos);
+                None
+            }
+        }
+    }
+
+    // Keep the edited chunk on disk, or forget it if it isn't edited anymore.
+    // Written to the region files by the next flush.
+    fn save_chunk(&mut self, chunk: &Chunk) {
+        let Some(storage) = &mut self.storage else {
+            return;
+        };
+        let result = if chunk.is_edited() {
+            storage.save(chunk)
+        } else {
+            storage.remove(chunk.pos)
+        };
+        if let Err(e) = result {
+            error!("Failed to save chunk {:?}: {e}", chunk.pos);
+        }
+    }
+
+    fn flush_storage(&mut self) {
+        if let Some(Err(e)) = self.storage.as_mut().map(Storage::flush) {
+            error!("Failed to save the world: {e}");
+        }
+    }
+
+    // save the loaded edited chunks, on exit
+    pub fn save(&mut self) {
+        let chunks: Vec<_> = self.chunks.values().cloned().collect();
+        for chunk in chunks {
+            self.save_chunk(&chunk.lock().unwrap());
+        }
+        self.flush_storage();
+    }
+
     #[profiling::function]
     fn update_visible_chunks(
         &mut self,
-        data: &RendererData,
         meshing_pool: &MeshingThreadPool,
+        generation_pool: &GenerationThreadPool,
         player_pos: Vec3,
+        frame_count: u64,
+        start: Instant,
     ) -> Result<()> {
         let player_chunk_pos = ChunkPos {
-            x: (player_pos.x / CHUNK_SIZE as f32).floor() as i32,
-            y: (player_pos.y / CHUNK_SIZE as f32).floor() as u32,
-            z: (player_pos.z / CHUNK_SIZE as f32).floor() as i32,
+            x: (player_pos.x / CHUNK_SIZE_X as f32).floor() as i32,
+            y: (player_pos.y / CHUNK_SIZE_Y as f32).floor() as u32,
+            z: (player_pos.z / CHUNK_SIZE_Z as f32).floor() as i32,
         };
+        meshing_pool.set_center(player_chunk_pos);
 
+        let render_distance = self.render_distance;
         let mut chunks_to_destroy = Vec::new();
         {
             profiling::scope!("chunks_to_destroy");
             for pos in self.chunks.keys() {
-                if (pos.x - player_chunk_pos.x).abs() > (RENDER_DISTANCE + 2) as i32 {
-                    chunks_to_destroy.push(*pos);
-                }
-                if (pos.y as i32 - player_chunk_pos.y as i32).abs() > (RENDER_DISTANCE + 2) as i32 {
-                    chunks_to_destroy.push(*pos);
-                }
-                if (pos.z - player_chunk_pos.z).abs() > (RENDER_DISTANCE + 2) as i32 {
+                if Self::is_far(*pos, player_chunk_pos, render_distance) {
                     chunks_to_destroy.push(*pos);
                 }
             }
         }
 
-        {
-            profiling::scope!("wait queues");
-            unsafe {
-                data.device.queue_wait_idle(data.graphics_queue)?;
-                data.device.queue_wait_idle(data.present_queue)?;
-            }
+        let center_changed = self.center != Some(player_chunk_pos);
+        if center_changed {
+            profiling::scope!("cancel generation");
+            // the results of the chunks already being generated are dropped when received
+            self.generating
+                .retain(|pos| !Self::is_far(*pos, player_chunk_pos, render_distance));
+            generation_pool.cancel(|pos| Self::is_far(pos, player_chunk_pos, render_distance));
+            generation_pool.set_center(player_chunk_pos);
+            self.center = Some(player_chunk_pos);
         }
 
         {
             profiling::scope!("dropping chunks");
+            // the buffers may be used by the frames in flight, they are kept until these are done
             for pos in chunks_to_destroy {
-                self.chunks.remove(&pos);
+                if let Some(chunk) = self.chunks.remove(&pos) {
+                    let mut locked = chunk.lock().unwrap();
+                    self.save_chunk(&locked);
+                    if let Some(buffer) = locked.buffer.take() {
+                        meshing_pool.recycle_buffer(buffer);
+                    }
+                    self.render_list_version += 1;
+                }
+            }
+            self.flush_storage();
+            meshing_pool.recycle_old_buffers(frame_count);
+        }
+
+        if center_changed {
+            profiling::scope!("update lods");
+            // the nearest chunks are remeshed first by the meshing threads
+            for (pos, chunk) in &self.chunks {
+                let mut locked = chunk.lock().unwrap();
+                if locked.set_lod(Self::lod(*pos, player_chunk_pos)) {
+                    meshing_pool.mesh_thread(Arc::downgrade(chunk), *pos);
+                }
             }
         }
 
         {
             profiling::scope!("new chunks");
-            for x in (player_chunk_pos.x - RENDER_DISTANCE as i32)
-                ..(player_chunk_pos.x + RENDER_DISTANCE as i32)
+            for x in (player_chunk_pos.x - render_distance as i32)
+                ..(player_chunk_pos.x + render_distance as i32)
             {
-                for y in (player_chunk_pos.y as i32 - RENDER_DISTANCE as i32)
-                    ..(player_chunk_pos.y as i32 + RENDER_DISTANCE as i32)
-                {
-                    if y < 0 || y > 10 {
-                        continue;
-                    }
-                    for z in (player_chunk_pos.z - RENDER_DISTANCE as i32)
-                        ..(player_chunk_pos.z + RENDER_DISTANCE as i32)
+                for y in Self::vertical_range(player_chunk_pos.y) {
+                    for z in (player_chunk_pos.z - render_distance as i32)
+                        ..(player_chunk_pos.z + render_distance as i32)
                     {
-                        let pos = ChunkPos { x, y: y as u32, z };
-                        if let std::collections::hash_map::Entry::Vacant(e) = self.chunks.entry(pos)
-                        {
-                            let chunk = Chunk::new(pos)?;
-                            let chunk = Arc::new(Mutex::new(chunk));
-                            meshing_pool.mesh_thread(Arc::downgrade(&chunk));
-                            e.insert(chunk);
+                        let pos = ChunkPos { x, y, z };
+                        if !self.chunks.contains_key(&pos) && self.generating.insert(pos) {
+                            generation_pool.generate(pos, self.generator.clone());
                         }
                     }
                 }
             }
         }
+
         {
-            profiling::scope!("meshed chunks add to render");
-            for chunk in meshing_pool.try_iter() {
-                if chunk.upgrade().is_some() {
-                    self.chunks_to_render.push(chunk);
+            profiling::scope!("generated chunks");
+            // what doesn't fit in the budget is left in the channel for the next ticks
+            for mut chunk in generation_pool.try_iter() {
+                let pos = chunk.pos;
+                // left the render distance while it was generated
+                if !self.generating.remove(&pos) {
+                    continue;
+                }
+                // the terrain is still generated, the neighbors need the features of a saved chunk
+                let saved = self.load_saved(pos);
+                Self::decorate(
+                    &self.chunks_around(pos),
+                    self.seed,
+                    &mut chunk,
+                    saved,
+                    meshing_pool,
+                );
+                chunk.set_lod(Self::lod(pos, player_chunk_pos));
+                chunk.state = ChunkState::Meshing;
+                let chunk = Arc::new(Mutex::new(chunk));
+                self.chunks.insert(pos, chunk.clone());
+                // the features may have changed the blocks of the chunks around too
+                self.update_borders(pos, meshing_pool);
+                for neighbor in self.chunks_around(pos) {
+                    let neighbor_pos = neighbor.lock().unwrap().pos;
+                    self.update_borders(neighbor_pos, meshing_pool);
+                }
+                let queued = meshing_pool.mesh_thread(Arc::downgrade(&chunk), pos);
+
+                // always add one chunk so the world loads even on slow frames
+                if !queued {
+                    trace!("Meshing queue full, generated chunks deferred");
+                    break;
+                }
+                if start.elapsed() > self.tick_budget {
+                    trace!("Tick budget exceeded, generated chunks deferred");
+                    break;
                 }
             }
         }
+        self.add_meshed_chunks(meshing_pool);
 
         Ok(())
     }
 
+    #[profiling::function]
+    fn add_meshed_chunks(&mut self, meshing_pool: &MeshingThreadPool) {
+        for chunk in meshing_pool.try_iter() {
+            if chunk.upgrade().is_some() {
+                self.chunks_to_render.push(chunk);
+                self.render_list_version += 1;
+            }
+        }
+    }
+
+    // the chunks this far from the player chunk are unloaded
+    fn is_far(pos: ChunkPos, player_chunk_pos: ChunkPos, render_distance: usize) -> bool {
+        (pos.x - player_chunk_pos.x).abs() > (render_distance + 2) as i32
+            || pos.y.abs_diff(player_chunk_pos.y) > (VERTICAL_RENDER_DISTANCE + 2) as u32
+            || (pos.z - player_chunk_pos.z).abs() > (render_distance + 2) as i32
+    }
+
+    // level of detail of the chunk meshes from the distance to the player chunk
+    fn lod(pos: ChunkPos, player_chunk_pos: ChunkPos) -> u32 {
+        let distance = (pos.x - player_chunk_pos.x)
+            .unsigned_abs()
+            .max((pos.z - player_chunk_pos.z).unsigned_abs())
+            .max(pos.y.abs_diff(player_chunk_pos.y)) as usize;
+        LOD_DISTANCES
+            .iter()
+            .filter(|&&lod_distance| distance > lod_distance)
+            .count() as u32
+    }
+
+    #[inline]
+    pub fn render_distance(&self) -> usize {
+        self.render_distance
+    }
+
+    // Clamped between 1 and MAX_RENDER_DISTANCE. The next tick unloads the chunks out of
+    // a shorter distance or starts loading the ones of a longer one.
+    pub fn set_render_distance(&mut self, distance: usize) {
+        let distance = distance.clamp(1, MAX_RENDER_DISTANCE);
+        if distance != self.render_distance {
+            info!("Render distance: {distance} chunks");
+            self.render_distance = distance;
+            // the chunks queued for generation are filtered again
+            self.center = None;
+        }
+    }
+
+    // chunk heights loaded around the player chunk height, inside the world
+    fn vertical_range(player_y: u32) -> Range<u32> {
+        // signed so the range can go below the world before being clamped
+        let (player_y, distance) = (player_y as i64, VERTICAL_RENDER_DISTANCE as i64);
+        let min = (player_y - distance).max(WORLD_MIN_CHUNK_Y as i64);
+        let max = (player_y + distance).min(WORLD_MAX_CHUNK_Y as i64 + 1);
+        min as u32..max.max(min) as u32
+    }
+
+    // Exchange the layers on the sides of a loaded chunk with its loaded neighbors so the
+    // faces between them are culled. The chunks whose faces changed are queued for meshing.
+    fn update_borders(&self, pos: ChunkPos, meshing_pool: &MeshingThreadPool) {
+        let Some(chunk) = self.chunks.get(&pos) else {
+            return;
+        };
+        let mut locked = chunk.lock().unwrap();
+        let mut outdated = false;
+        for side in 0..6 {
+            let opposite = (side + 3) % 6;
+            let Some(neighbor) = pos.neighbor(side).and_then(|pos| self.chunks.get(&pos)) else {
+                continue;
+            };
+            let mut neighbor_chunk = neighbor.lock().unwrap();
+            outdated |= locked.set_border(side, Some(neighbor_chunk.border(opposite)));
+            if neighbor_chunk.set_border(opposite, Some(locked.border(side))) {
+                meshing_pool.mesh_thread(Arc::downgrade(neighbor), neighbor_chunk.pos);
+            }
+        }
+        if outdated {
+            meshing_pool.mesh_thread(Arc::downgrade(chunk), pos);
+        }
+    }
+
+    // loaded chunks touching the chunk at pos
+    fn chunks_around(&self, pos: ChunkPos) -> Vec<Arc<Mutex<Chunk>>> {
+        let mut chunks = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    if (dx, dy, dz) == (0, 0, 0) || (pos.y == 0 && dy < 0) {
+                        continue;
+                    }
+                    let neighbor = ChunkPos {
+                        x: pos.x + dx,
+                        y: (pos.y as i32 + dy) as u32,
+                        z: pos.z + dz,
+                    };
+                    if let Some(chunk) = self.chunks.get(&neighbor) {
+                        chunks.push(chunk.clone());
+                    }
+                }
+            }
+        }
+        chunks
+    }
+
+    // Post generation pass placing the features of a newly generated chunk.
+    // Feature blocks spilling in a loaded neighbor are placed in it right away,
+    // the others are placed when the neighbor is generated, by pulling the
+    // features of the loaded chunks around it.
+    // The blocks of a saved chunk replace the generated ones, the features don't
+    // overwrite its edits.
+    #[profiling::function]
+    fn decorate(
+        neighbors: &[Arc<Mutex<Chunk>>],
+        seed: u64,
+        chunk: &mut Chunk,
+        saved: Option<Chunk>,
+        meshing_pool: &MeshingThreadPool,
+    ) {
+        chunk.features = generation::chunk_features(seed, chunk);
+        if let Some(saved) = saved {
+            chunk.restore_from(saved);
+        }
+        let features = std::mem::take(&mut chunk.features);
+        for feature in &features {
+            generation::place_feature_block(chunk, feature);
+        }
+
+        for neighbor in neighbors {
+            let mut neighbor_chunk = neighbor.lock().unwrap();
+            for feature in &neighbor_chunk.features {
+                generation::place_feature_block(chunk, feature);
+            }
+
+            let mut modified = false;
+            for feature in &features {
+                modified |= generation::place_feature_block(&mut neighbor_chunk, feature);
+            }
+            // a chunk still waiting for its first mesh will see the new blocks anyway
+            if modified && neighbor_chunk.state == ChunkState::Dirty {
+                meshing_pool.mesh_thread(Arc::downgrade(neighbor), neighbor_chunk.pos);
+            }
+        }
+        chunk.features = features;
+    }
+
+    // Write the chunks of the render list to a json file, without their blocks
+    pub fn dump_render_list(&self, path: &str, camera_pos: Vec3) -> Result<()> {
+        let mut chunks: Vec<_> = self
+            .chunks_to_render
+            .iter()
+            .filter_map(|chunk| chunk.upgrade())
+            .map(|chunk| {
+                let chunk = chunk.lock().unwrap();
+                (
+                    chunk.pos,
+                    chunk.state,
+                    chunk.vertices_count,
+                    chunk.indices_count,
+                )
+            })
+            .collect();
+        chunks.sort_by_key(|(pos, ..)| (pos.x, pos.y, pos.z));
+
+        let mut json = String::new();
+        writeln!(json, "{{")?;
+        writeln!(
+            json,
+            "  \"camera\": [{}, {}, {}],",
+            camera_pos.x, camera_pos.y, camera_pos.z
+        )?;
+        writeln!(json, "  \"chunks\": [")?;
+        for (i, (pos, state, vertices, indices)) in chunks.iter().enumerate() {
+            let separator = if i + 1 < chunks.len() { "," } else { "" };
+            writeln!(
+                json,
+                "    {{ \"pos\": [{}, {}, {}], \"state\": \"{:?}\", \"vertices\": {}, \"indices\": {} }}{}",
+                pos.x, pos.y, pos.z, state, vertices, indices, separator
+            )?;
+        }
+        writeln!(json, "  ]")?;
+        writeln!(json, "}}")?;
+
+        std::fs::write(path, json)?;
+        info!("Dumped {} chunks to {}", chunks.len(), path);
+        Ok(())
+    }
+
+    // Discard the blocks of a loaded chunk and generate it again from the seed.
+    // The edits are applied again on top of the new blocks if keep_edits is set.
+    pub fn regenerate(
+        &mut self,
+        pos: ChunkPos,
+        keep_edits: bool,
+        meshing_pool: &MeshingThreadPool,
+    ) -> Result<()> {
+        let chunk = match self.chunks.get(&pos) {
+            Some(chunk) => chunk.clone(),
+            None => return Ok(()),
+        };
+        debug!("Regenerate chunk {:?}", pos);
+
+        let mut generated = self.generate(pos);
+        Self::decorate(
+            &self.chunks_around(pos),
+            self.seed,
+            &mut generated,
+            None,
+            meshing_pool,
+        );
+
+        // the meshing threads can't be meshing it while it is locked, the old
+        // buffer is freed by the thread that remeshes it once the frames are done with it
+        let mut locked = chunk.lock().unwrap();
+        locked.regenerate_from(generated, keep_edits);
+        Self::queue_changed(&chunk, &mut locked, meshing_pool);
+        drop(locked);
+        self.update_borders(pos, meshing_pool);
+        Ok(())
+    }
+
+    // regenerate all the loaded chunks between min and max (inclusive)
+    pub fn regenerate_region(
+        &mut self,
+        min: ChunkPos,
+        max: ChunkPos,
+        keep_edits: bool,
+        meshing_pool: &MeshingThreadPool,
+    ) -> Result<()> {
+        let mut positions: Vec<_> = self
+            .chunks
+            .keys()
+            .filter(|pos| {
+                (min.x..=max.x).contains(&pos.x)
+                    && (min.y..=max.y).contains(&pos.y)
+                    && (min.z..=max.z).contains(&pos.z)
+            })
+            .copied()
+            .collect();
+        positions.sort_by_key(|pos| (pos.x, pos.y, pos.z));
+        for pos in positions {
+            self.regenerate(pos, keep_edits, meshing_pool)?;
+        }
+        Ok(())
+    }
+
+    // block at a world position, None if its chunk is not loaded
+    pub fn get_block(&self, pos: TVec3<i32>) -> Option<Block> {
+        let (chunk_pos, (x, y, z)) = ChunkPos::from_block_pos(pos)?;
+        let chunk = self.chunks.get(&chunk_pos)?;
+        let block = chunk.lock().unwrap().get_block(x, y, z);
+        Some(block)
+    }
+
+    // Change the block at a world position and queue the chunks whose mesh changed.
+    // The change is kept as an edit of the chunk. Returns false if its chunk is not loaded.
+    pub fn set_block(
+        &mut self,
+        pos: TVec3<i32>,
+        block: Block,
+        meshing_pool: &MeshingThreadPool,
+    ) -> bool {
+        let Some((chunk_pos, (x, y, z))) = ChunkPos::from_block_pos(pos) else {
+            return false;
+        };
+        let Some(chunk) = self.chunks.get(&chunk_pos) else {
+            return false;
+        };
+
+        let mut locked = chunk.lock().unwrap();
+        if locked.get_block(x, y, z) == block {
+            return true;
+        }
+        locked.edit_block(x, y, z, block);
+        Self::queue_changed(chunk, &mut locked, meshing_pool);
+        drop(locked);
+        // the neighbors cull their faces against the blocks on the sides
+        if Chunk::is_on_border(x, y, z) {
+            self.update_borders(chunk_pos, meshing_pool);
+        }
+        true
+    }
+
+    // Queue the mesh of a loaded chunk whose blocks changed, locked is the chunk.
+    // A chunk waiting for its first mesh will see the change, the loaded chunks are
+    // Meshing until then but one that wasn't queued yet gets its first mesh now.
+    fn queue_changed(
+        chunk: &Arc<Mutex<Chunk>>,
+        locked: &mut Chunk,
+        meshing_pool: &MeshingThreadPool,
+    ) {
+        match locked.state {
+            ChunkState::Meshing => {}
+            ChunkState::Ready | ChunkState::Dirty => {
+                locked.state = ChunkState::Dirty;
+                meshing_pool.mesh_thread(Arc::downgrade(chunk), locked.pos);
+            }
+            ChunkState::Ungenerated | ChunkState::Generating | ChunkState::Generated => {
+                locked.state = ChunkState::Meshing;
+                meshing_pool.mesh_thread(Arc::downgrade(chunk), locked.pos);
+            }
+        }
+    }
+
+    pub fn chunk_state(&self, pos: ChunkPos) -> ChunkState {
+        self.chunks
+            .get(&pos)
+            .map_or(ChunkState::Ungenerated, |chunk| chunk.lock().unwrap().state)
+    }
+
+    #[inline]
+    pub fn is_chunk_renderable(&self, pos: ChunkPos) -> bool {
+        self.chunk_state(pos) == ChunkState::Ready
+    }
+
     pub fn tick(
         &mut self,
-        data: &RendererData,
         meshing_pool: &MeshingThreadPool,
+        generation_pool: &GenerationThreadPool,
         player_pos: Vec3,
+        frame_count: u64,
     ) -> Result<()> {
-        self.update_visible_chunks(data, meshing_pool, player_pos)?;
+        let start = Instant::now();
+        self.update_visible_chunks(
+            meshing_pool,
+            generation_pool,
+            player_pos,
+            frame_count,
+            start,
+        )?;
+        self.last_tick_time = start.elapsed();
 
         Ok(())
     }
+
+    // Nothing is loaded nor unloaded, the chunks meshed by the threads are still drawn
+    // and the old buffers reused.
+    pub fn tick_paused(&mut self, meshing_pool: &MeshingThreadPool, frame_count: u64) {
+        let start = Instant::now();
+        meshing_pool.recycle_old_buffers(frame_count);
+        self.add_meshed_chunks(meshing_pool);
+        self.last_tick_time = start.elapsed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::vertex::Vertex;
+    use nalgebra_glm::vec2;
+
+    fn world() -> World {
+        World::new(None, LoadMode::Continue).unwrap()
+    }
+
+    #[test]
+    fn vertical_range_clamped() {
+        let distance = VERTICAL_RENDER_DISTANCE as u32;
+        assert_eq!(World::vertical_range(0), WORLD_MIN_CHUNK_Y..distance);
+        assert_eq!(
+            World::vertical_range(WORLD_MAX_CHUNK_Y),
+            WORLD_MAX_CHUNK_Y - distance..WORLD_MAX_CHUNK_Y + 1
+        );
+        assert!(World::vertical_range(WORLD_MAX_CHUNK_Y + distance + 5).is_empty());
+        assert!(World::vertical_range(u32::MAX).is_empty());
+    }
+
+    #[test]
+    fn no_chunks_below_the_world() {
+        let meshing_pool = MeshingThreadPool::new();
+        let generation_pool = GenerationThreadPool::new();
+        for y in [0.0, -1.0, -1000.0] {
+            let mut world = world();
+            world
+                .update_visible_chunks(
+                    &meshing_pool,
+                    &generation_pool,
+                    vec3(0.0, y, 0.0),
+                    0,
+                    Instant::now(),
+                )
+                .unwrap();
+            assert!(world.generating.iter().any(|pos| pos.y == 0));
+            assert!(world
+                .generating
+                .iter()
+                .all(|pos| (WORLD_MIN_CHUNK_Y..=WORLD_MAX_CHUNK_Y).contains(&pos.y)));
+        }
+    }
+
+    // generate and decorate the chunks in order, as the generated chunks are added
+    fn generate_in_order(seed: u64, order: &[ChunkPos]) -> World {
+        let meshing_pool = MeshingThreadPool::new();
+        let mut world = world();
+        world.seed = seed;
+        world.generator = Arc::new(NoiseGenerator::new(seed));
+        for &pos in order {
+            let mut chunk = world.generate(pos);
+            World::decorate(
+                &world.chunks_around(pos),
+                seed,
+                &mut chunk,
+                None,
+                &meshing_pool,
+            );
+            world.chunks.insert(pos, Arc::new(Mutex::new(chunk)));
+        }
+        world
+    }
+
+    fn block_at(world: &World, pos: TVec3<i32>) -> Block {
+        let (chunk, (x, y, z)) = ChunkPos::from_block_pos(pos).unwrap();
+        world.chunks[&chunk].lock().unwrap().get_block(x, y, z)
+    }
+
+    #[test]
+    fn features_across_chunks() {
+        let seed = 42;
+        let world = generate_in_order(seed, &[]);
+        // a chunk with a tree spilling in its neighbors
+        let (pos, features) = (0..64)
+            .flat_map(|x| (2..5).map(move |y| ChunkPos { x, y, z: 0 }))
+            .map(|pos| (pos, generation::chunk_features(seed, &world.generate(pos))))
+            .find(|(pos, features)| {
+                features
+                    .iter()
+                    .any(|f| ChunkPos::from_block_pos(f.pos).unwrap().0 != *pos)
+            })
+            .unwrap();
+        let mut spilled: Vec<_> = features
+            .iter()
+            .map(|f| ChunkPos::from_block_pos(f.pos).unwrap().0)
+            .filter(|other| *other != pos)
+            .collect();
+        spilled.sort_by_key(|p| (p.x, p.y, p.z));
+        spilled.dedup();
+
+        let first = generate_in_order(seed, &[&[pos], &spilled[..]].concat());
+        let last = generate_in_order(seed, &[&spilled[..], &[pos]].concat());
+        for feature in &features {
+            // air is always replaced, the rest was there before the tree
+            assert!(!block_at(&first, feature.pos).is_air());
+            assert_eq!(block_at(&first, feature.pos), block_at(&last, feature.pos));
+        }
+        for (chunk_pos, chunk) in &first.chunks {
+            // no edits, the same blocks give the same data
+            let other = last.chunks[chunk_pos].lock().unwrap();
+            assert_eq!(chunk.lock().unwrap().serialize(), other.serialize());
+        }
+    }
+
+    // what a meshing thread does with a queued chunk, without the upload
+    fn mesh(chunk: &Mutex<Chunk>) {
+        let zero = Vertex::new(vec3(0, 0, 0), vec3(0, 0, 0), 0, 0, vec2(0, 0), 0);
+        let mut vertices = vec![zero; 60000];
+        let mut indices = vec![0; 90000];
+        let mut chunk = chunk.lock().unwrap();
+        let lod = chunk.lod();
+        chunk.mesh(&mut vertices, &mut indices, lod).unwrap();
+        chunk.state = ChunkState::Ready;
+    }
+
+    // change a block of the chunk, whatever it was
+    fn toggle_block(world: &mut World, pos: TVec3<i32>, meshing_pool: &MeshingThreadPool) {
+        let block = match world.get_block(pos).unwrap() {
+            block if block.is_air() => generation::TERRAIN,
+            _ => Block::AIR,
+        };
+        assert!(world.set_block(pos, block, meshing_pool));
+    }
+
+    #[test]
+    fn chunk_lifecycle() {
+        let meshing_pool = MeshingThreadPool::new();
+        let mut generation_pool = GenerationThreadPool::new();
+        generation_pool.start_threads();
+        let mut world = world();
+        world.set_render_distance(1);
+        let pos = ChunkPos { x: 0, y: 3, z: 0 };
+        let player = pos.origin().map(|c| c as f32).add_scalar(8.0);
+        assert_eq!(world.chunk_state(pos), ChunkState::Ungenerated);
+        assert_eq!(Chunk::new(pos).state, ChunkState::Generating);
+        assert_eq!(world.generate(pos).state, ChunkState::Generated);
+
+        let start = Instant::now();
+        while !world.chunks.contains_key(&pos) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+            world
+                .update_visible_chunks(&meshing_pool, &generation_pool, player, 0, Instant::now())
+                .unwrap();
+        }
+        generation_pool.exit_all();
+        // queued for its first mesh, which will see the edits made until then
+        assert_eq!(world.chunk_state(pos), ChunkState::Meshing);
+        assert!(!world.is_chunk_renderable(pos));
+        toggle_block(&mut world, pos.origin(), &meshing_pool);
+        assert_eq!(world.chunk_state(pos), ChunkState::Meshing);
+
+        let chunk = world.chunks[&pos].clone();
+        mesh(&chunk);
+        assert!(world.is_chunk_renderable(pos));
+
+        // the outdated mesh is still drawn until the chunk is remeshed
+        toggle_block(&mut world, pos.origin().add_scalar(1), &meshing_pool);
+        assert_eq!(world.chunk_state(pos), ChunkState::Dirty);
+        assert!(world.chunk_state(pos).has_mesh() && !world.is_chunk_renderable(pos));
+        mesh(&chunk);
+        assert_eq!(world.chunk_state(pos), ChunkState::Ready);
+        world.regenerate(pos, true, &meshing_pool).unwrap();
+        assert_eq!(world.chunk_state(pos), ChunkState::Dirty);
+
+        // a loaded chunk that was never queued gets its first mesh
+        let other = ChunkPos { x: 40, y: 3, z: 0 };
+        world
+            .chunks
+            .insert(other, Arc::new(Mutex::new(world.generate(other))));
+        toggle_block(&mut world, other.origin(), &meshing_pool);
+        assert_eq!(world.chunk_state(other), ChunkState::Meshing);
+    }
 }